@@ -0,0 +1,43 @@
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Embeds the files of `board/` into the binary, so an installed one can
+/// build without a checkout, see `resources.rs`.
+fn main() -> io::Result<()> {
+    let root = Path::new(&env::var("CARGO_MANIFEST_DIR").expect("set by cargo")).join("board");
+    let mut files = vec![];
+    if root.is_dir() {
+        println!("cargo::rerun-if-changed={}", root.display());
+        collect(&root, &mut files)?;
+    } else {
+        // Watching a missing path reruns the script on every build.
+        println!("cargo::rerun-if-changed=build.rs");
+    }
+    files.sort();
+
+    let mut table = String::from("&[\n");
+    for path in files {
+        let name = path.strip_prefix(&root).expect("found in it");
+        table += &format!(
+            "    ({:?}, include_bytes!({path:?})),\n",
+            name.display().to_string()
+        );
+    }
+    table += "]\n";
+    let out = Path::new(&env::var("OUT_DIR").expect("set by cargo")).join("board_files.rs");
+    fs::write(out, table)
+}
+
+fn collect(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect(&path, files)?;
+        } else if path.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,144 @@
+use std::{
+    fs,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{board::Region, dirs, history};
+
+/// `backups` in the output directory.
+pub fn dir() -> PathBuf {
+    dirs::out("backups")
+}
+
+const MANIFEST: &str = "backup.json";
+
+#[derive(Serialize, Deserialize)]
+pub struct SavedRegion {
+    pub name: String,
+    pub offset: u64,
+    /// Size of the region on the device, the file leaves out the trailing
+    /// zeros.
+    pub size: u64,
+    pub file: String,
+}
+
+/// Describes one `backups/<time>/` directory.
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    pub time: u64,
+    pub command: String,
+    pub device: String,
+    pub id: Option<uuid::Uuid>,
+    pub regions: Vec<SavedRegion>,
+}
+
+pub struct Backup {
+    pub dir: PathBuf,
+    pub manifest: Manifest,
+}
+
+/// Copies `regions` of the device in `file` into a new backup directory,
+/// `None` if they hold nothing but zeros.
+pub fn snapshot<F>(
+    file: &mut F,
+    command: &str,
+    device: &Path,
+    id: Option<uuid::Uuid>,
+    regions: &[(&str, Region)],
+) -> io::Result<Option<PathBuf>>
+where
+    F: Read + Seek,
+{
+    let mut saved = vec![];
+    for &(name, region) in regions {
+        let mut data = vec![];
+        file.seek(SeekFrom::Start(region.offset))?;
+        file.take(region.size).read_to_end(&mut data)?;
+        let used = data.iter().rposition(|b| *b != 0).map_or(0, |p| p + 1);
+        data.truncate(used);
+        saved.push((name, region, data));
+    }
+    if saved.iter().all(|(_, _, data)| data.is_empty()) {
+        return Ok(None);
+    }
+
+    let time = history::now();
+    let stamp = history::file_stamp(time);
+    let root = dir();
+    let mut dir = root.join(&stamp);
+    for n in 1.. {
+        if !dir.exists() {
+            break;
+        }
+        dir = root.join(format!("{stamp}-{n}"));
+    }
+    fs::create_dir_all(&dir)?;
+    let mut manifest = Manifest {
+        time,
+        command: command.to_owned(),
+        device: fs::canonicalize(device)
+            .unwrap_or_else(|_| device.to_owned())
+            .display()
+            .to_string(),
+        id,
+        regions: vec![],
+    };
+    for (name, region, data) in saved {
+        let file = format!("{name}.bin");
+        fs::write(dir.join(&file), data)?;
+        manifest.regions.push(SavedRegion {
+            name: name.to_owned(),
+            offset: region.offset,
+            size: region.size,
+            file,
+        });
+    }
+    fs::write(dir.join(MANIFEST), serde_json::to_string_pretty(&manifest)?)?;
+
+    Ok(Some(dir))
+}
+
+/// Every backup, oldest first. Directories without a readable manifest are
+/// skipped.
+pub fn list() -> io::Result<Vec<Backup>> {
+    let entries = match fs::read_dir(dir()) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err),
+    };
+    let mut backups = vec![];
+    for entry in entries {
+        let dir = entry?.path();
+        let Ok(text) = fs::read_to_string(dir.join(MANIFEST)) else {
+            continue;
+        };
+        if let Ok(manifest) = serde_json::from_str(&text) {
+            backups.push(Backup { dir, manifest });
+        }
+    }
+    backups.sort_by(|a, b| (a.manifest.time, &a.dir).cmp(&(b.manifest.time, &b.dir)));
+
+    Ok(backups)
+}
+
+pub fn print(backups: &[Backup]) {
+    for b in backups {
+        let m = &b.manifest;
+        let names = m
+            .regions
+            .iter()
+            .map(|r| r.name.as_str())
+            .collect::<Vec<_>>();
+        let id = m.id.map(|id| id.to_string()).unwrap_or_default();
+        println!(
+            "{} {:<7} {} {} {id}",
+            b.dir.file_name().unwrap_or_default().to_string_lossy(),
+            m.command,
+            m.device,
+            names.join(",")
+        );
+    }
+}
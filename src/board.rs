@@ -0,0 +1,737 @@
+use std::{
+    collections::BTreeMap,
+    fmt, fs, io,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    common, dirs, partitions,
+    spl_header::{self, SplHeaderFormat, SplHeaderError},
+};
+
+pub const BOARDS_PATH: &str = "boards.toml";
+
+#[derive(Debug, Error)]
+pub enum BoardError {
+    #[error("read {BOARDS_PATH}: {0}")]
+    Read(#[from] io::Error),
+    #[error("parse {BOARDS_PATH}: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("board {name} in {BOARDS_PATH}: {err}")]
+    Invalid { name: String, err: toml::de::Error },
+    #[error("board {name} in {BOARDS_PATH} is based on unknown board {base}")]
+    UnknownBase { name: String, base: String },
+    #[error("unknown board {name}, expected one of {known}")]
+    Unknown { name: String, known: String },
+    #[error("board {0} has no u-boot")]
+    NoUboot(String),
+    #[error("board {0} has no u-boot proper image")]
+    NoUbootProper(String),
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Region {
+    pub offset: u64,
+    pub size: u64,
+}
+
+impl Region {
+    pub fn end(&self) -> u64 {
+        self.offset + self.size
+    }
+}
+
+/// Where each piece of firmware goes on a particular kind of medium, in
+/// bytes from the start of the whole device.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiskLayout {
+    /// SPL header followed by the SPL.
+    pub spl: Region,
+    /// OpenSBI, the payload slot of `fw_payload.bin` included.
+    pub opensbi: Region,
+    /// The tau image, inside the OpenSBI region where `fw_payload` expects
+    /// its payload and `fw_jump` jumps to.
+    pub tau: Region,
+    /// `history::IdBlock`, outside of the other regions.
+    pub id: Region,
+    /// A second tau image, known to boot, for the OS side to fall back to.
+    /// Only `update --recovery` writes it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recovery: Option<Region>,
+}
+
+impl DiskLayout {
+    /// The regions by name, `recovery` if there is one.
+    pub fn regions(&self) -> Vec<(&'static str, Region)> {
+        let mut regions = vec![
+            ("spl", self.spl),
+            ("opensbi", self.opensbi),
+            ("tau", self.tau),
+            ("id", self.id),
+        ];
+        regions.extend(self.recovery.map(|r| ("recovery", r)));
+        regions
+    }
+
+    /// End of the last region.
+    pub fn end(&self) -> u64 {
+        [self.spl, self.opensbi, self.tau, self.id]
+            .iter()
+            .chain(&self.recovery)
+            .map(Region::end)
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Byte ranges from `start` to the end of the last region that no region
+    /// covers.
+    pub fn gaps(&self, start: u64) -> Vec<Range<u64>> {
+        let mut regions = vec![self.spl, self.opensbi, self.tau, self.id];
+        regions.extend(self.recovery);
+        regions.sort_by_key(|r| r.offset);
+        let mut gaps = vec![];
+        let mut pos = start;
+        for r in regions {
+            if r.offset > pos {
+                gaps.push(pos..r.offset);
+            }
+            pos = pos.max(r.end());
+        }
+        gaps
+    }
+
+    /// Bytes used in each region by firmware of the given sizes.
+    pub fn usage(&self, spl: usize, opensbi: usize, tau: usize) -> [Usage; 3] {
+        [
+            ("spl", &self.spl, spl),
+            ("opensbi", &self.opensbi, opensbi),
+            ("tau", &self.tau, tau),
+        ]
+        .map(|(name, region, used)| Usage {
+            name,
+            used: used as u64,
+            size: region.size,
+        })
+    }
+}
+
+pub struct Usage {
+    pub name: &'static str,
+    pub used: u64,
+    pub size: u64,
+}
+
+impl Usage {
+    pub fn percent(&self) -> f64 {
+        self.used as f64 * 100.0 / self.size as f64
+    }
+}
+
+const SD_LAYOUT: DiskLayout = DiskLayout {
+    spl: Region {
+        offset: 0x200000,
+        size: 0x200000,
+    },
+    opensbi: Region {
+        offset: 0x400000,
+        size: 0x400000,
+    },
+    tau: Region {
+        offset: 0x400000 + FW_PAYLOAD_OFFSET,
+        size: 0x200000,
+    },
+    id: Region {
+        offset: 0x1ffe00,
+        size: 0x200,
+    },
+    recovery: None,
+};
+
+const EMMC_BOOT_LAYOUT: DiskLayout = DiskLayout {
+    spl: Region {
+        offset: 0x0,
+        size: 0xffe00,
+    },
+    opensbi: Region {
+        offset: 0x100000,
+        size: 0x300000,
+    },
+    tau: Region {
+        offset: 0x100000 + FW_PAYLOAD_OFFSET,
+        size: 0x100000,
+    },
+    id: Region {
+        offset: 0xffe00,
+        size: 0x200,
+    },
+    recovery: None,
+};
+
+/// OpenSBI generic platform places `FW_PAYLOAD_PATH` this far from
+/// `FW_TEXT_START` on rv64.
+pub const FW_PAYLOAD_OFFSET: u64 = 0x200000;
+
+/// Vendor and product id of a USB gadget.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UsbId {
+    pub vid: u16,
+    pub pid: u16,
+}
+
+impl fmt::Display for UsbId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04x}:{:04x}", self.vid, self.pid)
+    }
+}
+
+/// How u-boot on the board exposes its storage over USB.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Usb {
+    /// The `ums` mass storage gadget.
+    pub ums: UsbId,
+    /// The `dfu` gadget.
+    pub dfu: UsbId,
+    /// Name of the `dfu_alt_info` entry covering the tau region.
+    pub dfu_alt: String,
+}
+
+/// u-boot's default gadget ids, `CONFIG_USB_GADGET_VENDOR_NUM` and
+/// `CONFIG_USB_GADGET_PRODUCT_NUM`.
+fn uboot_usb() -> Usb {
+    let id = UsbId {
+        vid: 0x0525,
+        pid: 0xa4a5,
+    };
+    Usb {
+        ums: id,
+        dfu: id,
+        dfu_alt: "tau".to_owned(),
+    }
+}
+
+/// A repository `git_clone` puts into `dirs::clones`, pinned to a revision.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Source {
+    /// Directory in `dirs::clones`.
+    pub name: String,
+    pub repo: String,
+    pub revision: String,
+}
+
+impl Source {
+    fn new(name: &str, repo: &str, revision: &str) -> Self {
+        Source {
+            name: name.to_owned(),
+            repo: repo.to_owned(),
+            revision: revision.to_owned(),
+        }
+    }
+
+    pub fn dir(&self) -> PathBuf {
+        dirs::clones().join(&self.name)
+    }
+
+    pub fn fetch(&self) -> io::Result<PathBuf> {
+        let dir = common::git_clone(dirs::clones(), &self.repo, &self.revision, &self.name)?;
+        common::git_sync(&dir, &self.repo, &self.revision)?;
+        Ok(dir)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Uboot {
+    pub source: Source,
+    /// Applied in order on top of `source`, in the board directory. The
+    /// `git format-patch` series in `<board>/patches` goes after them.
+    pub patches: Vec<String>,
+    pub defconfig: String,
+    /// What goes into the SPL region, in the build directory.
+    pub image: String,
+    /// The u-boot proper FIT image with OpenSBI, in the build directory.
+    #[serde(default)]
+    pub proper: Option<String>,
+}
+
+impl Uboot {
+    /// u-boot builds out of tree, in the output directory.
+    pub fn build_dir(&self) -> PathBuf {
+        dirs::out(format!("{}-build", self.source.name))
+    }
+
+    pub fn spl(&self) -> PathBuf {
+        self.build_dir().join(&self.image)
+    }
+}
+
+/// The header in front of the SPL, `backup_offset` and `version` override
+/// the defaults of the format.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SplHeader {
+    #[serde(
+        serialize_with = "serialize_format",
+        deserialize_with = "deserialize_format"
+    )]
+    pub format: &'static SplHeaderFormat,
+    pub backup_offset: Option<u32>,
+    pub version: Option<u32>,
+}
+
+fn serialize_format<S>(format: &&'static SplHeaderFormat, s: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    s.serialize_str(format.name)
+}
+
+fn deserialize_format<'de, D>(d: D) -> Result<&'static SplHeaderFormat, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    spl_header::by_name(&String::deserialize(d)?).map_err(serde::de::Error::custom)
+}
+
+impl SplHeader {
+    /// The header for `spl` at the start of the SPL region `region`, with
+    /// the backup offset `backup` picks.
+    pub fn header(&self, spl: &[u8], region: Region) -> Result<Vec<u8>, SplHeaderError> {
+        let backup = self
+            .backup_offset
+            .or_else(|| self.backup(region, self.format.size + spl.len()));
+        self.format.header(spl, backup, self.version)
+    }
+
+    /// Where the second copy of an SPL of `len` bytes, header included,
+    /// goes, from the start of the SPL region `region`: `backup_offset`, or
+    /// the default of the format, or the middle of the region if the
+    /// default doesn't fit. `None` if the format doesn't have a backup or
+    /// the copy doesn't fit into the region after the first.
+    pub fn backup(&self, region: Region, len: usize) -> Option<u32> {
+        let (_, default) = self.format.backup_offset?;
+        let fits = |at: u64| at >= len as u64 && at + len as u64 <= region.size;
+        let at = match self.backup_offset {
+            Some(at) => u64::from(at),
+            None if fits(default.into()) => default.into(),
+            None => (region.size / 2) & !0x1ff,
+        };
+        fits(at).then_some(at as u32)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Partition {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ty: uuid::Uuid,
+}
+
+/// The partitions `format` creates over the SPL and OpenSBI regions.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Gpt {
+    pub spl: Partition,
+    pub opensbi: Partition,
+}
+
+impl Gpt {
+    /// Whether a partition of type `ty` holds firmware of this board.
+    pub fn is_firmware(&self, ty: uuid::Uuid) -> bool {
+        ty == self.spl.ty || ty == self.opensbi.ty
+    }
+}
+
+pub const SPL_PARTITION_TYPE: uuid::Uuid = uuid::uuid!("2E54B353-1271-4842-806F-E436D6AF6985");
+pub const UBOOT_PARTITION_TYPE: uuid::Uuid = uuid::uuid!("5B193300-FC78-40CD-8002-E86C45580B47");
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Board {
+    pub name: String,
+    /// Physical address OpenSBI is linked and loaded at.
+    pub fw_text_start: u64,
+    /// Layout of the SD card or the eMMC user area.
+    pub sd: DiskLayout,
+    /// Layout of the eMMC hardware boot partition (`mmcblkXbootY`).
+    pub emmc_boot: DiskLayout,
+    pub usb: Usb,
+    /// Device tree OpenSBI is built with, in the board directory. Compiled
+    /// from the `.dts` of the same name if there is one.
+    pub dtb: String,
+    /// `.dtbo` files in the board directory, applied in order on top of
+    /// `dtb`.
+    #[serde(default)]
+    pub overlays: Vec<String>,
+    /// Header the boot ROM expects in front of the SPL.
+    pub spl_header: SplHeader,
+    pub gpt: Gpt,
+    /// The partition table of `format`, the default one if empty.
+    #[serde(default)]
+    pub partitions: Vec<partitions::Entry>,
+    /// Where the SPL comes from, none for boards booted without one.
+    pub uboot: Option<Uboot>,
+    pub opensbi: Source,
+    /// OpenSBI `PLATFORM`.
+    #[serde(default = "generic_platform")]
+    pub platform: String,
+    /// Extra OpenSBI make variables, before those of `tau-builder.toml` and
+    /// `--opensbi-opt`.
+    #[serde(default)]
+    pub opensbi_vars: BTreeMap<String, String>,
+    /// Which OpenSBI firmware goes into the OpenSBI region.
+    #[serde(default)]
+    pub firmware_mode: FirmwareMode,
+}
+
+fn generic_platform() -> String {
+    "generic".to_owned()
+}
+
+/// How OpenSBI finds tau. Tau is linked at `Board::payload_base` and sits in
+/// its slot of the OpenSBI region either way.
+#[derive(Clone, Copy, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum FirmwareMode {
+    /// `fw_payload`, tau is the payload.
+    #[default]
+    Payload,
+    /// `fw_jump`, jumps to the fixed address of tau.
+    Jump,
+    /// `fw_dynamic`, the SPL tells where tau is.
+    Dynamic,
+}
+
+impl FirmwareMode {
+    pub fn file(self) -> &'static str {
+        match self {
+            FirmwareMode::Payload => "fw_payload.bin",
+            FirmwareMode::Jump => "fw_jump.bin",
+            FirmwareMode::Dynamic => "fw_dynamic.bin",
+        }
+    }
+}
+
+impl Board {
+    /// Physical address the first byte of the tau image runs at.
+    pub fn payload_base(&self) -> u64 {
+        self.fw_text_start + FW_PAYLOAD_OFFSET
+    }
+
+    pub fn dts(&self) -> String {
+        Path::new(&self.dtb)
+            .with_extension("dts")
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    pub fn uboot(&self) -> Result<&Uboot, BoardError> {
+        self.uboot
+            .as_ref()
+            .ok_or_else(|| BoardError::NoUboot(self.name.clone()))
+    }
+
+    pub fn uboot_proper(&self) -> Result<PathBuf, BoardError> {
+        let uboot = self.uboot()?;
+        let proper = uboot
+            .proper
+            .as_ref()
+            .ok_or_else(|| BoardError::NoUbootProper(self.name.clone()))?;
+        Ok(uboot.build_dir().join(proper))
+    }
+
+    /// `file` as the OpenSBI build of the platform leaves it in its clone.
+    pub fn opensbi_firmware(&self, file: &str) -> PathBuf {
+        self.opensbi
+            .dir()
+            .join("build/platform")
+            .join(&self.platform)
+            .join("firmware")
+            .join(file)
+    }
+
+    /// The firmware of `firmware_mode`.
+    pub fn opensbi_image(&self) -> PathBuf {
+        self.opensbi_firmware(self.firmware_mode.file())
+    }
+}
+
+/// The board used without `--board`.
+impl Default for Board {
+    fn default() -> Self {
+        visionfive2()
+    }
+}
+
+pub const DEFAULT: &str = "visionfive2";
+
+/// The board `--qemu` builds for.
+pub const QEMU: &str = "qemu";
+
+pub fn visionfive2() -> Board {
+    Board {
+        name: DEFAULT.to_owned(),
+        fw_text_start: 0x40000000,
+        sd: SD_LAYOUT,
+        emmc_boot: EMMC_BOOT_LAYOUT,
+        usb: uboot_usb(),
+        dtb: "jh7110-starfive-visionfive-2-v1.3b.dtb".to_owned(),
+        overlays: vec![],
+        spl_header: SplHeader {
+            format: &spl_header::JH7110,
+            backup_offset: None,
+            version: None,
+        },
+        gpt: Gpt {
+            spl: Partition {
+                name: "starfive_visionfive_2_u-boot-spl".to_owned(),
+                ty: SPL_PARTITION_TYPE,
+            },
+            opensbi: Partition {
+                name: "starfive_visionfive_2_u-boot".to_owned(),
+                ty: UBOOT_PARTITION_TYPE,
+            },
+        },
+        partitions: vec![],
+        uboot: Some(Uboot {
+            source: Source::new(
+                "u-boot-vf2",
+                "https://github.com/starfive-tech/u-boot.git",
+                "c4c67bb66ae6f41c98537d18cf5c3abc8b97b8e4",
+            ),
+            patches: vec!["jh7110-starfive-visionfive-2-v1.3b-u-boot.patch".to_owned()],
+            defconfig: "starfive_visionfive2_defconfig".to_owned(),
+            image: "spl/u-boot-spl.bin".to_owned(),
+            proper: Some("u-boot.itb".to_owned()),
+        }),
+        opensbi: Source::new(
+            "opensbi-vf2",
+            "https://github.com/starfive-tech/opensbi.git",
+            "1725bd71080960290fdde4499a58c25c09d5c8ee",
+        ),
+        platform: generic_platform(),
+        opensbi_vars: BTreeMap::new(),
+        firmware_mode: FirmwareMode::Payload,
+    }
+}
+
+/// A JH7110 board booting like the VisionFive 2, with its own device tree
+/// and u-boot patch. It gets clones of its own, `suffix` appended to their
+/// names, as both the u-boot and the OpenSBI builds land in paths named
+/// after the clone.
+fn jh7110_variant(name: &str, suffix: &str, dtb: &str, patch: &str) -> Board {
+    let vf2 = visionfive2();
+    let clone = |source: &Source| Source {
+        name: format!("{}-{suffix}", source.name),
+        ..source.clone()
+    };
+    Board {
+        name: name.to_owned(),
+        dtb: dtb.to_owned(),
+        uboot: vf2.uboot.as_ref().map(|uboot| Uboot {
+            source: clone(&uboot.source),
+            patches: vec![patch.to_owned()],
+            ..uboot.clone()
+        }),
+        opensbi: clone(&vf2.opensbi),
+        ..vf2
+    }
+}
+
+/// The v1.2A revision of the VisionFive 2.
+pub fn visionfive2_v1_2a() -> Board {
+    jh7110_variant(
+        "visionfive2-v1.2a",
+        "v1.2a",
+        "jh7110-starfive-visionfive-2-v1.2a.dtb",
+        "jh7110-starfive-visionfive-2-v1.2a-u-boot.patch",
+    )
+}
+
+/// The Milk-V Mars, the image layout and the partition names are those of
+/// the VisionFive 2 it derives from.
+pub fn milkv_mars() -> Board {
+    jh7110_variant(
+        "milkv-mars",
+        "mars",
+        "jh7110-milkv-mars.dtb",
+        "jh7110-milkv-mars-u-boot.patch",
+    )
+}
+
+/// The Pine64 Star64.
+pub fn star64() -> Board {
+    jh7110_variant(
+        "star64",
+        "star64",
+        "jh7110-pine64-star64.dtb",
+        "jh7110-pine64-star64-u-boot.patch",
+    )
+}
+
+/// The Sipeed Lichee Pi 4A. The TH1520 boot ROM only boots the eMMC, from
+/// its boot partition, where `u-boot-with-spl.bin` goes without a header.
+/// The 4 MiB of the boot partition are shared with OpenSBI and tau, the
+/// SD card layout is the one of the VisionFive 2. The RevyOS trees are
+/// followed by branch, pin them in `boards.toml`.
+pub fn lpi4a() -> Board {
+    Board {
+        name: "lpi4a".to_owned(),
+        // DRAM starts at 0.
+        fw_text_start: 0x0,
+        sd: SD_LAYOUT,
+        emmc_boot: DiskLayout {
+            spl: Region {
+                offset: 0x0,
+                size: 0x17fe00,
+            },
+            opensbi: Region {
+                offset: 0x180000,
+                size: 0x280000,
+            },
+            tau: Region {
+                offset: 0x180000 + FW_PAYLOAD_OFFSET,
+                size: 0x80000,
+            },
+            id: Region {
+                offset: 0x17fe00,
+                size: 0x200,
+            },
+            recovery: None,
+        },
+        usb: uboot_usb(),
+        dtb: "th1520-lichee-pi-4a.dtb".to_owned(),
+        overlays: vec![],
+        spl_header: SplHeader {
+            format: &spl_header::NONE,
+            backup_offset: None,
+            version: None,
+        },
+        gpt: Gpt {
+            spl: Partition {
+                name: "lpi4a_u-boot-spl".to_owned(),
+                ty: SPL_PARTITION_TYPE,
+            },
+            opensbi: Partition {
+                name: "lpi4a_opensbi".to_owned(),
+                ty: UBOOT_PARTITION_TYPE,
+            },
+        },
+        partitions: vec![],
+        uboot: Some(Uboot {
+            source: Source::new(
+                "u-boot-lpi4a",
+                "https://github.com/revyos/thead-u-boot.git",
+                "th1520",
+            ),
+            patches: vec![],
+            defconfig: "light_lpi4a_defconfig".to_owned(),
+            image: "u-boot-with-spl.bin".to_owned(),
+            proper: None,
+        }),
+        opensbi: Source::new(
+            "opensbi-lpi4a",
+            "https://github.com/revyos/thead-opensbi.git",
+            "th1520",
+        ),
+        platform: generic_platform(),
+        opensbi_vars: BTreeMap::new(),
+        firmware_mode: FirmwareMode::Payload,
+    }
+}
+
+/// The QEMU virt machine, the drive of `build-tau --drive` is laid out like
+/// the SD card.
+pub fn qemu_virt() -> Board {
+    Board {
+        name: QEMU.to_owned(),
+        fw_text_start: 0x80000000,
+        dtb: "qemu-riscv-virt.dtb".to_owned(),
+        uboot: None,
+        opensbi: Source::new(
+            "opensbi-qemu",
+            "https://github.com/riscv-software-src/opensbi.git",
+            "74434f255873d74e56cc50aa762d1caf24c099f8",
+        ),
+        ..visionfive2()
+    }
+}
+
+/// The built-in boards and those of `boards.toml`.
+pub struct Boards {
+    boards: BTreeMap<String, Board>,
+}
+
+impl Boards {
+    /// Reads `boards.toml` from the current directory, if there is one.
+    /// Every table is a board named by its key, a table with `base` only
+    /// lists what differs from that board.
+    pub fn load() -> Result<Self, BoardError> {
+        let mut boards = [
+            visionfive2(),
+            visionfive2_v1_2a(),
+            milkv_mars(),
+            star64(),
+            lpi4a(),
+            qemu_virt(),
+        ]
+        .into_iter()
+        .map(|b| (b.name.clone(), b))
+        .collect::<BTreeMap<_, _>>();
+        let text = match fs::read_to_string(BOARDS_PATH) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Boards { boards }),
+            Err(err) => return Err(err.into()),
+        };
+        let tables = toml::from_str::<BTreeMap<String, toml::Table>>(&text)?;
+        for (name, mut table) in tables {
+            let mut value = match table.remove("base") {
+                Some(base) => {
+                    let base = base.as_str().unwrap_or_default().to_owned();
+                    let board = boards.get(&base).ok_or_else(|| BoardError::UnknownBase {
+                        name: name.clone(),
+                        base,
+                    })?;
+                    toml::Table::try_from(board).expect("boards serialize")
+                }
+                None => toml::Table::new(),
+            };
+            merge(&mut value, table);
+            value.insert("name".to_owned(), name.clone().into());
+            let board = value.try_into().map_err(|err| BoardError::Invalid {
+                name: name.clone(),
+                err,
+            })?;
+            boards.insert(name, board);
+        }
+        Ok(Boards { boards })
+    }
+
+    pub fn get(&self, name: &str) -> Result<&Board, BoardError> {
+        self.boards.get(name).ok_or_else(|| BoardError::Unknown {
+            name: name.to_owned(),
+            known: self.boards.keys().cloned().collect::<Vec<_>>().join(", "),
+        })
+    }
+}
+
+/// Tables are merged key by key, anything else replaces what is there.
+fn merge(base: &mut toml::Table, over: toml::Table) {
+    for (key, value) in over {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(over)) => merge(base, over),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
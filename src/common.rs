@@ -4,9 +4,25 @@ use std::{
     process::{Command, Output, Stdio},
 };
 
-use object::{Object, ObjectSegment};
+use object::{
+    Endianness, FileKind,
+    elf::{
+        EF_RISCV_RVE, EM_386, EM_AARCH64, EM_ARM, EM_RISCV, EM_X86_64, ET_DYN, ET_EXEC, ET_REL,
+        FileHeader32, FileHeader64, PT_LOAD,
+    },
+    read::elf::{FileHeader, ProgramHeader},
+};
 use thiserror::Error;
 
+use crate::{
+    board::Board,
+    footer::{self, Footer},
+    history::ComponentHash,
+    interrupt,
+    layout::{ComponentKind, Layout},
+    metadata::{self, BuildInfo},
+};
+
 #[derive(Debug, Error)]
 #[error("failed to clone {0}")]
 pub struct GitCloneError(String);
@@ -19,13 +35,19 @@ pub struct ComposeError {
 }
 
 impl ComposeError {
-    pub fn io(path: &str, err: io::Error) -> Self {
+    pub fn io<P>(path: P, err: io::Error) -> Self
+    where
+        P: AsRef<Path>,
+    {
         Self::err(path, ElfError::Read(err))
     }
 
-    pub fn err(path: &str, err: ElfError) -> Self {
+    pub fn err<P>(path: P, err: ElfError) -> Self
+    where
+        P: AsRef<Path>,
+    {
         ComposeError {
-            file: path.to_owned(),
+            file: path.as_ref().display().to_string(),
             err,
         }
     }
@@ -39,8 +61,34 @@ pub enum ElfError {
     ElfParse(#[from] object::read::Error),
     #[error("segment range invalid or truncated")]
     ElfSegment,
-    #[error("output image is too small")]
-    ElfOutputTooSmall,
+    #[error("not an ELF file but {0:?}")]
+    NotElf(FileKind),
+    #[error("is a {0}, expected a 64-bit little endian RISC-V ELF, built for the wrong target?")]
+    Target(String),
+    #[error("is {0}, expected an executable")]
+    NotExecutable(&'static str),
+    #[error("is built for the RV64E base ISA")]
+    Rve,
+    #[error(
+        "entry point {entry:#x} isn't the lowest loaded address {base:#x}, the image is entered at its start"
+    )]
+    Entry { entry: u64, base: u64 },
+    #[error("linked at {actual:#x}, but the layout expects {expected:#x}")]
+    LinkBase { expected: u64, actual: u64 },
+    #[error("{size:#x} bytes don't fit into the {max:#x} bytes slot, {:#x} bytes over", size - max)]
+    TooBig { size: usize, max: usize },
+    #[error("runs to {end:#x} in memory, over {other} at {start:#x}")]
+    Tramples {
+        end: usize,
+        other: String,
+        start: usize,
+    },
+    #[error("slot {offset:#x}+{max:#x} is outside of the {image:#x} bytes image")]
+    Slot {
+        offset: usize,
+        max: usize,
+        image: usize,
+    },
 }
 
 #[derive(Debug, Error)]
@@ -52,6 +100,118 @@ pub enum BuildError {
     Cargo,
 }
 
+/// Writes the file next to `path` and renames it into place, so an
+/// interrupted run never leaves a truncated file behind.
+pub fn write_atomic<P>(path: P, data: &[u8]) -> io::Result<()>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(tmp, path)
+}
+
+/// Looks `name` up in the directories of `PATH`.
+pub fn find_in_path(name: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Parses a decimal or `0x`-prefixed hexadecimal number.
+pub fn parse_u64(s: &str) -> Result<u64, String> {
+    let res = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16),
+        None => s.replace('_', "").parse(),
+    };
+    res.map_err(|err| format!("{s:?}: {err}"))
+}
+
+/// A size in bytes, with an optional binary suffix: `4GiB`, `512M`, `64k`.
+/// Hex sizes take no suffix.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    if s.starts_with("0x") || s.starts_with("0X") {
+        return parse_u64(s);
+    }
+    let digits = s.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let shift = match s[digits.len()..].to_ascii_lowercase().as_str() {
+        "" | "b" => 0,
+        "k" | "kib" => 10,
+        "m" | "mib" => 20,
+        "g" | "gib" => 30,
+        "t" | "tib" => 40,
+        suffix => return Err(format!("{s:?}: unknown unit {suffix:?}")),
+    };
+    parse_u64(digits)?
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("{s:?}: too big"))
+}
+
+pub fn parse_u32(s: &str) -> Result<u32, String> {
+    u32::try_from(parse_u64(s)?).map_err(|err| format!("{s:?}: {err}"))
+}
+
+/// Asks a yes/no question on the terminal, defaulting to no.
+pub fn confirm(prompt: &str) -> io::Result<bool> {
+    use std::io::Write;
+
+    eprint!("{prompt} [y/N] ");
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Asks to type `name` on the terminal to go on.
+pub fn confirm_typed(name: &str) -> io::Result<bool> {
+    use std::io::Write;
+
+    eprint!("type {name} to continue: ");
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim() == name)
+}
+
+/// Marks a build directory while it is being built.
+pub const FAILED: &str = ".failed";
+
+/// Whether the build in `dir` finished, left `output` and was made of
+/// `inputs`, as recorded in the file `record` there. Otherwise `dir` is
+/// emptied and marked failed until `finish_build`, so an interrupted build
+/// is never mistaken for a good one.
+pub fn start_build(
+    dir: &Path,
+    output: &Path,
+    record: &str,
+    inputs: &str,
+    force: bool,
+) -> io::Result<bool> {
+    if !force
+        && !dir.join(FAILED).exists()
+        && output.exists()
+        && fs::read_to_string(dir.join(record)).is_ok_and(|found| found == inputs)
+    {
+        return Ok(true);
+    }
+    if dir.exists() {
+        fs::remove_dir_all(dir)?;
+    }
+    fs::create_dir_all(dir)?;
+    fs::write(dir.join(FAILED), "")?;
+    Ok(false)
+}
+
+/// Records `inputs` of the build in `dir` that succeeded and lifts the
+/// mark `start_build` left.
+pub fn finish_build(dir: &Path, record: &str, inputs: &str) -> io::Result<()> {
+    fs::write(dir.join(record), inputs)?;
+    fs::remove_file(dir.join(FAILED))
+}
+
 pub fn bail<E>(out: &Output, msg: impl Fn() -> E) -> Result<(), E> {
     if !out.status.success() {
         Err(msg())
@@ -60,120 +220,838 @@ pub fn bail<E>(out: &Output, msg: impl Fn() -> E) -> Result<(), E> {
     }
 }
 
-fn elf_to_raw(data: &[u8], image: &mut [u8]) -> Result<(), ElfError> {
-    let file = object::File::parse(data)?;
+/// Where `elf_to_raw` put a segment.
+pub struct Placement {
+    pub vaddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    /// Offset in the slice the ELF was extracted into.
+    pub offset: usize,
+}
 
-    let mut min_addr = u64::MAX;
-    let mut max_addr = 0u64;
-    let mut loads = Vec::new();
+pub struct ElfImage {
+    pub entry: u64,
+    /// The lowest address of the loaded segments.
+    pub base: u64,
+    /// Bytes from `base` to the end of the highest segment, BSS included.
+    pub extent: u64,
+    pub segments: Vec<Placement>,
+}
 
-    for seg in file.segments() {
-        // filter by seg.kind() to keep only PT_LOAD segments
-        let vaddr = seg.address(); // ELF p_vaddr
-        let filesz = seg.data().map(<[u8]>::len).unwrap_or_default() as u64; // p_filesz
-        let memsz = seg.size(); // p_memsz
+/// A `PT_LOAD` segment.
+struct Load<'data> {
+    vaddr: u64,
+    memsz: u64,
+    /// The `p_filesz` bytes of the file.
+    data: &'data [u8],
+}
 
-        if seg.size() == 0 {
-            continue;
+/// Rejects anything but a 64-bit little endian RISC-V executable, the
+/// loader is position independent so it may be a shared object.
+fn check_target<Elf>(header: &Elf, endian: Endianness) -> Result<(), ElfError>
+where
+    Elf: FileHeader<Endian = Endianness>,
+{
+    let machine = header.e_machine(endian);
+    let little = endian == Endianness::Little;
+    if !header.is_class_64() || !little || machine != EM_RISCV {
+        let class = if header.is_class_64() { 64 } else { 32 };
+        let endian = if little { "little" } else { "big" };
+        let machine = match machine {
+            EM_RISCV => "RISC-V".to_owned(),
+            EM_X86_64 => "x86-64".to_owned(),
+            EM_386 => "x86".to_owned(),
+            EM_AARCH64 => "AArch64".to_owned(),
+            EM_ARM => "Arm".to_owned(),
+            machine => format!("machine {machine}"),
+        };
+        let found = format!("{class}-bit {endian} endian ELF for {machine}");
+        return Err(ElfError::Target(found));
+    }
+    match header.e_type(endian) {
+        ET_EXEC | ET_DYN => {}
+        ET_REL => return Err(ElfError::NotExecutable("a relocatable object")),
+        _ => {
+            return Err(ElfError::NotExecutable(
+                "neither an executable nor a shared object",
+            ));
         }
-        // Track overall extent using p_memsz, but we copy only p_filesz bytes.
-        min_addr = cmp::min(min_addr, vaddr);
-        max_addr = cmp::max(max_addr, vaddr.saturating_add(memsz));
-        loads.push((vaddr, filesz, seg));
     }
+    if header.e_flags(endian) & EF_RISCV_RVE != 0 {
+        return Err(ElfError::Rve);
+    }
+    Ok(())
+}
 
-    for (vaddr, filesz, seg) in loads {
-        if filesz == 0 {
-            continue; // pure BSS portion in this segment—already zeroed
-        }
-        let bytes = seg.data().unwrap_or(&[]);
-        let off = (vaddr - min_addr) as usize;
-        let end = off + (filesz as usize);
-        if end > image.len() || bytes.len() < filesz as usize {
-            return Err(ElfError::ElfSegment);
-        }
-        if image.len() < end {
-            return Err(ElfError::ElfOutputTooSmall);
+/// The entry point and the segments. Only the `PT_LOAD` ones end up in the
+/// image, notes, the dynamic table and the like may claim any address.
+fn loads<Elf>(data: &[u8]) -> Result<(u64, Vec<Load<'_>>), ElfError>
+where
+    Elf: FileHeader<Endian = Endianness>,
+{
+    let header = Elf::parse(data)?;
+    let endian = header.endian()?;
+    check_target(header, endian)?;
+    let loads = header
+        .program_headers(endian, data)?
+        .iter()
+        .filter(|ph| ph.p_type(endian) == PT_LOAD && ph.p_memsz(endian).into() != 0)
+        .map(|ph| {
+            Ok(Load {
+                vaddr: ph.p_vaddr(endian).into(),
+                memsz: ph.p_memsz(endian).into(),
+                data: ph.data(endian, data).map_err(|()| ElfError::ElfSegment)?,
+            })
+        })
+        .collect::<Result<_, ElfError>>()?;
+    Ok((header.e_entry(endian).into(), loads))
+}
+
+fn elf_loads(data: &[u8]) -> Result<(u64, Vec<Load<'_>>), ElfError> {
+    match FileKind::parse(data)? {
+        FileKind::Elf32 => loads::<FileHeader32<Endianness>>(data),
+        FileKind::Elf64 => loads::<FileHeader64<Endianness>>(data),
+        kind => Err(ElfError::NotElf(kind)),
+    }
+}
+
+/// Bytes from the lowest address to the last byte copied out of the file.
+fn raw_size(loads: &[Load]) -> usize {
+    let min_addr = loads.iter().map(|l| l.vaddr).min().unwrap_or_default();
+    loads
+        .iter()
+        .map(|l| (l.vaddr - min_addr) as usize + l.data.len())
+        .max()
+        .unwrap_or_default()
+}
+
+/// How many bytes the ELF in `data` takes in the image.
+pub fn elf_size(data: &[u8]) -> Result<usize, ElfError> {
+    Ok(raw_size(&elf_loads(data)?.1))
+}
+
+fn elf_to_raw(data: &[u8], image: &mut [u8]) -> Result<ElfImage, ElfError> {
+    let (entry, loads) = elf_loads(data)?;
+
+    // Track overall extent using p_memsz, but we copy only p_filesz bytes.
+    let min_addr = loads.iter().map(|l| l.vaddr).min().unwrap_or_default();
+    let max_addr = loads
+        .iter()
+        .map(|l| l.vaddr.saturating_add(l.memsz))
+        .max()
+        .unwrap_or_default();
+
+    // BSS past the last byte copied may run over the slot, it isn't part of
+    // the image.
+    let size = raw_size(&loads);
+    if size > image.len() {
+        let max = image.len();
+        return Err(ElfError::TooBig { size, max });
+    }
+    // The gaps between the loads and their BSS are zero whatever the slot
+    // is padded with.
+    let extent = (max_addr.saturating_sub(min_addr) as usize).min(image.len());
+    image[..extent].fill(0);
+
+    let mut segments = Vec::with_capacity(loads.len());
+    for l in loads {
+        let off = (l.vaddr - min_addr) as usize;
+        segments.push(Placement {
+            vaddr: l.vaddr,
+            filesz: l.data.len() as u64,
+            memsz: l.memsz,
+            offset: off,
+        });
+        image[off..off + l.data.len()].copy_from_slice(l.data);
+    }
+
+    Ok(ElfImage {
+        entry,
+        base: min_addr,
+        extent: max_addr.saturating_sub(min_addr),
+        segments,
+    })
+}
+
+fn check_link_base(expected: u64, actual: u64) -> Result<(), ElfError> {
+    if expected != actual {
+        Err(ElfError::LinkBase { expected, actual })
+    } else {
+        Ok(())
+    }
+}
+
+/// Extra cargo options for the tau crates.
+#[derive(Default)]
+pub struct CargoOptions {
+    /// Features of every crate, or of one as `package/feature`.
+    pub features: Vec<String>,
+    /// Appended to the builder's own RUSTFLAGS.
+    pub rustflags: Vec<String>,
+    /// Instead of `release`.
+    pub profile: Option<String>,
+}
+
+/// The directory in `target` cargo writes the output of `profile` into,
+/// `release` by default.
+pub fn profile_dir(profile: Option<&str>) -> &str {
+    match profile {
+        None => "release",
+        Some("dev") => "debug",
+        Some(profile) => profile,
+    }
+}
+
+impl CargoOptions {
+    fn features(&self, package: &str, own: &[&str]) -> Option<String> {
+        let mut features = own.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+        for f in &self.features {
+            match f.split_once('/') {
+                Some((p, f)) if p == package => features.push(f.to_owned()),
+                Some(_) => {}
+                None => features.push(f.clone()),
+            }
         }
-        image[off..end].copy_from_slice(&bytes[..filesz as usize]);
+        (!features.is_empty()).then(|| format!("--features={}", features.join(",")))
     }
+}
 
-    Ok(())
+/// Keeps the checkout and the cargo home out of the panic locations and the
+/// debug info, the image then doesn't depend on where it was built.
+fn remap_path_prefixes() -> Vec<String> {
+    let cargo_home = std::env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| Some(PathBuf::from(std::env::var_os("HOME")?).join(".cargo")));
+    let remaps = [
+        std::env::current_dir().ok().map(|dir| (dir, ".")),
+        cargo_home.map(|dir| (dir, "/cargo")),
+    ];
+    // RUSTFLAGS is split on whitespace.
+    remaps
+        .into_iter()
+        .flatten()
+        .filter(|(from, _)| !from.to_string_lossy().contains(char::is_whitespace))
+        .map(|(from, to)| format!("--remap-path-prefix={}={to}", from.display()))
+        .collect()
+}
+
+fn cargo_build(
+    package: &str,
+    bin: &str,
+    features: &[&str],
+    rustflags: &[&str],
+    layout_dir: &Path,
+    opts: &CargoOptions,
+) -> Result<(), BuildError> {
+    let mut command = Command::new("cargo");
+    command
+        .env("TAU_LAYOUT_DIR", layout_dir)
+        .env(
+            "SOURCE_DATE_EPOCH",
+            metadata::source_date_epoch(Path::new(".")).to_string(),
+        )
+        .arg("build")
+        .arg(format!(
+            "--profile={}",
+            opts.profile.as_deref().unwrap_or("release")
+        ))
+        .arg(format!("--package={package}"))
+        .args(opts.features(package, features))
+        .arg(format!("--bin={bin}"));
+    let remap = remap_path_prefixes();
+    let rustflags = rustflags
+        .iter()
+        .copied()
+        .chain(remap.iter().map(String::as_str))
+        .chain(opts.rustflags.iter().map(String::as_str))
+        .collect::<Vec<_>>();
+    if !rustflags.is_empty() {
+        command.env("RUSTFLAGS", rustflags.join(" "));
+    }
+    let out = interrupt::run(command.stdout(Stdio::inherit()).stderr(Stdio::inherit()))?;
+    bail(&out, || BuildError::Cargo)
 }
 
-pub fn build_tau() -> Result<(), BuildError> {
-    let out = Command::new("cargo")
-        .env("RUSTFLAGS", "-C relocation-model=pie")
-        .args([
-            "build",
-            "--release",
-            "--package=supervisor",
-            "--features=panic-never",
-            "--bin=loader",
-        ])
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .output()?;
-    bail(&out, || BuildError::Cargo)?;
-
-    let out = Command::new("cargo")
-        .args([
-            "build",
-            "--release",
-            "--package=supervisor",
-            "--features=panic-never",
-            "--bin=supervisor",
-        ])
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .output()?;
-    bail(&out, || BuildError::Cargo)?;
-
-    let out = Command::new("cargo")
-        .args(["build", "--release", "--package=system", "--bin=system"])
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .output()?;
-    bail(&out, || BuildError::Cargo)?;
+/// Removes what cargo built for the tau crates with the profile of `opts`,
+/// their dependencies stay.
+pub fn clean_tau(opts: &CargoOptions) -> Result<(), BuildError> {
+    let out = interrupt::run(
+        Command::new("cargo")
+            .arg("clean")
+            .arg(format!(
+                "--profile={}",
+                opts.profile.as_deref().unwrap_or("release")
+            ))
+            .args(["--package=supervisor", "--package=system"])
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit()),
+    )?;
+    bail(&out, || BuildError::Cargo)
+}
+
+/// `layout_dir` holds the output of `memory_map::generate`, the firmware
+/// crates find it through `TAU_LAYOUT_DIR`.
+pub fn build_tau<P>(layout_dir: P, opts: &CargoOptions) -> Result<(), BuildError>
+where
+    P: AsRef<Path>,
+{
+    let layout_dir = fs::canonicalize(layout_dir)?;
+    let pie = ["-C", "relocation-model=pie"];
+    cargo_build(
+        "supervisor",
+        "loader",
+        &["panic-never"],
+        &pie,
+        &layout_dir,
+        opts,
+    )?;
+    cargo_build(
+        "supervisor",
+        "supervisor",
+        &["panic-never"],
+        &[],
+        &layout_dir,
+        opts,
+    )?;
+    cargo_build("system", "system", &[], &[], &layout_dir, opts)?;
 
     Ok(())
 }
 
-pub fn compose_tau_image() -> Result<Vec<u8>, ComposeError> {
-    let mut image = vec![0; 0x40000];
-    const SUPERVISOR_OFFSET: usize = 0x5000;
-    const SYSTEM_OFFSET: usize = 0x10000;
-    let path = "target/riscv64imac-unknown-none-elf/release/loader";
-    let data = fs::read(path).map_err(|err| ComposeError::io(path, err))?;
-    elf_to_raw(&data, &mut image[..SUPERVISOR_OFFSET])
-        .map_err(|err| ComposeError::err(path, err))?;
-    let path = "target/riscv64imac-unknown-none-elf/release/supervisor";
-    let data = fs::read(path).map_err(|err| ComposeError::io(path, err))?;
-    elf_to_raw(&data, &mut image[SUPERVISOR_OFFSET..SYSTEM_OFFSET])
-        .map_err(|err| ComposeError::err(path, err))?;
-    let path = "target/riscv64imac-unknown-none-elf/release/system";
-    let mut file = fs::File::open(path).map_err(|err| ComposeError::io(path, err))?;
-    io::copy(&mut file, &mut &mut image[SYSTEM_OFFSET..])
-        .map_err(|err| ComposeError::io(path, err))?;
+/// A component as placed by `compose_tau_image`.
+pub struct Placed {
+    pub name: String,
+    pub offset: usize,
+    pub max_size: usize,
+    /// Bytes occupied from `offset`.
+    pub len: usize,
+    /// Empty for raw components.
+    pub segments: Vec<Placement>,
+}
+
+pub struct Composed {
+    /// `size` bytes, followed by the footer.
+    pub image: Vec<u8>,
+    pub size: usize,
+    pub components: Vec<Placed>,
+}
+
+impl Composed {
+    /// `layout` with the image and the slot of the component grown as they
+    /// were to compose this image.
+    pub fn layout(&self, layout: &Layout) -> Layout {
+        let mut layout = layout.clone();
+        layout.size = self.size;
+        for (c, p) in layout.components.iter_mut().zip(&self.components) {
+            c.max_size = p.max_size;
+        }
+        layout
+    }
+
+    /// Human and script readable map of the image: component ranges, copied
+    /// segments, padding and the first bytes of every component.
+    pub fn dump_layout(&self) -> String {
+        use std::fmt::Write;
+
+        let mut out = String::new();
+        let mut placed = self.components.iter().collect::<Vec<_>>();
+        placed.sort_by_key(|p| p.offset);
+        let mut pos = 0;
+        for p in placed {
+            if p.offset > pos {
+                let _ = writeln!(out, "padding {pos:#08x}..{:#08x}", p.offset);
+            }
+            let end = p.offset + p.len;
+            let slot_end = p.offset + p.max_size;
+            let _ = writeln!(
+                out,
+                "component {} {:#08x}..{end:#08x} slot {:#08x}..{slot_end:#08x}",
+                p.name, p.offset, p.offset
+            );
+            for seg in &p.segments {
+                let _ = writeln!(
+                    out,
+                    "  segment vaddr {:#010x} filesz {:#07x} memsz {:#07x} at {:#08x}",
+                    seg.vaddr,
+                    seg.filesz,
+                    seg.memsz,
+                    p.offset + seg.offset
+                );
+            }
+            let head = &self.image[p.offset..cmp::min(p.offset + 16, self.image.len())];
+            let hex = head.iter().map(|b| format!("{b:02x}")).collect::<Vec<_>>();
+            let _ = writeln!(out, "  head {}", hex.join(" "));
+            pos = cmp::max(pos, end);
+        }
+        let size = self.size;
+        if pos < size {
+            let _ = writeln!(out, "padding {pos:#08x}..{size:#08x}");
+        }
+        let _ = writeln!(out, "footer {size:#08x}..{:#08x}", self.image.len());
+
+        out
+    }
+}
+
+/// Unless `check_address` is false, every position-independent ELF component
+/// must be linked at 0 and every other ELF component must be linked at the
+/// address its slot of the image runs at on `board`. An ELF component at
+/// offset 0 must be entered at its lowest address. The build metadata is
+/// written last, if the layout has a slot for it, followed by the footer.
+pub fn compose_tau_image(
+    layout: &Layout,
+    board: &Board,
+    check_address: bool,
+) -> Result<Composed, ComposeError> {
+    let mut image = vec![layout.fill; layout.size];
+    let mut components = Vec::with_capacity(layout.components.len());
+    let growable = layout.growable();
+    for (i, component) in layout.components.iter().enumerate() {
+        let path = &component.path;
+        let (offset, mut max) = (component.offset, component.max_size);
+        let grows = growable == Some(i);
+        if grows {
+            let grow_to = layout.grow_to.unwrap_or_default();
+            max = max.max(grow_to.saturating_sub(offset));
+            image.resize(image.len().max(offset + max), layout.fill);
+        }
+        let slot = offset
+            .checked_add(max)
+            .and_then(|end| image.get_mut(offset..end))
+            .ok_or_else(|| {
+                let image = layout.size;
+                ComposeError::err(path, ElfError::Slot { offset, max, image })
+            })?;
+        let data = fs::read(path).map_err(|err| ComposeError::io(path, err))?;
+        let (len, segments) = match component.kind.detect(&data) {
+            ComponentKind::Elf => {
+                let elf = elf_to_raw(&data, slot).map_err(|err| ComposeError::err(path, err))?;
+                if check_address {
+                    let expected = if component.position_independent {
+                        0
+                    } else {
+                        board.payload_base() + offset as u64
+                    };
+                    check_link_base(expected, elf.base)
+                        .map_err(|err| ComposeError::err(path, err))?;
+                }
+                // OpenSBI jumps to the start of the image
+                if offset == 0 && elf.entry != elf.base {
+                    let (entry, base) = (elf.entry, elf.base);
+                    return Err(ComposeError::err(path, ElfError::Entry { entry, base }));
+                }
+                (elf.extent as usize, elf.segments)
+            }
+            ComponentKind::Raw | ComponentKind::Payload | ComponentKind::Auto => {
+                let size = data.len();
+                if size > max {
+                    return Err(ComposeError::err(path, ElfError::TooBig { size, max }));
+                }
+                slot[..size].copy_from_slice(&data);
+                (size, vec![])
+            }
+        };
+        slot[len.min(max)..].fill(component.fill);
+        if grows {
+            // Only as far as the component needs.
+            max = len
+                .max(component.max_size)
+                .next_multiple_of(component.align)
+                .min(max);
+            image.truncate(image.len().min(offset + max).max(layout.size));
+        }
+        components.push(Placed {
+            name: component.name.clone(),
+            offset,
+            max_size: max,
+            len,
+            segments,
+        });
+    }
+
+    // The slots don't overlap, but the BSS of an ELF isn't bounded by its
+    // slot and would clear the next component once running.
+    for (i, (p, component)) in components.iter().zip(&layout.components).enumerate() {
+        let end = p.offset + p.len;
+        if let Some((_, other)) = components
+            .iter()
+            .enumerate()
+            .find(|(j, q)| *j != i && q.offset < end && p.offset < q.offset + q.max_size)
+        {
+            let err = ElfError::Tramples {
+                end,
+                other: other.name.clone(),
+                start: other.offset,
+            };
+            return Err(ComposeError::err(&component.path, err));
+        }
+    }
+
+    if let Some(slot) = layout.metadata {
+        let hashes = components
+            .iter()
+            .map(|p| {
+                let len = p.len.min(p.max_size);
+                ComponentHash::new(&p.name, &image[p.offset..p.offset + len])
+            })
+            .collect();
+        let block = BuildInfo::new(&board.name, hashes).to_bytes();
+        let (size, max) = (block.len(), slot.max_size);
+        if size > max {
+            return Err(ComposeError::err(
+                "metadata",
+                ElfError::TooBig { size, max },
+            ));
+        }
+        image[slot.offset..slot.offset + size].copy_from_slice(&block);
+    }
+    let entries = components
+        .iter()
+        .map(|p| {
+            let len = p.len.min(p.max_size);
+            footer::Entry::new(&p.name, p.offset, &image[p.offset..p.offset + len])
+        })
+        .collect();
+    let footer = Footer::new(&image, entries).to_bytes();
+    let size = image.len();
+    image.extend_from_slice(&footer);
 
-    Ok(image)
+    Ok(Composed {
+        image,
+        size,
+        components,
+    })
 }
 
 pub fn git_clone<P>(path: P, link: &str, rev: &str, name: &str) -> io::Result<PathBuf>
 where
     P: AsRef<Path>,
 {
+    // clone next to the destination, so an interrupted clone is not reused
     let new = path.as_ref().to_owned().join(name);
     if !new.exists() {
         fs::create_dir_all(&path)?;
-        let out = Command::new("git")
-            .current_dir(&path)
-            .args(["clone", "--depth=1", "--rev", rev, link, name])
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit())
-            .output()?;
+        let tmp = format!("{name}.tmp");
+        let tmp_path = path.as_ref().join(&tmp);
+        if tmp_path.exists() {
+            fs::remove_dir_all(&tmp_path)?;
+        }
+        let out = interrupt::run(
+            Command::new("git")
+                .current_dir(&path)
+                .args(["clone", "--depth=1", "--rev", rev, link, &tmp])
+                .stdout(Stdio::inherit())
+                .stderr(Stdio::inherit()),
+        )?;
         bail(&out, || io::Error::other(name.to_string()))?;
+        fs::rename(tmp_path, &new)?;
     }
 
     Ok(new)
 }
+
+/// Whether `rev` names a commit rather than a branch or a tag.
+pub fn is_commit(rev: &str) -> bool {
+    rev.len() == 40 && rev.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Moves the clone in `dir` to `rev` of `link` if it was cloned from
+/// another repository or has another commit checked out. A branch or a tag
+/// is only fetched again when the repository changes.
+pub fn git_sync<P>(dir: P, link: &str, rev: &str) -> io::Result<()>
+where
+    P: AsRef<Path>,
+{
+    let dir = dir.as_ref();
+    let query = |args: &[&str]| {
+        let out = Command::new("git").current_dir(dir).args(args).output()?;
+        io::Result::Ok(String::from_utf8_lossy(&out.stdout).trim().to_owned())
+    };
+    let origin = query(&["remote", "get-url", "origin"])?;
+    if origin == link && (!is_commit(rev) || query(&["rev-parse", "HEAD"])? == rev) {
+        return Ok(());
+    }
+    eprintln!("switching {} to {rev} of {link}", dir.display());
+    for args in [
+        &["fetch", "--depth=1", link, rev][..],
+        &["checkout", "--force", "FETCH_HEAD"],
+        &["remote", "set-url", "origin", link],
+    ] {
+        let out = interrupt::run(
+            Command::new("git")
+                .current_dir(dir)
+                .args(args)
+                .stdout(Stdio::inherit())
+                .stderr(Stdio::inherit()),
+        )?;
+        bail(&out, || io::Error::other(format!("git {}", args[0])))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use object::elf::{PT_DYNAMIC, PT_LOAD, PT_NOTE};
+
+    use std::fs;
+
+    use super::{ElfError, compose_tau_image, elf_size, elf_to_raw};
+    use crate::{
+        board, footer,
+        layout::Layout,
+        size,
+        testing::{self, Segment, TempDir},
+    };
+
+    const BASE: u64 = 0x40205000;
+
+    /// The default three components and a raw `config` after them, built
+    /// in a directory of its own named after `test`. The config is `config`
+    /// bytes long.
+    fn four_components(test: &str, config: usize) -> (TempDir, Layout) {
+        let tmp = TempDir::new(test);
+        let dir = tmp.path();
+        let loader = testing::elf(
+            0,
+            &[Segment {
+                kind: PT_LOAD,
+                vaddr: 0,
+                data: &[0x13; 0x20],
+                memsz: 0x20,
+            }],
+        );
+        let supervisor = with_extra(&[]);
+        let files: [(&str, &[u8]); 4] = [
+            ("loader", &loader),
+            ("supervisor", &supervisor),
+            ("system", &[0x55; 0x100]),
+            ("config", &vec![0xc0; config]),
+        ];
+        for (name, data) in files {
+            fs::write(dir.join(name), data).unwrap();
+        }
+        let toml = format!(
+            r#"
+            size = 0x41000
+
+            [[component]]
+            name = "loader"
+            path = "{dir}/loader"
+            offset = 0x0
+            max_size = 0x5000
+            type = "elf"
+            position_independent = true
+
+            [[component]]
+            name = "supervisor"
+            path = "{dir}/supervisor"
+            offset = 0x5000
+            max_size = 0xb000
+            type = "elf"
+
+            [[component]]
+            name = "system"
+            path = "{dir}/system"
+            offset = 0x10000
+            max_size = 0x30000
+            type = "raw"
+
+            [[component]]
+            name = "config"
+            path = "{dir}/config"
+            offset = 0x40000
+            max_size = 0x1000
+            type = "raw"
+            "#,
+            dir = dir.display()
+        );
+        (tmp, toml::from_str(&toml).unwrap())
+    }
+
+    #[test]
+    fn fourth_raw_component() {
+        let (_dir, layout) = four_components("fourth-raw-component", 0x800);
+        layout.validate().unwrap();
+        let composed = compose_tau_image(&layout, &board::visionfive2(), true).unwrap();
+        assert_eq!(composed.size, 0x41000);
+        let image = &composed.image;
+        assert!(image[0x40000..0x40800].iter().all(|b| *b == 0xc0));
+        assert!(image[0x40800..0x41000].iter().all(|b| *b == 0));
+        assert!(image[0x10000..0x10100].iter().all(|b| *b == 0x55));
+
+        let footer::Check::Ok(footer) = footer::check(image, composed.size) else {
+            panic!("no valid footer");
+        };
+        let names = footer.entries.iter().map(|e| e.name.as_str());
+        assert!(names.eq(["loader", "supervisor", "system", "config"]));
+        assert_eq!(
+            (footer.entries[3].offset, footer.entries[3].size),
+            (0x40000, 0x800)
+        );
+
+        let manifest = composed.layout(&layout).to_toml().unwrap();
+        let reread: Layout = toml::from_str(&manifest).unwrap();
+        assert_eq!(reread.components[3].offset, 0x40000);
+        let budgets = size::budgets(&layout).unwrap();
+        assert_eq!(budgets[3].name, "config");
+        assert_eq!((budgets[3].used, budgets[3].max_size), (0x800, 0x1000));
+    }
+
+    /// Scripts parse `--dump-layout`, its format only changes on purpose.
+    #[test]
+    fn dump_layout() {
+        const EXPECTED: &str = "\
+component loader 0x000000..0x000020 slot 0x000000..0x005000
+  segment vaddr 0x00000000 filesz 0x00020 memsz 0x00020 at 0x000000
+  head 13 13 13 13 13 13 13 13 13 13 13 13 13 13 13 13
+padding 0x000020..0x005000
+component supervisor 0x005000..0x005140 slot 0x005000..0x010000
+  segment vaddr 0x40205000 filesz 0x00010 memsz 0x00010 at 0x005000
+  segment vaddr 0x40205100 filesz 0x00008 memsz 0x00040 at 0x005100
+  head 01 01 01 01 01 01 01 01 01 01 01 01 01 01 01 01
+padding 0x005140..0x010000
+component system 0x010000..0x010100 slot 0x010000..0x040000
+  head 55 55 55 55 55 55 55 55 55 55 55 55 55 55 55 55
+padding 0x010100..0x040000
+component config 0x040000..0x040800 slot 0x040000..0x041000
+  head c0 c0 c0 c0 c0 c0 c0 c0 c0 c0 c0 c0 c0 c0 c0 c0
+padding 0x040800..0x041000
+footer 0x041000..0x0410a8
+";
+        let (_dir, layout) = four_components("dump-layout", 0x800);
+        let composed = compose_tau_image(&layout, &board::visionfive2(), true).unwrap();
+        assert_eq!(composed.dump_layout(), EXPECTED);
+    }
+
+    #[test]
+    fn oversized_raw_component() {
+        let (_dir, layout) = four_components("oversized-raw-component", 0x1001);
+        let err = compose_tau_image(&layout, &board::visionfive2(), true)
+            .err()
+            .expect("config doesn't fit its slot");
+        assert!(matches!(
+            err.err,
+            ElfError::TooBig {
+                size: 0x1001,
+                max: 0x1000
+            }
+        ));
+    }
+
+    /// Two loads with BSS after the second, and the extra segments before
+    /// and after them.
+    fn with_extra(extra: &[Segment]) -> Vec<u8> {
+        let mut segments = vec![
+            Segment {
+                kind: PT_LOAD,
+                vaddr: BASE,
+                data: &[1; 0x10],
+                memsz: 0x10,
+            },
+            Segment {
+                kind: PT_LOAD,
+                vaddr: BASE + 0x100,
+                data: &[2; 0x8],
+                memsz: 0x40,
+            },
+        ];
+        segments.extend_from_slice(extra);
+        testing::elf(BASE, &segments)
+    }
+
+    #[test]
+    fn non_load_segments_are_ignored() {
+        let note = [0xee; 0x20];
+        let extra = [
+            Segment {
+                kind: PT_NOTE,
+                vaddr: 0x3,
+                data: &note,
+                memsz: 0x20,
+            },
+            Segment {
+                kind: PT_DYNAMIC,
+                vaddr: 0xffff_ffff_0000_0007,
+                data: &note,
+                memsz: 0x20,
+            },
+        ];
+        let plain = with_extra(&[]);
+        let elf = with_extra(&extra);
+        assert_eq!(elf_size(&elf).unwrap(), 0x108);
+        assert_eq!(elf_size(&elf).unwrap(), elf_size(&plain).unwrap());
+
+        let (mut image, mut expected) = (vec![0; 0x200], vec![0; 0x200]);
+        let raw = elf_to_raw(&elf, &mut image).unwrap();
+        elf_to_raw(&plain, &mut expected).unwrap();
+        assert_eq!(image, expected);
+        assert_eq!((raw.base, raw.entry, raw.extent), (BASE, BASE, 0x140));
+        assert_eq!(raw.segments.len(), 2);
+        assert!(!image.contains(&0xee));
+    }
+
+    #[test]
+    fn empty_loads_are_ignored() {
+        let extra = [Segment {
+            kind: PT_LOAD,
+            vaddr: 0x1,
+            data: &[],
+            memsz: 0,
+        }];
+        let (mut image, mut expected) = (vec![0; 0x200], vec![0; 0x200]);
+        let raw = elf_to_raw(&with_extra(&extra), &mut image).unwrap();
+        elf_to_raw(&with_extra(&[]), &mut expected).unwrap();
+        assert_eq!(raw.base, BASE);
+        assert_eq!(image, expected);
+    }
+
+    #[test]
+    fn fill_leaves_bss_zeroed() {
+        let (_dir, mut layout) = four_components("fill-leaves-bss-zeroed", 0x800);
+        layout.fill = 0xff;
+        layout.components.iter_mut().for_each(|c| c.fill = 0xff);
+        let composed = compose_tau_image(&layout, &board::visionfive2(), true).unwrap();
+        let supervisor = &composed.image[0x5000..0x10000];
+        assert!(supervisor[..0x10].iter().all(|b| *b == 1));
+        assert!(supervisor[0x10..0x100].iter().all(|b| *b == 0));
+        assert!(supervisor[0x100..0x108].iter().all(|b| *b == 2));
+        assert!(supervisor[0x108..0x140].iter().all(|b| *b == 0));
+        assert!(supervisor[0x140..].iter().all(|b| *b == 0xff));
+    }
+
+    #[test]
+    fn interrupted_build_is_redone() {
+        let tmp = TempDir::new("build");
+        let dir = tmp.path();
+        let spl = dir.join("spl/u-boot-spl.bin");
+        let inputs = "cbf43926 board/u-boot.patch\n";
+        // An earlier build was interrupted after it configured the tree and
+        // left an SPL of a build before that.
+        fs::create_dir_all(spl.parent().unwrap()).unwrap();
+        fs::write(&spl, b"old spl").unwrap();
+        fs::write(dir.join(".config"), b"half").unwrap();
+        fs::write(dir.join(".patches"), inputs).unwrap();
+        fs::write(dir.join(super::FAILED), b"").unwrap();
+
+        assert!(!super::start_build(dir, &spl, ".patches", inputs, false).unwrap());
+        let left = fs::read_dir(dir).unwrap().map(|e| e.unwrap().file_name());
+        assert_eq!(left.collect::<Vec<_>>(), [super::FAILED]);
+        // Interrupted again, the build is still not taken.
+        fs::create_dir_all(spl.parent().unwrap()).unwrap();
+        fs::write(&spl, b"partial spl").unwrap();
+        assert!(!super::start_build(dir, &spl, ".patches", inputs, false).unwrap());
+
+        fs::create_dir_all(spl.parent().unwrap()).unwrap();
+        fs::write(&spl, b"spl").unwrap();
+        super::finish_build(dir, ".patches", inputs).unwrap();
+        assert!(super::start_build(dir, &spl, ".patches", inputs, false).unwrap());
+        // Other patches, or --force-rebuild, start over.
+        assert!(!super::start_build(dir, &spl, ".patches", "", false).unwrap());
+        fs::create_dir_all(spl.parent().unwrap()).unwrap();
+        fs::write(&spl, b"spl").unwrap();
+        super::finish_build(dir, ".patches", inputs).unwrap();
+        assert!(!super::start_build(dir, &spl, ".patches", inputs, true).unwrap());
+        assert!(!spl.exists());
+    }
+}
@@ -0,0 +1,99 @@
+use std::{collections::BTreeMap, fs, io};
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{
+    board::{self, Board, BoardError},
+    hooks::Hooks,
+    layout::{self, Layout, LayoutError},
+    partitions::{self, DataPartition},
+    size,
+};
+
+pub const CONFIG_PATH: &str = "tau-builder.toml";
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("read {CONFIG_PATH}: {0}")]
+    Read(#[from] io::Error),
+    #[error("parse {CONFIG_PATH}: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("{0}")]
+    Board(#[from] BoardError),
+    #[error("{0}")]
+    Layout(#[from] LayoutError),
+    #[error("the layout is set in both {CONFIG_PATH} and {}", layout::LAYOUT_PATH)]
+    LayoutTwice,
+    #[error(
+        "[data] in {CONFIG_PATH} only adds to the default partitions, list it in [[partitions]]"
+    )]
+    DataAndPartitions,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub layout: Layout,
+    pub hooks: Hooks,
+    /// Extra OpenSBI make variables, see `--opensbi-opt`.
+    pub opensbi: BTreeMap<String, String>,
+    pub size: size::Limits,
+    pub data: Option<DataPartition>,
+    pub partitions: Vec<partitions::Entry>,
+    /// Picked with `--board` from `boards.toml`, not from this file.
+    #[serde(skip)]
+    pub board: Board,
+    /// What `--qemu` builds for.
+    #[serde(skip)]
+    pub qemu: Board,
+    /// `--dry-run`, not from this file.
+    #[serde(skip)]
+    pub dry_run: bool,
+}
+
+impl Config {
+    /// Reads `tau-builder.toml` from the current directory, if there is one,
+    /// with the board `board` of the registry and the layout of
+    /// `layout.toml`, if there is one.
+    pub fn load(board: &str) -> Result<Self, ConfigError> {
+        let table = match fs::read_to_string(CONFIG_PATH) {
+            Ok(text) => toml::from_str::<toml::Table>(&text)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => toml::Table::new(),
+            Err(err) => return Err(err.into()),
+        };
+        let has_layout = table.contains_key("layout");
+        let mut config = table.try_into::<Config>()?;
+        if let Some(layout) = Layout::load()? {
+            if has_layout {
+                return Err(ConfigError::LayoutTwice);
+            }
+            config.layout = layout;
+        }
+        config.layout.validate()?;
+        if config.data.is_some() && !config.partitions.is_empty() {
+            return Err(ConfigError::DataAndPartitions);
+        }
+        let boards = board::Boards::load()?;
+        config.board = boards.get(board)?.clone();
+        config.qemu = boards.get(board::QEMU)?.clone();
+        Ok(config)
+    }
+
+    /// What `format` lays down: `[[partitions]]` of this file, else those
+    /// of the board, else the default table.
+    pub fn partitions(&self) -> Vec<partitions::Entry> {
+        if !self.partitions.is_empty() {
+            self.partitions.clone()
+        } else if !self.board.partitions.is_empty() {
+            self.board.partitions.clone()
+        } else {
+            partitions::default_table(&self.board.gpt, self.data.as_ref())
+        }
+    }
+
+    /// The QEMU board if `qemu`, the selected one otherwise.
+    pub fn board(&self, qemu: bool) -> &Board {
+        if qemu { &self.qemu } else { &self.board }
+    }
+}
@@ -0,0 +1,302 @@
+use std::{
+    fs,
+    io::{self, Read, Seek, SeekFrom},
+    ops::Range,
+    path::Path,
+};
+
+use gpt::disk::LogicalBlockSize;
+use object::{Object, ObjectSection};
+use serde::Serialize;
+
+use crate::{
+    board::DiskLayout,
+    disk,
+    layout::{ComponentKind, Layout},
+};
+
+#[derive(Serialize)]
+pub struct SectionDelta {
+    pub section: &'static str,
+    pub a: u64,
+    pub b: u64,
+}
+
+#[derive(Serialize)]
+pub struct ComponentDiff {
+    pub name: String,
+    pub same: bool,
+    /// Bytes up to the last non-zero byte of the slot.
+    pub used_a: usize,
+    pub used_b: usize,
+    pub crc_a: u32,
+    pub crc_b: u32,
+    pub sections: Vec<SectionDelta>,
+}
+
+const SECTIONS: [&str; 4] = [".text", ".rodata", ".data", ".bss"];
+
+fn slot(image: &[u8], offset: usize, max_size: usize) -> &[u8] {
+    let start = offset.min(image.len());
+    let end = offset.saturating_add(max_size).min(image.len());
+    &image[start..end]
+}
+
+fn used(data: &[u8]) -> usize {
+    data.iter().rposition(|b| *b != 0).map_or(0, |pos| pos + 1)
+}
+
+fn section_sizes<P>(path: P) -> Option<[u64; 4]>
+where
+    P: AsRef<Path>,
+{
+    let data = fs::read(path).ok()?;
+    let file = object::File::parse(&*data).ok()?;
+    Some(SECTIONS.map(|name| file.section_by_name(name).map_or(0, |s| s.size())))
+}
+
+/// Compares two composed images slot by slot. For differing ELF components,
+/// section sizes are compared if `elf_a` and `elf_b` hold the ELFs, each
+/// named after its component.
+pub fn diff_images(
+    layout: &Layout,
+    a: &[u8],
+    b: &[u8],
+    elf_a: Option<&Path>,
+    elf_b: Option<&Path>,
+) -> Vec<ComponentDiff> {
+    let c = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+    layout
+        .components
+        .iter()
+        .map(|component| {
+            let sa = slot(a, component.offset, component.max_size);
+            let sb = slot(b, component.offset, component.max_size);
+            let same = sa == sb;
+            let mut sections = vec![];
+            if let (false, ComponentKind::Elf | ComponentKind::Auto, Some(elf_a), Some(elf_b)) =
+                (same, component.kind, elf_a, elf_b)
+            {
+                let sizes_a = section_sizes(elf_a.join(&component.name));
+                let sizes_b = section_sizes(elf_b.join(&component.name));
+                if let (Some(sizes_a), Some(sizes_b)) = (sizes_a, sizes_b) {
+                    sections = SECTIONS
+                        .into_iter()
+                        .zip(sizes_a.into_iter().zip(sizes_b))
+                        .filter(|(_, (a, b))| a != b)
+                        .map(|(section, (a, b))| SectionDelta { section, a, b })
+                        .collect();
+                }
+            }
+            ComponentDiff {
+                name: component.name.clone(),
+                same,
+                used_a: used(sa),
+                used_b: used(sb),
+                crc_a: c.checksum(sa),
+                crc_b: c.checksum(sb),
+                sections,
+            }
+        })
+        .collect()
+}
+
+fn human(bytes: u64) -> String {
+    if bytes < 1024 {
+        format!("{bytes} B")
+    } else {
+        format!("{:.1} KiB", bytes as f64 / 1024.0)
+    }
+}
+
+pub fn print(diffs: &[ComponentDiff], json: bool) -> io::Result<()> {
+    if json {
+        serde_json::to_writer_pretty(io::stdout(), diffs)?;
+        println!();
+        return Ok(());
+    }
+    for diff in diffs {
+        let status = if diff.same { "same" } else { "differs" };
+        println!(
+            "{:<12} {status:<8} {:#x} / {:#x} bytes, crc32 {:08x} / {:08x}",
+            diff.name, diff.used_a, diff.used_b, diff.crc_a, diff.crc_b
+        );
+        for delta in &diff.sections {
+            let change = if delta.b > delta.a {
+                format!("grew by {}", human(delta.b - delta.a))
+            } else {
+                format!("shrank by {}", human(delta.a - delta.b))
+            };
+            println!("  {} {} {change}", diff.name, delta.section);
+        }
+    }
+
+    Ok(())
+}
+
+/// Named byte ranges of a disk of `block` sized sectors laid out as
+/// `layout`, in order: the GPT, the firmware regions with the tau image split
+/// into its components, and whatever lies between them.
+pub fn disk_regions(
+    layout: &DiskLayout,
+    tau: &Layout,
+    block: LogicalBlockSize,
+) -> Vec<(String, Range<u64>)> {
+    let gpt_end = disk::gpt_end(block);
+    let mut regions = vec![
+        ("gpt".to_owned(), 0..gpt_end),
+        ("spl".to_owned(), layout.spl.offset..layout.spl.end()),
+        (
+            "opensbi".to_owned(),
+            layout.opensbi.offset..layout.tau.offset,
+        ),
+        ("opensbi".to_owned(), layout.tau.end()..layout.opensbi.end()),
+        ("id".to_owned(), layout.id.offset..layout.id.end()),
+    ];
+    let mut pos = layout.tau.offset;
+    let mut components = tau.components.iter().collect::<Vec<_>>();
+    components.sort_by_key(|c| c.offset);
+    for c in components {
+        let start = layout.tau.offset + c.offset as u64;
+        if start > pos {
+            regions.push(("tau".to_owned(), pos..start));
+        }
+        let end = (start + c.max_size as u64).min(layout.tau.end());
+        regions.push((format!("tau/{}", c.name), start..end));
+        pos = pos.max(end);
+    }
+    if pos < layout.tau.end() {
+        regions.push(("tau".to_owned(), pos..layout.tau.end()));
+    }
+    regions.extend(
+        layout
+            .gaps(gpt_end)
+            .into_iter()
+            .map(|gap| ("unused".to_owned(), gap)),
+    );
+    regions.retain(|(_, range)| !range.is_empty());
+    regions.sort_by_key(|(_, range)| range.start);
+    regions
+}
+
+#[derive(Serialize)]
+pub struct RegionDiff {
+    pub name: String,
+    pub start: u64,
+    pub end: u64,
+    /// Bytes that differ.
+    pub bytes: u64,
+    /// Differing byte ranges, adjacent bytes merged.
+    pub changed: Vec<Range<u64>>,
+}
+
+/// Reads `range`, past the end of the file reads as zeros.
+fn read_range<F>(file: &mut F, range: &Range<u64>, buf: &mut Vec<u8>) -> io::Result<()>
+where
+    F: Read + Seek,
+{
+    buf.clear();
+    file.seek(SeekFrom::Start(range.start))?;
+    file.take(range.end - range.start).read_to_end(buf)?;
+    buf.resize((range.end - range.start) as usize, 0);
+    Ok(())
+}
+
+/// Compares two disks or images region by region.
+pub fn diff_disks<A, B>(
+    a: &mut A,
+    b: &mut B,
+    regions: &[(String, Range<u64>)],
+) -> io::Result<Vec<RegionDiff>>
+where
+    A: Read + Seek,
+    B: Read + Seek,
+{
+    const BLOCK: u64 = 0x100000;
+
+    let (mut buf_a, mut buf_b) = (vec![], vec![]);
+    let mut diffs = vec![];
+    for (name, range) in regions {
+        let mut changed: Vec<Range<u64>> = vec![];
+        let mut pos = range.start;
+        while pos < range.end {
+            let chunk = pos..(pos + BLOCK).min(range.end);
+            read_range(a, &chunk, &mut buf_a)?;
+            read_range(b, &chunk, &mut buf_b)?;
+            for (i, _) in buf_a
+                .iter()
+                .zip(&buf_b)
+                .enumerate()
+                .filter(|(_, (x, y))| x != y)
+            {
+                let at = chunk.start + i as u64;
+                match changed.last_mut() {
+                    Some(last) if last.end == at => last.end += 1,
+                    _ => changed.push(at..(at + 1)),
+                }
+            }
+            pos = chunk.end;
+        }
+        diffs.push(RegionDiff {
+            name: name.clone(),
+            start: range.start,
+            end: range.end,
+            bytes: changed.iter().map(|r| r.end - r.start).sum(),
+            changed,
+        });
+    }
+
+    Ok(diffs)
+}
+
+pub fn print_disks(diffs: &[RegionDiff], json: bool) -> io::Result<()> {
+    // Enough to see where a change is without flooding the terminal.
+    const MAX_RANGES: usize = 8;
+
+    if json {
+        serde_json::to_writer_pretty(io::stdout(), diffs)?;
+        println!();
+        return Ok(());
+    }
+    for diff in diffs {
+        let span = format!("{:#010x}..{:#010x}", diff.start, diff.end);
+        if diff.changed.is_empty() {
+            println!("{:<16} {span} same", diff.name);
+            continue;
+        }
+        println!(
+            "{:<16} {span} differs, {} in {} range(s)",
+            diff.name,
+            human(diff.bytes),
+            diff.changed.len()
+        );
+        for r in diff.changed.iter().take(MAX_RANGES) {
+            println!("  {:#010x}..{:#010x}", r.start, r.end);
+        }
+        if diff.changed.len() > MAX_RANGES {
+            println!("  ... {} more", diff.changed.len() - MAX_RANGES);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use gpt::disk::LogicalBlockSize;
+
+    use crate::{board, disk, layout::Layout};
+
+    #[test]
+    fn gpt_of_4k_disk() {
+        let layout = board::visionfive2().sd;
+        for block in [LogicalBlockSize::Lb512, LogicalBlockSize::Lb4096] {
+            let regions = super::disk_regions(&layout, &Layout::default(), block);
+            let gpt_end = disk::gpt_end(block);
+            assert_eq!(regions[0], ("gpt".to_owned(), 0..gpt_end));
+            // Nothing else claims the end of the GPT.
+            assert!(regions[1..].iter().all(|(_, range)| range.start >= gpt_end));
+            assert!(regions.windows(2).all(|w| w[0].1.end <= w[1].1.start));
+        }
+    }
+}
@@ -0,0 +1,50 @@
+//! Where the builder keeps what it creates. Artifacts and build trees go to
+//! `--out-dir`, the clones to the user's cache, where `cargo clean` can't
+//! reach them.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+pub const DEFAULT_OUT_DIR: &str = "target/tau-builder";
+
+static OUT_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Called by `main` before anything is read or written.
+pub fn set_out_dir(dir: PathBuf) {
+    let _ = OUT_DIR.set(dir);
+}
+
+pub fn out_dir() -> &'static Path {
+    OUT_DIR
+        .get()
+        .map_or(Path::new(DEFAULT_OUT_DIR), PathBuf::as_path)
+}
+
+/// `name` in the output directory.
+pub fn out<P>(name: P) -> PathBuf
+where
+    P: AsRef<Path>,
+{
+    out_dir().join(name)
+}
+
+/// The composed tau image.
+pub fn image() -> PathBuf {
+    out("tau")
+}
+
+/// The layout the image was composed with, every offset resolved.
+pub fn manifest() -> PathBuf {
+    out("tau.toml")
+}
+
+/// `$XDG_CACHE_HOME/tau-builder`, `~/.cache` by default, or `clones` in the
+/// output directory without a home.
+pub fn clones() -> PathBuf {
+    std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| Some(PathBuf::from(std::env::var_os("HOME")?).join(".cache")))
+        .map_or_else(|| out("clones"), |cache| cache.join("tau-builder"))
+}
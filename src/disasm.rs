@@ -0,0 +1,102 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use object::{Object, ObjectSegment, ObjectSymbol};
+use thiserror::Error;
+
+use crate::{common, opensbi};
+
+#[derive(Debug, Error)]
+pub enum DisasmError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("no riscv objdump found, install binutils for one of {0}")]
+    NoObjdump(String),
+    #[error("objdump failed: {0}")]
+    Objdump(String),
+}
+
+/// Symbols of an ELF component and the address its first loaded byte is
+/// linked at.
+pub struct Symbols {
+    pub base: u64,
+    pub names: BTreeMap<u64, String>,
+}
+
+impl Symbols {
+    /// `None` if `data` is not an object file.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let file = object::File::parse(data).ok()?;
+        let base = file
+            .segments()
+            .filter(|seg| seg.size() != 0)
+            .map(|seg| seg.address())
+            .min()?;
+        let names = file
+            .symbols()
+            .filter(|sym| sym.is_definition() && sym.kind() != object::SymbolKind::Section)
+            .filter_map(|sym| Some((sym.address(), sym.name().ok()?.to_owned())))
+            .filter(|(_, name)| !name.is_empty() && !name.starts_with(".L"))
+            .collect();
+        Some(Symbols { base, names })
+    }
+}
+
+/// The first riscv capable objdump in `PATH`.
+pub fn objdump() -> Result<PathBuf, DisasmError> {
+    let names = opensbi::GNU_PREFIXES
+        .iter()
+        .map(|prefix| format!("{prefix}objdump"))
+        .collect::<Vec<_>>();
+    names
+        .iter()
+        .find_map(|name| common::find_in_path(name))
+        .ok_or_else(|| DisasmError::NoObjdump(names.join(", ")))
+}
+
+/// Disassembles `data` as if loaded at `vma`, printing a label in front of
+/// every address one of `symbols` points to.
+pub fn print(
+    objdump: &Path,
+    name: &str,
+    data: &[u8],
+    vma: u64,
+    symbols: Option<&Symbols>,
+) -> Result<(), DisasmError> {
+    let tmp = std::env::temp_dir().join(format!("tau-builder-disasm-{name}.bin"));
+    fs::write(&tmp, data)?;
+    let out = Command::new(objdump)
+        .args(["-D", "-b", "binary", "-m", "riscv:rv64"])
+        .arg(format!("--adjust-vma={vma:#x}"))
+        .arg(&tmp)
+        .output();
+    let _ = fs::remove_file(&tmp);
+    let out = out?;
+    if !out.status.success() {
+        let err = String::from_utf8_lossy(&out.stderr);
+        return Err(DisasmError::Objdump(err.trim().to_owned()));
+    }
+
+    println!("{name} at {vma:#x}, {:#x} bytes", data.len());
+    let text = String::from_utf8_lossy(&out.stdout);
+    // Instruction lines look like `  80200000:\t00000297 \tauipc\tt0,0x0`.
+    let lines = text.lines().skip_while(|line| !line.ends_with(">:"));
+    for line in lines.skip(1) {
+        let addr = line
+            .split_once(':')
+            .and_then(|(addr, _)| u64::from_str_radix(addr.trim(), 16).ok());
+        if let (Some(addr), Some(symbols)) = (addr, symbols)
+            && let Some(name) = symbols.names.get(&addr)
+        {
+            println!("\n<{name}>:");
+        }
+        println!("{line}");
+    }
+    println!();
+
+    Ok(())
+}
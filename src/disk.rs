@@ -0,0 +1,1959 @@
+use std::{
+    fs,
+    io::{self, IsTerminal, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Instant,
+};
+
+use gpt::disk::LogicalBlockSize;
+use thiserror::Error;
+
+use crate::{
+    board::{DiskLayout, Gpt, Region, Usage},
+    interrupt, partitions,
+};
+
+#[derive(Debug, Error)]
+pub enum DiskError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("{len:#x} bytes at {offset:#x} do not fit into the device of {size:#x} bytes")]
+    DoesNotFit { offset: u64, len: u64, size: u64 },
+    #[error("verification failed at {0:#x}")]
+    Verify(u64),
+    #[error(
+        "raw device flashing is only supported on Linux; use an image file and a tool like balenaEtcher"
+    )]
+    UnsupportedHost,
+    #[error("sudo: {0}")]
+    Sudo(String),
+    #[error("{0} region is {1:.1}% full")]
+    Full(&'static str, f64),
+    #[error("{0} doesn't cover the tau region")]
+    Partition(String),
+    #[error(
+        "interrupted, data is written and synced up to {0:#x}, continue with --resume-from {0:#x}"
+    )]
+    Interrupted(u64),
+    #[error("gpt: {0}")]
+    Gpt(String),
+    #[error("mbr: {0}")]
+    Mbr(String),
+}
+
+/// End of the primary GPT, the protective MBR, the header and 128 entries,
+/// on a disk of `block` sized logical blocks.
+pub const fn gpt_end(block: LogicalBlockSize) -> u64 {
+    2 * block.as_u64() + 128 * 128
+}
+
+/// `gpt_end` of a disk of 512 byte blocks, any image file.
+pub const GPT_END: u64 = gpt_end(LogicalBlockSize::Lb512);
+
+/// `--sector-size`, 512 or 4096.
+pub fn parse_block_size(s: &str) -> Result<LogicalBlockSize, String> {
+    let size = s.parse::<u64>().map_err(|e| e.to_string())?;
+    LogicalBlockSize::try_from(size).map_err(|_| format!("{size}: expected 512 or 4096"))
+}
+
+/// Something firmware is written to: a device, an image file, or an image
+/// in memory.
+pub trait Target: Read + Write + Seek {
+    /// Makes the written data durable.
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Tells the device `range` is unused, if it supports that.
+    fn discard(&mut self, range: &Range<u64>) -> io::Result<()> {
+        let _ = range;
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Frees `range` so it reads back as zeros, if the target can do that
+    /// without writing them. The target grows to `range.end` if it is
+    /// shorter.
+    fn punch_hole(&mut self, range: &Range<u64>) -> io::Result<()> {
+        let _ = range;
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+impl Target for fs::File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
+
+    fn discard(&mut self, range: &Range<u64>) -> io::Result<()> {
+        discard(self, range)
+    }
+
+    fn punch_hole(&mut self, range: &Range<u64>) -> io::Result<()> {
+        punch_hole(self, range)
+    }
+}
+
+/// How `open` opens a device for writing.
+#[derive(Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum IoMode {
+    /// `direct` for block devices, `buffered` for image files.
+    #[default]
+    Auto,
+    /// With `O_DIRECT`, past the page cache, so a write fails as soon as
+    /// the medium does and syncing has nothing left to flush.
+    Direct,
+    /// Through the page cache.
+    Buffered,
+}
+
+/// Alignment of the buffers of `O_DIRECT` reads and writes, enough for any
+/// logical block size.
+const DIRECT_ALIGN: usize = 4096;
+
+/// A device or image file opened by `open`. With `O_DIRECT`, `direct` is
+/// the block size reads and writes are widened to, the blocks only partly
+/// written being read first.
+#[derive(Debug)]
+pub struct Device {
+    file: fs::File,
+    direct: Option<u64>,
+    pos: u64,
+}
+
+/// Opens `path` for reading and writing as `mode` asks.
+pub fn open<P>(path: P, mode: IoMode) -> io::Result<Device>
+where
+    P: AsRef<Path>,
+{
+    let direct = match mode {
+        IoMode::Auto => is_device(&path),
+        IoMode::Direct => true,
+        IoMode::Buffered => false,
+    };
+    let mut options = fs::OpenOptions::new();
+    options.read(true).write(true);
+    if direct {
+        direct_flag(&mut options)?;
+    }
+    let file = options.open(&path)?;
+    let direct = direct.then(|| block_size(&path).map_or(DIRECT_ALIGN as u64, |b| b.as_u64()));
+    Ok(Device {
+        file,
+        direct,
+        pos: 0,
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn direct_flag(options: &mut fs::OpenOptions) -> io::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    options.custom_flags(libc::O_DIRECT);
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn direct_flag(options: &mut fs::OpenOptions) -> io::Result<()> {
+    let _ = options;
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "O_DIRECT is only supported on Linux, use --io buffered",
+    ))
+}
+
+/// `len` zeros at an address aligned to `DIRECT_ALIGN`, in `buf`.
+fn aligned(buf: &mut Vec<u8>, len: usize) -> &mut [u8] {
+    *buf = vec![0; len + DIRECT_ALIGN];
+    let at = buf.as_ptr().align_offset(DIRECT_ALIGN);
+    &mut buf[at..at + len]
+}
+
+/// Reads into `buf` at `offset` up to its end or the end of the file,
+/// returning how much was read.
+fn read_full_at(file: &fs::File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::unix::fs::FileExt;
+
+    let mut done = 0;
+    while done < buf.len() {
+        match file.read_at(&mut buf[done..], offset + done as u64) {
+            Ok(0) => break,
+            Ok(n) => done += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(done)
+}
+
+impl Device {
+    pub fn sync_all(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    /// The whole blocks of `block` bytes around `len` bytes at the position.
+    fn span(&self, len: usize, block: u64) -> Range<u64> {
+        let start = self.pos / block * block;
+        start..(self.pos + len as u64).next_multiple_of(block)
+    }
+
+    fn read_direct(&mut self, out: &mut [u8], block: u64) -> io::Result<usize> {
+        let len = out.len().min(BLOCK);
+        let span = self.span(len, block);
+        let mut buf = vec![];
+        let buf = aligned(&mut buf, (span.end - span.start) as usize);
+        let skip = (self.pos - span.start) as usize;
+        let n = read_full_at(&self.file, buf, span.start)?
+            .saturating_sub(skip)
+            .min(len);
+        out[..n].copy_from_slice(&buf[skip..skip + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn write_direct(&mut self, data: &[u8], block: u64) -> io::Result<usize> {
+        use std::os::unix::fs::FileExt;
+
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let data = &data[..data.len().min(BLOCK)];
+        let span = self.span(data.len(), block);
+        let mut buf = vec![];
+        let buf = aligned(&mut buf, (span.end - span.start) as usize);
+        let (skip, block) = ((self.pos - span.start) as usize, block as usize);
+        let last = buf.len() - block;
+        if skip != 0 {
+            read_full_at(&self.file, &mut buf[..block], span.start)?;
+        }
+        // unless the head block read above is the last one too
+        if skip + data.len() != buf.len() && (skip == 0 || last != 0) {
+            read_full_at(&self.file, &mut buf[last..], span.start + last as u64)?;
+        }
+        buf[skip..skip + data.len()].copy_from_slice(data);
+        self.file.write_all_at(buf, span.start)?;
+        self.pos += data.len() as u64;
+        Ok(data.len())
+    }
+}
+
+impl Read for Device {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.direct {
+            Some(block) => self.read_direct(buf, block),
+            None => self.file.read(buf),
+        }
+    }
+}
+
+impl Write for Device {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.direct {
+            Some(block) => self.write_direct(buf, block),
+            None => self.file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for Device {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        if self.direct.is_none() {
+            return self.file.seek(pos);
+        }
+        let (base, delta) = match pos {
+            SeekFrom::Start(n) => (n, 0),
+            SeekFrom::Current(d) => (self.pos, d),
+            SeekFrom::End(d) => (self.file.seek(SeekFrom::End(0))?, d),
+        };
+        self.pos = base
+            .checked_add_signed(delta)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        Ok(self.pos)
+    }
+}
+
+impl Target for Device {
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    fn discard(&mut self, range: &Range<u64>) -> io::Result<()> {
+        discard(&self.file, range)
+    }
+
+    fn punch_hole(&mut self, range: &Range<u64>) -> io::Result<()> {
+        punch_hole(&mut self.file, range)
+    }
+}
+
+impl Target for io::Cursor<Vec<u8>> {
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<T> Target for &mut T
+where
+    T: Target,
+{
+    fn sync(&mut self) -> io::Result<()> {
+        (**self).sync()
+    }
+
+    fn discard(&mut self, range: &Range<u64>) -> io::Result<()> {
+        (**self).discard(range)
+    }
+
+    fn punch_hole(&mut self, range: &Range<u64>) -> io::Result<()> {
+        (**self).punch_hole(range)
+    }
+}
+
+#[cfg(unix)]
+pub fn is_device<P>(path: P) -> bool
+where
+    P: AsRef<Path>,
+{
+    use std::os::unix::fs::FileTypeExt;
+
+    fs::metadata(path)
+        .is_ok_and(|m| m.file_type().is_block_device() || m.file_type().is_char_device())
+}
+
+#[cfg(not(unix))]
+pub fn is_device<P>(path: P) -> bool
+where
+    P: AsRef<Path>,
+{
+    let _ = path;
+    false
+}
+
+/// Fails early if `path` is a device and this host can't flash it, otherwise
+/// escalates privileges when writing to a device. Regular image files are
+/// written as the current user on every host.
+pub fn prepare_target<P>(path: P) -> Result<(), DiskError>
+where
+    P: AsRef<Path>,
+{
+    if !is_device(&path) {
+        return Ok(());
+    }
+    if cfg!(not(target_os = "linux")) {
+        return Err(DiskError::UnsupportedHost);
+    }
+    sudo::escalate_if_needed().map_err(|err| DiskError::Sudo(err.to_string()))?;
+
+    Ok(())
+}
+
+/// Returns the kernel name of the device if `path` is an eMMC hardware boot
+/// partition, like `mmcblk0boot0`.
+#[cfg(target_os = "linux")]
+pub fn emmc_boot_partition<P>(path: P) -> Option<String>
+where
+    P: AsRef<Path>,
+{
+    let path = fs::canonicalize(path).ok()?;
+    let name = path.file_name()?.to_str()?;
+    let (_, rest) = name.strip_prefix("mmcblk")?.split_once("boot")?;
+    if !rest.is_empty() && rest.bytes().all(|b| b.is_ascii_digit()) {
+        Some(name.to_owned())
+    } else {
+        None
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn emmc_boot_partition<P>(path: P) -> Option<String>
+where
+    P: AsRef<Path>,
+{
+    let _ = path;
+    None
+}
+
+/// Clears `/sys/block/<dev>/force_ro` and restores the original value when
+/// dropped, so the boot partition doesn't stay writable even on panic.
+pub struct ForceRoGuard {
+    path: PathBuf,
+    original: String,
+}
+
+impl ForceRoGuard {
+    pub fn unlock(dev: &str) -> io::Result<Self> {
+        let path = Path::new("/sys/block").join(dev).join("force_ro");
+        let original = fs::read_to_string(&path)?.trim().to_owned();
+        fs::write(&path, "0")?;
+        Ok(ForceRoGuard { path, original })
+    }
+}
+
+impl Drop for ForceRoGuard {
+    fn drop(&mut self) {
+        if let Err(err) = fs::write(&self.path, &self.original) {
+            eprintln!("failed to restore {}: {err}", self.path.display());
+        }
+    }
+}
+
+/// If `path` is a partition block device, returns the device of the whole
+/// disk and the offset of the partition on it.
+#[cfg(target_os = "linux")]
+pub fn partition_of<P>(path: P) -> Option<(PathBuf, u64)>
+where
+    P: AsRef<Path>,
+{
+    let dev = fs::canonicalize(path).ok()?;
+    let sys = fs::canonicalize(Path::new("/sys/class/block").join(dev.file_name()?)).ok()?;
+    if !sys.join("partition").exists() {
+        return None;
+    }
+    let start = fs::read_to_string(sys.join("start")).ok()?;
+    let start = start.trim().parse::<u64>().ok()? * 512;
+    let parent = Path::new("/dev").join(sys.parent()?.file_name()?);
+    Some((parent, start))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn partition_of<P>(path: P) -> Option<(PathBuf, u64)>
+where
+    P: AsRef<Path>,
+{
+    let _ = path;
+    None
+}
+
+/// What sysfs tells about a block device.
+pub struct DeviceInfo {
+    /// Vendor and model, or the name of an MMC card.
+    pub model: Option<String>,
+    pub size: u64,
+    pub removable: bool,
+}
+
+impl std::fmt::Display for DeviceInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}, {:#x} bytes ({:.1} GiB), {}",
+            self.model.as_deref().unwrap_or("unknown model"),
+            self.size,
+            self.size as f64 / (1u64 << 30) as f64,
+            if self.removable { "removable" } else { "fixed" }
+        )
+    }
+}
+
+/// The model, size and removability of the block device at `path`, of the
+/// disk it is on for a partition, `None` for anything else.
+#[cfg(target_os = "linux")]
+pub fn device_info<P>(path: P) -> Option<DeviceInfo>
+where
+    P: AsRef<Path>,
+{
+    if !is_device(&path) {
+        return None;
+    }
+    let dev = fs::canonicalize(path).ok()?;
+    let sys = fs::canonicalize(Path::new("/sys/class/block").join(dev.file_name()?)).ok()?;
+    let read = |path: PathBuf| {
+        let s = fs::read_to_string(path).ok()?;
+        Some(s.trim().to_owned()).filter(|s| !s.is_empty())
+    };
+    let size = read(sys.join("size"))?.parse::<u64>().ok()? * 512;
+    let disk = if sys.join("partition").exists() {
+        sys.parent()?
+    } else {
+        &sys
+    };
+    let model = [
+        read(disk.join("device/vendor")),
+        read(disk.join("device/model")),
+    ]
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>();
+    let model = if model.is_empty() {
+        read(disk.join("device/name"))
+    } else {
+        Some(model.join(" "))
+    };
+    Some(DeviceInfo {
+        model,
+        size,
+        removable: read(disk.join("removable")).as_deref() == Some("1"),
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn device_info<P>(path: P) -> Option<DeviceInfo>
+where
+    P: AsRef<Path>,
+{
+    let _ = path;
+    None
+}
+
+/// The logical block size the block device at `path` reports, of the disk
+/// it is on for a partition, `None` for anything else.
+#[cfg(target_os = "linux")]
+pub fn block_size<P>(path: P) -> Option<LogicalBlockSize>
+where
+    P: AsRef<Path>,
+{
+    if !is_device(&path) {
+        return None;
+    }
+    let dev = fs::canonicalize(path).ok()?;
+    let sys = fs::canonicalize(Path::new("/sys/class/block").join(dev.file_name()?)).ok()?;
+    let disk = if sys.join("partition").exists() {
+        sys.parent()?
+    } else {
+        &sys
+    };
+    let size = fs::read_to_string(disk.join("queue/logical_block_size")).ok()?;
+    LogicalBlockSize::try_from(size.trim().parse::<u64>().ok()?).ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn block_size<P>(path: P) -> Option<LogicalBlockSize>
+where
+    P: AsRef<Path>,
+{
+    let _ = path;
+    None
+}
+
+/// Why writing the block device at `path` could take the running system
+/// down, or destroy a system disk: mounted or swap partitions, devices
+/// stacked on it, the root filesystem, or an EFI System Partition on a disk
+/// without the `firmware` partitions.
+#[cfg(target_os = "linux")]
+pub fn in_use<P>(path: P, firmware: &Gpt) -> Vec<String>
+where
+    P: AsRef<Path>,
+{
+    use std::os::unix::fs::MetadataExt;
+
+    if !is_device(&path) {
+        return vec![];
+    }
+    let Some(name) = fs::canonicalize(&path)
+        .ok()
+        .and_then(|dev| Some(dev.file_name()?.to_str()?.to_owned()))
+    else {
+        return vec![];
+    };
+    let sys = |name: &str| Path::new("/sys/class/block").join(name);
+    // The device and its partitions.
+    let mut names = vec![name.clone()];
+    for entry in fs::read_dir(sys(&name)).into_iter().flatten().flatten() {
+        let child = entry.file_name().to_string_lossy().into_owned();
+        if child.starts_with(&name) && entry.path().join("partition").exists() {
+            names.push(child);
+        }
+    }
+    let ours = |source: &str| {
+        fs::canonicalize(source)
+            .ok()
+            .and_then(|p| Some(p.file_name()?.to_str()?.to_owned()))
+            .is_some_and(|n| names.contains(&n))
+    };
+
+    let mut found = vec![];
+    for line in fs::read_to_string("/proc/mounts")
+        .unwrap_or_default()
+        .lines()
+    {
+        let mut fields = line.split_whitespace();
+        if let (Some(source), Some(target)) = (fields.next(), fields.next())
+            && ours(source)
+        {
+            found.push(format!("{source} is mounted at {target}"));
+        }
+    }
+    let swaps = fs::read_to_string("/proc/swaps").unwrap_or_default();
+    for line in swaps.lines().skip(1) {
+        if let Some(source) = line.split_whitespace().next()
+            && ours(source)
+        {
+            found.push(format!("{source} is used as swap"));
+        }
+    }
+    // `major:minor` of the filesystem mounted at `/`, `/dev/root` in
+    // `/proc/mounts` doesn't always resolve.
+    let root = fs::metadata("/").map(|m| {
+        let dev = m.dev();
+        let major = ((dev >> 32) & 0xffff_f000) | ((dev >> 8) & 0xfff);
+        let minor = ((dev >> 12) & 0xffff_ff00) | (dev & 0xff);
+        format!("{major}:{minor}")
+    });
+    for n in &names {
+        if let Ok(root) = &root
+            && fs::read_to_string(sys(n).join("dev")).is_ok_and(|dev| dev.trim() == root)
+        {
+            found.push(format!("{n} holds the root filesystem"));
+        }
+        for holder in fs::read_dir(sys(n).join("holders"))
+            .into_iter()
+            .flatten()
+            .flatten()
+        {
+            found.push(format!(
+                "{n} is used by {}",
+                holder.file_name().to_string_lossy()
+            ));
+        }
+    }
+
+    let disk = fs::File::open(&path).ok().and_then(|file| {
+        gpt::GptConfig::new()
+            .writable(false)
+            .logical_block_size(block_size(&path).unwrap_or(LogicalBlockSize::Lb512))
+            .open_from_device(file)
+            .ok()
+    });
+    if let Some(disk) = disk {
+        let types = disk
+            .partitions()
+            .values()
+            .map(|p| p.part_type_guid.guid)
+            .collect::<Vec<_>>();
+        if types.contains(&gpt::partition_types::EFI.guid)
+            && !types.iter().any(|ty| firmware.is_firmware(*ty))
+        {
+            found.push(format!("{name} has an EFI System Partition"));
+        }
+    }
+
+    found
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn in_use<P>(path: P, firmware: &Gpt) -> Vec<String>
+where
+    P: AsRef<Path>,
+{
+    let _ = (path, firmware);
+    vec![]
+}
+
+/// Prints how full each region is and fails if any is above `warn_percent`
+/// and `strict` is set.
+pub fn check_usage(usage: &[Usage], warn_percent: f64, strict: bool) -> Result<(), DiskError> {
+    for u in usage {
+        let percent = u.percent();
+        let mark = if percent > warn_percent { " !" } else { "" };
+        eprintln!(
+            "{:<8} {:#10x} / {:#10x} bytes {percent:5.1}%{mark}",
+            u.name, u.used, u.size
+        );
+    }
+    for u in usage {
+        let percent = u.percent();
+        if percent > warn_percent {
+            if strict {
+                return Err(DiskError::Full(u.name, percent));
+            }
+            eprintln!("warning: {} region is {percent:.1}% full", u.name);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn device_size<F>(file: &mut F) -> io::Result<u64>
+where
+    F: Seek,
+{
+    let size = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(size)
+}
+
+pub fn check_fits(offset: u64, len: usize, size: u64) -> Result<(), DiskError> {
+    let len = len as u64;
+    if offset.checked_add(len).is_none_or(|end| end > size) {
+        return Err(DiskError::DoesNotFit { offset, len, size });
+    }
+    Ok(())
+}
+
+const BLOCK: usize = 0x100000;
+
+/// Granularity of the fill runs `write_sparse` skips.
+const SPARSE_BLOCK: usize = 0x1000;
+
+/// Writes `data` at `offset` without writing its runs of a single byte, the
+/// fill of the image: zero runs become holes in image files, and on devices
+/// the runs are only written where the device doesn't already hold the
+/// same, reading being much faster than writing on SD cards and flash.
+fn write_sparse<T>(target: &mut T, offset: u64, data: &[u8]) -> io::Result<()>
+where
+    T: Target,
+{
+    let fill = |b: &[u8]| b.first().copied().filter(|x| b.iter().all(|y| y == x));
+    let mut at = 0;
+    while at < data.len() {
+        let run_fill = fill(&data[at..(at + SPARSE_BLOCK).min(data.len())]);
+        let mut end = at;
+        while end < data.len() {
+            let next = (end + SPARSE_BLOCK).min(data.len());
+            if fill(&data[end..next]) != run_fill {
+                break;
+            }
+            end = next;
+        }
+        let run = offset + at as u64..offset + end as u64;
+        let Some(byte) = run_fill else {
+            target.seek(SeekFrom::Start(run.start))?;
+            target.write_all(&data[at..end])?;
+            at = end;
+            continue;
+        };
+        // Holes read back as zeros only.
+        if byte != 0 || target.punch_hole(&run).is_err() {
+            let mut current = vec![0; end - at];
+            target.seek(SeekFrom::Start(run.start))?;
+            let held = match target.read_exact(&mut current) {
+                Ok(()) => current.iter().all(|x| *x == byte),
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => false,
+                Err(err) => return Err(err),
+            };
+            if !held {
+                target.seek(SeekFrom::Start(run.start))?;
+                target.write_all(&data[at..end])?;
+            }
+        }
+        at = end;
+    }
+
+    Ok(())
+}
+
+/// Writes `data` at `offset` block by block, skipping fill runs the target
+/// already holds. On Ctrl-C it stops after the current block and reports how
+/// far it got.
+pub fn write_chunked<T>(target: &mut T, offset: u64, data: &[u8]) -> Result<(), DiskError>
+where
+    T: Target,
+{
+    let progress = Progress::new(offset, data.len() as u64);
+    let mut written = 0;
+    for block in data.chunks(BLOCK) {
+        write_sparse(target, offset + written as u64, block)?;
+        written += block.len();
+        progress.draw(written as u64);
+        if interrupt::interrupted() && written < data.len() {
+            progress.finish(written as u64);
+            target.flush()?;
+            return Err(DiskError::Interrupted(offset + written as u64));
+        }
+    }
+    progress.finish(written as u64);
+    target.flush()?;
+
+    Ok(())
+}
+
+const MIB: f64 = 1048576.0;
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Called by `main` for `--quiet`, leaves out the progress bars.
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+/// The progress bar on stderr of writing `len` bytes at `offset`, with the
+/// throughput and the time left. Redrawn only on a terminal, elsewhere just
+/// the last state is printed.
+struct Progress {
+    offset: u64,
+    len: u64,
+    started: Instant,
+    redraw: bool,
+}
+
+impl Progress {
+    fn new(offset: u64, len: u64) -> Self {
+        let progress = Progress {
+            offset,
+            len,
+            started: Instant::now(),
+            redraw: io::stderr().is_terminal(),
+        };
+        progress.draw(0);
+        progress
+    }
+
+    fn draw(&self, done: u64) {
+        // `finish` draws the end
+        if self.redraw && done < self.len && !QUIET.load(Ordering::Relaxed) {
+            eprint!("\r{}", self.line(done));
+        }
+    }
+
+    fn finish(&self, done: u64) {
+        if !QUIET.load(Ordering::Relaxed) {
+            let start = if self.redraw { "\r" } else { "" };
+            eprintln!("{start}{}", self.line(done));
+        }
+    }
+
+    fn line(&self, done: u64) -> String {
+        const WIDTH: u64 = 30;
+        let filled = if self.len == 0 {
+            WIDTH
+        } else {
+            done * WIDTH / self.len
+        };
+        let secs = self.started.elapsed().as_secs_f64().max(0.001);
+        let rate = done as f64 / secs;
+        let eta = if rate > 0.0 {
+            format!("{:.0}s", (self.len - done) as f64 / rate)
+        } else {
+            "-".to_owned()
+        };
+        format!(
+            "{:#x} [{}{}] {:.1}/{:.1} MiB {:.1} MiB/s eta {eta} ",
+            self.offset,
+            "#".repeat(filled as usize),
+            " ".repeat((WIDTH - filled) as usize),
+            done as f64 / MIB,
+            self.len as f64 / MIB,
+            rate / MIB,
+        )
+    }
+}
+
+/// Copies `len` bytes of `src` to the start of `target` block by block,
+/// drawing a progress bar on stderr, and syncs it. Ctrl-C stops it like
+/// `write_chunked`.
+pub fn stream<R, T>(src: &mut R, len: u64, target: &mut T) -> Result<(), DiskError>
+where
+    R: Read,
+    T: Target,
+{
+    let progress = Progress::new(0, len);
+    let mut block = vec![0; BLOCK];
+    let mut done = 0;
+    while done < len {
+        let n = (len - done).min(BLOCK as u64) as usize;
+        src.read_exact(&mut block[..n])?;
+        write_sparse(target, done, &block[..n])?;
+        done += n as u64;
+        progress.draw(done);
+        if interrupt::interrupted() && done < len {
+            progress.finish(done);
+            target.sync()?;
+            return Err(DiskError::Interrupted(done));
+        }
+    }
+    progress.finish(done);
+    target.sync()?;
+
+    Ok(())
+}
+
+pub fn verify<F>(file: &mut F, offset: u64, data: &[u8]) -> Result<(), DiskError>
+where
+    F: Read + Seek,
+{
+    let mut readback = vec![0; data.len()];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut readback)?;
+    if let Some(pos) = readback.iter().zip(data).position(|(a, b)| a != b) {
+        return Err(DiskError::Verify(offset + pos as u64));
+    }
+
+    Ok(())
+}
+
+/// Whether the device already holds `data` at `offset`.
+pub fn matches<F>(file: &mut F, offset: u64, data: &[u8]) -> Result<bool, DiskError>
+where
+    F: Read + Seek,
+{
+    match verify(file, offset, data) {
+        Ok(()) => Ok(true),
+        Err(DiskError::Verify(_)) => Ok(false),
+        Err(DiskError::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Whether `file` holds all of `data` at their offsets.
+pub fn matches_all<F>(file: &mut F, data: &[(u64, &[u8])]) -> Result<bool, DiskError>
+where
+    F: Read + Seek,
+{
+    for (offset, data) in data {
+        if !matches(file, *offset, data)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Writes `data` at `offset`, syncs it and reads it back. Writing starts
+/// `skip` bytes in, to continue an interrupted write; the whole range is
+/// verified regardless.
+pub fn write_verified<T>(
+    file: &mut T,
+    offset: u64,
+    data: &[u8],
+    skip: usize,
+) -> Result<(), DiskError>
+where
+    T: Target,
+{
+    let skip = skip.min(data.len());
+    let res = write_chunked(file, offset + skip as u64, &data[skip..]);
+    file.sync()?;
+    res?;
+    verify(file, offset, data)
+}
+
+#[cfg(target_os = "linux")]
+fn discard(file: &fs::File, range: &Range<u64>) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    const BLKDISCARD: u64 = 0x1277;
+    let arg = [range.start, range.end - range.start];
+    // SAFETY: the ioctl only reads the two u64 of `arg`
+    let res = unsafe { libc::ioctl(file.as_raw_fd(), BLKDISCARD as _, arg.as_ptr()) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn discard(file: &fs::File, range: &Range<u64>) -> io::Result<()> {
+    let _ = (file, range);
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(target_os = "linux")]
+fn punch_hole(file: &mut fs::File, range: &Range<u64>) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // a device would take the seek as done and leave its old data
+    let meta = file.metadata()?;
+    if !meta.is_file() {
+        return Err(io::ErrorKind::Unsupported.into());
+    }
+    if meta.len() < range.end {
+        file.set_len(range.end)?;
+    }
+    let start = range.start.min(meta.len());
+    let end = range.end.min(meta.len());
+    if start == end {
+        return Ok(());
+    }
+    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    // SAFETY: plain syscall on an owned descriptor
+    let res = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            mode,
+            start as libc::off_t,
+            (end - start) as libc::off_t,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn punch_hole(file: &mut fs::File, range: &Range<u64>) -> io::Result<()> {
+    let _ = (file, range);
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Zeroes `range`. Discarding is tried first, but since discarded blocks
+/// don't have to read back as zeros, the range is checked and written over
+/// if they don't.
+pub fn wipe<T>(file: &mut T, range: Range<u64>) -> Result<(), DiskError>
+where
+    T: Target,
+{
+    let zeros = vec![0; (range.end - range.start) as usize];
+    if file.discard(&range).is_ok() && matches(file, range.start, &zeros)? {
+        eprintln!("discarded {:#x}..{:#x}", range.start, range.end);
+        return Ok(());
+    }
+    let res = write_chunked(file, range.start, &zeros);
+    file.sync()?;
+    res?;
+    eprintln!("zeroed {:#x}..{:#x}", range.start, range.end);
+
+    Ok(())
+}
+
+/// A partition `format` creates.
+pub struct PartitionSpec {
+    pub id: u32,
+    pub name: String,
+    pub ty: uuid::Uuid,
+    pub mbr_type: u8,
+    pub region: Region,
+    /// The unique GUID of the partition, a random one if `None`.
+    pub guid: Option<uuid::Uuid>,
+}
+
+/// The partitions `format` creates for `layout`.
+pub fn firmware_partitions(layout: &DiskLayout, firmware: &Gpt) -> [PartitionSpec; 2] {
+    [
+        PartitionSpec {
+            id: 1,
+            name: firmware.spl.name.clone(),
+            ty: firmware.spl.ty,
+            mbr_type: partitions::mbr_type(firmware.spl.ty),
+            region: layout.spl,
+            guid: None,
+        },
+        PartitionSpec {
+            id: 2,
+            name: firmware.opensbi.name.clone(),
+            ty: firmware.opensbi.ty,
+            mbr_type: partitions::mbr_type(firmware.opensbi.ty),
+            region: layout.opensbi,
+            guid: None,
+        },
+    ]
+}
+
+fn gpt_type(guid: uuid::Uuid) -> gpt::partition_types::Type {
+    gpt::partition_types::Type {
+        guid,
+        os: gpt::partition_types::OperatingSystem::None,
+    }
+}
+
+/// The MBR in front of a GPT holding `specs`: the protective one, or with
+/// `hybrid` the first three of `specs` and then the protective partition,
+/// over the GPT up to the first of them.
+fn gpt_mbr(
+    specs: &[PartitionSpec],
+    hybrid: bool,
+    block: LogicalBlockSize,
+) -> Result<gpt::mbr::ProtectiveMBR, DiskError> {
+    let mut mbr = gpt::mbr::ProtectiveMBR::with_lb_size(0xFF_FF_FF_FF);
+    if !hybrid {
+        return Ok(mbr);
+    }
+    let mirrored = &specs[..specs.len().min(3)];
+    for (i, spec) in mirrored.iter().enumerate() {
+        mbr.set_partition(i, mbr_record(spec, block)?);
+    }
+    let first = specs
+        .iter()
+        .map(|s| s.region.offset)
+        .min()
+        .unwrap_or(gpt_end(block));
+    let protective =
+        gpt::mbr::PartRecord::new_protective(Some((first / block.as_u64() - 1) as u32));
+    mbr.set_partition(mirrored.len(), protective);
+    Ok(mbr)
+}
+
+/// Writes `gpt_mbr` to LBA 0, a hybrid one with the `disk_signature`.
+fn write_gpt_mbr<D>(
+    device: &mut D,
+    specs: &[PartitionSpec],
+    hybrid: bool,
+    block: LogicalBlockSize,
+    guid: Option<uuid::Uuid>,
+) -> Result<(), DiskError>
+where
+    D: gpt::DiskDevice,
+{
+    let mut mbr = gpt_mbr(specs, hybrid, block)?;
+    if hybrid {
+        mbr.set_disk_signature(disk_signature(device, guid)?);
+    }
+    mbr.overwrite_lba0(device)
+        .map_err(|e| DiskError::Mbr(e.to_string()))?;
+    Ok(())
+}
+
+/// The first bytes of the disk `guid` if there is one, or the signature of
+/// the MBR on `device`, a random one if it has none.
+fn disk_signature<D>(device: &mut D, guid: Option<uuid::Uuid>) -> io::Result<[u8; 4]>
+where
+    D: gpt::DiskDevice,
+{
+    if let Some(guid) = guid {
+        return Ok(guid.as_bytes()[..4].try_into().expect("four bytes"));
+    }
+    let signature = gpt::mbr::read_disk_signature(device)?;
+    if signature == [0; 4] {
+        let random = uuid::Uuid::new_v4();
+        Ok(random.as_bytes()[..4].try_into().expect("four bytes"))
+    } else {
+        Ok(signature)
+    }
+}
+
+/// Whether the partition records of the MBR on `device` are those of
+/// `gpt_mbr`.
+pub fn gpt_mbr_matches<D>(
+    mut device: D,
+    specs: &[PartitionSpec],
+    hybrid: bool,
+    block: LogicalBlockSize,
+) -> bool
+where
+    D: gpt::DiskDevice,
+{
+    let Ok(expected) = gpt_mbr(specs, hybrid, block) else {
+        return false;
+    };
+    let mut lba0 = [0; 512];
+    device.seek(SeekFrom::Start(0)).is_ok()
+        && device.read_exact(&mut lba0).is_ok()
+        && lba0[446..] == expected.to_bytes()[446..]
+}
+
+/// Lays down a fresh GPT holding `specs`, with a protective MBR, or a
+/// hybrid one with `hybrid`. The disk gets `guid`, or a random GUID.
+pub fn write_gpt<D>(
+    device: D,
+    specs: &[PartitionSpec],
+    hybrid: bool,
+    block: LogicalBlockSize,
+    guid: Option<uuid::Uuid>,
+) -> Result<D, DiskError>
+where
+    D: gpt::DiskDevice,
+{
+    let err = |err: &dyn std::fmt::Display| DiskError::Gpt(err.to_string());
+    let mut disk = gpt::GptConfig::default()
+        .writable(true)
+        .logical_block_size(block)
+        .create_from_device(device, guid)
+        .map_err(|e| err(&e))?;
+    for spec in specs {
+        let (lba, blocks) = (
+            spec.region.offset / block.as_u64(),
+            spec.region.size / block.as_u64(),
+        );
+        disk.add_partition_at(&spec.name, spec.id, lba, blocks, gpt_type(spec.ty), 0)
+            .map_err(|e| err(&e))?;
+    }
+    set_guids(&mut disk, specs, block)?;
+    let mut device = disk.write().map_err(|e| err(&e))?;
+    write_gpt_mbr(&mut device, specs, hybrid, block, guid)?;
+
+    Ok(device)
+}
+
+/// Gives the partitions of `specs` in the GPT of `disk` the GUIDs of their
+/// spec, those that have one.
+fn set_guids<D>(
+    disk: &mut gpt::GptDisk<D>,
+    specs: &[PartitionSpec],
+    block: LogicalBlockSize,
+) -> Result<(), DiskError>
+where
+    D: gpt::DiskDevice,
+{
+    let mut partitions = disk.take_partitions();
+    for p in partitions.values_mut() {
+        if let Some(guid) = specs
+            .iter()
+            .find(|spec| in_place(p, spec, block))
+            .and_then(|spec| spec.guid)
+        {
+            p.part_guid = guid;
+        }
+    }
+    disk.update_partitions(partitions)
+        .map_err(|e| DiskError::Gpt(e.to_string()))
+}
+
+/// The MBR partition record of `spec`, with the CHS fields past their
+/// range, as for any partition above 8 GiB.
+fn mbr_record(
+    spec: &PartitionSpec,
+    block: LogicalBlockSize,
+) -> Result<gpt::mbr::PartRecord, DiskError> {
+    let lba = |x: u64| {
+        u32::try_from(x / block.as_u64()).map_err(|_| {
+            DiskError::Mbr(format!("{} is beyond the 2^32 blocks of an MBR", spec.name))
+        })
+    };
+    Ok(gpt::mbr::PartRecord {
+        boot_indicator: 0,
+        start_head: 0xfe,
+        start_sector: 0xff,
+        start_track: 0xff,
+        os_type: spec.mbr_type,
+        end_head: 0xfe,
+        end_sector: 0xff,
+        end_track: 0xff,
+        lb_start: lba(spec.region.offset)?,
+        lb_size: lba(spec.region.size)?,
+    })
+}
+
+/// Lays down an MBR holding `specs` as the primary partitions, keeping the
+/// disk signature, and clears the headers of a GPT so nothing reads the
+/// disk as one.
+pub fn write_mbr<D>(
+    mut device: D,
+    specs: &[PartitionSpec],
+    block: LogicalBlockSize,
+    guid: Option<uuid::Uuid>,
+) -> Result<D, DiskError>
+where
+    D: gpt::DiskDevice,
+{
+    let err = |err: &dyn std::fmt::Display| DiskError::Mbr(err.to_string());
+    if specs.len() > 4 {
+        return Err(err(&format!("{} partitions, an MBR holds 4", specs.len())));
+    }
+    let mut mbr = gpt::mbr::ProtectiveMBR::new();
+    mbr.set_partition(0, gpt::mbr::PartRecord::zero());
+    for (i, spec) in specs.iter().enumerate() {
+        mbr.set_partition(i, mbr_record(spec, block)?);
+    }
+    mbr.set_disk_signature(disk_signature(&mut device, guid)?);
+    mbr.overwrite_lba0(&mut device).map_err(|e| err(&e))?;
+
+    // The primary GPT follows the MBR, the backup one ends the disk.
+    let zeros = vec![0; (gpt_end(block) - block.as_u64()) as usize];
+    let size = device.seek(SeekFrom::End(0))?;
+    let first = block.as_u64();
+    for start in [first, size.saturating_sub(zeros.len() as u64)] {
+        if start >= first && start + zeros.len() as u64 <= size {
+            device.seek(SeekFrom::Start(start))?;
+            device.write_all(&zeros)?;
+        }
+    }
+    device.flush()?;
+
+    Ok(device)
+}
+
+/// The disk signature, if `device` has an MBR holding exactly `specs` and
+/// no GPT, and it is the one of `guid` if given.
+pub fn mbr_matches<D>(
+    mut device: D,
+    specs: &[PartitionSpec],
+    block: LogicalBlockSize,
+    guid: Option<uuid::Uuid>,
+) -> Option<u32>
+where
+    D: gpt::DiskDevice,
+{
+    let mbr = gpt::mbr::ProtectiveMBR::from_disk(&mut device, block).ok()?;
+    let mut header = [0; 8];
+    device.seek(SeekFrom::Start(block.as_u64())).ok()?;
+    device.read_exact(&mut header).ok()?;
+    if &header == b"EFI PART" {
+        return None;
+    }
+    let same = (0..4).all(|i| {
+        let record = mbr.partition(i).unwrap_or_else(gpt::mbr::PartRecord::zero);
+        match specs.get(i).map(|spec| mbr_record(spec, block)) {
+            Some(Ok(spec)) => record.to_bytes() == spec.to_bytes(),
+            Some(Err(_)) => false,
+            None => record.os_type == 0,
+        }
+    });
+    let signature = *mbr.disk_signature();
+    let same = same && guid.is_none_or(|guid| guid.as_bytes()[..4] == signature);
+    same.then(|| u32::from_le_bytes(signature))
+}
+
+/// Rewrites the partitions of `firmware` type in the GPT on `device` to
+/// `specs`, keeping the other partitions and the disk GUID. Partitions
+/// already as specified are left alone. A spec whose
+/// number is taken gets the next free one, the SPL finds u-boot by type.
+/// With `hybrid`, `specs` go into the MBR as well. The disk GUID becomes
+/// `guid` if given. Returns the partitions kept.
+pub fn merge_gpt<D>(
+    device: D,
+    specs: &[PartitionSpec],
+    firmware: &Gpt,
+    hybrid: bool,
+    block: LogicalBlockSize,
+    guid: Option<uuid::Uuid>,
+) -> Result<(D, Vec<String>), DiskError>
+where
+    D: gpt::DiskDevice,
+{
+    let err = |err: &dyn std::fmt::Display| DiskError::Gpt(err.to_string());
+    let lb = block.as_u64();
+    let mut disk = gpt::GptConfig::new()
+        .writable(true)
+        .logical_block_size(block)
+        .open_from_device(device)
+        .map_err(|e| err(&format!("no partition table to keep, {e}")))?;
+
+    let mut kept = vec![];
+    let mut missing = specs.iter().collect::<Vec<_>>();
+    for (id, p) in disk.partitions().clone() {
+        if let Some(at) = missing.iter().position(|spec| in_place(&p, spec, block)) {
+            missing.remove(at);
+            continue;
+        }
+        if firmware.is_firmware(p.part_type_guid.guid) {
+            disk.remove_partition(id);
+            continue;
+        }
+        let (first, last) = (p.first_lba * lb, (p.last_lba + 1) * lb);
+        let describe = || format!("partition {id} \"{}\" at {first:#x}..{last:#x}", p.name);
+        if let Some(spec) = specs
+            .iter()
+            .find(|spec| first < spec.region.end() && spec.region.offset < last)
+        {
+            return Err(err(&format!(
+                "{} is in the way of {}",
+                describe(),
+                spec.name
+            )));
+        }
+        kept.push(describe());
+    }
+    for spec in missing {
+        let id = match disk.partitions().get(&spec.id) {
+            Some(p) if p.is_used() => disk
+                .find_next_partition_id()
+                .ok_or_else(|| err(&"partition table is full"))?,
+            _ => spec.id,
+        };
+        let (lba, blocks) = (spec.region.offset / lb, spec.region.size / lb);
+        disk.add_partition_at(&spec.name, id, lba, blocks, gpt_type(spec.ty), 0)
+            .map_err(|e| err(&e))?;
+    }
+    // The headers take the disk GUID when `set_guids` rebuilds them.
+    if guid.is_some() {
+        disk.update_guid(guid);
+    }
+    set_guids(&mut disk, specs, block)?;
+    let mut device = disk.write().map_err(|e| err(&e))?;
+    write_gpt_mbr(&mut device, specs, hybrid, block, guid)?;
+
+    Ok((device, kept))
+}
+
+/// Whether `p` is where `spec` goes, of its type and name.
+fn in_place(p: &gpt::partition::Partition, spec: &PartitionSpec, block: LogicalBlockSize) -> bool {
+    p.name == spec.name
+        && p.part_type_guid.guid == spec.ty
+        && p.first_lba == spec.region.offset / block.as_u64()
+        && p.last_lba + 1 == spec.region.end() / block.as_u64()
+}
+
+/// `in_place`, and with the GUID of `spec` if it has one.
+fn is_spec(p: &gpt::partition::Partition, spec: &PartitionSpec, block: LogicalBlockSize) -> bool {
+    in_place(p, spec, block) && spec.guid.is_none_or(|guid| p.part_guid == guid)
+}
+
+/// The disk GUID, if `device` has a GPT holding exactly `specs`, or with
+/// `others` at least `specs`, under any partition number, and it is `guid`
+/// if given.
+pub fn gpt_matches<D>(
+    device: D,
+    specs: &[PartitionSpec],
+    others: bool,
+    block: LogicalBlockSize,
+    guid: Option<uuid::Uuid>,
+) -> Option<uuid::Uuid>
+where
+    D: gpt::DiskDevice,
+{
+    let disk = gpt::GptConfig::new()
+        .writable(false)
+        .logical_block_size(block)
+        .open_from_device(device)
+        .ok()?;
+    let partitions = disk.partitions();
+    let same = (others || partitions.len() == specs.len())
+        && specs.iter().all(|spec| {
+            partitions
+                .iter()
+                .any(|(id, p)| (others || *id == spec.id) && is_spec(p, spec, block))
+        });
+    let same = same && guid.is_none_or(|guid| guid == *disk.guid());
+    same.then(|| *disk.guid())
+}
+
+/// Re-reads the GPT `format` wrote: both headers with their checksums, and
+/// the two partition arrays, which must agree, hold `specs` and leave the
+/// `raw` regions to the firmware partitions. The MBR must be the one for
+/// `hybrid`, so overwriting it destroyed nothing.
+pub fn check_gpt<D>(
+    mut device: D,
+    specs: &[PartitionSpec],
+    hybrid: bool,
+    raw: &[(&str, Region)],
+    firmware: &Gpt,
+    block: LogicalBlockSize,
+) -> Result<(), DiskError>
+where
+    D: gpt::DiskDevice,
+{
+    let err = |err: &dyn std::fmt::Display| DiskError::Gpt(err.to_string());
+    let lb = block.as_u64();
+    let last_lba = device_size(&mut device)? / lb - 1;
+    if !gpt_mbr_matches(&mut device, specs, hybrid, block) {
+        return Err(DiskError::Mbr(
+            "LBA 0 isn't the MBR written with the GPT".to_owned(),
+        ));
+    }
+    let disk = gpt::GptConfig::new()
+        .writable(false)
+        .logical_block_size(block)
+        .open_from_device(&mut device)
+        .map_err(|e| err(&format!("unreadable after writing, {e}")))?;
+    let primary = disk
+        .primary_header()
+        .map_err(|e| err(&format!("primary header: {e}")))?
+        .clone();
+    let backup = disk
+        .backup_header()
+        .map_err(|e| err(&format!("backup header: {e}")))?
+        .clone();
+    let partitions = disk.partitions().clone();
+    drop(disk);
+
+    if primary.current_lba != 1 || primary.backup_lba != last_lba {
+        return Err(err(&format!(
+            "primary header at LBA {} points to a backup at LBA {}, the disk ends at LBA {last_lba}",
+            primary.current_lba, primary.backup_lba
+        )));
+    }
+    if backup.current_lba != last_lba || backup.backup_lba != 1 {
+        return Err(err(&format!(
+            "backup header at LBA {} isn't the last LBA {last_lba} or doesn't point back to LBA 1",
+            backup.current_lba
+        )));
+    }
+    let same = primary.disk_guid == backup.disk_guid
+        && primary.first_usable == backup.first_usable
+        && primary.last_usable == backup.last_usable
+        && primary.num_parts == backup.num_parts
+        && primary.crc32_parts == backup.crc32_parts;
+    if !same {
+        return Err(err(&"the primary and backup headers disagree"));
+    }
+    let backup_partitions = gpt::partition::file_read_partitions(&mut device, &backup, block)
+        .map_err(|e| err(&format!("backup partition array: {e}")))?;
+    if backup_partitions != partitions {
+        return Err(err(&"the primary and backup partition arrays differ"));
+    }
+
+    if let Some(spec) = specs
+        .iter()
+        .find(|spec| !partitions.values().any(|p| is_spec(p, spec, block)))
+    {
+        return Err(err(&format!("partition {} is missing", spec.name)));
+    }
+    let usable = primary.first_usable * lb..(primary.last_usable + 1) * lb;
+    for (name, region) in raw {
+        if region.offset < usable.start || region.end() > usable.end {
+            return Err(err(&format!(
+                "the {name} region at {:#x}..{:#x} overlaps a GPT",
+                region.offset,
+                region.end()
+            )));
+        }
+        for (id, p) in &partitions {
+            let (first, last) = (p.first_lba * lb, (p.last_lba + 1) * lb);
+            if !firmware.is_firmware(p.part_type_guid.guid)
+                && first < region.end()
+                && region.offset < last
+            {
+                return Err(err(&format!(
+                    "partition {id} \"{}\" at {first:#x}..{last:#x} covers the {name} region",
+                    p.name
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Re-reads the MBR `format` wrote, it must hold `specs` and leave the
+/// `raw` regions to the firmware partitions.
+pub fn check_mbr<D>(
+    device: D,
+    specs: &[PartitionSpec],
+    raw: &[(&str, Region)],
+    firmware: &Gpt,
+    block: LogicalBlockSize,
+) -> Result<(), DiskError>
+where
+    D: gpt::DiskDevice,
+{
+    if mbr_matches(device, specs, block, None).is_none() {
+        return Err(DiskError::Mbr(
+            "LBA 0 doesn't hold the partitions written".to_owned(),
+        ));
+    }
+    for (name, region) in raw {
+        if region.offset < block.as_u64() {
+            return Err(DiskError::Mbr(format!(
+                "the {name} region overlaps the MBR"
+            )));
+        }
+        if let Some(spec) = specs.iter().find(|spec| {
+            !firmware.is_firmware(spec.ty)
+                && spec.region.offset < region.end()
+                && region.offset < spec.region.end()
+        }) {
+            return Err(DiskError::Mbr(format!(
+                "partition {} covers the {name} region",
+                spec.name
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// The block size of the GPT on `device`, for an image file that doesn't
+/// tell.
+pub fn gpt_block_size<D>(mut device: D) -> Option<LogicalBlockSize>
+where
+    D: Read + Seek,
+{
+    [LogicalBlockSize::Lb512, LogicalBlockSize::Lb4096]
+        .into_iter()
+        .find(|block| {
+            let mut header = [0; 8];
+            device.seek(SeekFrom::Start(block.as_u64())).is_ok()
+                && device.read_exact(&mut header).is_ok()
+                && &header == b"EFI PART"
+        })
+}
+
+/// Rewrites the backup GPT at the end of `device` as a copy of the primary
+/// one, the header and the partition array, after the primary alone was
+/// written back. The primary is taken as found, partition numbers and all.
+pub fn restore_backup_gpt<D>(device: &mut D, block: LogicalBlockSize) -> Result<(), DiskError>
+where
+    D: Read + Write + Seek,
+{
+    let err = |msg: &str| DiskError::Gpt(msg.to_owned());
+    let crc = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+    let lb = block.as_u64();
+    let u32_at = |b: &[u8], at: usize| u32::from_le_bytes(b[at..][..4].try_into().unwrap());
+    let u64_at = |b: &[u8], at: usize| u64::from_le_bytes(b[at..][..8].try_into().unwrap());
+
+    let mut header = vec![0; lb as usize];
+    device.seek(SeekFrom::Start(lb))?;
+    device.read_exact(&mut header)?;
+    let header_size = u32_at(&header, 12) as usize;
+    if &header[..8] != b"EFI PART" || !(92..=header.len()).contains(&header_size) {
+        return Err(err("no primary GPT header"));
+    }
+    let mut check = header[..header_size].to_vec();
+    check[16..20].fill(0);
+    if crc.checksum(&check) != u32_at(&header, 16) {
+        return Err(err("the primary GPT header is corrupt"));
+    }
+    let (current, backup) = (u64_at(&header, 24), u64_at(&header, 32));
+    let array_len = u32_at(&header, 80) as usize * u32_at(&header, 84) as usize;
+    let mut array = vec![0; array_len];
+    device.seek(SeekFrom::Start(u64_at(&header, 72) * lb))?;
+    device.read_exact(&mut array)?;
+    if crc.checksum(&array) != u32_at(&header, 88) {
+        return Err(err("the primary GPT partition array is corrupt"));
+    }
+    let array_at = backup
+        .checked_sub((array_len as u64).div_ceil(lb))
+        .filter(|_| backup > current)
+        .ok_or_else(|| err("the primary GPT header misplaces the backup"))?;
+    if device.seek(SeekFrom::End(0))? < (backup + 1) * lb {
+        return Err(err("the backup GPT is past the end of the disk"));
+    }
+
+    check[24..32].copy_from_slice(&backup.to_le_bytes());
+    check[32..40].copy_from_slice(&current.to_le_bytes());
+    check[72..80].copy_from_slice(&array_at.to_le_bytes());
+    let sum = crc.checksum(&check);
+    check[16..20].copy_from_slice(&sum.to_le_bytes());
+    header[..header_size].copy_from_slice(&check);
+    device.seek(SeekFrom::Start(array_at * lb))?;
+    device.write_all(&array)?;
+    device.seek(SeekFrom::Start(backup * lb))?;
+    device.write_all(&header)?;
+    device.flush()?;
+    Ok(())
+}
+
+/// Grows the last partition of the GPT on `device` to the end of the disk
+/// and moves the backup GPT there, for an image written to a bigger disk.
+/// A record for the partition in a hybrid MBR grows along. Returns what
+/// grew, `None` if the partition already ends the disk.
+pub fn expand_gpt<D>(
+    device: D,
+    firmware: &Gpt,
+    block: LogicalBlockSize,
+) -> Result<(D, Option<String>), DiskError>
+where
+    D: gpt::DiskDevice,
+{
+    let err = |err: &dyn std::fmt::Display| DiskError::Gpt(err.to_string());
+    let lb = block.as_u64();
+    let mut disk = gpt::GptConfig::new()
+        .writable(true)
+        .logical_block_size(block)
+        .open_from_device(device)
+        .map_err(|e| err(&format!("no partition table to expand, {e}")))?;
+    let header = disk.header().clone();
+    let array = (u64::from(header.num_parts) * u64::from(header.part_size)).div_ceil(lb);
+    let backup_lba = disk.device_mut().seek(SeekFrom::End(0))? / lb - 1;
+    let last_usable = backup_lba - array - 1;
+
+    let mut partitions = disk.take_partitions();
+    let (id, p) = partitions
+        .iter_mut()
+        .filter(|(_, p)| p.is_used())
+        .max_by_key(|(_, p)| p.last_lba)
+        .ok_or_else(|| err(&"no partition to grow"))?;
+    let (id, first_lba, old_last) = (*id, p.first_lba, p.last_lba);
+    let describe = |last: u64| {
+        format!(
+            "partition {id} \"{}\" at {:#x}..{:#x}",
+            p.name,
+            first_lba * lb,
+            (last + 1) * lb
+        )
+    };
+    if firmware.is_firmware(p.part_type_guid.guid) {
+        return Err(err(&format!(
+            "{} is the last one, a firmware partition of a fixed size",
+            describe(old_last)
+        )));
+    }
+    if old_last >= last_usable && header.backup_lba == backup_lba {
+        return Ok((disk.take_device(), None));
+    }
+    p.last_lba = last_usable.max(old_last);
+    let grown = format!("{} to {:#x}", describe(old_last), (p.last_lba + 1) * lb);
+    let (old_blocks, new_blocks) = (old_last + 1 - first_lba, p.last_lba + 1 - first_lba);
+    // The headers find the new end of the disk when they are rebuilt.
+    disk.update_partitions(partitions).map_err(|e| err(&e))?;
+    let mut device = disk.write().map_err(|e| err(&e))?;
+
+    // The old backup GPT is now inside the grown partition.
+    if header.backup_lba < backup_lba {
+        let start = (header.backup_lba - array) * lb;
+        device.seek(SeekFrom::Start(start))?;
+        device.write_all(&vec![0; ((array + 1) * lb) as usize])?;
+    }
+
+    let mut mbr = gpt::mbr::ProtectiveMBR::from_disk(&mut device, block)
+        .map_err(|e| DiskError::Mbr(e.to_string()))?;
+    for i in 0..4 {
+        let Some(mut record) = mbr.partition(i) else {
+            continue;
+        };
+        if u64::from(record.lb_start) == first_lba && u64::from(record.lb_size) == old_blocks {
+            record.lb_size = u32::try_from(new_blocks).unwrap_or(u32::MAX);
+            mbr.set_partition(i, record);
+        }
+    }
+    mbr.overwrite_lba0(&mut device)
+        .map_err(|e| DiskError::Mbr(e.to_string()))?;
+    device.flush()?;
+
+    Ok((device, Some(grown)))
+}
+
+/// What `probe_gpt` found on a disk.
+pub enum GptProbe {
+    /// The StarFive firmware partitions are present.
+    Firmware,
+    /// There is a GPT, but no partition covers the range.
+    Clear,
+    /// Partitions of something else cover the range.
+    Foreign(String),
+    NoGpt,
+}
+
+/// Looks at the GPT of `device` to tell whether writing `range` would
+/// destroy somebody's data. Partitions of the types in `firmware` are
+/// known to be safe.
+pub fn probe_gpt<D>(
+    device: D,
+    range: Range<u64>,
+    firmware: &Gpt,
+    block: LogicalBlockSize,
+) -> GptProbe
+where
+    D: gpt::DiskDevice,
+{
+    let disk = match gpt::GptConfig::new()
+        .writable(false)
+        .logical_block_size(block)
+        .open_from_device(device)
+    {
+        Ok(disk) => disk,
+        Err(_) => return GptProbe::NoGpt,
+    };
+    let lb_size = *disk.logical_block_size();
+
+    let firmware = disk
+        .partitions()
+        .values()
+        .any(|p| firmware.is_firmware(p.part_type_guid.guid));
+    if firmware {
+        return GptProbe::Firmware;
+    }
+
+    let covering = disk
+        .partitions()
+        .iter()
+        .filter_map(|(id, p)| {
+            let start = p.bytes_start(lb_size).ok()?;
+            let end = start + p.bytes_len(lb_size).ok()?;
+            (start < range.end && range.start < end).then(|| {
+                let guid = p.part_type_guid.guid;
+                format!(
+                    "partition {id} \"{}\" of type {guid} at {start:#x}..{end:#x}",
+                    p.name
+                )
+            })
+        })
+        .collect::<Vec<_>>();
+    if covering.is_empty() {
+        GptProbe::Clear
+    } else {
+        GptProbe::Foreign(covering.join(", "))
+    }
+}
+
+/// Like `probe_gpt`, for a disk with a plain MBR. Its partitions of the MBR
+/// types of `firmware` are the firmware.
+pub fn probe_mbr<D>(
+    mut device: D,
+    range: Range<u64>,
+    firmware: &Gpt,
+    block: LogicalBlockSize,
+) -> GptProbe
+where
+    D: gpt::DiskDevice,
+{
+    let Ok(mbr) = gpt::mbr::ProtectiveMBR::from_disk(&mut device, block) else {
+        return GptProbe::NoGpt;
+    };
+    let records = (0..4)
+        .filter_map(|i| Some((i + 1, mbr.partition(i)?)))
+        // A protective MBR without its GPT is no table at all.
+        .filter(|(_, r)| r.os_type != 0 && r.os_type != 0xee)
+        .collect::<Vec<_>>();
+    if records.is_empty() {
+        return GptProbe::NoGpt;
+    }
+    let types = [firmware.spl.ty, firmware.opensbi.ty].map(partitions::mbr_type);
+    if records.iter().any(|(_, r)| types.contains(&r.os_type)) {
+        return GptProbe::Firmware;
+    }
+
+    let covering = records
+        .iter()
+        .filter_map(|(id, r)| {
+            let start = u64::from(r.lb_start) * block.as_u64();
+            let end = start + u64::from(r.lb_size) * block.as_u64();
+            (start < range.end && range.start < end).then(|| {
+                format!(
+                    "mbr partition {id} of type {:#04x} at {start:#x}..{end:#x}",
+                    r.os_type
+                )
+            })
+        })
+        .collect::<Vec<_>>();
+    if covering.is_empty() {
+        GptProbe::Clear
+    } else {
+        GptProbe::Foreign(covering.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use gpt::disk::LogicalBlockSize;
+
+    use super::{DiskError, GptProbe};
+    use crate::{board, inspect, spl_header, testing};
+
+    const SIZE: u64 = 0x2000000;
+
+    fn spl(board: &board::Board) -> Vec<u8> {
+        let payload = vec![0x5a; 0x1000];
+        let header = board.spl_header.header(&payload, board.sd.spl).unwrap();
+        [header, payload].concat()
+    }
+
+    #[test]
+    fn format_then_inspect() {
+        let board = board::visionfive2();
+        let layout = board.sd;
+        let (spl, opensbi) = (spl(&board), b"OpenSBI v1.3".repeat(0x100));
+        let path = testing::sparse_file("tau-builder-format-then-inspect.img", SIZE).unwrap();
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        testing::populate(&mut file, &layout, &board.gpt, &spl, &opensbi).unwrap();
+
+        let specs = super::firmware_partitions(&layout, &board.gpt);
+        let raw = layout.regions();
+        super::check_gpt(
+            &mut file,
+            &specs,
+            false,
+            &raw,
+            &board.gpt,
+            LogicalBlockSize::Lb512,
+        )
+        .unwrap();
+        assert!(
+            super::gpt_matches(&mut file, &specs, false, LogicalBlockSize::Lb512, None).is_some()
+        );
+        let mut padded = spl.clone();
+        padded.resize(layout.spl.size as usize, 0);
+        assert_eq!(
+            testing::region_crc(&mut file, layout.spl).unwrap(),
+            crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC).checksum(&padded)
+        );
+        let parsed = spl_header::detect(&[&spl_header::JH7110], &padded).unwrap();
+        assert_eq!(parsed.crc_ok, Some(true));
+        inspect::print(&mut file, &layout, &[&spl_header::JH7110], None, 0).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn merge_keeps_disk_guid() {
+        let board = board::visionfive2();
+        let specs = super::firmware_partitions(&board.sd, &board.gpt);
+        let block = LogicalBlockSize::Lb512;
+        let path = testing::sparse_file("tau-builder-merge-gpt.img", SIZE).unwrap();
+        let open = || {
+            fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&path)
+                .unwrap()
+        };
+        super::write_gpt(open(), &specs, false, block, None).unwrap();
+        let first = super::gpt_matches(open(), &specs, false, block, None).unwrap();
+
+        // With --keep-partitions the table is rewritten, not the disk GUID.
+        super::merge_gpt(open(), &specs, &board.gpt, false, block, None).unwrap();
+        let merged = super::gpt_matches(open(), &specs, false, block, None);
+        assert_eq!(merged, Some(first));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn restore_backup_gpt() {
+        let board = board::visionfive2();
+        let layout = board.sd;
+        let mut target = testing::image(SIZE as usize);
+        testing::populate(&mut target, &layout, &board.gpt, &spl(&board), b"").unwrap();
+        let written = target.get_ref().clone();
+
+        // What `rollback` leaves after writing back the saved primary GPT.
+        let backup = SIZE as usize - super::GPT_END as usize + 0x200..SIZE as usize;
+        target.get_mut()[backup.clone()].fill(0);
+        super::restore_backup_gpt(&mut target, LogicalBlockSize::Lb512).unwrap();
+        assert!(target.get_ref()[backup.clone()] == written[backup]);
+        let specs = super::firmware_partitions(&layout, &board.gpt);
+        let raw = layout.regions();
+        super::check_gpt(
+            &mut target,
+            &specs,
+            false,
+            &raw,
+            &board.gpt,
+            LogicalBlockSize::Lb512,
+        )
+        .unwrap();
+
+        target.get_mut()[0x200..0x400].fill(0);
+        assert!(matches!(
+            super::restore_backup_gpt(&mut target, LogicalBlockSize::Lb512),
+            Err(DiskError::Gpt(_))
+        ));
+    }
+
+    #[test]
+    fn verification_fails_on_corruption() {
+        let board = board::visionfive2();
+        let layout = board.sd;
+        let data = vec![0xa5; 0x3000];
+        let bad = layout.tau.offset + 0x1000..layout.tau.offset + 0x1010;
+        let mut target = testing::Faulty {
+            inner: testing::image(SIZE as usize),
+            bad: bad.clone(),
+        };
+        match super::write_verified(&mut target, layout.tau.offset, &data, 0) {
+            Err(DiskError::Verify(at)) => assert!(bad.contains(&at)),
+            res => panic!("expected a verification failure, got {res:?}"),
+        }
+
+        let mut target = testing::image(SIZE as usize);
+        super::write_verified(&mut target, layout.tau.offset, &data, 0).unwrap();
+        testing::corrupt(&mut target, bad.clone()).unwrap();
+        assert!(matches!(
+            super::verify(&mut target, layout.tau.offset, &data),
+            Err(DiskError::Verify(_))
+        ));
+    }
+
+    #[test]
+    fn partition_by_guid() {
+        let board = board::visionfive2();
+        let layout = board.sd;
+        let mut target = testing::image(SIZE as usize);
+        let tau = layout.tau.offset..layout.tau.end();
+        assert!(matches!(
+            super::probe_gpt(
+                &mut target,
+                tau.clone(),
+                &board.gpt,
+                LogicalBlockSize::Lb512
+            ),
+            GptProbe::NoGpt
+        ));
+        testing::populate(&mut target, &layout, &board.gpt, &spl(&board), b"").unwrap();
+        assert!(matches!(
+            super::probe_gpt(
+                &mut target,
+                tau.clone(),
+                &board.gpt,
+                LogicalBlockSize::Lb512
+            ),
+            GptProbe::Firmware
+        ));
+
+        // The same partitions under other type GUIDs belong to someone else.
+        let mut other = board.gpt.clone();
+        other.spl.ty = uuid::uuid!("0FC63DAF-8483-4772-8E79-3D69D8477DE4");
+        other.opensbi.ty = other.spl.ty;
+        assert!(!other.is_firmware(board.gpt.spl.ty));
+        match super::probe_gpt(&mut target, tau, &other, LogicalBlockSize::Lb512) {
+            GptProbe::Foreign(found) => assert!(found.contains(&board.gpt.opensbi.name)),
+            _ => panic!("expected the OpenSBI partition"),
+        }
+    }
+}
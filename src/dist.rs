@@ -0,0 +1,53 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use sha2::{Digest, Sha256};
+
+/// Name of the manifest in the dist directory, in `sha256sum` format.
+pub const MANIFEST: &str = "SHA256SUMS";
+
+/// A release file and what it is made from.
+pub struct Artifact {
+    /// Prefix of the file name, the version and `ext` follow.
+    pub name: String,
+    pub ext: &'static str,
+    pub data: Vec<u8>,
+}
+
+/// `git describe` of the working directory, for file names.
+pub fn version() -> Option<String> {
+    let out = Command::new("git")
+        .args(["describe", "--tags", "--always", "--dirty"])
+        .output()
+        .ok()?;
+    let version = String::from_utf8(out.stdout).ok()?;
+    Some(version.trim().to_owned()).filter(|v| out.status.success() && !v.is_empty())
+}
+
+/// Writes `artifacts` into `dir` named `<name>-<version>.<ext>` together
+/// with the manifest, returns the written paths. Files of an earlier run are
+/// left alone.
+pub fn write<P>(dir: P, version: &str, artifacts: &[Artifact]) -> io::Result<Vec<PathBuf>>
+where
+    P: AsRef<Path>,
+{
+    let dir = dir.as_ref();
+    fs::create_dir_all(dir)?;
+    let mut manifest = String::new();
+    let mut written = vec![];
+    for a in artifacts {
+        let file = format!("{}-{version}.{}", a.name, a.ext);
+        let path = dir.join(&file);
+        fs::write(&path, &a.data)?;
+        manifest += &format!("{:x}  {file}\n", Sha256::digest(&a.data));
+        written.push(path);
+    }
+    let path = dir.join(MANIFEST);
+    fs::write(&path, manifest)?;
+    written.push(path);
+
+    Ok(written)
+}
@@ -0,0 +1,140 @@
+use std::process::Command;
+
+use crate::{common, opensbi};
+
+/// The target tau is built for.
+pub const RUST_TARGET: &str = "riscv64imac-unknown-none-elf";
+
+struct Tool {
+    name: &'static str,
+    needed_by: &'static str,
+    fix: &'static str,
+}
+
+const TOOLS: &[Tool] = &[
+    Tool {
+        name: "git",
+        needed_by: "cloning u-boot and OpenSBI",
+        fix: "install git from your distribution",
+    },
+    Tool {
+        name: "make",
+        needed_by: "building u-boot and OpenSBI",
+        fix: "install make, usually in build-essential or base-devel",
+    },
+    Tool {
+        name: "cargo",
+        needed_by: "building tau",
+        fix: "install rustup from https://rustup.rs",
+    },
+    Tool {
+        name: "dtc",
+        needed_by: "boards with a .dts",
+        fix: "install dtc, usually in device-tree-compiler",
+    },
+    Tool {
+        name: "fdtoverlay",
+        needed_by: "device tree overlays",
+        fix: "install fdtoverlay, usually in device-tree-compiler",
+    },
+    Tool {
+        name: "mkimage",
+        needed_by: "fit, unless u-boot was built",
+        fix: "install mkimage, usually in u-boot-tools",
+    },
+    Tool {
+        name: "qemu-system-riscv64",
+        needed_by: "run",
+        fix: "install qemu-system-riscv64, usually in qemu-system-misc",
+    },
+    Tool {
+        name: "dfu-util",
+        needed_by: "update --usb dfu",
+        fix: "install dfu-util",
+    },
+];
+
+/// First line of `name --version`.
+fn version(name: &str) -> Option<String> {
+    let out = Command::new(name).arg("--version").output().ok()?;
+    let text = String::from_utf8_lossy(&out.stdout);
+    text.lines().next().map(|line| line.trim().to_owned())
+}
+
+fn report(name: &str, found: bool) {
+    if found {
+        let version = version(name).unwrap_or_default();
+        println!("  ok      {name} {version}");
+    } else {
+        println!("  missing {name}");
+    }
+}
+
+/// Probes the host for everything the builder runs, returns how many
+/// problems were found.
+pub fn check() -> usize {
+    let mut problems = 0;
+
+    for tool in TOOLS {
+        let found = common::find_in_path(tool.name).is_some();
+        report(tool.name, found);
+        if !found {
+            println!("          needed by {}, {}", tool.needed_by, tool.fix);
+            problems += 1;
+        }
+    }
+
+    // OpenSBI builds with either toolchain, u-boot needs the GNU one.
+    // Reports every tool, so no short-circuiting.
+    let missing_llvm = opensbi::LLVM
+        .iter()
+        .filter(|name| {
+            let found = common::find_in_path(name).is_some();
+            report(name, found);
+            !found
+        })
+        .count();
+    let gnu = opensbi::GNU_PREFIXES
+        .iter()
+        .map(|prefix| format!("{prefix}gcc"))
+        .find(|gcc| common::find_in_path(gcc).is_some());
+    match &gnu {
+        Some(gcc) => report(gcc, true),
+        None => {
+            report(&format!("{}gcc", opensbi::GNU_PREFIXES[0]), false);
+            println!(
+                "          needed by build-firmware, install a riscv64 cross gcc, \
+                 usually gcc-riscv64-linux-gnu"
+            );
+            problems += 1;
+        }
+    }
+    if missing_llvm != 0 && gnu.is_none() {
+        println!("          OpenSBI needs clang and ld.lld or a riscv64 cross gcc");
+    }
+
+    let installed = Command::new("rustup")
+        .args(["target", "list", "--installed"])
+        .output()
+        .ok()
+        .map(|out| {
+            String::from_utf8_lossy(&out.stdout)
+                .lines()
+                .any(|line| line.trim() == RUST_TARGET)
+        });
+    match installed {
+        Some(true) => println!("  ok      rust target {RUST_TARGET}"),
+        Some(false) => {
+            println!("  missing rust target {RUST_TARGET}");
+            println!("          run `rustup target add {RUST_TARGET}`");
+            problems += 1;
+        }
+        None => {
+            println!("  missing rustup, can't check the rust target {RUST_TARGET}");
+            println!("          install rustup from https://rustup.rs");
+            problems += 1;
+        }
+    }
+
+    problems
+}
@@ -0,0 +1,115 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use thiserror::Error;
+
+use crate::{common, interrupt};
+
+#[derive(Debug, Error)]
+pub enum DtcError {
+    #[error("{tool} not found in PATH, needed for {}", dts.display())]
+    Missing { tool: &'static str, dts: PathBuf },
+    #[error("{tool} failed on {}", dts.display())]
+    Failed { tool: &'static str, dts: PathBuf },
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// Where the board headers (`dt-bindings`, the SoC `.dtsi`) are found in a
+/// u-boot tree, older trees lack `dts/upstream`.
+const UBOOT_INCLUDES: [&str; 4] = [
+    "include",
+    "arch/riscv/dts",
+    "dts/upstream/include",
+    "dts/upstream/src/riscv",
+];
+
+/// The include directories of the u-boot tree in `dir` that exist.
+pub fn uboot_includes(dir: &Path) -> Vec<PathBuf> {
+    UBOOT_INCLUDES
+        .iter()
+        .map(|include| dir.join(include))
+        .filter(|path| path.is_dir())
+        .collect()
+}
+
+/// Runs `dts` through the C preprocessor, like the kernel does for the
+/// `#include`s, and then through `dtc` into `out`.
+pub fn compile(dts: &Path, includes: &[PathBuf], out: &Path) -> Result<(), DtcError> {
+    let find = |tool| {
+        common::find_in_path(tool).ok_or_else(|| DtcError::Missing {
+            tool,
+            dts: dts.into(),
+        })
+    };
+    let (cpp, dtc) = (find("cpp")?, find("dtc")?);
+    if let Some(parent) = out.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let pre = out.with_extension("dts.tmp");
+    let out_cpp = interrupt::run(
+        Command::new(cpp)
+            .args([
+                "-nostdinc",
+                "-undef",
+                "-D__DTS__",
+                "-x",
+                "assembler-with-cpp",
+            ])
+            .args(includes.iter().map(|i| format!("-I{}", i.display())))
+            .arg(dts)
+            .arg("-o")
+            .arg(&pre)
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit()),
+    )?;
+    common::bail(&out_cpp, || DtcError::Failed {
+        tool: "cpp",
+        dts: dts.into(),
+    })?;
+
+    let out_dtc = interrupt::run(
+        Command::new(dtc)
+            .args(["-I", "dts", "-O", "dtb"])
+            .args(includes.iter().flat_map(|i| ["-i".as_ref(), i.as_os_str()]))
+            .arg("-o")
+            .arg(out)
+            .arg(&pre)
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit()),
+    )?;
+    fs::remove_file(&pre)?;
+    common::bail(&out_dtc, || DtcError::Failed {
+        tool: "dtc",
+        dts: dts.into(),
+    })
+}
+
+/// Applies `overlays` in order on top of `base` with `fdtoverlay`.
+pub fn apply_overlays(base: &Path, overlays: &[PathBuf], out: &Path) -> Result<(), DtcError> {
+    let fdtoverlay = common::find_in_path("fdtoverlay").ok_or_else(|| DtcError::Missing {
+        tool: "fdtoverlay",
+        dts: base.into(),
+    })?;
+    if let Some(parent) = out.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let out_fdt = interrupt::run(
+        Command::new(fdtoverlay)
+            .arg("-i")
+            .arg(base)
+            .arg("-o")
+            .arg(out)
+            .args(overlays)
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit()),
+    )?;
+    common::bail(&out_fdt, || DtcError::Failed {
+        tool: "fdtoverlay",
+        dts: base.into(),
+    })
+}
@@ -0,0 +1,298 @@
+//! Just enough of the flattened device tree format to check the DTB handed
+//! to OpenSBI.
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum FdtError {
+    #[error("bad magic {0:#010x}, not a DTB")]
+    Magic(u32),
+    #[error("truncated at {0:#x}")]
+    Truncated(usize),
+    #[error("unexpected token {token:#x} at {offset:#x}")]
+    Token { offset: usize, token: u32 },
+}
+
+const MAGIC: u32 = 0xd00dfeed;
+
+const BEGIN_NODE: u32 = 1;
+const END_NODE: u32 = 2;
+const PROP: u32 = 3;
+const NOP: u32 = 4;
+
+pub struct Node {
+    /// With the unit address, empty for the root.
+    pub name: String,
+    pub props: Vec<(String, Vec<u8>)>,
+    pub children: Vec<Node>,
+}
+
+impl Node {
+    pub fn prop(&self, name: &str) -> Option<&[u8]> {
+        self.props
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// The first of the strings of the property.
+    pub fn string(&self, name: &str) -> Option<String> {
+        self.strings(name).into_iter().next()
+    }
+
+    pub fn strings(&self, name: &str) -> Vec<String> {
+        self.prop(name).map_or(vec![], |value| {
+            value
+                .split(|b| *b == 0)
+                .filter(|s| !s.is_empty())
+                .map(|s| String::from_utf8_lossy(s).into_owned())
+                .collect()
+        })
+    }
+
+    pub fn u32(&self, name: &str) -> Option<u32> {
+        Some(u32::from_be_bytes(
+            self.prop(name)?.get(..4)?.try_into().ok()?,
+        ))
+    }
+
+    /// Name without the unit address.
+    pub fn base_name(&self) -> &str {
+        self.name.split('@').next().unwrap_or_default()
+    }
+
+    pub fn child(&self, name: &str) -> Option<&Node> {
+        self.children
+            .iter()
+            .find(|c| c.name == name)
+            .or_else(|| self.children.iter().find(|c| c.base_name() == name))
+    }
+
+    /// Node at the absolute `path`.
+    pub fn find(&self, path: &str) -> Option<&Node> {
+        path.split('/')
+            .filter(|part| !part.is_empty())
+            .try_fold(self, |node, part| node.child(part))
+    }
+
+    fn enabled(&self) -> bool {
+        self.string("status")
+            .is_none_or(|status| status == "okay" || status == "ok")
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn u32(&self, at: usize) -> Result<u32, FdtError> {
+        let bytes = self.data.get(at..at + 4).ok_or(FdtError::Truncated(at))?;
+        Ok(u32::from_be_bytes(bytes.try_into().expect("four bytes")))
+    }
+
+    fn cstr(&self, at: usize) -> Result<&str, FdtError> {
+        let rest = self.data.get(at..).ok_or(FdtError::Truncated(at))?;
+        let len = rest
+            .iter()
+            .position(|b| *b == 0)
+            .ok_or(FdtError::Truncated(self.data.len()))?;
+        Ok(std::str::from_utf8(&rest[..len]).unwrap_or_default())
+    }
+}
+
+fn align(x: usize) -> usize {
+    (x + 3) & !3
+}
+
+/// Parses the structure block of the DTB in `data` into its root node.
+pub fn parse(data: &[u8]) -> Result<Node, FdtError> {
+    let r = Reader { data };
+    let magic = r.u32(0)?;
+    if magic != MAGIC {
+        return Err(FdtError::Magic(magic));
+    }
+    let structs = r.u32(8)? as usize;
+    let strings = r.u32(12)? as usize;
+
+    // Nodes being filled in, the root at the bottom.
+    let mut stack: Vec<Node> = vec![];
+    let mut at = structs;
+    loop {
+        let token = r.u32(at)?;
+        let offset = at;
+        at += 4;
+        match token {
+            BEGIN_NODE => {
+                let name = r.cstr(at)?.to_owned();
+                at = align(at + name.len() + 1);
+                stack.push(Node {
+                    name,
+                    props: vec![],
+                    children: vec![],
+                });
+            }
+            END_NODE => {
+                let node = stack.pop().ok_or(FdtError::Token { offset, token })?;
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(node),
+                    None => return Ok(node),
+                }
+            }
+            PROP => {
+                let len = r.u32(at)? as usize;
+                let name = r.cstr(strings + r.u32(at + 4)? as usize)?.to_owned();
+                at += 8;
+                let value = data.get(at..at + len).ok_or(FdtError::Truncated(at))?;
+                at = align(at + len);
+                let node = stack.last_mut().ok_or(FdtError::Token { offset, token })?;
+                node.props.push((name, value.to_vec()));
+            }
+            NOP => {}
+            _ => return Err(FdtError::Token { offset, token }),
+        }
+    }
+}
+
+/// Reads `cells` big endian cells from the start of `data`.
+fn cells(data: &[u8], cells: u32) -> Option<(u64, &[u8])> {
+    let len = cells as usize * 4;
+    let value = data.get(..len)?.chunks(4).fold(0u64, |acc, c| {
+        acc << 32 | u64::from(u32::from_be_bytes(c.try_into().unwrap()))
+    });
+    Some((value, &data[len..]))
+}
+
+pub struct Hart {
+    pub id: u64,
+    pub isa: Option<String>,
+}
+
+/// What OpenSBI relies on in the DTB.
+pub struct Summary {
+    pub model: Option<String>,
+    pub compatible: Vec<String>,
+    /// Start and size of the memory banks.
+    pub memory: Vec<(u64, u64)>,
+    pub stdout_path: Option<String>,
+    /// The node `stdout_path` resolves to.
+    pub stdout: Option<String>,
+    pub harts: Vec<Hart>,
+    pub problems: Vec<String>,
+}
+
+impl Summary {
+    pub fn new(root: &Node) -> Self {
+        let mut problems = vec![];
+
+        let address_cells = root.u32("#address-cells").unwrap_or(2);
+        let size_cells = root.u32("#size-cells").unwrap_or(1);
+        let mut memory = vec![];
+        for node in root.children.iter().filter(|c| {
+            c.base_name() == "memory" || c.string("device_type").as_deref() == Some("memory")
+        }) {
+            let mut reg = node.prop("reg").unwrap_or_default();
+            while address_cells + size_cells > 0
+                && let Some((start, rest)) = cells(reg, address_cells)
+                && let Some((size, rest)) = cells(rest, size_cells)
+            {
+                memory.push((start, size));
+                reg = rest;
+            }
+        }
+        if memory.iter().all(|(_, size)| *size == 0) {
+            problems.push("no memory node with a non-empty reg".to_owned());
+        }
+
+        let stdout_path = root.find("/chosen").and_then(|c| c.string("stdout-path"));
+        let stdout = match &stdout_path {
+            None => {
+                problems.push("no /chosen/stdout-path".to_owned());
+                None
+            }
+            Some(stdout_path) => {
+                let path = stdout_path.split(':').next().unwrap_or_default();
+                let path = if path.starts_with('/') {
+                    Some(path.to_owned())
+                } else {
+                    root.find("/aliases").and_then(|a| a.string(path))
+                };
+                match path.as_deref().and_then(|p| Some((p, root.find(p)?))) {
+                    Some((path, node)) if node.enabled() => Some(path.to_owned()),
+                    Some((path, _)) => {
+                        problems.push(format!("stdout-path {path} is disabled"));
+                        Some(path.to_owned())
+                    }
+                    None => {
+                        problems.push(format!("stdout-path {stdout_path} doesn't resolve"));
+                        None
+                    }
+                }
+            }
+        };
+
+        let mut harts = vec![];
+        if let Some(cpus) = root.find("/cpus") {
+            let cpu_cells = cpus.u32("#address-cells").unwrap_or(1);
+            for cpu in cpus
+                .children
+                .iter()
+                .filter(|c| c.string("device_type").as_deref() == Some("cpu") && c.enabled())
+            {
+                let id = cpu
+                    .prop("reg")
+                    .and_then(|reg| cells(reg, cpu_cells))
+                    .map_or(0, |(id, _)| id);
+                let isa = cpu
+                    .string("riscv,isa")
+                    .or_else(|| cpu.string("riscv,isa-base"));
+                harts.push(Hart { id, isa });
+            }
+        }
+        if harts.is_empty() {
+            problems.push("no enabled cpu in /cpus".to_owned());
+        }
+
+        Summary {
+            model: root.string("model"),
+            compatible: root.strings("compatible"),
+            memory,
+            stdout_path,
+            stdout,
+            harts,
+            problems,
+        }
+    }
+
+    pub fn print(&self) {
+        println!("model       {}", self.model.as_deref().unwrap_or("-"));
+        println!("compatible  {}", self.compatible.join(", "));
+        for (start, size) in &self.memory {
+            println!(
+                "memory      {start:#x}..{:#x} ({} MiB)",
+                start + size,
+                size >> 20
+            );
+        }
+        match (&self.stdout_path, &self.stdout) {
+            (Some(path), Some(node)) if path != node => println!("stdout-path {path} -> {node}"),
+            (Some(path), _) => println!("stdout-path {path}"),
+            (None, _) => println!("stdout-path -"),
+        }
+        let ids = self
+            .harts
+            .iter()
+            .map(|h| h.id.to_string())
+            .collect::<Vec<_>>();
+        let isa = self.harts.iter().find_map(|h| h.isa.as_deref());
+        println!(
+            "harts       {} ({}) {}",
+            self.harts.len(),
+            ids.join(", "),
+            isa.unwrap_or_default()
+        );
+        for problem in &self.problems {
+            println!("problem     {problem}");
+        }
+    }
+}
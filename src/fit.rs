@@ -0,0 +1,117 @@
+//! Packaging tau as a FIT image, for boards that boot it from u-boot proper
+//! rather than as the OpenSBI payload.
+
+use std::{
+    fmt::Write as _,
+    fs, io,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use thiserror::Error;
+
+use crate::{common, interrupt};
+
+#[derive(Debug, Error)]
+pub enum FitError {
+    #[error("mkimage not found in PATH or the u-boot build, needed for {}", .0.display())]
+    Missing(PathBuf),
+    #[error("mkimage failed on {}", .0.display())]
+    Failed(PathBuf),
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// What goes into the FIT image.
+pub struct Fit<'a> {
+    pub board: &'a str,
+    pub image: &'a Path,
+    pub dtb: &'a Path,
+    /// Where u-boot loads tau and jumps to.
+    pub load: u64,
+    /// Key name hint and algorithm the configuration is signed with.
+    pub signature: Option<(&'a str, &'a str)>,
+}
+
+/// `<...>` of an address, in as many cells as `#address-cells` says.
+fn cells(addr: u64, two: bool) -> String {
+    if two {
+        format!("<{:#x} {:#x}>", addr >> 32, addr as u32)
+    } else {
+        format!("<{addr:#x}>")
+    }
+}
+
+impl Fit<'_> {
+    /// The image tree source for `mkimage -f`. U-boot starts tau like a
+    /// Linux kernel, with the hart id in `a0` and the DTB in `a1`, the way
+    /// OpenSBI does.
+    pub fn its(&self) -> String {
+        let two = self.load > u64::from(u32::MAX);
+        let addr = cells(self.load, two);
+        let mut out = String::new();
+        let _ = writeln!(out, "/dts-v1/;\n\n/ {{");
+        let _ = writeln!(out, "\tdescription = \"tau for {}\";", self.board);
+        let _ = writeln!(out, "\t#address-cells = <{}>;\n", if two { 2 } else { 1 });
+        let _ = writeln!(out, "\timages {{\n\t\ttau {{");
+        let _ = writeln!(out, "\t\t\tdata = /incbin/(\"{}\");", self.image.display());
+        let _ = writeln!(out, "\t\t\ttype = \"kernel\";");
+        let _ = writeln!(out, "\t\t\tarch = \"riscv\";");
+        let _ = writeln!(out, "\t\t\tos = \"linux\";");
+        let _ = writeln!(out, "\t\t\tcompression = \"none\";");
+        let _ = writeln!(out, "\t\t\tload = {addr};");
+        let _ = writeln!(out, "\t\t\tentry = {addr};");
+        let _ = writeln!(out, "\t\t\thash-1 {{ algo = \"sha256\"; }};");
+        let _ = writeln!(out, "\t\t}};\n\t\tfdt {{");
+        let _ = writeln!(out, "\t\t\tdata = /incbin/(\"{}\");", self.dtb.display());
+        let _ = writeln!(out, "\t\t\ttype = \"flat_dt\";");
+        let _ = writeln!(out, "\t\t\tarch = \"riscv\";");
+        let _ = writeln!(out, "\t\t\tcompression = \"none\";");
+        let _ = writeln!(out, "\t\t\thash-1 {{ algo = \"sha256\"; }};");
+        let _ = writeln!(out, "\t\t}};\n\t}};\n");
+        let _ = writeln!(
+            out,
+            "\tconfigurations {{\n\t\tdefault = \"conf\";\n\t\tconf {{"
+        );
+        let _ = writeln!(out, "\t\t\tkernel = \"tau\";");
+        let _ = writeln!(out, "\t\t\tfdt = \"fdt\";");
+        if let Some((key, algo)) = self.signature {
+            let _ = writeln!(out, "\t\t\tsignature-1 {{");
+            let _ = writeln!(out, "\t\t\t\talgo = \"{algo}\";");
+            let _ = writeln!(out, "\t\t\t\tkey-name-hint = \"{key}\";");
+            let _ = writeln!(out, "\t\t\t\tsign-images = \"kernel\", \"fdt\";");
+            let _ = writeln!(out, "\t\t\t}};");
+        }
+        let _ = writeln!(out, "\t\t}};\n\t}};\n}};");
+        out
+    }
+}
+
+/// Runs `mkimage` on the `its` file into `out`, signing with the keys in
+/// `key_dir` and marking them as required if given. The `built` one, from
+/// the u-boot build, is preferred over the one in PATH.
+pub fn mkimage(
+    built: Option<PathBuf>,
+    its: &Path,
+    out: &Path,
+    key_dir: Option<&Path>,
+) -> Result<(), FitError> {
+    let mkimage = built
+        .filter(|path| path.is_file())
+        .or_else(|| common::find_in_path("mkimage"))
+        .ok_or_else(|| FitError::Missing(its.into()))?;
+    if let Some(parent) = out.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut cmd = Command::new(mkimage);
+    cmd.arg("-f").arg(its);
+    if let Some(key_dir) = key_dir {
+        cmd.arg("-k").arg(key_dir).arg("-r");
+    }
+    let res = interrupt::run(
+        cmd.arg(out)
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit()),
+    )?;
+    common::bail(&res, || FitError::Failed(its.into()))
+}
@@ -0,0 +1,203 @@
+//! The table of components and the checksums appended to the composed
+//! image, so the image describes itself and a torn write or bit rot on the
+//! card shows up without the local build. The checksums don't go in front,
+//! the image is entered at offset 0.
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+use sha2::{Digest, Sha256};
+
+use crate::history;
+
+const MAGIC: &[u8; 8] = b"TAU-SUM\0";
+
+/// Magic, length of the covered image, CRC-32, SHA-256, number of entries
+/// and CRC-32 of the entries, which follow.
+pub const SIZE: usize = 56;
+
+/// Name, offset, size and CRC-32.
+pub const ENTRY_SIZE: usize = 28;
+
+const NAME: usize = 16;
+
+/// More would be garbage that happens to follow the magic.
+const MAX_ENTRIES: usize = 256;
+
+pub struct Entry {
+    /// At most 16 bytes are kept.
+    pub name: String,
+    pub offset: u32,
+    pub size: u32,
+    pub crc32: u32,
+}
+
+impl Entry {
+    /// For the `data` at `offset` of the image.
+    pub fn new(name: &str, offset: usize, data: &[u8]) -> Self {
+        let mut end = name.len().min(NAME);
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        Entry {
+            name: name[..end].to_owned(),
+            offset: offset as u32,
+            size: data.len() as u32,
+            crc32: history::crc32(data),
+        }
+    }
+
+    fn to_bytes(&self) -> [u8; ENTRY_SIZE] {
+        let mut b = [0; ENTRY_SIZE];
+        b[..self.name.len()].copy_from_slice(self.name.as_bytes());
+        b[16..20].copy_from_slice(&self.offset.to_le_bytes());
+        b[20..24].copy_from_slice(&self.size.to_le_bytes());
+        b[24..28].copy_from_slice(&self.crc32.to_le_bytes());
+        b
+    }
+
+    fn parse(b: &[u8]) -> Option<Self> {
+        let name = &b[..NAME];
+        let len = name.iter().position(|c| *c == 0).unwrap_or(NAME);
+        let word = |at: usize| Some(u32::from_le_bytes(b.get(at..at + 4)?.try_into().ok()?));
+        Some(Entry {
+            name: String::from_utf8_lossy(&name[..len]).into_owned(),
+            offset: word(16)?,
+            size: word(20)?,
+            crc32: word(24)?,
+        })
+    }
+
+    /// Whether the component in `image` still matches.
+    pub fn matches(&self, image: &[u8]) -> bool {
+        let (offset, size) = (self.offset as usize, self.size as usize);
+        image
+            .get(offset..offset + size)
+            .is_some_and(|data| history::crc32(data) == self.crc32)
+    }
+}
+
+pub struct Footer {
+    pub len: u32,
+    pub crc32: u32,
+    pub sha256: [u8; 32],
+    pub entries: Vec<Entry>,
+    /// As stored, see `Check::Mismatch`.
+    pub entries_crc32: u32,
+}
+
+pub enum Check {
+    Ok(Footer),
+    /// No footer at the end of the image.
+    Missing,
+    /// The footer was written, but the image or the table no longer matches
+    /// it.
+    Mismatch(Footer),
+}
+
+fn entries_crc32(entries: &[Entry]) -> u32 {
+    let table = entries.iter().flat_map(Entry::to_bytes).collect::<Vec<_>>();
+    history::crc32(&table)
+}
+
+impl Footer {
+    pub fn new(image: &[u8], entries: Vec<Entry>) -> Self {
+        Footer {
+            len: image.len() as u32,
+            crc32: history::crc32(image),
+            sha256: Sha256::digest(image).into(),
+            entries_crc32: entries_crc32(&entries),
+            entries,
+        }
+    }
+
+    /// With the table.
+    pub fn size(&self) -> usize {
+        SIZE + self.entries.len() * ENTRY_SIZE
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut b = vec![0; SIZE];
+        b[..8].copy_from_slice(MAGIC);
+        b[8..12].copy_from_slice(&self.len.to_le_bytes());
+        b[12..16].copy_from_slice(&self.crc32.to_le_bytes());
+        b[16..48].copy_from_slice(&self.sha256);
+        b[48..52].copy_from_slice(&(self.entries.len() as u32).to_le_bytes());
+        b[52..56].copy_from_slice(&self.entries_crc32.to_le_bytes());
+        b.extend(self.entries.iter().flat_map(Entry::to_bytes));
+        b
+    }
+
+    pub fn parse(b: &[u8]) -> Option<Self> {
+        if b.get(..8)? != MAGIC {
+            return None;
+        }
+        let word = |at: usize| Some(u32::from_le_bytes(b.get(at..at + 4)?.try_into().ok()?));
+        let count = word(48)? as usize;
+        if count > MAX_ENTRIES {
+            return None;
+        }
+        let table = b.get(SIZE..SIZE + count * ENTRY_SIZE)?;
+        Some(Footer {
+            len: word(8)?,
+            crc32: word(12)?,
+            sha256: b.get(16..48)?.try_into().ok()?,
+            entries: table
+                .chunks(ENTRY_SIZE)
+                .map(Entry::parse)
+                .collect::<Option<_>>()?,
+            entries_crc32: word(52)?,
+        })
+    }
+
+    /// The entries whose component in `image` doesn't match.
+    pub fn damaged(&self, image: &[u8]) -> Vec<&str> {
+        self.entries
+            .iter()
+            .filter(|e| !e.matches(image))
+            .map(|e| e.name.as_str())
+            .collect()
+    }
+}
+
+/// Checks the `size` bytes image at the start of `data` against the footer
+/// that follows it.
+pub fn check(data: &[u8], size: usize) -> Check {
+    let Some(footer) = data.get(size..).and_then(Footer::parse) else {
+        return Check::Missing;
+    };
+    let image = &data[..size];
+    if footer.len as usize == size
+        && footer.crc32 == history::crc32(image)
+        && footer.sha256 == <[u8; 32]>::from(Sha256::digest(image))
+        && footer.entries_crc32 == entries_crc32(&footer.entries)
+    {
+        Check::Ok(footer)
+    } else {
+        Check::Mismatch(footer)
+    }
+}
+
+/// Reads the `size` bytes image at `offset` of `file` with the footer that
+/// follows it, if there is one, for `check`.
+pub fn read_at<F>(file: &mut F, offset: u64, size: usize) -> io::Result<Vec<u8>>
+where
+    F: Read + Seek,
+{
+    let mut data = vec![0; size + SIZE];
+    file.seek(SeekFrom::Start(offset))?;
+    match file.read_exact(&mut data) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(vec![]),
+        Err(err) => return Err(err),
+    }
+    if data[size..].starts_with(MAGIC) {
+        let count = u32::from_le_bytes(data[size + 48..size + 52].try_into().unwrap());
+        let mut table = vec![0; (count as usize).min(MAX_ENTRIES) * ENTRY_SIZE];
+        match file.read_exact(&mut table) {
+            Ok(()) => data.extend(table),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(data)
+}
@@ -0,0 +1,243 @@
+use std::{
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+const MAGIC: &[u8; 8] = b"TAU-ID\0\x01";
+
+/// Identification block in the reserved `id` region of the disk layout. The
+/// region is outside of every firmware region, so it never takes part in
+/// verification.
+pub struct IdBlock {
+    pub id: uuid::Uuid,
+    pub image_crc: u32,
+    pub time: u64,
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC).checksum(data)
+}
+
+impl IdBlock {
+    pub const SIZE: usize = 512;
+
+    /// Keeps the id of `previous`, so a card stays the same card across
+    /// updates.
+    pub fn new(previous: Option<&IdBlock>, image_crc: u32) -> Self {
+        IdBlock {
+            id: previous.map_or_else(uuid::Uuid::new_v4, |b| b.id),
+            image_crc,
+            time: now(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut b = [0; Self::SIZE];
+        b[..8].copy_from_slice(MAGIC);
+        b[8..24].copy_from_slice(self.id.as_bytes());
+        b[24..28].copy_from_slice(&self.image_crc.to_le_bytes());
+        b[28..36].copy_from_slice(&self.time.to_le_bytes());
+        let crc = crc32(&b[..Self::SIZE - 4]);
+        b[Self::SIZE - 4..].copy_from_slice(&crc.to_le_bytes());
+        b
+    }
+
+    pub fn parse(b: &[u8; Self::SIZE]) -> Option<Self> {
+        let crc = u32::from_le_bytes(b[Self::SIZE - 4..].try_into().ok()?);
+        if &b[..8] != MAGIC || crc32(&b[..Self::SIZE - 4]) != crc {
+            return None;
+        }
+        Some(IdBlock {
+            id: uuid::Uuid::from_slice(&b[8..24]).ok()?,
+            image_crc: u32::from_le_bytes(b[24..28].try_into().ok()?),
+            time: u64::from_le_bytes(b[28..36].try_into().ok()?),
+        })
+    }
+
+    pub fn read<F>(file: &mut F, offset: u64) -> io::Result<Option<Self>>
+    where
+        F: Read + Seek,
+    {
+        let mut b = [0; Self::SIZE];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut b)?;
+        Ok(Self::parse(&b))
+    }
+
+    pub fn write<F>(&self, file: &mut F, offset: u64) -> io::Result<()>
+    where
+        F: Write + Seek,
+    {
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&self.to_bytes())?;
+        file.flush()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ComponentHash {
+    pub name: String,
+    pub crc32: String,
+}
+
+impl ComponentHash {
+    pub fn new(name: &str, data: &[u8]) -> Self {
+        ComponentHash {
+            name: name.to_owned(),
+            crc32: format!("{:08x}", crc32(data)),
+        }
+    }
+}
+
+/// One line of `history.jsonl`.
+#[derive(Serialize, Deserialize)]
+pub struct Record {
+    /// Seconds since the Unix epoch.
+    pub time: u64,
+    pub command: String,
+    pub device: String,
+    pub serial: Option<String>,
+    pub id: Option<uuid::Uuid>,
+    pub image_crc32: String,
+    pub components: Vec<ComponentHash>,
+    pub version: String,
+    pub duration_ms: u64,
+}
+
+impl Record {
+    pub fn new<P>(command: &str, device: P, image: &[u8], components: Vec<ComponentHash>) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        let device = device.as_ref();
+        Record {
+            time: now(),
+            command: command.to_owned(),
+            device: fs::canonicalize(device)
+                .unwrap_or_else(|_| device.to_owned())
+                .display()
+                .to_string(),
+            serial: serial(device),
+            id: None,
+            image_crc32: format!("{:08x}", crc32(image)),
+            components,
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            duration_ms: 0,
+        }
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Serial number of the disk as reported by the kernel, if any.
+#[cfg(target_os = "linux")]
+pub fn serial<P>(path: P) -> Option<String>
+where
+    P: AsRef<Path>,
+{
+    let dev = fs::canonicalize(path).ok()?;
+    let sys = Path::new("/sys/block").join(dev.file_name()?);
+    let serial = fs::read_to_string(sys.join("device/serial")).ok()?;
+    Some(serial.trim().to_owned()).filter(|s| !s.is_empty())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn serial<P>(path: P) -> Option<String>
+where
+    P: AsRef<Path>,
+{
+    let _ = path;
+    None
+}
+
+/// `$XDG_DATA_HOME/tau-builder/history.jsonl`, `~/.local/share` by default.
+pub fn path() -> Option<PathBuf> {
+    let data = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| Some(PathBuf::from(std::env::var_os("HOME")?).join(".local/share")))?;
+    Some(data.join("tau-builder").join("history.jsonl"))
+}
+
+pub fn append(record: &Record) -> io::Result<()> {
+    let path = path().ok_or_else(|| io::Error::other("no home directory"))?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut line = serde_json::to_string(record)?;
+    line.push('\n');
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(line.as_bytes())
+}
+
+/// Every record, oldest first. Lines that don't parse are skipped.
+pub fn load() -> io::Result<Vec<Record>> {
+    let Some(path) = path() else {
+        return Ok(vec![]);
+    };
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err),
+    };
+    Ok(text
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+/// `YYYY-MM-DD hh:mm:ss` in UTC.
+pub fn timestamp(secs: u64) -> String {
+    let (days, rest) = (secs / 86400, secs % 86400);
+    // Howard Hinnant's civil_from_days.
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + i64::from(m <= 2);
+    format!(
+        "{y:04}-{m:02}-{d:02} {:02}:{:02}:{:02}",
+        rest / 3600,
+        rest / 60 % 60,
+        rest % 60
+    )
+}
+
+/// `timestamp` fit for a file name, `YYYY-MM-DD_hh-mm-ss`.
+pub fn file_stamp(secs: u64) -> String {
+    timestamp(secs).replace(' ', "_").replace(':', "-")
+}
+
+pub fn print(records: &[&Record], json: bool) -> serde_json::Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(records)?);
+        return Ok(());
+    }
+    for r in records {
+        let id = r.id.map(|id| id.to_string()).unwrap_or_default();
+        println!(
+            "{} {:<7} {} {} crc32 {} {}ms {id}",
+            timestamp(r.time),
+            r.command,
+            r.device,
+            r.serial.as_deref().unwrap_or("-"),
+            r.image_crc32,
+            r.duration_ms
+        );
+    }
+    Ok(())
+}
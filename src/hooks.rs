@@ -0,0 +1,147 @@
+use std::{io, process::Command};
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::interrupt;
+
+#[derive(Debug, Error)]
+pub enum HookError {
+    #[error("hook {0}: {1}")]
+    Spawn(&'static str, io::Error),
+    #[error("hook {0} failed: {1}")]
+    Failed(&'static str, std::process::ExitStatus),
+}
+
+/// User commands run around the build and flash steps, from `[hooks]` in the
+/// config. Placeholders `{image}`, `{device}`, `{manifest}` and `{board}`
+/// are substituted before the command is passed to `sh -c`. A hook is
+/// killed like any other command after `--command-timeout`.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Hooks {
+    pub pre_build_tau: Option<String>,
+    pub post_build_tau: Option<String>,
+    pub pre_update: Option<String>,
+    pub post_update: Option<String>,
+    /// Fail the run if a `post_*` hook fails, instead of only reporting it.
+    pub post_fatal: bool,
+}
+
+#[derive(Clone, Copy)]
+pub enum HookPoint {
+    PreBuildTau,
+    PostBuildTau,
+    PreUpdate,
+    PostUpdate,
+}
+
+impl HookPoint {
+    pub fn name(self) -> &'static str {
+        match self {
+            HookPoint::PreBuildTau => "pre_build_tau",
+            HookPoint::PostBuildTau => "post_build_tau",
+            HookPoint::PreUpdate => "pre_update",
+            HookPoint::PostUpdate => "post_update",
+        }
+    }
+
+    fn is_pre(self) -> bool {
+        matches!(self, HookPoint::PreBuildTau | HookPoint::PreUpdate)
+    }
+}
+
+#[derive(Default)]
+pub struct Vars<'a> {
+    pub image: &'a str,
+    pub device: &'a str,
+    pub manifest: &'a str,
+    pub board: &'a str,
+}
+
+impl Hooks {
+    fn get(&self, point: HookPoint) -> Option<&str> {
+        match point {
+            HookPoint::PreBuildTau => self.pre_build_tau.as_deref(),
+            HookPoint::PostBuildTau => self.post_build_tau.as_deref(),
+            HookPoint::PreUpdate => self.pre_update.as_deref(),
+            HookPoint::PostUpdate => self.post_update.as_deref(),
+        }
+    }
+
+    /// The command configured at `point`, with `vars` substituted.
+    pub fn command(&self, point: HookPoint, vars: &Vars) -> Option<String> {
+        let command = self
+            .get(point)?
+            .replace("{image}", vars.image)
+            .replace("{device}", vars.device)
+            .replace("{manifest}", vars.manifest)
+            .replace("{board}", vars.board);
+        Some(command)
+    }
+
+    /// Runs `command`, the one `command` returned for `point`.
+    pub fn run(&self, point: HookPoint, command: &str) -> Result<(), HookError> {
+        let name = point.name();
+        eprintln!("hook {name}: {command}");
+
+        let status = interrupt::run(Command::new("sh").args(["-c", command]))
+            .map(|out| out.status)
+            .map_err(|err| HookError::Spawn(name, err))?;
+        if status.success() {
+            Ok(())
+        } else if point.is_pre() || self.post_fatal {
+            Err(HookError::Failed(name, status))
+        } else {
+            eprintln!("hook {name} failed: {status}");
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{HookError, HookPoint, Hooks, Vars};
+    use crate::testing::TempDir;
+
+    #[test]
+    fn marker_file() {
+        let tmp = TempDir::new("hook");
+        let dir = tmp.path();
+        let marker = dir.join("marker");
+        let hooks = Hooks {
+            post_update: Some(format!("echo {{board}} {{device}} > {}", marker.display())),
+            ..Hooks::default()
+        };
+        let vars = Vars {
+            device: "/dev/sdX",
+            board: "visionfive2",
+            ..Vars::default()
+        };
+        assert!(hooks.command(HookPoint::PreUpdate, &vars).is_none());
+        let command = hooks.command(HookPoint::PostUpdate, &vars).unwrap();
+        hooks.run(HookPoint::PostUpdate, &command).unwrap();
+        assert_eq!(
+            fs::read_to_string(&marker).unwrap(),
+            "visionfive2 /dev/sdX\n"
+        );
+    }
+
+    #[test]
+    fn failing_hook() {
+        let mut hooks = Hooks::default();
+        // Only a failing pre hook or, with `post_fatal`, a post hook stops the run.
+        assert!(matches!(
+            hooks.run(HookPoint::PreBuildTau, "exit 1"),
+            Err(HookError::Failed("pre_build_tau", status)) if status.code() == Some(1)
+        ));
+        hooks.run(HookPoint::PostBuildTau, "exit 1").unwrap();
+        hooks.post_fatal = true;
+        assert!(matches!(
+            hooks.run(HookPoint::PostBuildTau, "exit 1"),
+            Err(HookError::Failed("post_build_tau", _))
+        ));
+    }
+}
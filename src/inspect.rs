@@ -0,0 +1,292 @@
+use std::{
+    fs,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use gpt::disk::LogicalBlockSize;
+
+use crate::{
+    board::{DiskLayout, Region},
+    footer,
+    history::{self, IdBlock},
+    metadata::BuildInfo,
+    spl_header::{self, SplHeaderFormat},
+};
+
+fn read_region<F>(file: &mut F, region: Region) -> io::Result<Vec<u8>>
+where
+    F: Read + Seek,
+{
+    let mut data = vec![];
+    file.seek(SeekFrom::Start(region.offset))?;
+    file.take(region.size).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Length of `data` without the trailing zeros.
+fn used(data: &[u8]) -> usize {
+    data.iter().rposition(|b| *b != 0).map_or(0, |p| p + 1)
+}
+
+/// The OpenSBI region runs up to tau, which sits inside it.
+fn opensbi_region(layout: &DiskLayout) -> Region {
+    Region {
+        offset: layout.opensbi.offset,
+        size: layout.tau.offset - layout.opensbi.offset,
+    }
+}
+
+fn open_gpt(
+    file: &mut fs::File,
+    block: LogicalBlockSize,
+) -> Result<gpt::GptDisk<&mut fs::File>, gpt::GptError> {
+    gpt::GptConfig::new()
+        .writable(false)
+        .logical_block_size(block)
+        .open_from_device(file)
+}
+
+fn print_gpt(file: &mut fs::File) {
+    // The GPT of a disk of 4K sectors starts at 4096.
+    let disk = match open_gpt(file, LogicalBlockSize::Lb512) {
+        Err(err) => match open_gpt(file, LogicalBlockSize::Lb4096) {
+            Ok(disk) => disk,
+            Err(_) => {
+                println!("gpt: none ({err})");
+                print_mbr(file, LogicalBlockSize::Lb512);
+                return;
+            }
+        },
+        Ok(disk) => disk,
+    };
+    let lb_size = *disk.logical_block_size();
+    match lb_size {
+        LogicalBlockSize::Lb512 => println!("gpt: disk guid {}", disk.guid()),
+        LogicalBlockSize::Lb4096 => {
+            println!("gpt: disk guid {}, 4096 byte sectors", disk.guid())
+        }
+    }
+    for (id, p) in disk.partitions() {
+        let start = p.bytes_start(lb_size).unwrap_or_default();
+        let end = start + p.bytes_len(lb_size).unwrap_or_default();
+        println!(
+            "  {id} {start:#010x}..{end:#010x} {} \"{}\"",
+            p.part_type_guid.guid, p.name
+        );
+    }
+    // A hybrid MBR has more than the protective partition.
+    let hybrid = gpt::mbr::ProtectiveMBR::from_disk(file, lb_size).is_ok_and(|mbr| {
+        (0..4).any(|i| {
+            mbr.partition(i)
+                .is_some_and(|r| ![0, 0xee].contains(&r.os_type))
+        })
+    });
+    if hybrid {
+        print_mbr(file, lb_size);
+    }
+}
+
+fn print_mbr(file: &mut fs::File, block: LogicalBlockSize) {
+    let Ok(mbr) = gpt::mbr::ProtectiveMBR::from_disk(file, block) else {
+        return;
+    };
+    let signature = u32::from_le_bytes(*mbr.disk_signature());
+    println!("mbr: disk signature {signature:08x}");
+    for (i, r) in (0..4).filter_map(|i| Some((i + 1, mbr.partition(i)?))) {
+        if r.os_type != 0 {
+            let start = u64::from(r.lb_start) * block.as_u64();
+            let end = start + u64::from(r.lb_size) * block.as_u64();
+            println!("  {i} {start:#010x}..{end:#010x} type {:#04x}", r.os_type);
+        }
+    }
+}
+
+/// The checksums and the component table after the `image_size` bytes
+/// image at the start of `data`.
+pub fn print_footer(data: &[u8], image_size: usize) {
+    let footer = match footer::check(data, image_size) {
+        footer::Check::Ok(f) => {
+            println!("  footer ok, crc32 {:08x}", f.crc32);
+            f
+        }
+        footer::Check::Mismatch(f) => {
+            println!("  footer crc32 {:08x} doesn't match the image", f.crc32);
+            f
+        }
+        footer::Check::Missing => {
+            println!("  footer: none");
+            return;
+        }
+    };
+    for e in &footer.entries {
+        let state = if e.matches(data) { "ok" } else { "damaged" };
+        println!(
+            "  {:<16} {:#08x}..{:#08x} crc32 {:08x} {state}",
+            e.name,
+            e.offset,
+            e.offset + e.size,
+            e.crc32
+        );
+    }
+}
+
+/// Prints the GPT, the SPL header, what sits in the OpenSBI, tau and
+/// recovery regions, the footer and build metadata of the `image_size` bytes
+/// tau images and the id block of the disk in `file` laid out as `layout`.
+pub fn print(
+    file: &mut fs::File,
+    layout: &DiskLayout,
+    formats: &[&SplHeaderFormat],
+    local_tau: Option<&[u8]>,
+    image_size: usize,
+) -> io::Result<()> {
+    print_gpt(file);
+
+    let spl = read_region(file, layout.spl)?;
+    match spl_header::detect(formats, &spl) {
+        Some(h) => {
+            println!("spl header at {:#x}: {}", layout.spl.offset, h.format);
+            println!("  payload size {:#x}", h.payload_size);
+            if let Some(backup) = h.backup_offset {
+                println!("  backup offset {backup:#x}");
+                let at = layout.spl.offset + u64::from(backup);
+                let copy = spl
+                    .get(backup as usize..)
+                    .and_then(|data| spl_header::detect(formats, data));
+                match copy {
+                    Some(copy) if copy.crc_ok != Some(false) => {
+                        println!("  backup copy at {at:#x}")
+                    }
+                    Some(_) => println!("  backup copy at {at:#x}, crc doesn't match the payload"),
+                    None if at < layout.spl.end() => println!("  no backup copy at {at:#x}"),
+                    None => println!("  backup copy at {at:#x} is outside of the spl region"),
+                }
+            }
+            if let Some(version) = h.version {
+                println!("  version {version:#010x}");
+            }
+            match h.crc_ok {
+                Some(true) => println!("  crc matches"),
+                Some(false) => println!("  crc doesn't match the payload"),
+                None => {}
+            }
+        }
+        None => println!("spl header at {:#x}: not found", layout.spl.offset),
+    }
+
+    let opensbi = read_region(file, opensbi_region(layout))?;
+    const BANNER: &[u8] = b"OpenSBI";
+    let version = opensbi
+        .windows(BANNER.len())
+        .position(|w| w == BANNER)
+        .map(|pos| {
+            let rest = &opensbi[pos..];
+            let end = rest
+                .iter()
+                .position(|b| !b.is_ascii_graphic() && *b != b' ')
+                .unwrap_or(rest.len());
+            String::from_utf8_lossy(&rest[..end]).into_owned()
+        });
+    match version {
+        Some(version) => println!("opensbi at {:#x}: {version}", layout.opensbi.offset),
+        None if opensbi.iter().all(|b| *b == 0) => {
+            println!("opensbi at {:#x}: empty", layout.opensbi.offset)
+        }
+        None => println!("opensbi at {:#x}: unknown data", layout.opensbi.offset),
+    }
+
+    print_slot(file, "tau", layout.tau, local_tau, image_size)?;
+    if let Some(recovery) = layout.recovery {
+        print_slot(file, "recovery", recovery, local_tau, image_size)?;
+    }
+
+    match IdBlock::read(file, layout.id.offset).ok().flatten() {
+        Some(block) => println!(
+            "id {} written {} with image crc32 {:08x}",
+            block.id,
+            history::timestamp(block.time),
+            block.image_crc
+        ),
+        None => println!("id block: none"),
+    }
+
+    Ok(())
+}
+
+/// What sits in the tau image slot `region`.
+fn print_slot(
+    file: &mut fs::File,
+    name: &str,
+    region: Region,
+    local_tau: Option<&[u8]>,
+    image_size: usize,
+) -> io::Result<()> {
+    let tau = read_region(file, region)?;
+    let used = used(&tau);
+    print!(
+        "{name} at {:#x}: {used:#x} bytes used, crc32 {:08x}",
+        region.offset,
+        history::crc32(&tau[..used])
+    );
+    match local_tau {
+        Some(local) if tau.starts_with(local) => println!(", matches the local image"),
+        Some(_) => println!(", differs from the local image"),
+        None => println!(),
+    }
+    print_footer(&tau, image_size);
+    match BuildInfo::find(&tau) {
+        Some((at, info)) => {
+            println!("metadata at {:#x}:", region.offset + at as u64);
+            info.print();
+        }
+        None => println!("metadata: none"),
+    }
+
+    Ok(())
+}
+
+/// Writes the SPL with its header, the OpenSBI payload and the tau image
+/// found on the disk in `file` to `spl.bin`, `fw_payload.bin` and `tau` in
+/// `out_dir`. The SPL is cut by the size in its header, the others by their
+/// trailing zeros.
+pub fn extract<P>(
+    file: &mut fs::File,
+    layout: &DiskLayout,
+    formats: &[&SplHeaderFormat],
+    out_dir: P,
+) -> io::Result<Vec<PathBuf>>
+where
+    P: AsRef<Path>,
+{
+    let out_dir = out_dir.as_ref();
+    fs::create_dir_all(out_dir)?;
+    let mut written = vec![];
+    let mut save = |name: &str, data: &[u8]| {
+        let path = out_dir.join(name);
+        fs::write(&path, data)?;
+        println!("{}: {:#x} bytes", path.display(), data.len());
+        written.push(path);
+        io::Result::Ok(())
+    };
+
+    let spl = read_region(file, layout.spl)?;
+    match formats.iter().find_map(|f| Some((f, f.parse(&spl)?))) {
+        Some((format, h)) => {
+            let end = (format.size + h.payload_size as usize).min(spl.len());
+            save("spl.bin", &spl[..end])?;
+        }
+        None => eprintln!(
+            "warning: no spl header at {:#x}, skipping",
+            layout.spl.offset
+        ),
+    }
+
+    let opensbi = read_region(file, opensbi_region(layout))?;
+    save("fw_payload.bin", &opensbi[..used(&opensbi)])?;
+
+    let tau = read_region(file, layout.tau)?;
+    save("tau", &tau[..used(&tau)])?;
+
+    Ok(written)
+}
@@ -0,0 +1,104 @@
+use std::{
+    io,
+    os::unix::process::CommandExt,
+    process::{self, Command, Output},
+    sync::{
+        Mutex, OnceLock,
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
+    thread,
+    time::Duration,
+};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Process groups of the running children.
+static CHILDREN: Mutex<Vec<i32>> = Mutex::new(Vec::new());
+
+static TIMEOUT: OnceLock<Duration> = OnceLock::new();
+
+/// The first Ctrl-C is forwarded to the children and remembered, so the
+/// current step can stop at a safe point; the second one exits immediately.
+pub fn install() -> Result<(), ctrlc::Error> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            eprintln!("interrupted again, exiting");
+            process::exit(130);
+        }
+        eprintln!("interrupted, stopping; press Ctrl-C again to exit immediately");
+        let children = CHILDREN.lock().unwrap_or_else(|err| err.into_inner());
+        for &pgid in children.iter() {
+            // SAFETY: plain syscall, the group may already be gone
+            unsafe { libc::killpg(pgid, libc::SIGINT) };
+        }
+    })
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Kill the commands `run` runs once they take longer than `timeout`.
+pub fn set_timeout(timeout: Duration) {
+    let _ = TIMEOUT.set(timeout);
+}
+
+/// Runs the command to completion like `Command::output`, but in its own
+/// process group, so it receives Ctrl-C only through `install`'s handler,
+/// after which tau-builder waits for it to exit.
+pub fn run(command: &mut Command) -> io::Result<Output> {
+    run_for(command, TIMEOUT.get().copied())
+}
+
+fn run_for(command: &mut Command, timeout: Option<Duration>) -> io::Result<Output> {
+    if interrupted() {
+        return Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted"));
+    }
+    let child = command.process_group(0).spawn()?;
+    let pgid = child.id() as i32;
+    CHILDREN
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .push(pgid);
+    let (done, wait) = mpsc::channel::<()>();
+    let watchdog = timeout.map(|timeout| {
+        thread::spawn(move || {
+            let expired = wait.recv_timeout(timeout) == Err(mpsc::RecvTimeoutError::Timeout);
+            if expired {
+                // SAFETY: plain syscall, the group may already be gone
+                unsafe { libc::killpg(pgid, libc::SIGKILL) };
+            }
+            expired
+        })
+    });
+    let out = child.wait_with_output();
+    drop(done);
+    let expired = watchdog.is_some_and(|watchdog| watchdog.join().unwrap_or(false));
+    CHILDREN
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .retain(|&p| p != pgid);
+    match timeout {
+        Some(timeout) if expired => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("killed after {}s", timeout.as_secs_f32()),
+        )),
+        _ => out,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, process::Command, time::Duration};
+
+    #[test]
+    fn timeout() {
+        let mut sleep = Command::new("sleep");
+        sleep.arg("10");
+        let err = super::run_for(&mut sleep, Some(Duration::from_millis(100))).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        let out = super::run_for(&mut Command::new("true"), Some(Duration::from_secs(10))).unwrap();
+        assert!(out.status.success());
+    }
+}
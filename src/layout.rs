@@ -0,0 +1,396 @@
+use std::{fs, io, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::common;
+
+/// Where the OS side keeps its layout, instead of `[layout]` in
+/// `tau-builder.toml`.
+pub const LAYOUT_PATH: &str = "layout.toml";
+
+#[derive(Debug, Error)]
+pub enum LayoutError {
+    #[error("read {LAYOUT_PATH}: {0}")]
+    Read(#[from] io::Error),
+    #[error("parse {LAYOUT_PATH}: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("component {name} at {offset:#x}..{end:#x} is outside of the {size:#x} byte image")]
+    Outside {
+        name: String,
+        offset: usize,
+        end: usize,
+        size: usize,
+    },
+    #[error("component {name} ending at {end:#x} overlaps {other} starting at {start:#x}")]
+    Overlap {
+        name: String,
+        end: usize,
+        other: String,
+        start: usize,
+    },
+    #[error("component {0} is listed twice")]
+    Duplicate(String),
+    #[error("component {name} has offset {value:?}, expected a number or \"auto\"")]
+    Offset { name: String, value: String },
+    #[error("component {name} at {offset:#x}..{end:#x} isn't aligned to {align:#x}")]
+    Misaligned {
+        name: String,
+        offset: usize,
+        end: usize,
+        align: usize,
+    },
+    #[error("grow_to {grow_to:#x} is smaller than the {size:#x} byte image")]
+    GrowTo { grow_to: usize, size: usize },
+    #[error("serialize the layout: {0}")]
+    Serialize(#[from] toml::ser::Error),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ComponentKind {
+    /// Loadable segments are extracted with `elf_to_raw`.
+    Elf,
+    /// Copied verbatim.
+    Raw,
+    /// Copied verbatim, but not built with tau: an initial filesystem or
+    /// other data the system reads at run time. It is watched for changes
+    /// and left out of `disasm` unless named.
+    Payload,
+    /// An ELF or a flat binary, told apart by the ELF magic.
+    #[default]
+    Auto,
+}
+
+impl ComponentKind {
+    /// `Auto` resolved for the file holding `data`.
+    pub fn detect(self, data: &[u8]) -> Self {
+        match self {
+            ComponentKind::Auto if data.starts_with(b"\x7fELF") => ComponentKind::Elf,
+            ComponentKind::Auto => ComponentKind::Raw,
+            kind => kind,
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct Component {
+    pub name: String,
+    pub path: PathBuf,
+    pub offset: usize,
+    pub max_size: usize,
+    #[serde(rename = "type")]
+    pub kind: ComponentKind,
+    /// The component is linked at 0 and relocates itself.
+    pub position_independent: bool,
+    /// Both ends of the slot are multiples of it, so the slot covers whole
+    /// erase blocks and can be rewritten without touching its neighbours.
+    pub align: usize,
+    /// What the slot is padded with past the component, the fill of the
+    /// layout by default.
+    pub fill: u8,
+}
+
+/// Where compose writes the build metadata, see `metadata`.
+#[derive(Clone, Copy, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetadataSlot {
+    pub offset: usize,
+    #[serde(default = "metadata_size")]
+    pub max_size: usize,
+}
+
+fn metadata_size() -> usize {
+    0x200
+}
+
+/// Placement of the components inside the composed tau image.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(try_from = "Manifest")]
+pub struct Layout {
+    pub size: usize,
+    /// What the image is padded with between the slots, and the default
+    /// `fill` of the components. 0xff suits SPI NOR, which erases to it.
+    pub fill: u8,
+    /// The image may grow up to this size when the last component outgrows
+    /// its slot, the slot then takes what the component needs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grow_to: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<MetadataSlot>,
+    #[serde(rename = "component")]
+    pub components: Vec<Component>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Offset {
+    At(usize),
+    Named(String),
+}
+
+/// A component as written, the offset may be `"auto"`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Entry {
+    name: String,
+    path: PathBuf,
+    offset: Offset,
+    max_size: usize,
+    #[serde(rename = "type", default)]
+    kind: ComponentKind,
+    #[serde(default)]
+    position_independent: bool,
+    #[serde(default)]
+    align: Option<usize>,
+    #[serde(default)]
+    fill: Option<u8>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    size: usize,
+    /// The alignment of the components that don't set their own, the
+    /// erase block of the medium, 4 KiB for SD cards or 64 KiB for SPI NOR.
+    #[serde(default)]
+    align: Option<usize>,
+    #[serde(default)]
+    fill: u8,
+    #[serde(default)]
+    grow_to: Option<usize>,
+    #[serde(default)]
+    metadata: Option<MetadataSlot>,
+    component: Vec<Entry>,
+}
+
+impl TryFrom<Manifest> for Layout {
+    type Error = LayoutError;
+
+    /// An `"auto"` offset places the component right after the previous
+    /// slot, rounded up to its alignment.
+    fn try_from(manifest: Manifest) -> Result<Self, Self::Error> {
+        let mut components = Vec::<Component>::with_capacity(manifest.component.len());
+        for entry in manifest.component {
+            let align = entry.align.or(manifest.align).unwrap_or(1).max(1);
+            let offset = match entry.offset {
+                Offset::At(offset) => offset,
+                Offset::Named(value) if value == "auto" => components
+                    .last()
+                    .map_or(0, |c| c.offset + c.max_size)
+                    .next_multiple_of(align),
+                Offset::Named(value) => {
+                    return Err(LayoutError::Offset {
+                        name: entry.name,
+                        value,
+                    });
+                }
+            };
+            components.push(Component {
+                name: entry.name,
+                path: entry.path,
+                offset,
+                max_size: entry.max_size,
+                kind: entry.kind,
+                position_independent: entry.position_independent,
+                align,
+                fill: entry.fill.unwrap_or(manifest.fill),
+            });
+        }
+        Ok(Layout {
+            size: manifest.size,
+            fill: manifest.fill,
+            grow_to: manifest.grow_to,
+            metadata: manifest.metadata,
+            components,
+        })
+    }
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        let elf = |name: &str, offset, max_size, position_independent| Component {
+            name: name.to_owned(),
+            path: format!("target/riscv64imac-unknown-none-elf/release/{name}").into(),
+            offset,
+            max_size,
+            kind: ComponentKind::Elf,
+            position_independent,
+            align: 1,
+            fill: 0,
+        };
+        Layout {
+            size: 0x40000,
+            fill: 0,
+            grow_to: None,
+            metadata: None,
+            components: vec![
+                elf("loader", 0x0, 0x5000, true),
+                elf("supervisor", 0x5000, 0xb000, false),
+                Component {
+                    kind: ComponentKind::Raw,
+                    ..elf("system", 0x10000, 0x30000, false)
+                },
+            ],
+        }
+    }
+}
+
+/// Parses `path@offset`, an input of the standalone `compose`.
+pub fn parse_input(s: &str) -> Result<(PathBuf, usize), String> {
+    let (path, offset) = s
+        .rsplit_once('@')
+        .ok_or_else(|| format!("{s:?}: expected path@offset"))?;
+    let offset = common::parse_u64(offset)?;
+    Ok((path.into(), offset as usize))
+}
+
+impl Layout {
+    /// A layout of `inputs`, kind, path and offset, with every slot running
+    /// up to the next one and the last up to `size`. The components are
+    /// named after the files.
+    pub fn from_inputs(mut inputs: Vec<(ComponentKind, PathBuf, usize)>, size: usize) -> Self {
+        inputs.sort_by_key(|(_, _, offset)| *offset);
+        let ends = inputs
+            .iter()
+            .skip(1)
+            .map(|(_, _, offset)| *offset)
+            .chain([size])
+            .collect::<Vec<_>>();
+        let components = inputs
+            .into_iter()
+            .zip(ends)
+            .map(|((kind, path, offset), end)| Component {
+                name: path.file_name().map_or_else(
+                    || path.display().to_string(),
+                    |name| name.to_string_lossy().into_owned(),
+                ),
+                path,
+                offset,
+                max_size: end.saturating_sub(offset),
+                kind,
+                position_independent: false,
+                align: 1,
+                fill: 0,
+            })
+            .collect();
+        Layout {
+            size,
+            fill: 0,
+            grow_to: None,
+            metadata: None,
+            components,
+        }
+    }
+
+    /// The ELF components at the output of the cargo profile writing into
+    /// `dir` instead of `release`.
+    pub fn with_profile(&self, dir: &str) -> Self {
+        let mut layout = self.clone();
+        for c in &mut layout.components {
+            if matches!(c.kind, ComponentKind::Elf | ComponentKind::Auto) {
+                c.path = c
+                    .path
+                    .iter()
+                    .map(|part| {
+                        if part == "release" {
+                            dir.as_ref()
+                        } else {
+                            part
+                        }
+                    })
+                    .collect();
+            }
+        }
+        layout
+    }
+
+    /// Reads `layout.toml` from the current directory, if there is one.
+    pub fn load() -> Result<Option<Self>, LayoutError> {
+        match fs::read_to_string(LAYOUT_PATH) {
+            Ok(text) => Ok(Some(toml::from_str(&text)?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// The component that may outgrow its slot with `grow_to`: the last one,
+    /// unless the metadata comes after it.
+    pub fn growable(&self) -> Option<usize> {
+        self.grow_to?;
+        let (i, last) = self
+            .components
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, c)| c.offset)?;
+        match self.metadata {
+            Some(m) if m.offset > last.offset => None,
+            _ => Some(i),
+        }
+    }
+
+    /// The layout with every offset resolved, in the format it is read in.
+    pub fn to_toml(&self) -> Result<String, LayoutError> {
+        Ok(toml::to_string(self)?)
+    }
+
+    /// Checks that every slot lies inside the image, is aligned and that
+    /// slots don't overlap, so compose only has to check the binaries fit
+    /// their slots.
+    pub fn validate(&self) -> Result<(), LayoutError> {
+        if let Some(grow_to) = self.grow_to
+            && grow_to < self.size
+        {
+            let size = self.size;
+            return Err(LayoutError::GrowTo { grow_to, size });
+        }
+        for c in &self.components {
+            if !c.offset.is_multiple_of(c.align) || !c.max_size.is_multiple_of(c.align) {
+                return Err(LayoutError::Misaligned {
+                    name: c.name.clone(),
+                    offset: c.offset,
+                    end: c.offset + c.max_size,
+                    align: c.align,
+                });
+            }
+        }
+        let mut slots = self
+            .components
+            .iter()
+            .map(|c| (c.name.as_str(), c.offset, c.max_size))
+            .chain(self.metadata.map(|m| ("metadata", m.offset, m.max_size)))
+            .collect::<Vec<_>>();
+        slots.sort_by_key(|(_, offset, _)| *offset);
+        for &(name, offset, max_size) in &slots {
+            let end = offset + max_size;
+            if end > self.size {
+                return Err(LayoutError::Outside {
+                    name: name.to_owned(),
+                    offset,
+                    end,
+                    size: self.size,
+                });
+            }
+        }
+        for pair in slots.windows(2) {
+            let ((name, offset, max_size), (other, start, _)) = (pair[0], pair[1]);
+            if offset + max_size > start {
+                return Err(LayoutError::Overlap {
+                    name: name.to_owned(),
+                    end: offset + max_size,
+                    other: other.to_owned(),
+                    start,
+                });
+            }
+        }
+        for (i, c) in self.components.iter().enumerate() {
+            if self.components[..i]
+                .iter()
+                .any(|other| other.name == c.name)
+            {
+                return Err(LayoutError::Duplicate(c.name.clone()));
+            }
+        }
+        Ok(())
+    }
+}
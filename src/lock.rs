@@ -0,0 +1,108 @@
+use std::{
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+    process,
+};
+
+/// Advisory lock on a file in the output directory, released when dropped.
+pub struct Lock {
+    _file: fs::File,
+}
+
+impl Lock {
+    /// Blocks until no other tau-builder holds the lock, unless `wait` is
+    /// false, in which case it fails right away.
+    pub fn acquire<P>(path: P, wait: bool) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(fs::TryLockError::WouldBlock) => {
+                let mut pid = String::new();
+                file.read_to_string(&mut pid)?;
+                let msg = format!(
+                    "another tau-builder instance is running (pid {})",
+                    pid.trim()
+                );
+                if !wait {
+                    return Err(io::Error::new(io::ErrorKind::WouldBlock, msg));
+                }
+                eprintln!("{msg}, waiting");
+                file.lock()?;
+            }
+            Err(fs::TryLockError::Error(err)) => return Err(err),
+        }
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", process::id())?;
+
+        Ok(Lock { _file: file })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process::Command};
+
+    use super::Lock;
+
+    /// Where `held_elsewhere` tries to take the lock, set only in the child
+    /// process `excludes_other_processes` runs.
+    const CHILD_LOCK: &str = "TAU_BUILDER_TEST_LOCK";
+
+    #[test]
+    fn excludes_other_processes() {
+        let path = env::temp_dir().join(format!("tau-builder-lock-{}", std::process::id()));
+        let child = || {
+            Command::new(env::current_exe().unwrap())
+                .args([
+                    "--exact",
+                    "lock::tests::held_elsewhere",
+                    "--include-ignored",
+                ])
+                .env(CHILD_LOCK, &path)
+                .output()
+                .unwrap()
+        };
+
+        let lock = Lock::acquire(&path, false).unwrap();
+        let out = child();
+        assert!(
+            out.status.success(),
+            "{}",
+            String::from_utf8_lossy(&out.stdout)
+        );
+        drop(lock);
+        let out = child();
+        assert!(!out.status.success(), "the lock is free again");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    #[ignore = "run by excludes_other_processes"]
+    fn held_elsewhere() {
+        let Some(path) = env::var_os(CHILD_LOCK) else {
+            return;
+        };
+        let err = Lock::acquire(path, false).err().expect("the lock is held");
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+        assert!(
+            err.to_string()
+                .contains("another tau-builder instance is running (pid ")
+        );
+    }
+}
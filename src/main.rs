@@ -1,245 +1,3814 @@
 pub mod common;
+pub mod board;
+pub mod disk;
+pub mod summary;
+pub mod lock;
+pub mod layout;
+pub mod config;
+pub mod diff;
+pub mod hooks;
+pub mod opensbi;
+pub mod interrupt;
+pub mod memory_map;
+pub mod plan;
+pub mod usb;
+pub mod history;
+pub mod spl_header;
+pub mod resources;
+pub mod inspect;
+pub mod doctor;
+pub mod disasm;
+pub mod dist;
+pub mod serial;
+pub mod watch;
+pub mod uboot_env;
+pub mod size;
+pub mod test_boot;
+pub mod xmodem;
+pub mod backup;
+pub mod dirs;
+pub mod dtc;
+pub mod fdt;
+pub mod status;
+pub mod metadata;
+pub mod footer;
+pub mod fit;
+pub mod partitions;
+pub mod manifest;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 
 use std::{
     fs,
+    io::{self, Read, Seek},
+    ops::Range,
     path::{Path, PathBuf},
-    process::{Command, Stdio},
+    process::{Command, ExitCode, Stdio},
+    time::{Duration, Instant},
 };
 
 use clap::{Parser, Subcommand};
+use sha2::{Digest, Sha256};
+
+use self::{
+    board::Board,
+    config::Config,
+    hooks::{HookPoint, Vars},
+    resources::Resources,
+    summary::{Outcome, Summary},
+};
 
 #[derive(Parser)]
 struct Args {
+    /// Don't print the summary block at the end of the run.
+    #[clap(long, global = true)]
+    no_summary: bool,
+    /// Don't draw progress bars while writing, for scripts.
+    #[clap(long, global = true)]
+    quiet: bool,
+    /// Fail instead of waiting when another instance holds the lock.
+    #[clap(long, global = true)]
+    no_wait: bool,
+    /// Print the hooks instead of running them, and what `format` and
+    /// `update` would write instead of writing it. The builds still run.
+    #[clap(long, global = true)]
+    dry_run: bool,
+    /// Append the steps to this file as they start and end, one JSON object
+    /// per line, named like in the summary and `--list-steps`.
+    #[clap(long, global = true, value_name = "PATH")]
+    events: Option<PathBuf>,
+    /// Kill the hooks, builds and tools the builder runs once they take
+    /// longer than this many seconds.
+    #[clap(long, global = true, value_name = "SECS")]
+    command_timeout: Option<u64>,
+    /// Directory with the board DTBs and patches, `./board` by default, or
+    /// without one the files built into the binary.
+    #[clap(long, global = true)]
+    board_dir: Option<PathBuf>,
+    /// One of the built-in boards or of `boards.toml`.
+    #[clap(long, global = true, default_value = board::DEFAULT)]
+    board: String,
+    /// Which OpenSBI firmware is built and written, instead of the board's.
+    #[clap(long, global = true, value_enum)]
+    firmware_mode: Option<board::FirmwareMode>,
+    /// The tau cargo workspace to work in, the current directory by default.
+    /// Like `make -C`, relative paths are taken from there.
+    #[clap(long, global = true, env = "TAU_WORKSPACE")]
+    workspace: Option<PathBuf>,
+    /// Where the images, build trees and backups go, `target/tau-builder` by
+    /// default. The clones are kept in `$XDG_CACHE_HOME/tau-builder`.
+    #[clap(long, global = true, env = "TAU_OUT_DIR")]
+    out_dir: Option<PathBuf>,
     #[clap(subcommand)]
     command: ArgsCommand,
 }
 
 #[derive(Subcommand)]
 enum ArgsCommand {
-    BuildFirmware,
+    BuildFirmware {
+        /// Remove the u-boot build directory and configure from scratch.
+        #[clap(long)]
+        force_rebuild: bool,
+        /// Also build u-boot proper, for `format --uboot-proper`.
+        #[clap(long)]
+        uboot_proper: bool,
+        #[clap(flatten)]
+        sources: SourceArgs,
+        /// Device tree overlay applied after the board's, may be repeated.
+        #[clap(long)]
+        overlay: Vec<PathBuf>,
+        #[clap(flatten)]
+        opensbi: OpensbiArgs,
+        #[clap(flatten)]
+        plan: plan::PlanArgs,
+    },
     Format {
         #[clap(long)]
         path: PathBuf,
+        /// Create a sparse image file of this size at `path`, or resize the
+        /// one there, instead of writing to a device.
+        #[clap(long, value_parser = common::parse_size)]
+        size: Option<u64>,
+        #[clap(flatten)]
+        sizes: SizeArgs,
+        #[clap(flatten)]
+        opts: FormatArgs,
+        #[clap(flatten)]
+        env: EnvArgs,
     },
     BuildTau {
+        #[clap(flatten)]
+        qemu: QemuArgs,
+        #[clap(flatten)]
+        opensbi: OpensbiArgs,
+        #[clap(flatten)]
+        build: BuildArgs,
+        #[clap(flatten)]
+        plan: plan::PlanArgs,
+        /// Build tau once more from a clean target directory and fail if
+        /// the image composed from either build differs.
         #[clap(long)]
-        qemu: bool,
+        check_reproducible: bool,
     },
     Update {
+        #[clap(long, required_unless_present = "usb")]
+        path: Option<PathBuf>,
+        /// Reach the board through the USB gadget of its u-boot.
+        #[clap(long, value_enum, conflicts_with = "path")]
+        usb: Option<usb::Mode>,
+        /// Seconds to wait for the USB gadget to show up.
+        #[clap(long, default_value_t = 60)]
+        usb_timeout: u64,
+        #[clap(flatten)]
+        compose: ComposeArgs,
+        #[clap(flatten)]
+        write: WriteArgs,
+    },
+    /// Boot the image of `build-tau --qemu` in QEMU, with the console on the
+    /// terminal. Quit with Ctrl-A X.
+    Run {
+        #[clap(flatten)]
+        machine: MachineArgs,
+        /// Passed to QEMU after the builder's own arguments.
+        #[clap(last = true)]
+        qemu_args: Vec<String>,
+    },
+    /// Boot the QEMU payload headless and check its console output.
+    TestBoot {
+        #[clap(flatten)]
+        machine: MachineArgs,
+        /// The boot succeeds once a line contains this.
+        #[clap(long, required = true)]
+        success: Vec<String>,
+        /// The boot fails once a line contains this, a panic or an OpenSBI
+        /// trap by default.
+        #[clap(long)]
+        failure: Vec<String>,
+        /// Seconds to wait for a marker.
+        #[clap(long, default_value_t = 60)]
+        timeout: u64,
+    },
+    /// Write a complete prebuilt image file to a device.
+    Flash {
+        #[clap(long)]
+        image: PathBuf,
+        #[clap(long)]
+        path: PathBuf,
+        /// Don't ask before overwriting a device. A device that is mounted,
+        /// in use or looks like a system disk is flashed once its name is
+        /// typed in.
+        #[clap(long)]
+        force: bool,
+        #[clap(flatten)]
+        io: IoArgs,
+    },
+    /// Grow the last partition of a disk and move the backup GPT to its
+    /// end, after writing an image made for a smaller disk.
+    Expand {
+        #[clap(long)]
+        path: PathBuf,
+        #[clap(flatten)]
+        confirm: ConfirmArgs,
+        #[clap(flatten)]
+        io: IoArgs,
+    },
+    /// Copy the firmware components off a disk into separate files.
+    Extract {
+        #[clap(long)]
+        path: PathBuf,
+        #[clap(long)]
+        out: PathBuf,
+        /// Try every known SPL header format, not only the board's.
+        #[clap(long)]
+        any_format: bool,
+    },
+    /// Summarize the DTB OpenSBI is built with and check what it relies on.
+    InspectDtb {
+        /// The board's DTB, overlays applied, by default.
+        #[clap(long)]
+        path: Option<PathBuf>,
+    },
+    /// Compose an image out of arbitrary ELFs and binaries, without the
+    /// layout of tau. Each slot runs up to the next input.
+    Compose {
+        /// An ELF whose loadable segments go at the offset.
+        #[clap(long, value_name = "PATH@OFFSET", value_parser = layout::parse_input)]
+        #[clap(required_unless_present_any = ["bin", "input"])]
+        elf: Vec<(PathBuf, usize)>,
+        /// A binary copied verbatim to the offset.
+        #[clap(long, value_name = "PATH@OFFSET", value_parser = layout::parse_input)]
+        bin: Vec<(PathBuf, usize)>,
+        /// An ELF or a binary, told apart by the ELF magic.
+        #[clap(long, value_name = "PATH@OFFSET", value_parser = layout::parse_input)]
+        input: Vec<(PathBuf, usize)>,
+        #[clap(long)]
+        out: PathBuf,
+        /// Size of the image, up to the end of the last input by default.
+        #[clap(long, value_parser = common::parse_u64)]
+        size: Option<u64>,
+        /// Append the footer with the checksums of the inputs, like the tau
+        /// image has.
+        #[clap(long)]
+        footer: bool,
+        /// Print the map of the composed image.
+        #[clap(long)]
+        dump_layout: bool,
+    },
+    /// Put the boot ROM header in front of an SPL binary, or take it off.
+    SplHeader {
+        #[clap(long)]
+        input: PathBuf,
+        #[clap(long)]
+        output: PathBuf,
+        /// Header format, the board's by default.
+        #[clap(long, value_parser = spl_header::by_name)]
+        format: Option<&'static spl_header::SplHeaderFormat>,
+        #[clap(long, value_parser = common::parse_u32, conflicts_with = "strip")]
+        backup_offset: Option<u32>,
+        #[clap(long, value_parser = common::parse_u32, conflicts_with = "strip")]
+        version: Option<u32>,
+        /// Remove an existing header instead of adding one.
+        #[clap(long)]
+        strip: bool,
+    },
+    /// Disassemble the components of the tau image or of the image on a disk.
+    Disasm {
+        /// Read the image from this disk instead of the tau image.
+        #[clap(long)]
+        path: Option<PathBuf>,
+        /// Components to disassemble, all of them by default.
+        components: Vec<String>,
+    },
+    /// Package the composed tau and the board's DTB as a FIT image, for
+    /// booting tau from u-boot proper.
+    Fit {
+        #[clap(flatten)]
+        compose: ComposeArgs,
+        /// Sign the configuration with the key `--key-name` found here.
+        #[clap(long, requires = "key_name")]
+        key_dir: Option<PathBuf>,
+        #[clap(long, requires = "key_dir")]
+        key_name: Option<String>,
+        #[clap(long, default_value = "sha256,rsa2048")]
+        key_algo: String,
+    },
+    /// Collect the release artifacts with a checksum manifest.
+    Dist {
+        #[clap(long, default_value = "dist")]
+        out: PathBuf,
+        /// Goes into the file names, `git describe` by default.
+        #[clap(long)]
+        release: Option<String>,
+    },
+    /// Open the board's UART, Ctrl-] exits.
+    Serial {
+        #[clap(long, default_value = "/dev/ttyUSB0")]
+        device: PathBuf,
+        #[clap(long, default_value_t = 115200)]
+        baud: u32,
+        /// Where the session is logged, `serial/<time>.log` in the output
+        /// directory by default.
+        #[clap(long)]
+        log: Option<PathBuf>,
+    },
+    /// Rebuild and recompose tau whenever its sources change.
+    Watch {
+        /// Directories to watch, the firmware crates by default.
+        #[clap(long = "dir")]
+        dirs: Vec<PathBuf>,
+        /// Poll interval in milliseconds.
+        #[clap(long, default_value_t = 500)]
+        interval: u64,
+        /// Compose for QEMU instead of the board.
+        #[clap(long)]
+        qemu: bool,
+        #[clap(flatten)]
+        build: BuildArgs,
+    },
+    /// Build or edit u-boot environment images.
+    Env {
+        #[clap(subcommand)]
+        command: EnvCommand,
+    },
+    /// Show how much of its slot each component takes, section by section.
+    Size,
+    /// Upload the SPL to a board in UART boot mode.
+    Recover {
+        #[clap(long, default_value = "/dev/ttyUSB0")]
+        device: PathBuf,
+        #[clap(long, default_value_t = 115200)]
+        baud: u32,
+        /// Send this file as is instead of the built SPL with its header.
+        #[clap(long)]
+        file: Option<PathBuf>,
+        /// Seconds to wait for the boot ROM.
+        #[clap(long, default_value_t = 60)]
+        wait: u64,
+    },
+    /// Write firmware saved to `backups` back to a device.
+    Rollback {
+        #[clap(long)]
+        path: PathBuf,
+        /// Name of the backup directory, the latest backup of the device by
+        /// default.
+        #[clap(long)]
+        to: Option<String>,
+        #[clap(flatten)]
+        io: IoArgs,
+    },
+    /// List the firmware saved by `format` and `update` before overwriting it.
+    ListBackups,
+    /// Show which artifacts are built and which are stale.
+    Status,
+    /// Check that the tools the builder runs are installed.
+    Doctor,
+    /// Decode the firmware on a disk or an image file.
+    Inspect {
+        #[clap(long)]
+        path: PathBuf,
+        /// Try every known SPL header format, not only the board's.
+        #[clap(long)]
+        any_format: bool,
+        /// `path` is a composed tau image, only decode its build metadata
+        /// and footer.
+        #[clap(long, conflicts_with = "any_format")]
+        image: bool,
+    },
+    /// Compare what a disk holds with the local artifacts.
+    Verify {
+        #[clap(long)]
+        path: PathBuf,
+    },
+    /// Remove what the builder created, leaving cargo's output.
+    #[clap(group(clap::ArgGroup::new("scope").required(true).multiple(true)))]
+    Clean {
+        /// Build directories and composed images.
+        #[clap(long, group = "scope")]
+        artifacts: bool,
+        /// Cloned u-boot and OpenSBI sources, their in-tree builds included.
+        #[clap(long, group = "scope")]
+        clones: bool,
+        #[clap(long, group = "scope")]
+        all: bool,
+    },
+    /// Render the layout into files the firmware crates can include.
+    GenLayout {
+        /// `layout` in the output directory by default.
+        #[clap(long)]
+        dir: Option<PathBuf>,
+        /// Addresses for the QEMU virt machine instead of the board.
+        #[clap(long)]
+        qemu: bool,
+        /// Also write `layout.h`.
+        #[clap(long)]
+        c_header: bool,
+    },
+    /// Show when and with what devices were flashed.
+    History {
+        #[clap(subcommand)]
+        command: HistoryCommand,
+    },
+    /// Compare the firmware regions of two disks or disk images.
+    Diff {
+        a: PathBuf,
+        b: PathBuf,
+        #[clap(long)]
+        json: bool,
+    },
+    /// Compare two composed images component by component.
+    DiffImage {
+        a: PathBuf,
+        b: PathBuf,
+        /// Directory with the component ELFs `a` was composed from.
+        #[clap(long)]
+        elf_a: Option<PathBuf>,
+        /// Directory with the component ELFs `b` was composed from.
+        #[clap(long)]
+        elf_b: Option<PathBuf>,
+        #[clap(long)]
+        json: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum HistoryCommand {
+    List {
+        #[clap(long)]
+        json: bool,
+    },
+    /// Records of the device, matched by the id block written on it.
+    Show {
+        #[clap(long)]
+        path: PathBuf,
+        #[clap(long)]
+        json: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum EnvCommand {
+    /// Turn a file of `key=value` lines into an environment image.
+    Build {
+        #[clap(long)]
+        input: PathBuf,
+        #[clap(long)]
+        output: PathBuf,
+        #[clap(flatten)]
+        format: uboot_env::EnvFormat,
+    },
+    /// Print the environment on a disk or in an image.
+    Show {
+        #[clap(long)]
+        path: PathBuf,
+        #[clap(long, value_parser = common::parse_u64)]
+        offset: u64,
+        #[clap(flatten)]
+        format: uboot_env::EnvFormat,
+    },
+    /// Change the environment on a disk or in an image in place.
+    Set {
         #[clap(long)]
         path: PathBuf,
+        #[clap(long, value_parser = common::parse_u64)]
+        offset: u64,
+        #[clap(flatten)]
+        format: uboot_env::EnvFormat,
+        #[clap(value_name = "KEY=VALUE", value_parser = opensbi::parse_opt)]
+        vars: Vec<(String, String)>,
+        /// Remove a variable.
+        #[clap(long)]
+        unset: Vec<String>,
+        #[clap(flatten)]
+        io: IoArgs,
     },
 }
 
-fn build_spl() -> anyhow::Result<()> {
-    const REVISION: &str = "c4c67bb66ae6f41c98537d18cf5c3abc8b97b8e4";
-    const REPO: &str = "https://github.com/starfive-tech/u-boot.git";
-    let dir = common::git_clone("target", REPO, REVISION, "u-boot-vf2")?;
+/// A u-boot environment `format` writes next to the firmware.
+#[derive(clap::Args)]
+struct EnvArgs {
+    /// File of `key=value` lines.
+    #[clap(long, requires = "env_offset")]
+    env: Option<PathBuf>,
+    /// Where the environment goes, in bytes from the start of the device.
+    #[clap(long, value_parser = common::parse_u64)]
+    env_offset: Option<u64>,
+    #[clap(flatten)]
+    format: uboot_env::EnvFormat,
+}
+
+impl EnvArgs {
+    /// The offset and the block to write, refusing places that overlap the
+    /// GPT or the firmware.
+    fn block(
+        &self,
+        layout: &board::DiskLayout,
+        start: u64,
+    ) -> anyhow::Result<Option<(u64, Vec<u8>)>> {
+        let (Some(path), Some(offset)) = (&self.env, self.env_offset) else {
+            return Ok(None);
+        };
+        let env = uboot_env::Env::parse_text(&fs::read_to_string(path)?)?;
+        let block = env.to_bytes(&self.format)?;
+        let end = offset + block.len() as u64;
+        let last = [layout.spl, layout.opensbi, layout.tau, layout.id]
+            .iter()
+            .map(board::Region::end)
+            .max()
+            .unwrap_or_default();
+        let free = offset >= last
+            || layout
+                .gaps(start)
+                .iter()
+                .any(|gap| gap.start <= offset && end <= gap.end);
+        if !free {
+            return Err(anyhow::anyhow!(
+                "environment at {offset:#x}..{end:#x} overlaps the partition table or the firmware"
+            ));
+        }
+        Ok(Some((offset, block)))
+    }
+}
 
-    let out_file = <str as AsRef<Path>>::as_ref("target/u-boot-vf2-build/spl/u-boot-spl.bin");
-    if out_file.exists() {
+fn write_env<T>(
+    file: &mut T,
+    env: &Option<(u64, Vec<u8>)>,
+    summary: &mut Summary,
+) -> anyhow::Result<()>
+where
+    T: disk::Target,
+{
+    let Some((offset, block)) = env else {
         return Ok(());
+    };
+    summary.step("write-env", |_| {
+        if disk::matches(file, *offset, block)? {
+            return anyhow::Ok(Outcome::Cached);
+        }
+        disk::write_verified(file, *offset, block, 0)?;
+        anyhow::Ok(Outcome::Rebuilt)
+    })
+}
+
+/// Build from other repositories than the board's, the same clones are
+/// switched over. `boards.toml` can change them for good.
+#[derive(clap::Args)]
+struct SourceArgs {
+    #[clap(long)]
+    uboot_repo: Option<String>,
+    #[clap(long)]
+    uboot_rev: Option<String>,
+    #[clap(long)]
+    opensbi_repo: Option<String>,
+    #[clap(long)]
+    opensbi_rev: Option<String>,
+}
+
+impl SourceArgs {
+    fn apply(self, board: &mut Board) -> anyhow::Result<()> {
+        let set = |source: &mut board::Source, repo: Option<String>, rev: Option<String>| {
+            source.repo = repo.unwrap_or_else(|| source.repo.clone());
+            source.revision = rev.unwrap_or_else(|| source.revision.clone());
+        };
+        if self.uboot_repo.is_some() || self.uboot_rev.is_some() {
+            let name = board.name.clone();
+            let uboot = board
+                .uboot
+                .as_mut()
+                .ok_or(board::BoardError::NoUboot(name))?;
+            set(&mut uboot.source, self.uboot_repo, self.uboot_rev);
+        }
+        set(&mut board.opensbi, self.opensbi_repo, self.opensbi_rev);
+        Ok(())
+    }
+}
+
+#[derive(clap::Args)]
+struct OpensbiArgs {
+    /// Extra OpenSBI make variable, appended after the builder's own.
+    #[clap(long = "opensbi-opt", value_name = "KEY=VALUE", value_parser = opensbi::parse_opt)]
+    opts: Vec<(String, String)>,
+    /// Allow `--opensbi-opt` to override PLATFORM, FW_FDT_PATH and FW_TEXT_START.
+    #[clap(long = "opensbi-opt-unsafe")]
+    allow_unsafe: bool,
+    #[clap(long = "opensbi-toolchain", value_enum, default_value_t)]
+    toolchain: opensbi::Toolchain,
+}
+
+impl OpensbiArgs {
+    fn options(&self, config: &Config, board: &Board) -> opensbi::Options {
+        opensbi::Options::new(
+            &board.opensbi_vars,
+            &config.opensbi,
+            &self.opts,
+            self.allow_unsafe,
+            self.toolchain,
+        )
+    }
+}
+
+impl ArgsCommand {
+    /// Whether the command writes into the output directory or a device.
+    fn needs_lock(&self) -> bool {
+        match self {
+            ArgsCommand::BuildFirmware { .. } => true,
+            ArgsCommand::Format { .. } => true,
+            ArgsCommand::BuildTau { .. } => true,
+            ArgsCommand::Update { .. } => true,
+            ArgsCommand::Run { .. } => false,
+            ArgsCommand::Clean { .. } => true,
+            ArgsCommand::Verify { .. } => false,
+            ArgsCommand::Inspect { .. } => false,
+            ArgsCommand::Doctor => false,
+            ArgsCommand::Status => false,
+            ArgsCommand::ListBackups => false,
+            ArgsCommand::Rollback { .. } => true,
+            ArgsCommand::Recover { .. } => false,
+            ArgsCommand::TestBoot { .. } => false,
+            ArgsCommand::Size => false,
+            ArgsCommand::Env { command } => matches!(command, EnvCommand::Set { .. }),
+            ArgsCommand::Watch { .. } => true,
+            ArgsCommand::Serial { .. } => false,
+            ArgsCommand::Dist { .. } => true,
+            ArgsCommand::Fit { .. } => true,
+            ArgsCommand::Disasm { .. } => false,
+            ArgsCommand::Compose { .. } => false,
+            ArgsCommand::SplHeader { .. } => false,
+            ArgsCommand::Extract { .. } => false,
+            ArgsCommand::InspectDtb { path } => path.is_none(),
+            ArgsCommand::Flash { .. } => true,
+            ArgsCommand::Expand { .. } => true,
+            ArgsCommand::GenLayout { .. } => true,
+            ArgsCommand::History { .. } => false,
+            ArgsCommand::DiffImage { .. } => false,
+            ArgsCommand::Diff { .. } => false,
+        }
     }
+}
+
+/// The patches a u-boot build directory was built with, one per line with
+/// its CRC-32.
+const UBOOT_PATCHES: &str = ".patches";
+
+fn build_spl(force_rebuild: bool, board: &Board, res: &Resources) -> anyhow::Result<Outcome> {
+    let uboot = board.uboot()?;
+    let patches = uboot
+        .patches
+        .iter()
+        .map(|patch| res.path(patch))
+        .collect::<Result<Vec<_>, _>>()?;
+    let series = res.series(&format!("{}/patches", board.name))?;
+    let applied = patches
+        .iter()
+        .chain(&series)
+        .map(|patch| {
+            Ok(format!(
+                "{:08x} {}\n",
+                history::crc32(&fs::read(patch)?),
+                patch.display()
+            ))
+        })
+        .collect::<io::Result<String>>()?;
+    let dir = uboot.source.fetch()?;
 
-    Command::new("git")
-        .current_dir(&dir)
-        .args(["checkout", "."])
-        .output()?;
-    let out = Command::new("git")
-        .current_dir(&dir)
-        .args([
-            "apply",
-            "../../board/jh7110-starfive-visionfive-2-v1.3b-u-boot.patch",
-        ])
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .output()?;
-    common::bail(&out, || anyhow::anyhow!("apply u-boot patch"))?;
+    let build_dir = uboot.build_dir();
+    if common::start_build(
+        &build_dir,
+        &uboot.spl(),
+        UBOOT_PATCHES,
+        &applied,
+        force_rebuild,
+    )? {
+        return Ok(Outcome::Cached);
+    }
 
-    fs::create_dir("target/u-boot-vf2-build").unwrap_or_default();
+    for args in [&["checkout", "."][..], &["clean", "-fd"]] {
+        let out = interrupt::run(
+            Command::new("git")
+                .current_dir(&dir)
+                .args(args)
+                .stdout(Stdio::inherit())
+                .stderr(Stdio::inherit()),
+        )?;
+        common::bail(&out, || anyhow::anyhow!("reset u-boot sources"))?;
+    }
+    for patch in &patches {
+        let out = interrupt::run(
+            Command::new("git")
+                .current_dir(&dir)
+                .arg("apply")
+                .arg(patch)
+                .stdout(Stdio::inherit())
+                .stderr(Stdio::inherit()),
+        )?;
+        common::bail(&out, || anyhow::anyhow!("apply {}", patch.display()))?;
+    }
+    // `git am` names the patch that fails; the commits are dropped
+    // afterwards so the clone stays at the pinned revision and the next
+    // build resets it like the plain diffs.
+    if !series.is_empty() {
+        let git = |args: &[&str]| {
+            let mut command = Command::new("git");
+            command
+                .current_dir(&dir)
+                .env("GIT_COMMITTER_NAME", "tau-builder")
+                .env("GIT_COMMITTER_EMAIL", "tau-builder@localhost")
+                .args(args);
+            command
+        };
+        let base = git(&["rev-parse", "HEAD"]).output()?;
+        common::bail(&base, || anyhow::anyhow!("read the u-boot revision"))?;
+        let base = String::from_utf8_lossy(&base.stdout).trim().to_owned();
+        for patch in &series {
+            let out = interrupt::run(
+                git(&["am", "--quiet"])
+                    .arg(patch)
+                    .stdout(Stdio::inherit())
+                    .stderr(Stdio::inherit()),
+            )?;
+            if !out.status.success() {
+                git(&["am", "--abort"]).output()?;
+                git(&["reset", "--hard", "--quiet", &base]).output()?;
+                return Err(anyhow::anyhow!("git am {}", patch.display()));
+            }
+        }
+        let out = git(&["reset", "--quiet", &base]).output()?;
+        common::bail(&out, || anyhow::anyhow!("reset u-boot to {base}"))?;
+    }
 
+    let out_dir = format!("O={}", fs::canonicalize(&build_dir)?.display());
     let args = &[
-        "O=../u-boot-vf2-build",
+        out_dir.as_str(),
         "CROSS_COMPILE=riscv64-unknown-linux-gnu-",
         "ARCH=riscv",
     ];
+    let epoch = metadata::source_date_epoch(&dir);
     let invocations = [
         args.iter().copied().chain(Some("olddefconfig")),
-        args.iter()
-            .copied()
-            .chain(Some("starfive_visionfive2_defconfig")),
+        args.iter().copied().chain(Some(uboot.defconfig.as_str())),
         args.iter().copied().chain(None),
     ];
     for invocation in invocations {
-        let out = Command::new("make")
-            .current_dir(&dir)
-            .args(invocation)
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit())
-            .output()?;
+        let out = interrupt::run(
+            Command::new("make")
+                .current_dir(&dir)
+                .env("SOURCE_DATE_EPOCH", epoch.to_string())
+                .args(invocation)
+                .stdout(Stdio::inherit())
+                .stderr(Stdio::inherit()),
+        )?;
         common::bail(&out, || anyhow::anyhow!("build u-boot"))?;
     }
+    common::finish_build(&build_dir, UBOOT_PATCHES, &applied)?;
 
-    Ok(())
+    Ok(Outcome::Rebuilt)
 }
 
-fn calc_spl_header(
-    spl: &[u8],
-    backup_offset: Option<u32>,
-    version: Option<u32>,
-) -> anyhow::Result<[u8; 0x400]> {
-    if spl.len() > 180048 {
-        return Err(anyhow::anyhow!("spl too big"));
-    }
-    let c = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
-    let checksum = c.checksum(spl);
-
-    let mut header = [0; 0x400];
-    let mut write_at = |i: usize, x: u32| {
-        header[(i * 4)..((i + 1) * 4)].clone_from_slice(&x.to_le_bytes());
-    };
-    write_at(0x00, 0x240);
-    write_at(0x01, backup_offset.unwrap_or(0x200000));
-    write_at(0xa1, version.unwrap_or(0x01010101));
-    write_at(0xa2, spl.len() as u32);
-    write_at(0xa3, 0x400);
-    write_at(0xa4, checksum);
-
-    Ok(header)
-}
-
-fn build_opensbi() -> anyhow::Result<()> {
-    const REVISION: &str = "1725bd71080960290fdde4499a58c25c09d5c8ee";
-    const REPO: &str = "https://github.com/starfive-tech/opensbi.git";
-    let dir = common::git_clone("target", REPO, REVISION, "opensbi-vf2")?;
-
-    let out = Command::new("make")
-        .current_dir(dir)
-        .args([
-            "CC=clang",
-            "LD=ld.lld",
-            "LLVM=1",
-            "PLATFORM=generic",
-            "FW_FDT_PATH=../../board/jh7110-starfive-visionfive-2-v1.3b.dtb",
-            // "FW_PAYLOAD_PATH=../tau",
-            "FW_TEXT_START=0x40000000",
-        ])
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .output()?;
-    common::bail(&out, || anyhow::anyhow!("build opensbi for vf2"))?;
-
-    // "target/opensbi-vf2/build/platform/generic/firmware/fw_payload.bin"
-    Ok(())
-}
-
-fn build_opensbi_qemu() -> anyhow::Result<()> {
-    const REVISION: &str = "74434f255873d74e56cc50aa762d1caf24c099f8";
-    const REPO: &str = "https://github.com/riscv-software-src/opensbi.git";
-    let dir = common::git_clone("target", REPO, REVISION, "opensbi-qemu")?;
-    let image = common::compose_tau_image()?;
-    fs::write("target/tau", image)?;
-
-    let out = Command::new("make")
-        .current_dir(dir)
-        .args([
-            "CC=clang",
-            "LD=ld.lld",
-            "LLVM=1",
-            "PLATFORM=generic",
-            "FW_FDT_PATH=../../board/qemu-riscv-virt.dtb",
-            "FW_PAYLOAD_PATH=../tau",
-            "FW_TEXT_START=0x80000000",
-        ])
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .output()?;
-    common::bail(&out, || anyhow::anyhow!("build opensbi for qemu"))?;
-    // "target/opensbi-qemu/build/platform/generic/firmware/fw_payload.elf"
-
-    Ok(())
-}
-
-fn format<P>(path: P) -> anyhow::Result<()>
-where
-    P: AsRef<Path>,
-{
-    use std::io::{Write, SeekFrom, Seek};
+/// The DTB in the board directory, or compiled into the output directory
+/// when the board directory has its `.dts`, with the headers of the u-boot
+/// clone.
+fn base_dtb(board: &Board, res: &Resources) -> anyhow::Result<PathBuf> {
+    let Ok(dts) = res.path(&board.dts()) else {
+        return Ok(res.path(&board.dtb)?);
+    };
+    let includes = dts
+        .parent()
+        .map(Path::to_owned)
+        .into_iter()
+        .chain(
+            board
+                .uboot
+                .iter()
+                .flat_map(|u| dtc::uboot_includes(&u.source.dir())),
+        )
+        .collect::<Vec<_>>();
+    let out = dirs::out("dtb").join(&board.dtb);
+    dtc::compile(&dts, &includes, &out)?;
+    Ok(fs::canonicalize(out)?)
+}
 
-    sudo::escalate_if_needed().map_err(|err| anyhow::anyhow!("sudo: {err}"))?;
+/// The DTB OpenSBI gets, the board's overlays applied on top of `base_dtb`.
+fn board_dtb(board: &Board, res: &Resources) -> anyhow::Result<PathBuf> {
+    let dtb = base_dtb(board, res)?;
+    if board.overlays.is_empty() {
+        return Ok(dtb);
+    }
+    let overlays = board
+        .overlays
+        .iter()
+        .map(|overlay| res.path(overlay))
+        .collect::<Result<Vec<_>, _>>()?;
+    let out = dirs::out("dtb").join(Path::new(&board.dtb).with_extension("overlaid.dtb"));
+    dtc::apply_overlays(&dtb, &overlays, &out)?;
+    Ok(fs::canonicalize(out)?)
+}
 
-    let mut disk = gpt::GptConfig::default()
-        .writable(true)
-        .logical_block_size(gpt::disk::LogicalBlockSize::Lb512)
-        .create(path)?;
+/// A DTB OpenSBI can't make sense of boots into a silent hang, so it is
+/// checked before embedding.
+fn check_dtb(path: &Path) -> anyhow::Result<fdt::Summary> {
+    let root =
+        fdt::parse(&fs::read(path)?).map_err(|err| anyhow::anyhow!("{}: {err}", path.display()))?;
+    Ok(fdt::Summary::new(&root))
+}
 
-    let name = "starfive_visionfive_2_u-boot-spl";
-    let ty = gpt::partition_types::Type {
-        guid: uuid::Uuid::parse_str("2E54B353-1271-4842-806F-E436D6AF6985").expect("this is valid"),
-        os: gpt::partition_types::OperatingSystem::None,
-    };
-    disk.add_partition_at(name, 1, 4096, 4096, ty, 0)?;
+fn ensure_dtb(dtb: &Path) -> anyhow::Result<()> {
+    let summary = check_dtb(dtb)?;
+    if !summary.problems.is_empty() {
+        return Err(anyhow::anyhow!(
+            "{}: {}, see inspect-dtb",
+            dtb.display(),
+            summary.problems.join(", ")
+        ));
+    }
+    Ok(())
+}
 
-    let name = "starfive_visionfive_2_u-boot";
-    let ty = gpt::partition_types::Type {
-        guid: uuid::Uuid::parse_str("5B193300-FC78-40CD-8002-E86C45580B47").expect("this is valid"),
-        os: gpt::partition_types::OperatingSystem::None,
+fn inspect_dtb(board: &Board, res: &Resources, path: Option<PathBuf>) -> anyhow::Result<()> {
+    let path = match path {
+        Some(path) => path,
+        None => board_dtb(board, res)?,
     };
-    disk.add_partition_at(name, 2, 8192, 8192, ty, 0)?;
+    let summary = check_dtb(&path)?;
+    summary.print();
+    if !summary.problems.is_empty() {
+        return Err(anyhow::anyhow!("{} has problems", path.display()));
+    }
 
-    let mut file = disk.write()?;
-    let lb_size = 0xFF_FF_FF_FF;
-    let mbr = gpt::mbr::ProtectiveMBR::with_lb_size(lb_size);
-    mbr.overwrite_lba0(&mut file).unwrap();
+    Ok(())
+}
 
-    let spl = fs::read("target/u-boot-vf2-build/spl/u-boot-spl.bin")?;
-    let spl_header = calc_spl_header(&spl, None, None)?;
-    let open_sbi = fs::read("target/opensbi-vf2/build/platform/generic/firmware/fw_payload.bin")?;
+fn build_opensbi(opts: &opensbi::Options, board: &Board, res: &Resources) -> anyhow::Result<()> {
+    let dtb = board_dtb(board, res)?;
+    ensure_dtb(&dtb)?;
+    let dir = board.opensbi.fetch()?;
 
-    file.seek(SeekFrom::Start(0x200000))?;
-    file.write_all(&spl_header)?;
-    file.write_all(&spl)?;
-    file.seek(SeekFrom::Start(0x400000))?;
-    file.write_all(&open_sbi)?;
-    file.sync_all()?;
+    let mut args = vec![
+        format!("PLATFORM={}", board.platform),
+        format!("FW_FDT_PATH={}", dtb.display()),
+        // "FW_PAYLOAD_PATH=../tau",
+        format!("FW_TEXT_START={:#x}", board.fw_text_start),
+    ];
+    if let board::FirmwareMode::Jump = board.firmware_mode {
+        args.push(format!("FW_JUMP_ADDR={:#x}", board.payload_base()));
+    }
+    let what = format!("build opensbi for {}", board.name);
+    opensbi::make(dir, &args, opts, &what)?;
 
+    // fw_payload.bin, fw_jump.bin and fw_dynamic.bin
     Ok(())
 }
 
-fn update<P>(path: P) -> anyhow::Result<()>
-where
-    P: AsRef<Path>,
-{
-    use std::io::{Write, SeekFrom, Seek};
+fn build_opensbi_qemu(
+    opts: &opensbi::Options,
+    board: &Board,
+    virt: &VirtArgs,
+    res: &Resources,
+) -> anyhow::Result<()> {
+    let dtb = virt.dtb(board, res)?;
+    ensure_dtb(&dtb)?;
+    let dir = board.opensbi.fetch()?;
 
-    sudo::escalate_if_needed().map_err(|err| anyhow::anyhow!("sudo: {err}"))?;
+    let args = [
+        format!("PLATFORM={}", board.platform),
+        format!("FW_FDT_PATH={}", dtb.display()),
+        format!(
+            "FW_PAYLOAD_PATH={}",
+            fs::canonicalize(dirs::image())?.display()
+        ),
+        format!("FW_TEXT_START={:#x}", board.fw_text_start),
+    ];
+    let what = format!("build opensbi for {}", board.name);
+    opensbi::make(&dir, &args, opts, &what)?;
+    // fw_payload.elf
 
-    let image = common::compose_tau_image()?;
-    let mut file = fs::OpenOptions::new().read(true).write(true).open(&path)?;
-    file.seek(SeekFrom::Start(0x200000))?;
-    file.write_all(&image)?;
-    file.sync_all()?;
+    // It carries the image, whose manifest was written before.
+    if let Some(mut manifest) = manifest::Manifest::load()? {
+        manifest.opensbi = opensbi::Build::last(dir);
+        common::write_atomic(dirs::manifest(), manifest.to_toml()?.as_bytes())?;
+    }
 
     Ok(())
 }
 
-fn main() {
-    let Args { command } = Args::parse();
-    let res = match command {
-        ArgsCommand::BuildFirmware => build_spl().and_then(|()| build_opensbi()),
-        ArgsCommand::Format { path } => format(path),
-        ArgsCommand::BuildTau { qemu } => {
-            if qemu {
-                common::build_tau()
-                    .map_err(anyhow::Error::from)
-                    .and_then(|()| build_opensbi_qemu())
+/// u-boot proper is packed with OpenSBI's `fw_dynamic.bin` into a FIT image,
+/// in the build directory the SPL was built in.
+fn build_uboot_proper(board: &Board) -> anyhow::Result<()> {
+    let uboot = board.uboot()?;
+    let opensbi = fs::canonicalize(board.opensbi_firmware("fw_dynamic.bin"))?;
+    let out = interrupt::run(
+        Command::new("make")
+            .current_dir(uboot.source.dir())
+            .env(
+                "SOURCE_DATE_EPOCH",
+                metadata::source_date_epoch(&uboot.source.dir()).to_string(),
+            )
+            .arg(format!(
+                "O={}",
+                fs::canonicalize(uboot.build_dir())?.display()
+            ))
+            .args(["CROSS_COMPILE=riscv64-unknown-linux-gnu-", "ARCH=riscv"])
+            .arg(format!("OPENSBI={}", opensbi.display()))
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit()),
+    )?;
+    common::bail(&out, || anyhow::anyhow!("build u-boot proper"))
+}
+
+fn build_firmware(
+    force_rebuild: bool,
+    uboot_proper: bool,
+    board: &Board,
+    opensbi: &opensbi::Options,
+    plan: &plan::PlanArgs,
+    res: &Resources,
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
+    let spl = board.uboot()?.spl();
+    let fw_payload = board.opensbi_image();
+    let proper = uboot_proper.then(|| board.uboot_proper()).transpose()?;
+    let mut steps = vec![
+        plan::Step::new("build-spl", [&spl]),
+        plan::Step::new("build-opensbi", [&fw_payload]),
+    ];
+    if let Some(proper) = &proper {
+        steps.push(plan::Step::new("build-uboot-proper", [proper]).needs(&["build-opensbi"]));
+    }
+    if plan.list_steps {
+        plan::PlanArgs::print(&steps);
+        return Ok(());
+    }
+    let plan = plan.resolve(&steps)?;
+
+    if plan.runs("build-spl") {
+        summary.step("build-spl", |_| build_spl(force_rebuild, board, res))?;
+    } else {
+        summary.skipped("build-spl");
+    }
+    summary.artifact(&spl);
+    if plan.runs("build-opensbi") {
+        summary.step("build-opensbi", |_| {
+            build_opensbi(opensbi, board, res).map(|()| Outcome::Rebuilt)
+        })?;
+    } else {
+        summary.skipped("build-opensbi");
+    }
+    summary.artifact(&fw_payload);
+    if let Some(proper) = &proper {
+        if plan.runs("build-uboot-proper") {
+            summary.step("build-uboot-proper", |_| {
+                build_uboot_proper(board).map(|()| Outcome::Rebuilt)
+            })?;
+        } else {
+            summary.skipped("build-uboot-proper");
+        }
+        summary.artifact(proper);
+        summary.next("tau-builder format --uboot-proper --path /dev/sdX");
+    } else {
+        summary.next("tau-builder format --path /dev/sdX");
+    }
+
+    Ok(())
+}
+
+fn run_hook(
+    config: &Config,
+    point: HookPoint,
+    vars: &Vars,
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
+    let Some(command) = config.hooks.command(point, vars) else {
+        return Ok(());
+    };
+    if config.dry_run {
+        eprintln!("dry run, not running hook {}: {command}", point.name());
+        summary.skipped(point.name());
+        return Ok(());
+    }
+    summary.step(point.name(), |_| {
+        config.hooks.run(point, &command).map(|()| Outcome::Rebuilt)
+    })?;
+
+    Ok(())
+}
+
+const QEMU: &str = "qemu-system-riscv64";
+
+/// Composes the current build, rebuilds tau from scratch and composes again,
+/// naming the slots that came out different.
+fn reproducible(
+    layout: &layout::Layout,
+    board: &Board,
+    build: &BuildArgs,
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
+    let opts = build.cargo_options();
+    let layout = build.compose.layout(layout);
+    let check_address = !build.compose.skip_address_check;
+    summary.step("check-reproducible", |_| {
+        let first = common::compose_tau_image(&layout, board, check_address)?;
+        common::clean_tau(&opts)?;
+        common::build_tau(memory_map::default_dir(), &opts)?;
+        let second = common::compose_tau_image(&layout, board, check_address)?;
+        if first.image == second.image {
+            println!(
+                "reproducible, sha256 {:x}",
+                Sha256::digest(&first.image[..first.size])
+            );
+            return anyhow::Ok(Outcome::Rebuilt);
+        }
+        let same = |offset: usize, size: usize| {
+            first.image[offset..][..size] == second.image[offset..][..size]
+        };
+        let mut differ = first
+            .components
+            .iter()
+            .filter(|c| !same(c.offset, c.max_size))
+            .map(|c| c.name.as_str())
+            .collect::<Vec<_>>();
+        if let Some(m) = layout.metadata
+            && !same(m.offset, m.max_size)
+        {
+            differ.push("metadata");
+        }
+        Err(anyhow::anyhow!(
+            "not reproducible, {} differ between the builds",
+            differ.join(", ")
+        ))
+    })
+}
+
+/// The QEMU drive image of `build-tau --qemu --drive`.
+fn qemu_drive() -> PathBuf {
+    dirs::out("tau-qemu.img")
+}
+
+fn build_tau(
+    config: &Config,
+    qemu_args: &QemuArgs,
+    build: &BuildArgs,
+    opensbi: &opensbi::Options,
+    plan: &plan::PlanArgs,
+    res: &Resources,
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
+    let qemu = qemu_args.qemu;
+    let board = config.board(qemu);
+    let compose = &build.compose;
+    let layout = compose.layout(&config.layout);
+    let fw_payload = config.qemu.opensbi_firmware("fw_payload.elf");
+    let layout_dir = memory_map::default_dir();
+    let image = dirs::image();
+    let drive = qemu_drive();
+    let mut steps = vec![
+        plan::Step::new(
+            "gen-layout",
+            [layout_dir.join("layout.ld"), layout_dir.join("layout.rs")],
+        ),
+        plan::Step::new(
+            "build-tau",
+            layout
+                .components
+                .iter()
+                .filter(|c| c.kind != layout::ComponentKind::Payload)
+                .map(|c| &c.path),
+        )
+        .needs(&["gen-layout"]),
+    ];
+    if qemu {
+        steps.push(plan::Step::new("compose", [&image]).needs(&["build-tau"]));
+        steps.push(plan::Step::new("build-opensbi-qemu", [&fw_payload]).needs(&["compose"]));
+    }
+    if qemu_args.drive {
+        steps.push(plan::Step::new("make-drive", [&drive]).needs(&["build-opensbi-qemu"]));
+    }
+    if plan.list_steps {
+        plan::PlanArgs::print(&steps);
+        return Ok(());
+    }
+    let plan = plan.resolve(&steps)?;
+
+    let image_var = image.to_string_lossy();
+    let manifest = dirs::manifest().display().to_string();
+    let vars = Vars {
+        image: &image_var,
+        manifest: &manifest,
+        board: &board.name,
+        ..Vars::default()
+    };
+    run_hook(config, HookPoint::PreBuildTau, &vars, summary)?;
+    if plan.runs("gen-layout") {
+        summary.step("gen-layout", |_| {
+            memory_map::generate(&config.layout, board, &layout_dir, false)
+                .map(|_| Outcome::Rebuilt)
+        })?;
+    } else {
+        summary.skipped("gen-layout");
+    }
+    if plan.runs("build-tau") {
+        summary.step("build-tau", |_| {
+            common::build_tau(&layout_dir, &build.cargo_options()).map(|()| Outcome::Rebuilt)
+        })?;
+    } else {
+        summary.skipped("build-tau");
+    }
+    if compose.debug {
+        warn_budgets(&layout)?;
+    }
+    if qemu {
+        if plan.runs("compose") {
+            summary.step("compose", |_| {
+                let composed =
+                    common::compose_tau_image(&layout, &config.qemu, !compose.skip_address_check)?;
+                if compose.dump_layout {
+                    print!("{}", composed.dump_layout());
+                }
+                write_image(&composed, &layout, &config.qemu, compose, config)?;
+                anyhow::Ok(Outcome::Rebuilt)
+            })?;
+        } else {
+            summary.skipped("compose");
+        }
+        summary.artifact(&image);
+        summary.artifact(dirs::manifest());
+        if plan.runs("build-opensbi-qemu") {
+            summary.step("build-opensbi-qemu", |_| {
+                build_opensbi_qemu(opensbi, &config.qemu, &qemu_args.virt, res)
+                    .map(|()| Outcome::Rebuilt)
+            })?;
+        } else {
+            summary.skipped("build-opensbi-qemu");
+        }
+        summary.artifact(&fw_payload);
+        let run = format!("tau-builder run{}", qemu_args.virt.run_flags());
+        if qemu_args.drive {
+            if plan.runs("make-drive") {
+                summary.step("make-drive", |_| {
+                    make_qemu_drive(&config.qemu).map(|()| Outcome::Rebuilt)
+                })?;
             } else {
-                common::build_tau().map_err(anyhow::Error::from)
+                summary.skipped("make-drive");
             }
+            summary.artifact(&drive);
+            summary.next(format!("{run} --drive {}", drive.display()));
+        } else {
+            summary.next(run);
         }
-        ArgsCommand::Update { path } => update(path),
-    };
-    if let Err(err) = res {
-        eprintln!("{err}");
+    } else {
+        summary.next("tau-builder update --path /dev/sdX");
+    }
+    run_hook(config, HookPoint::PostBuildTau, &vars, summary)?;
+
+    Ok(())
+}
+
+/// Lays the QEMU OpenSBI out like `format` does on an SD card, and checks
+/// the tau image lands in the tau region the way `update` writes it.
+fn make_qemu_drive(board: &Board) -> anyhow::Result<()> {
+    let layout = board.sd;
+    let open_sbi = fs::read(board.opensbi_firmware("fw_payload.bin"))?;
+    let tau = fs::read(dirs::image())?;
+    disk::check_fits(layout.opensbi.offset, open_sbi.len(), layout.opensbi.end())?;
+
+    let drive = qemu_drive();
+    let tmp = drive.with_extension("img.tmp");
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&tmp)?;
+    // Room for the backup GPT after the last region.
+    file.set_len(layout.opensbi.end() + 0x100000)?;
+    let mut file = disk::write_gpt(
+        file,
+        &disk::firmware_partitions(&layout, &board.gpt),
+        false,
+        gpt::disk::LogicalBlockSize::Lb512,
+        None,
+    )?;
+    disk::write_verified(&mut file, layout.opensbi.offset, &open_sbi, 0)?;
+    if !disk::matches(&mut file, layout.tau.offset, &tau)? {
+        return Err(anyhow::anyhow!(
+            "fw_payload.bin doesn't carry the tau image at {:#x}",
+            layout.tau.offset
+        ));
+    }
+    fs::rename(tmp, drive)?;
+
+    Ok(())
+}
+
+#[derive(clap::Args)]
+struct QemuArgs {
+    /// Build for the QEMU virt machine: the tau image is embedded into
+    /// OpenSBI `fw_payload.elf`, to be booted with `-bios`.
+    #[clap(long)]
+    qemu: bool,
+    /// Also write `tau-qemu.img`, a GPT image laid out like the SD card,
+    /// checking the tau image lands in its region. QEMU still boots
+    /// `fw_payload.elf`, the drive is only there for the guest to read.
+    #[clap(long, requires = "qemu")]
+    drive: bool,
+    #[clap(flatten)]
+    virt: VirtArgs,
+}
+
+/// The machine OpenSBI is built for with `--qemu`. Unless one of these is
+/// given, the board's DTB is used, otherwise QEMU dumps the DTB of the
+/// machine.
+#[derive(clap::Args)]
+struct VirtArgs {
+    /// Number of harts.
+    #[clap(long, requires = "qemu")]
+    smp: Option<u32>,
+    /// Guest memory, in QEMU's `-m` syntax.
+    #[clap(long, requires = "qemu")]
+    memory: Option<String>,
+    /// Device to add to the machine, QEMU's `-device`, may be repeated.
+    #[clap(long, requires = "qemu")]
+    device: Vec<String>,
+}
+
+impl VirtArgs {
+    fn is_default(&self) -> bool {
+        self.smp.is_none() && self.memory.is_none() && self.device.is_empty()
+    }
+
+    /// The same machine for `run`.
+    fn run_flags(&self) -> String {
+        let mut flags = String::new();
+        if let Some(smp) = self.smp {
+            flags += &format!(" --smp {smp}");
+        }
+        if let Some(memory) = &self.memory {
+            flags += &format!(" --memory {memory}");
+        }
+        for device in &self.device {
+            flags += &format!(" --device {device}");
+        }
+        flags
+    }
+
+    /// The dumped DTB is named after its CRC, so OpenSBI is rebuilt when
+    /// the machine changes.
+    fn dtb(&self, board: &Board, res: &Resources) -> anyhow::Result<PathBuf> {
+        if self.is_default() {
+            return board_dtb(board, res);
+        }
+        let qemu = common::find_in_path(QEMU)
+            .ok_or_else(|| anyhow::anyhow!("{QEMU} not found in PATH, needed to dump the DTB"))?;
+        fs::create_dir_all(dirs::out_dir())?;
+        let tmp = fs::canonicalize(dirs::out_dir())?.join("qemu-virt.dtb.tmp");
+        let mut command = Command::new(qemu);
+        command
+            .arg("-M")
+            .arg(format!("virt,dumpdtb={}", tmp.display()))
+            .args(["-display", "none"]);
+        if let Some(smp) = self.smp {
+            command.arg("-smp").arg(smp.to_string());
+        }
+        if let Some(memory) = &self.memory {
+            command.args(["-m", memory]);
+        }
+        for device in &self.device {
+            command.args(["-device", device]);
+        }
+        let out = interrupt::run(command.stdout(Stdio::inherit()).stderr(Stdio::inherit()))?;
+        common::bail(&out, || anyhow::anyhow!("dump the qemu dtb"))?;
+        let dtb = fs::read(&tmp)?;
+        fs::remove_file(&tmp)?;
+        let path = tmp.with_file_name(format!("qemu-virt-{:08x}.dtb", history::crc32(&dtb)));
+        fs::write(&path, dtb)?;
+        Ok(path)
+    }
+}
+
+#[derive(clap::Args)]
+struct ComposeArgs {
+    /// Don't check that the ELFs are linked where the layout places them.
+    #[clap(long)]
+    skip_address_check: bool,
+    /// Print the map of the composed image.
+    #[clap(long)]
+    dump_layout: bool,
+    /// Cargo profile the ELFs are built with, `release` by default.
+    #[clap(long)]
+    profile: Option<String>,
+    /// Same as `--profile=dev`, only warn when the ELFs overrun their slots.
+    #[clap(long, conflicts_with = "profile")]
+    debug: bool,
+    /// Fail instead of warning when a component grows past the `[size]`
+    /// limits.
+    #[clap(long)]
+    deny_growth: bool,
+}
+
+impl ComposeArgs {
+    fn profile(&self) -> Option<&str> {
+        if self.debug {
+            Some("dev")
+        } else {
+            self.profile.as_deref()
+        }
+    }
+
+    /// The layout with the ELFs taken from the output of `--profile`.
+    fn layout(&self, layout: &layout::Layout) -> layout::Layout {
+        layout.with_profile(common::profile_dir(self.profile()))
+    }
+}
+
+#[derive(clap::Args)]
+struct CargoArgs {
+    /// Extra cargo features, `package/feature` enables it for one crate
+    /// only.
+    #[clap(long = "features", value_delimiter = ',')]
+    features: Vec<String>,
+    /// Extra RUSTFLAGS for the tau crates.
+    #[clap(long = "rustflags", allow_hyphen_values = true)]
+    rustflags: Vec<String>,
+}
+
+#[derive(clap::Args)]
+struct BuildArgs {
+    #[clap(flatten)]
+    cargo: CargoArgs,
+    #[clap(flatten)]
+    compose: ComposeArgs,
+}
+
+impl BuildArgs {
+    fn cargo_options(&self) -> common::CargoOptions {
+        common::CargoOptions {
+            features: self.cargo.features.clone(),
+            rustflags: self.cargo.rustflags.clone(),
+            profile: self.compose.profile().map(str::to_owned),
+        }
+    }
+}
+
+#[derive(Clone, Copy, clap::Args)]
+struct IoArgs {
+    /// How to open the device: `direct` writes past the page cache, so a
+    /// failing medium shows at the write that hit it. The default for block
+    /// devices.
+    #[clap(long, value_enum, default_value_t)]
+    io: disk::IoMode,
+}
+
+#[derive(Clone, Copy, clap::Args)]
+struct ConfirmArgs {
+    /// Don't ask before writing a block device.
+    #[clap(long, short)]
+    yes: bool,
+}
+
+#[derive(Clone, Copy, clap::Args)]
+struct BackupArgs {
+    /// Don't save the regions about to be overwritten to `backups`.
+    #[clap(long)]
+    no_backup: bool,
+}
+
+#[derive(clap::Args)]
+struct WriteArgs {
+    /// Write even if the disk doesn't look like it holds StarFive firmware,
+    /// or if components left out by `--only` don't match the local build.
+    #[clap(long)]
+    force: bool,
+    /// Continue an interrupted write from this device offset.
+    #[clap(long, value_parser = common::parse_u64, conflicts_with = "only")]
+    resume_from: Option<u64>,
+    /// Write only the slot of this component, may be repeated.
+    #[clap(long, value_name = "COMPONENT")]
+    only: Vec<String>,
+    #[clap(flatten)]
+    backup: BackupArgs,
+    /// Write the recovery slot of the disk layout instead of the primary
+    /// one, meant for a build known to boot.
+    #[clap(long)]
+    recovery: bool,
+    #[clap(flatten)]
+    confirm: ConfirmArgs,
+    #[clap(flatten)]
+    io: IoArgs,
+}
+
+#[derive(clap::Args)]
+struct FormatArgs {
+    /// Lay down a fresh GPT and rewrite the firmware even if the disk
+    /// already holds the same.
+    #[clap(long)]
+    reinit: bool,
+    /// Zero the space between the firmware regions.
+    #[clap(long)]
+    wipe_gaps: bool,
+    #[clap(flatten)]
+    backup: BackupArgs,
+    /// Write u-boot proper into the OpenSBI partition instead of
+    /// `fw_payload.bin`, to reach the u-boot shell for netboot or when tau
+    /// doesn't boot.
+    #[clap(long)]
+    uboot_proper: bool,
+    /// Keep the partitions already on the disk, a rootfs for one, and only
+    /// (re)create the firmware partitions in the GPT.
+    #[clap(long, conflicts_with_all = ["reinit", "wipe_gaps"])]
+    keep_partitions: bool,
+    /// Format a device that is mounted, in use or looks like a system disk,
+    /// once its name is typed in.
+    #[clap(long)]
+    force: bool,
+    #[clap(flatten)]
+    confirm: ConfirmArgs,
+    /// The kind of partition table to write.
+    #[clap(long, value_enum, default_value_t)]
+    table: partitions::Table,
+    /// Logical sector size the table counts in, 512 or 4096. By default
+    /// what the device reports, 512 for an image file.
+    #[clap(long, value_parser = disk::parse_block_size)]
+    sector_size: Option<gpt::disk::LogicalBlockSize>,
+    /// Derive the disk and partition GUIDs from this UUID and the board
+    /// instead of making random ones, so the same command gives the same
+    /// table.
+    #[clap(long, value_name = "UUID")]
+    guid_namespace: Option<uuid::Uuid>,
+    #[clap(flatten)]
+    io: IoArgs,
+}
+
+#[derive(clap::Args)]
+struct MachineArgs {
+    #[clap(long, default_value_t = 1)]
+    smp: u32,
+    /// Guest memory, in QEMU's `-m` syntax.
+    #[clap(long, default_value = "1G")]
+    memory: String,
+    /// Device to add to the machine, QEMU's `-device`, may be repeated.
+    #[clap(long)]
+    device: Vec<String>,
+    /// Attach this image as a virtio drive, see `build-tau --drive`. The
+    /// firmware is still loaded with `-bios`, not from the drive.
+    #[clap(long)]
+    drive: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct SizeArgs {
+    /// Warn when a firmware region is fuller than this, in percent.
+    #[clap(long, default_value_t = 85.0)]
+    size_warn_threshold: f64,
+    /// Make the size warning an error.
+    #[clap(long)]
+    strict_sizes: bool,
+}
+
+/// Lists what `format` would write to `path`: the partition table, the
+/// firmware and the zeros over the gaps, if they are due, and the
+/// environment.
+fn dry_run_format(
+    path: &Path,
+    table: Option<Range<u64>>,
+    firmware: Option<&[(u64, &[u8])]>,
+    gaps: Option<&[Range<u64>]>,
+    env: &Option<(u64, Vec<u8>)>,
+) {
+    let mut writes = vec![];
+    writes.extend(table.map(|range| ("partition table", range)));
+    for (offset, data) in firmware.unwrap_or_default() {
+        writes.push(("firmware", *offset..*offset + data.len() as u64));
+    }
+    for gap in gaps.unwrap_or_default() {
+        writes.push(("zeros", gap.clone()));
+    }
+    if let Some((offset, block)) = env {
+        writes.push(("environment", *offset..*offset + block.len() as u64));
+    }
+    if writes.is_empty() {
+        eprintln!("dry run, {} is up to date", path.display());
+    }
+    for (what, range) in writes {
+        eprintln!(
+            "dry run, not writing {what} to {} {:#x}..{:#x}",
+            path.display(),
+            range.start,
+            range.end
+        );
+    }
+}
+
+fn wipe(file: &mut disk::Device, gaps: &[Range<u64>], summary: &mut Summary) -> anyhow::Result<()> {
+    summary.step("wipe-gaps", |_| {
+        for gap in gaps {
+            disk::wipe(file, gap.clone())?;
+        }
+        anyhow::Ok(Outcome::Rebuilt)
+    })
+}
+
+/// Prints what the block device at `path` is, fails if it's smaller than
+/// `needed` bytes, and unless `yes` asks to go on.
+fn check_device<P>(path: P, needed: u64, yes: bool) -> anyhow::Result<()>
+where
+    P: AsRef<Path>,
+{
+    let Some(info) = disk::device_info(&path) else {
+        return Ok(());
+    };
+    let device = path.as_ref().display();
+    eprintln!("{device}: {info}");
+    if info.size < needed {
+        return Err(anyhow::anyhow!(
+            "{device} has {:#x} bytes, the layout needs {needed:#x}",
+            info.size
+        ));
+    }
+    if !yes && !common::confirm(&format!("write to {device}?"))? {
+        return Err(anyhow::anyhow!("aborted"));
+    }
+    Ok(())
+}
+
+fn format<P>(
+    config: &Config,
+    path: P,
+    size: Option<u64>,
+    sizes: &SizeArgs,
+    opts: &FormatArgs,
+    env: &EnvArgs,
+    summary: &mut Summary,
+) -> anyhow::Result<()>
+where
+    P: AsRef<Path>,
+{
+    let FormatArgs {
+        reinit,
+        wipe_gaps,
+        backup: BackupArgs { mut no_backup },
+        uboot_proper,
+        keep_partitions,
+        force,
+        confirm: ConfirmArgs { yes },
+        table,
+        sector_size,
+        guid_namespace,
+        io: IoArgs { io },
+    } = *opts;
+    if keep_partitions && table == partitions::Table::Mbr {
+        return Err(anyhow::anyhow!("--keep-partitions only works on a GPT"));
+    }
+    let started = Instant::now();
+    let board = &config.board;
+    if let Some(size) = size {
+        if disk::is_device(&path) {
+            let path = path.as_ref().display();
+            return Err(anyhow::anyhow!(
+                "{path} is a device, --size is for image files"
+            ));
+        }
+        if config.dry_run && !path.as_ref().exists() {
+            let path = path.as_ref().display();
+            return Err(anyhow::anyhow!(
+                "{path} doesn't exist, --dry-run doesn't create it"
+            ));
+        }
+        // Nothing to back up in a new file.
+        no_backup |= !path.as_ref().exists();
+        if !config.dry_run {
+            let file = fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?;
+            file.set_len(size)?;
+        }
+    }
+    disk::prepare_target(&path)?;
+
+    let spl = fs::read(board.uboot()?.spl())?;
+    let (second, open_sbi) = if uboot_proper {
+        ("uboot", fs::read(board.uboot_proper()?)?)
+    } else {
+        ("opensbi", fs::read(board.opensbi_image())?)
+    };
+
+    let emmc_boot = disk::emmc_boot_partition(&path);
+    let layout = match emmc_boot {
+        Some(_) => board.emmc_boot,
+        None => board.sd,
+    };
+    let spl = [board.spl_header.header(&spl, layout.spl)?, spl].concat();
+    let spl_backup = board.spl_header.backup(layout.spl, spl.len());
+    if spl_backup.is_none() && board.spl_header.format.backup_offset.is_some() {
+        eprintln!("warning: no room for a second copy of the SPL in its region");
+    }
+    // The boot ROM falls back to the second copy of the SPL when the first
+    // is corrupt.
+    let firmware = std::iter::once(layout.spl.offset)
+        .chain(spl_backup.map(|at| layout.spl.offset + u64::from(at)))
+        .map(|offset| (offset, &spl[..]))
+        .chain([(layout.opensbi.offset, &open_sbi[..])])
+        .collect::<Vec<_>>();
+    let block = match (sector_size, disk::block_size(&path)) {
+        (Some(given), Some(found)) if given != found => {
+            return Err(anyhow::anyhow!(
+                "{} has {found} byte sectors, not {given}",
+                path.as_ref().display()
+            ));
+        }
+        (given, found) => given
+            .or(found)
+            .unwrap_or(gpt::disk::LogicalBlockSize::Lb512),
+    };
+    let gpt_end = if emmc_boot.is_some() {
+        0
+    } else {
+        disk::gpt_end(block)
+    };
+    // Room for the backup GPT after the regions.
+    let needed = layout.end() + gpt_end.saturating_sub(block.as_u64());
+    let found = disk::in_use(&path, &board.gpt);
+    check_device(&path, needed, yes || !found.is_empty())?;
+    if !found.is_empty() {
+        let device = path.as_ref().display().to_string();
+        for reason in &found {
+            eprintln!("{reason}");
+        }
+        if !force {
+            return Err(anyhow::anyhow!(
+                "refusing to format {device}, it is in use; pass --force to format it anyway"
+            ));
+        }
+        if !common::confirm_typed(&device)? {
+            return Err(anyhow::anyhow!("aborted"));
+        }
+    }
+    let env = env.block(&layout, gpt_end)?;
+    let usage = layout.usage(spl.len(), open_sbi.len(), config.layout.size);
+    disk::check_usage(&usage, sizes.size_warn_threshold, sizes.strict_sizes)?;
+    disk::check_fits(layout.spl.offset, spl.len(), layout.spl.end())?;
+    disk::check_fits(layout.opensbi.offset, open_sbi.len(), layout.opensbi.end())?;
+    if uboot_proper {
+        // `update` still writes the tau slot.
+        disk::check_fits(layout.opensbi.offset, open_sbi.len(), layout.tau.offset)?;
+    }
+    let image = [&spl[..], &open_sbi].concat();
+    // `format` leaves the tau slot inside the OpenSBI region alone.
+    let opensbi_before_tau = board::Region {
+        offset: layout.opensbi.offset,
+        size: layout.tau.offset - layout.opensbi.offset,
+    };
+    let hashes = || {
+        vec![
+            history::ComponentHash::new("spl", &spl),
+            history::ComponentHash::new(second, &open_sbi),
+        ]
+    };
+
+    if let Some(dev) = emmc_boot {
+        if config.data.is_some() {
+            eprintln!("no GPT on the eMMC boot partition, not creating the data partition");
+        }
+        if config.dry_run {
+            let up_to_date = !reinit && disk::matches_all(&mut fs::File::open(&path)?, &firmware)?;
+            let gaps = wipe_gaps.then(|| layout.gaps(0));
+            let firmware = (!up_to_date).then_some(&firmware[..]);
+            dry_run_format(path.as_ref(), None, firmware, gaps.as_deref(), &env);
+            return Ok(());
+        }
+        let _guard = disk::ForceRoGuard::unlock(&dev)?;
+        let mut file = disk::open(&path, io)?;
+        let size = disk::device_size(&mut file)?;
+        disk::check_fits(layout.opensbi.offset, open_sbi.len(), size)?;
+        let up_to_date = !reinit && disk::matches_all(&mut file, &firmware)?;
+        if up_to_date {
+            eprintln!("firmware is already up to date");
+            summary.step("write-firmware", |_| anyhow::Ok(Outcome::Cached))?;
+            if wipe_gaps {
+                wipe(&mut file, &layout.gaps(0), summary)?;
+            }
+            write_env(&mut file, &env, summary)?;
+            return Ok(());
+        }
+        if !no_backup {
+            let regions = [("spl", layout.spl), ("opensbi", opensbi_before_tau)];
+            backup(
+                &mut file,
+                "format",
+                path.as_ref(),
+                &layout,
+                &regions,
+                summary,
+            )?;
+        }
+        summary.step("write-firmware", |summary| {
+            for (offset, data) in &firmware {
+                let res = disk::write_verified(&mut file, *offset, data, 0);
+                summary.verified(!matches!(res, Err(disk::DiskError::Verify(_))));
+                res?;
+                summary.written(&path, *offset, data.len());
+            }
+            anyhow::Ok(Outcome::Rebuilt)
+        })?;
+        if wipe_gaps {
+            wipe(&mut file, &layout.gaps(0), summary)?;
+        }
+        write_env(&mut file, &env, summary)?;
+        drop(file);
+        let id = layout.id.offset;
+        record_flash(
+            "format",
+            path.as_ref(),
+            Some((id, io)),
+            &image,
+            hashes(),
+            started,
+            summary,
+        )?;
+        summary.next(format!(
+            "tau-builder update --path {}",
+            path.as_ref().display()
+        ));
+
+        return Ok(());
+    }
+
+    let disk_size = disk::device_size(&mut fs::File::open(&path)?)?;
+    let mut parts = partitions::resolve(&config.partitions(), &layout, disk_size, block)?;
+    let derive = |name: &str| {
+        guid_namespace.map(|namespace| partitions::derived_guid(namespace, &board.name, name))
+    };
+    let disk_guid = derive("disk");
+    for spec in &mut parts {
+        spec.guid = derive(&format!("partition {}", spec.name));
+    }
+    // Keep the table, and so the disk GUID, of a disk formatted before.
+    let hybrid = table == partitions::Table::Hybrid;
+    let existing = match table {
+        _ if reinit => None,
+        partitions::Table::Gpt | partitions::Table::Hybrid => {
+            let f = fs::File::open(&path)?;
+            let guid = disk::gpt_matches(f, &parts, keep_partitions, block, disk_guid);
+            let mbr = disk::gpt_mbr_matches(fs::File::open(&path)?, &parts, hybrid, block);
+            guid.filter(|_| mbr).map(|guid| format!("disk GUID {guid}"))
+        }
+        partitions::Table::Mbr => {
+            disk::mbr_matches(fs::File::open(&path)?, &parts, block, disk_guid)
+                .map(|signature| format!("disk signature {signature:08x}"))
+        }
+    };
+    let step = match table {
+        partitions::Table::Gpt | partitions::Table::Hybrid => "write-gpt",
+        partitions::Table::Mbr => "write-mbr",
+    };
+    let up_to_date =
+        existing.is_some() && disk::matches_all(&mut fs::File::open(&path)?, &firmware)?;
+    if config.dry_run {
+        let table = existing.is_none().then_some(0..gpt_end);
+        let gaps = wipe_gaps.then(|| layout.gaps(gpt_end));
+        let firmware = (!up_to_date).then_some(&firmware[..]);
+        dry_run_format(path.as_ref(), table, firmware, gaps.as_deref(), &env);
+        return Ok(());
+    }
+    if !up_to_date && !no_backup {
+        let regions = [
+            (
+                "gpt",
+                board::Region {
+                    offset: 0,
+                    size: gpt_end,
+                },
+            ),
+            ("spl", layout.spl),
+            ("opensbi", opensbi_before_tau),
+        ];
+        let mut file = fs::File::open(&path)?;
+        backup(
+            &mut file,
+            "format",
+            path.as_ref(),
+            &layout,
+            &regions,
+            summary,
+        )?;
+    }
+    let mut file = match existing {
+        Some(id) => {
+            eprintln!("partition table is up to date, keeping {id}");
+            summary.step(step, |_| anyhow::Ok(Outcome::Cached))?;
+            disk::open(&path, io)?
+        }
+        None => {
+            let mut file = None;
+            summary.step(step, |_| {
+                let f = disk::open(&path, io)?;
+                let f = match table {
+                    partitions::Table::Mbr => disk::write_mbr(f, &parts, block, disk_guid)?,
+                    _ if keep_partitions => {
+                        let (f, kept) =
+                            disk::merge_gpt(f, &parts, &board.gpt, hybrid, block, disk_guid)?;
+                        for p in kept {
+                            eprintln!("keeping {p}");
+                        }
+                        f
+                    }
+                    _ => disk::write_gpt(f, &parts, hybrid, block, disk_guid)?,
+                };
+                let f = file.insert(f);
+                f.sync_all()?;
+                anyhow::Ok(Outcome::Rebuilt)
+            })?;
+            file.expect("written above")
+        }
+    };
+    let file = &mut file;
+    let gaps = layout.gaps(gpt_end);
+    let check_table = |file: &mut disk::Device| -> Result<(), disk::DiskError> {
+        match table {
+            partitions::Table::Mbr => {
+                disk::check_mbr(file, &parts, &layout.regions(), &board.gpt, block)
+            }
+            _ => disk::check_gpt(file, &parts, hybrid, &layout.regions(), &board.gpt, block),
+        }
+    };
+
+    if up_to_date {
+        eprintln!("firmware is already up to date");
+        summary.step("write-firmware", |_| anyhow::Ok(Outcome::Cached))?;
+        summary.step("check-table", |_| {
+            check_table(file)?;
+            anyhow::Ok(Outcome::Rebuilt)
+        })?;
+        if wipe_gaps {
+            wipe(file, &gaps, summary)?;
+        }
+        write_env(file, &env, summary)?;
+        summary.next(format!(
+            "tau-builder update --path {}",
+            path.as_ref().display()
+        ));
+        return Ok(());
+    }
+
+    summary.step("write-firmware", |summary| {
+        for (offset, data) in &firmware {
+            disk::write_chunked(file, *offset, data)?;
+        }
+        file.sync_all()?;
+        for (offset, data) in &firmware {
+            summary.written(&path, *offset, data.len());
+        }
+        anyhow::Ok(Outcome::Rebuilt)
+    })?;
+    summary.step("check-table", |_| {
+        check_table(file)?;
+        anyhow::Ok(Outcome::Rebuilt)
+    })?;
+    if wipe_gaps {
+        wipe(file, &gaps, summary)?;
+    }
+    write_env(file, &env, summary)?;
+    let id = layout.id.offset;
+    record_flash(
+        "format",
+        path.as_ref(),
+        Some((id, io)),
+        &image,
+        hashes(),
+        started,
+        summary,
+    )?;
+    summary.next(format!(
+        "tau-builder update --path {}",
+        path.as_ref().display()
+    ));
+
+    Ok(())
+}
+
+/// Writes the image and next to it the manifest with the layout it was
+/// composed with, after comparing the sizes to the previous compose.
+fn write_image(
+    composed: &common::Composed,
+    layout: &layout::Layout,
+    board: &Board,
+    compose: &ComposeArgs,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let sizes = composed
+        .components
+        .iter()
+        .map(|c| size::ComponentSize {
+            name: c.name.clone(),
+            size: c.len as u64,
+            max_size: c.max_size as u64,
+        })
+        .collect();
+    let record = size::SizeRecord::new(common::profile_dir(compose.profile()), sizes);
+    let previous = size::last(&record.profile)?;
+    let regressions = record.regressions(previous.as_ref(), &config.size);
+    if compose.deny_growth && !regressions.is_empty() {
+        return Err(size::SizeError::Regressed(regressions).into());
+    }
+    for r in regressions {
+        eprintln!("warning: {r}");
+    }
+    if composed.size != layout.size {
+        eprintln!(
+            "warning: the image grew from {:#x} to {:#x} bytes, past what gen-layout told tau",
+            layout.size, composed.size
+        );
+    }
+
+    common::write_atomic(dirs::image(), &composed.image)?;
+    let manifest = manifest::Manifest {
+        layout: composed.layout(layout),
+        usage: built_usage(board, composed.image.len())?
+            .iter()
+            .map(Into::into)
+            .collect(),
+        opensbi: opensbi::Build::last(board.opensbi.dir()),
+    };
+    common::write_atomic(dirs::manifest(), manifest.to_toml()?.as_bytes())?;
+    size::append(&record)?;
+    Ok(())
+}
+
+fn compose_update(
+    config: &Config,
+    compose: &ComposeArgs,
+    summary: &mut Summary,
+) -> anyhow::Result<common::Composed> {
+    let mut composed = None;
+    summary.step("compose", |_| {
+        let check_address = !compose.skip_address_check;
+        let layout = compose.layout(&config.layout);
+        let c = common::compose_tau_image(&layout, &config.board, check_address)?;
+        if compose.dump_layout {
+            print!("{}", c.dump_layout());
+        }
+        write_image(&c, &layout, &config.board, compose, config)?;
+        composed = Some(c);
+        anyhow::Ok(Outcome::Rebuilt)
+    })?;
+    summary.artifact(dirs::image());
+    summary.artifact(dirs::manifest());
+
+    Ok(composed.expect("set by the successful step"))
+}
+
+fn update_usb(
+    config: &Config,
+    mode: usb::Mode,
+    timeout: Duration,
+    compose: &ComposeArgs,
+    write: &WriteArgs,
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
+    let gadget = &config.board.usb;
+    match mode {
+        usb::Mode::Ums => {
+            let path = usb::wait_ums(gadget.ums, timeout)?;
+            update(config, path, compose, write, summary)
+        }
+        usb::Mode::Dfu => {
+            if !write.only.is_empty() || write.resume_from.is_some() || write.recovery {
+                return Err(anyhow::anyhow!(
+                    "--only, --resume-from and --recovery need direct access to the device, use --usb ums"
+                ));
+            }
+            let composed = compose_update(config, compose, summary)?;
+            let tau = config.board.sd.tau;
+            disk::check_fits(tau.offset, composed.image.len(), tau.end())?;
+            let image = dirs::image();
+            let image_var = image.to_string_lossy();
+            let manifest = dirs::manifest().display().to_string();
+            let vars = Vars {
+                image: &image_var,
+                device: "dfu",
+                manifest: &manifest,
+                board: &config.board.name,
+            };
+            run_hook(config, HookPoint::PreUpdate, &vars, summary)?;
+            if config.dry_run {
+                eprintln!("dry run, not sending {} over dfu", image.display());
+                summary.skipped("dfu");
+            } else {
+                summary.step("dfu", |_| {
+                    usb::dfu(gadget.dfu, &gadget.dfu_alt, &image, timeout)
+                        .map(|()| Outcome::Rebuilt)
+                })?;
+            }
+            run_hook(config, HookPoint::PostUpdate, &vars, summary)
+        }
+    }
+}
+
+/// `path` is either the whole disk, an image file of it, or the partition
+/// holding OpenSBI, in which case offsets are relative to the partition.
+fn update<P>(
+    config: &Config,
+    path: P,
+    compose: &ComposeArgs,
+    write: &WriteArgs,
+    summary: &mut Summary,
+) -> anyhow::Result<()>
+where
+    P: AsRef<Path>,
+{
+    let started = Instant::now();
+    let WriteArgs {
+        force,
+        resume_from,
+        ref only,
+        backup: BackupArgs { no_backup },
+        recovery,
+        confirm: ConfirmArgs { yes },
+        io: IoArgs { io },
+    } = *write;
+    if let Some(name) = only
+        .iter()
+        .find(|name| !config.layout.components.iter().any(|c| &c.name == *name))
+    {
+        return Err(anyhow::anyhow!("no component {name} in the layout"));
+    }
+    disk::prepare_target(&path)?;
+
+    let common::Composed {
+        image,
+        size: image_size,
+        components,
+    } = compose_update(config, compose, summary)?;
+
+    let device = path.as_ref().display().to_string();
+    let image_var = dirs::image().display().to_string();
+    let manifest = dirs::manifest().display().to_string();
+    let vars = Vars {
+        image: &image_var,
+        device: &device,
+        manifest: &manifest,
+        board: &config.board.name,
+    };
+    run_hook(config, HookPoint::PreUpdate, &vars, summary)?;
+
+    let board = &config.board;
+    let (layout, force_ro) = match disk::emmc_boot_partition(&path) {
+        Some(dev) => {
+            let guard = disk::ForceRoGuard::unlock(&dev)?;
+            (board.emmc_boot, Some(guard))
+        }
+        None => (board.sd, None),
+    };
+    let (slot_name, region) = if recovery {
+        let region = layout.recovery.ok_or_else(|| {
+            anyhow::anyhow!("the disk layout of {} has no recovery slot", board.name)
+        })?;
+        ("recovery", region)
+    } else {
+        ("tau", layout.tau)
+    };
+    disk::check_fits(region.offset, image.len(), region.end())?;
+    let (whole, start) = disk::partition_of(&path).unwrap_or((path.as_ref().to_owned(), 0));
+    let offset = region
+        .offset
+        .checked_sub(start)
+        .ok_or_else(|| disk::DiskError::Partition(device.clone()))?;
+    check_device(&path, offset + image.len() as u64, yes)?;
+
+    if force_ro.is_none() {
+        let range = region.offset..(region.offset + image.len() as u64);
+        // An image file may be of a disk of either sector size.
+        let blocks = match disk::block_size(&whole) {
+            Some(block) => vec![block],
+            None => vec![
+                gpt::disk::LogicalBlockSize::Lb512,
+                gpt::disk::LogicalBlockSize::Lb4096,
+            ],
+        };
+        let mut probe = disk::GptProbe::NoGpt;
+        for block in &blocks {
+            probe = disk::probe_gpt(fs::File::open(&whole)?, range.clone(), &board.gpt, *block);
+            if !matches!(probe, disk::GptProbe::NoGpt) {
+                break;
+            }
+        }
+        if matches!(probe, disk::GptProbe::NoGpt) {
+            probe = disk::probe_mbr(fs::File::open(&whole)?, range, &board.gpt, blocks[0]);
+        }
+        match probe {
+            disk::GptProbe::Firmware | disk::GptProbe::Clear => {}
+            disk::GptProbe::Foreign(found) if !force => {
+                return Err(anyhow::anyhow!(
+                    "refusing to write {device}, it holds {found}; pass --force to overwrite"
+                ));
+            }
+            disk::GptProbe::Foreign(found) => eprintln!("overwriting {found}"),
+            disk::GptProbe::NoGpt => {
+                eprintln!("warning: {device} has no partition table");
+                if !force && !common::confirm("write anyway?")? {
+                    return Err(anyhow::anyhow!("aborted"));
+                }
+            }
+        }
+    }
+
+    let mut file = disk::open(&path, io)?;
+    let size = disk::device_size(&mut file)?;
+    disk::check_fits(offset, image.len(), size)?;
+    let skip = match resume_from {
+        Some(resume) if (offset..=(offset + image.len() as u64)).contains(&resume) => {
+            (resume - offset) as usize
+        }
+        Some(resume) => {
+            let end = offset + image.len() as u64;
+            return Err(anyhow::anyhow!(
+                "--resume-from {resume:#x} is outside of {offset:#x}..{end:#x}"
+            ));
+        }
+        None => 0,
+    };
+    // Slots to write, relative to the image. A partial write only makes
+    // sense if the rest of the device already holds the same build.
+    let mut slots = vec![(0, image.len())];
+    let mut mixed = false;
+    if !only.is_empty() {
+        let (selected, rest) = components
+            .iter()
+            .partition::<Vec<_>, _>(|c| only.contains(&c.name));
+        let mut stale = vec![];
+        for c in rest {
+            let slot = &image[c.offset..(c.offset + c.max_size)];
+            match disk::verify(&mut file, offset + c.offset as u64, slot) {
+                Ok(()) => {}
+                Err(disk::DiskError::Verify(_)) => stale.push(c.name.as_str()),
+                Err(err) => return Err(err.into()),
+            }
+        }
+        if !stale.is_empty() {
+            let stale = stale.join(", ");
+            if !force {
+                return Err(anyhow::anyhow!(
+                    "{stale} on {device} differ from the local build; pass --force to write only {}",
+                    only.join(", ")
+                ));
+            }
+            eprintln!("warning: leaving {stale} on {device} as they are");
+            mixed = true;
+        }
+        // The slots are aligned in the image, the image may not be on the device.
+        let layout_components = config.layout.components.iter();
+        for c in layout_components.filter(|c| only.contains(&c.name)) {
+            let at = offset + c.offset as u64;
+            if !at.is_multiple_of(c.align as u64) {
+                eprintln!(
+                    "warning: {} at {at:#x} on {device} isn't aligned to {:#x}, its neighbours share the erase block",
+                    c.name, c.align
+                );
+            }
+        }
+        slots = selected.iter().map(|c| (c.offset, c.max_size)).collect();
+        // They change with every compose and the footer covers them.
+        slots.extend(config.layout.metadata.map(|m| (m.offset, m.max_size)));
+        slots.push((image_size, image.len() - image_size));
+    }
+    if config.dry_run {
+        for (start, len) in slots {
+            let at = offset + start as u64;
+            let end = at + (len as u64);
+            eprintln!(
+                "dry run, not writing {device} {:#x}..{end:#x}",
+                at + skip as u64
+            );
+        }
+        summary.skipped("write-tau");
+        return run_hook(config, HookPoint::PostUpdate, &vars, summary);
+    }
+    if !no_backup {
+        let mut whole_file = fs::File::open(&whole)?;
+        let regions = [(slot_name, region)];
+        // Recorded as the whole disk, the one `rollback` looks backups up by.
+        backup(
+            &mut whole_file,
+            "update",
+            &whole,
+            &layout,
+            &regions,
+            summary,
+        )?;
+    }
+    summary.step("write-tau", |summary| {
+        // `--resume-from` conflicts with `--only`, so `skip` is 0 for slots.
+        for (start, len) in slots {
+            let at = offset + start as u64;
+            let res = disk::write_verified(&mut file, at, &image[start..][..len], skip);
+            summary.verified(!matches!(res, Err(disk::DiskError::Verify(_))));
+            res?;
+            summary.written(&path, at, len);
+        }
+        // whatever wasn't written must hold the same build too
+        let written = footer::read_at(&mut file, offset, image_size)?;
+        match footer::check(&written, image_size) {
+            footer::Check::Ok(_) => {}
+            _ if mixed => {
+                eprintln!("warning: {slot_name} on {device} mixes builds, the footer won't match")
+            }
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "{slot_name} on {device} doesn't match its footer"
+                ));
+            }
+        }
+        anyhow::Ok(Outcome::Rebuilt)
+    })?;
+    drop(file);
+    let hashes = components
+        .iter()
+        .map(|c| history::ComponentHash::new(&c.name, &image[c.offset..][..c.max_size]))
+        .collect();
+    // The id block describes what the card boots.
+    let (command, id) = if recovery {
+        ("update --recovery", None)
+    } else {
+        ("update", Some((layout.id.offset, io)))
+    };
+    record_flash(command, &whole, id, &image, hashes, started, summary)?;
+    run_hook(config, HookPoint::PostUpdate, &vars, summary)?;
+
+    Ok(())
+}
+
+/// Saves `regions` of the device in `file` to `backups` in the output
+/// directory.
+fn backup<F>(
+    file: &mut F,
+    command: &str,
+    device: &Path,
+    layout: &board::DiskLayout,
+    regions: &[(&str, board::Region)],
+    summary: &mut Summary,
+) -> anyhow::Result<()>
+where
+    F: Read + Seek,
+{
+    summary.step("backup", |_| {
+        let id = history::IdBlock::read(file, layout.id.offset)
+            .ok()
+            .flatten()
+            .map(|block| block.id);
+        match backup::snapshot(file, command, device, id, regions)? {
+            Some(dir) => {
+                eprintln!("saved the old firmware to {}", dir.display());
+                anyhow::Ok(Outcome::Rebuilt)
+            }
+            None => anyhow::Ok(Outcome::Cached),
+        }
+    })
+}
+
+fn rollback<P>(
+    board: &Board,
+    path: P,
+    to: Option<&str>,
+    io: disk::IoMode,
+    summary: &mut Summary,
+) -> anyhow::Result<()>
+where
+    P: AsRef<Path>,
+{
+    let started = Instant::now();
+    disk::prepare_target(&path)?;
+    let (layout, force_ro) = match disk::emmc_boot_partition(&path) {
+        Some(dev) => {
+            let guard = disk::ForceRoGuard::unlock(&dev)?;
+            (board.emmc_boot, Some(guard))
+        }
+        None => (board.sd, None),
+    };
+    let (whole, _) = disk::partition_of(&path).unwrap_or((path.as_ref().to_owned(), 0));
+    let device = fs::canonicalize(&whole)?.display().to_string();
+    let id = history::IdBlock::read(&mut fs::File::open(&whole)?, layout.id.offset)
+        .ok()
+        .flatten()
+        .map(|block| block.id);
+
+    let backups = backup::list()?;
+    let chosen = match to {
+        Some(name) => backups
+            .iter()
+            .find(|b| b.dir.file_name().is_some_and(|n| n == name))
+            .ok_or_else(|| anyhow::anyhow!("no backup {name}, see list-backups"))?,
+        // The id survives the device being renamed, the path covers disks
+        // backed up before they had one.
+        None => backups
+            .iter()
+            .rev()
+            .find(|b| match (b.manifest.id, id) {
+                (Some(a), Some(b)) => a == b,
+                _ => b.manifest.device == device,
+            })
+            .ok_or_else(|| anyhow::anyhow!("no backup of {device}, see list-backups"))?,
+    };
+    eprintln!(
+        "restoring {} taken by {} of {}",
+        chosen.dir.display(),
+        chosen.manifest.command,
+        chosen.manifest.device
+    );
+
+    let mut regions = vec![];
+    for r in &chosen.manifest.regions {
+        let mut data = fs::read(chosen.dir.join(&r.file))?;
+        if data.len() as u64 > r.size {
+            return Err(anyhow::anyhow!("{} is bigger than its region", r.file));
+        }
+        data.resize(r.size as usize, 0);
+        regions.push((r.name.as_str(), r.offset, data));
+    }
+    // The backup holds the primary GPT only, the backup one is rebuilt
+    // from it, of the sector size the primary one was saved for.
+    let gpt_block = match regions.iter().find(|(name, _, _)| *name == "gpt") {
+        Some((_, _, data)) => {
+            let blocks = [
+                gpt::disk::LogicalBlockSize::Lb512,
+                gpt::disk::LogicalBlockSize::Lb4096,
+            ];
+            let block = blocks
+                .into_iter()
+                .find(|block| disk::gpt_end(*block) == data.len() as u64)
+                .ok_or_else(|| anyhow::anyhow!("the saved GPT is of no known sector size"))?;
+            Some(block)
+        }
+        None => None,
+    };
+    let mut file = disk::open(&whole, io)?;
+    let size = disk::device_size(&mut file)?;
+    for (_, offset, data) in &regions {
+        disk::check_fits(*offset, data.len(), size)?;
+    }
+    summary.step("rollback", |summary| {
+        for (name, offset, data) in &regions {
+            let res = disk::write_verified(&mut file, *offset, data, 0);
+            summary.verified(!matches!(res, Err(disk::DiskError::Verify(_))));
+            res.map_err(|err| anyhow::anyhow!("{name}: {err}"))?;
+            summary.written(&whole, *offset, data.len());
+        }
+        if let Some(block) = gpt_block {
+            disk::restore_backup_gpt(&mut file, block)?;
+        }
+        anyhow::Ok(Outcome::Rebuilt)
+    })?;
+    drop(file);
+
+    let image = regions
+        .iter()
+        .flat_map(|(_, _, data)| data)
+        .copied()
+        .collect::<Vec<_>>();
+    let hashes = regions
+        .iter()
+        .map(|(name, _, data)| history::ComponentHash::new(name, data))
+        .collect();
+    record_flash(
+        "rollback",
+        &whole,
+        Some((layout.id.offset, io)),
+        &image,
+        hashes,
+        started,
+        summary,
+    )?;
+    drop(force_ro);
+
+    Ok(())
+}
+
+/// Stamps the id block of the device and appends the write to the history.
+/// Failing to record the history doesn't fail the command.
+/// The id block at the offset in `id` is written with the device opened as
+/// its mode asks, and left alone without `id`.
+fn record_flash(
+    command: &str,
+    device: &Path,
+    id: Option<(u64, disk::IoMode)>,
+    image: &[u8],
+    components: Vec<history::ComponentHash>,
+    started: Instant,
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
+    let mut record = history::Record::new(command, device, image, components);
+    if let Some((id_offset, io)) = id {
+        summary.step("write-id", |_| {
+            let mut file = disk::open(device, io)?;
+            let previous = history::IdBlock::read(&mut file, id_offset)?;
+            let block = history::IdBlock::new(previous.as_ref(), history::crc32(image));
+            block.write(&mut file, id_offset)?;
+            disk::Target::sync(&mut file)?;
+            record.id = Some(block.id);
+            anyhow::Ok(Outcome::Rebuilt)
+        })?;
+    }
+    record.duration_ms = started.elapsed().as_millis() as u64;
+    if let Err(err) = history::append(&record) {
+        eprintln!("warning: failed to record the history: {err}");
+    }
+
+    Ok(())
+}
+
+fn history(board: &Board, command: HistoryCommand) -> anyhow::Result<()> {
+    let records = history::load()?;
+    match command {
+        HistoryCommand::List { json } => {
+            history::print(&records.iter().collect::<Vec<_>>(), json)?;
+        }
+        HistoryCommand::Show { path, json } => {
+            disk::prepare_target(&path)?;
+            let layout = match disk::emmc_boot_partition(&path) {
+                Some(_) => board.emmc_boot,
+                None => board.sd,
+            };
+            let (whole, _) = disk::partition_of(&path).unwrap_or((path.clone(), 0));
+            let block = history::IdBlock::read(&mut fs::File::open(&whole)?, layout.id.offset)?;
+            let Some(block) = block else {
+                return Err(anyhow::anyhow!(
+                    "{} has no id block, it wasn't flashed by tau-builder",
+                    whole.display()
+                ));
+            };
+            if !json {
+                println!(
+                    "id {}, last written {} with image crc32 {:08x}",
+                    block.id,
+                    history::timestamp(block.time),
+                    block.image_crc
+                );
+            }
+            let matching = records
+                .iter()
+                .filter(|r| r.id == Some(block.id))
+                .collect::<Vec<_>>();
+            history::print(&matching, json)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn flash<P, Q>(
+    board: &Board,
+    image: P,
+    path: Q,
+    force: bool,
+    io: disk::IoMode,
+    summary: &mut Summary,
+) -> anyhow::Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let device = path.as_ref().display().to_string();
+    if disk::partition_of(&path).is_some() || disk::emmc_boot_partition(&path).is_some() {
+        return Err(anyhow::anyhow!(
+            "{device} is a partition, flash the whole device"
+        ));
+    }
+    disk::prepare_target(&path)?;
+
+    let mut src = fs::File::open(&image)?;
+    let len = src.metadata()?.len();
+    let is_device = disk::is_device(&path);
+    if !is_device {
+        // An image file grows to fit.
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        file.set_len(file.metadata()?.len().max(len))?;
+    }
+    let mut file = disk::open(&path, io)?;
+    let size = disk::device_size(&mut file)?;
+    if is_device {
+        disk::check_fits(0, len as usize, size)?;
+        eprintln!("everything on {device} will be overwritten");
+        let found = disk::in_use(&path, &board.gpt);
+        if !found.is_empty() {
+            for reason in &found {
+                eprintln!("{reason}");
+            }
+            if !force {
+                return Err(anyhow::anyhow!(
+                    "refusing to flash {device}, it is in use; pass --force to flash it anyway"
+                ));
+            }
+            if !common::confirm_typed(&device)? {
+                return Err(anyhow::anyhow!("aborted"));
+            }
+        } else if !force && !common::confirm("continue?")? {
+            return Err(anyhow::anyhow!("aborted"));
+        }
+    }
+    summary.step("flash", |summary| {
+        disk::stream(&mut src, len, &mut file)?;
+        summary.written(&path, 0, len as usize);
+        anyhow::Ok(Outcome::Rebuilt)
+    })?;
+    if is_device && size > len && disk::gpt_block_size(&mut src).is_some() {
+        summary.next(format!("tau-builder expand --path {device}"));
+    }
+
+    Ok(())
+}
+
+fn expand<P>(
+    board: &Board,
+    path: P,
+    yes: bool,
+    io: disk::IoMode,
+    summary: &mut Summary,
+) -> anyhow::Result<()>
+where
+    P: AsRef<Path>,
+{
+    let device = path.as_ref().display().to_string();
+    if disk::partition_of(&path).is_some() || disk::emmc_boot_partition(&path).is_some() {
+        return Err(anyhow::anyhow!(
+            "{device} is a partition, expand the whole device"
+        ));
+    }
+    disk::prepare_target(&path)?;
+    let found = disk::in_use(&path, &board.gpt);
+    if !found.is_empty() {
+        for reason in &found {
+            eprintln!("{reason}");
+        }
+        return Err(anyhow::anyhow!("refusing to expand {device}, it is in use"));
+    }
+    check_device(&path, 0, yes)?;
+
+    let block = match disk::block_size(&path) {
+        Some(block) => block,
+        None => disk::gpt_block_size(fs::File::open(&path)?)
+            .unwrap_or(gpt::disk::LogicalBlockSize::Lb512),
+    };
+    summary.step("expand", |_| {
+        let file = disk::open(&path, io)?;
+        let (mut file, grown) = disk::expand_gpt(file, &board.gpt, block)?;
+        disk::Target::sync(&mut file)?;
+        match grown {
+            Some(grown) => {
+                eprintln!("grew {grown}, grow the filesystem in it as well");
+                anyhow::Ok(Outcome::Rebuilt)
+            }
+            None => {
+                eprintln!("the last partition already ends the disk");
+                anyhow::Ok(Outcome::Cached)
+            }
+        }
+    })
+}
+
+fn watch(
+    config: &Config,
+    dirs: Vec<PathBuf>,
+    interval: Duration,
+    qemu: bool,
+    build: &BuildArgs,
+) -> anyhow::Result<()> {
+    let board = config.board(qemu);
+    let compose = &build.compose;
+    let layout = compose.layout(&config.layout);
+    let mut dirs = if dirs.is_empty() {
+        watch::DEFAULT_DIRS.map(PathBuf::from).to_vec()
+    } else {
+        dirs
+    };
+    dirs.extend(
+        layout
+            .components
+            .iter()
+            .filter(|c| c.kind == layout::ComponentKind::Payload)
+            .map(|c| c.path.clone()),
+    );
+    let rebuild = || {
+        let layout_dir = memory_map::default_dir();
+        memory_map::generate(&config.layout, board, &layout_dir, false)?;
+        common::build_tau(&layout_dir, &build.cargo_options())?;
+        if compose.debug {
+            warn_budgets(&layout)?;
+        }
+        let composed = common::compose_tau_image(&layout, board, !compose.skip_address_check)?;
+        if compose.dump_layout {
+            print!("{}", composed.dump_layout());
+        }
+        write_image(&composed, &layout, board, compose, config)?;
+        for c in &composed.components {
+            println!("  {:<12} {:#x} of {:#x} bytes", c.name, c.len, c.max_size);
+        }
+        println!(
+            "{} {:#x} bytes, crc32 {:08x}",
+            dirs::image().display(),
+            composed.image.len(),
+            history::crc32(&composed.image)
+        );
+        anyhow::Ok(())
+    };
+
+    let mut watcher = watch::Watcher::new(dirs, interval);
+    loop {
+        // A failed build is reported and waits for the next change.
+        if let Err(err) = rebuild() {
+            eprintln!("error: {err}");
+        }
+        if interrupt::interrupted() {
+            break;
+        }
+        eprintln!("watching {} files, Ctrl-C to stop", watcher.files());
+        let Some(changed) = watcher.wait() else {
+            break;
+        };
+        match &changed[..] {
+            [one] => eprintln!("{} changed, rebuilding", one.display()),
+            many => eprintln!("{} files changed, rebuilding", many.len()),
+        }
+    }
+
+    Ok(())
+}
+
+fn env(command: EnvCommand) -> anyhow::Result<()> {
+    let read_block = |path: &Path, offset: u64, format: &uboot_env::EnvFormat| {
+        disk::prepare_target(path)?;
+        let mut file = fs::File::open(path)?;
+        let mut block = vec![0; format.env_size as usize];
+        file.seek(io::SeekFrom::Start(offset))?;
+        file.read_exact(&mut block)?;
+        anyhow::Ok(block)
+    };
+    match command {
+        EnvCommand::Build {
+            input,
+            output,
+            format,
+        } => {
+            let env = uboot_env::Env::parse_text(&fs::read_to_string(input)?)?;
+            fs::write(output, env.to_bytes(&format)?)?;
+        }
+        EnvCommand::Show {
+            path,
+            offset,
+            format,
+        } => {
+            let block = read_block(&path, offset, &format)?;
+            print!(
+                "{}",
+                uboot_env::Env::parse_bytes(&block, &format)?.to_text()
+            );
+        }
+        EnvCommand::Set {
+            path,
+            offset,
+            format,
+            vars,
+            unset,
+            io: IoArgs { io },
+        } => {
+            let block = read_block(&path, offset, &format)?;
+            let mut env = uboot_env::Env::parse_bytes(&block, &format)?;
+            for key in &unset {
+                if env.vars.remove(key).is_none() {
+                    eprintln!("warning: {key} is not set");
+                }
+            }
+            env.vars.extend(vars);
+            let block = env.to_bytes(&format)?;
+            let mut file = disk::open(&path, io)?;
+            disk::write_verified(&mut file, offset, &block, 0)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn size(config: &Config) -> anyhow::Result<()> {
+    let budgets = size::budgets(&config.layout)?;
+    size::print(&budgets);
+    let over = budgets
+        .iter()
+        .filter(|b| b.exceeded())
+        .map(|b| b.name.as_str())
+        .collect::<Vec<_>>();
+    if !over.is_empty() {
+        return Err(anyhow::anyhow!("{} exceed their slots", over.join(", ")));
+    }
+
+    Ok(())
+}
+
+/// Debug builds are expected to be big, the image just can't be composed
+/// until they fit.
+fn warn_budgets(layout: &layout::Layout) -> Result<(), size::SizeError> {
+    for b in size::budgets(layout)?.iter().filter(|b| b.exceeded()) {
+        eprintln!(
+            "warning: debug {} takes {:#x} bytes of its {:#x} bytes slot",
+            b.name, b.used, b.max_size
+        );
+    }
+
+    Ok(())
+}
+
+fn test_boot(
+    board: &Board,
+    machine: &MachineArgs,
+    success: &[String],
+    mut failure: Vec<String>,
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    if failure.is_empty() {
+        failure = test_boot::DEFAULT_FAILURE.map(String::from).to_vec();
+    }
+    let mut command = qemu_command(board, machine)?;
+    match test_boot::run(&mut command, success, &failure, timeout)? {
+        test_boot::Verdict::Success => {
+            eprintln!("boot ok");
+            Ok(())
+        }
+        test_boot::Verdict::Failure(line) => Err(anyhow::anyhow!("boot failed: {line}")),
+        test_boot::Verdict::Timeout => {
+            Err(anyhow::anyhow!("no marker within {}s", timeout.as_secs()))
+        }
+        test_boot::Verdict::Exited(status) => Err(anyhow::anyhow!(
+            "qemu exited with {status} before any marker"
+        )),
+        test_boot::Verdict::Interrupted => Err(anyhow::anyhow!("interrupted")),
+    }
+}
+
+fn recover(
+    board: &Board,
+    device: PathBuf,
+    baud: u32,
+    file: Option<PathBuf>,
+    wait: Duration,
+) -> anyhow::Result<()> {
+    let data = match file {
+        Some(file) => fs::read(file)?,
+        None => {
+            let spl = fs::read(board.uboot()?.spl())
+                .map_err(|err| anyhow::anyhow!("spl: {err}, run build-firmware first"))?;
+            let header = board.spl_header.header(&spl, board.sd.spl)?;
+            [header, spl].concat()
+        }
+    };
+    let mut port = serial::Port::open(&device, baud)?;
+    eprintln!(
+        "waiting for the boot ROM on {}, set the boot switches to UART and reset the board",
+        device.display()
+    );
+    xmodem::send(&mut port, &data, wait)?;
+    eprintln!("sent {} bytes, the SPL is starting", data.len());
+    eprintln!("next: tau-builder serial --device {}", device.display());
+
+    Ok(())
+}
+
+fn status(config: &Config, res: &Resources) {
+    let clone = |source: &board::Source| Some((source.dir(), source.revision.clone()));
+    let mut stages = vec![];
+    if let Some(uboot) = &config.board.uboot {
+        stages.push(status::Stage {
+            name: "spl".to_owned(),
+            artifact: uboot.spl(),
+            sources: Some(uboot.source.dir())
+                .into_iter()
+                .chain(uboot.patches.iter().filter_map(|p| res.path(p).ok()))
+                .collect(),
+            clone: clone(&uboot.source),
+            failed_marker: Some(uboot.build_dir().join(common::FAILED)),
+        });
+    }
+    stages.push(status::Stage {
+        name: config.board.opensbi.name.clone(),
+        artifact: config.board.opensbi_image(),
+        sources: [
+            Some(config.board.opensbi.dir()),
+            res.path(&config.board.dtb).ok(),
+            res.path(&config.board.dts()).ok(),
+        ]
+        .into_iter()
+        .chain(config.board.overlays.iter().map(|o| res.path(o).ok()))
+        .flatten()
+        .collect(),
+        clone: clone(&config.board.opensbi),
+        failed_marker: None,
+    });
+    stages.push(status::Stage {
+        name: config.qemu.opensbi.name.clone(),
+        artifact: config.qemu.opensbi_firmware("fw_payload.elf"),
+        sources: [
+            Some(config.qemu.opensbi.dir()),
+            res.path(&config.qemu.dtb).ok(),
+            res.path(&config.qemu.dts()).ok(),
+            // the payload is linked in
+            Some(dirs::image()),
+        ]
+        .into_iter()
+        .flatten()
+        .collect(),
+        clone: clone(&config.qemu.opensbi),
+        failed_marker: None,
+    });
+    for c in &config.layout.components {
+        let sources = match c.kind {
+            layout::ComponentKind::Elf => watch::DEFAULT_DIRS
+                .iter()
+                .map(PathBuf::from)
+                .chain(Some(memory_map::default_dir()))
+                .collect(),
+            layout::ComponentKind::Raw | layout::ComponentKind::Auto => vec![],
+            layout::ComponentKind::Payload => vec![c.path.clone()],
+        };
+        stages.push(status::Stage {
+            name: c.name.clone(),
+            artifact: c.path.clone(),
+            sources,
+            clone: None,
+            failed_marker: None,
+        });
+    }
+    stages.push(status::Stage {
+        name: "tau".to_owned(),
+        artifact: dirs::image(),
+        sources: config
+            .layout
+            .components
+            .iter()
+            .map(|c| c.path.clone())
+            .collect(),
+        clone: None,
+        failed_marker: None,
+    });
+
+    let reports = stages.iter().map(status::check).collect::<Vec<_>>();
+    status::print(&reports);
+    match manifest::Manifest::load() {
+        Ok(manifest) => {
+            status::print_usage(manifest.map(|m| m.usage).as_deref().unwrap_or_default())
+        }
+        Err(err) => eprintln!("{err}"),
+    }
+}
+
+/// Opens the whole device behind `path` for reading, with the layout and
+/// the SPL header formats to look for.
+fn open_firmware<P>(
+    board: &Board,
+    path: P,
+    any_format: bool,
+) -> anyhow::Result<(
+    fs::File,
+    board::DiskLayout,
+    Vec<&'static spl_header::SplHeaderFormat>,
+)>
+where
+    P: AsRef<Path>,
+{
+    disk::prepare_target(&path)?;
+    let layout = match disk::emmc_boot_partition(&path) {
+        Some(_) => board.emmc_boot,
+        None => board.sd,
+    };
+    let (whole, _) = disk::partition_of(&path).unwrap_or((path.as_ref().to_owned(), 0));
+    let formats = if any_format {
+        spl_header::FORMATS.to_vec()
+    } else {
+        vec![board.spl_header.format]
+    };
+
+    Ok((fs::File::open(whole)?, layout, formats))
+}
+
+fn inspect<P>(
+    board: &Board,
+    image_size: usize,
+    path: P,
+    any_format: bool,
+    image: bool,
+) -> anyhow::Result<()>
+where
+    P: AsRef<Path>,
+{
+    if image {
+        let data = fs::read(path)?;
+        match metadata::BuildInfo::find(&data) {
+            Some((at, info)) => {
+                println!("metadata at {at:#x}:");
+                info.print();
+            }
+            None => println!("metadata: none"),
+        }
+        inspect::print_footer(&data, image_size);
+        return Ok(());
+    }
+    let (mut file, layout, formats) = open_firmware(board, path, any_format)?;
+    let local_tau = fs::read(dirs::image()).ok();
+    inspect::print(
+        &mut file,
+        &layout,
+        &formats,
+        local_tau.as_deref(),
+        image_size,
+    )?;
+
+    Ok(())
+}
+
+fn extract<P, Q>(board: &Board, path: P, out: Q, any_format: bool) -> anyhow::Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let (mut file, layout, formats) = open_firmware(board, path, any_format)?;
+    inspect::extract(&mut file, &layout, &formats, out)?;
+
+    Ok(())
+}
+
+fn disasm(config: &Config, path: Option<PathBuf>, names: &[String]) -> anyhow::Result<()> {
+    let layout = &config.layout;
+    if let Some(name) = names
+        .iter()
+        .find(|name| !layout.components.iter().any(|c| &c.name == *name))
+    {
+        return Err(anyhow::anyhow!("no component {name} in the layout"));
+    }
+    let objdump = disasm::objdump()?;
+    let image = match path {
+        Some(path) => {
+            let (mut file, disk_layout, _) = open_firmware(&config.board, path, false)?;
+            let mut image = vec![0; layout.size];
+            file.seek(io::SeekFrom::Start(disk_layout.tau.offset))?;
+            file.read_exact(&mut image)?;
+            image
+        }
+        None => fs::read(dirs::image())?,
+    };
+
+    let base = config.board.payload_base();
+    for c in &layout.components {
+        if !names.is_empty() && !names.contains(&c.name) {
+            continue;
+        }
+        if names.is_empty() && c.kind == layout::ComponentKind::Payload {
+            continue;
+        }
+        let slot = image
+            .get(c.offset..(c.offset + c.max_size))
+            .ok_or_else(|| anyhow::anyhow!("{} is outside of the image", c.name))?;
+        let used = slot.iter().rposition(|b| *b != 0).map_or(0, |p| p + 1);
+        // Symbols come from the local build, so they only line up with an
+        // image built from it.
+        let symbols = match c.kind {
+            layout::ComponentKind::Elf | layout::ComponentKind::Auto => fs::read(&c.path)
+                .ok()
+                .and_then(|data| disasm::Symbols::parse(&data)),
+            layout::ComponentKind::Raw | layout::ComponentKind::Payload => None,
+        };
+        let vma = match &symbols {
+            Some(symbols) => symbols.base,
+            None => base + c.offset as u64,
+        };
+        disasm::print(&objdump, &c.name, &slot[..used], vma, symbols.as_ref())?;
+    }
+
+    Ok(())
+}
+
+fn compose<P>(
+    board: &Board,
+    inputs: Vec<(layout::ComponentKind, PathBuf, usize)>,
+    out: P,
+    size: Option<usize>,
+    footer: bool,
+    dump_layout: bool,
+) -> anyhow::Result<()>
+where
+    P: AsRef<Path>,
+{
+    let size = match (size, inputs.iter().max_by_key(|(_, _, offset)| *offset)) {
+        (Some(size), _) => size,
+        (None, Some((kind, path, offset))) => {
+            let data = fs::read(path).map_err(|err| common::ComposeError::io(path, err))?;
+            let len = match kind.detect(&data) {
+                layout::ComponentKind::Elf => {
+                    common::elf_size(&data).map_err(|err| common::ComposeError::err(path, err))?
+                }
+                _ => data.len(),
+            };
+            offset + len
+        }
+        (None, None) => 0,
+    };
+    let layout = layout::Layout::from_inputs(inputs, size);
+    layout.validate()?;
+    // Nothing to check the link addresses against.
+    let composed = common::compose_tau_image(&layout, board, false)?;
+    if dump_layout {
+        print!("{}", composed.dump_layout());
+    }
+    let end = if footer {
+        composed.image.len()
+    } else {
+        composed.size
+    };
+    common::write_atomic(&out, &composed.image[..end])?;
+    eprintln!("wrote {end} bytes to {}", out.as_ref().display());
+
+    Ok(())
+}
+
+fn spl_header_command<P, Q>(
+    input: P,
+    output: Q,
+    format: &spl_header::SplHeaderFormat,
+    backup_offset: Option<u32>,
+    version: Option<u32>,
+    strip: bool,
+) -> anyhow::Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let data = fs::read(&input)?;
+    let out = if strip {
+        let parsed = format.parse(&data).ok_or_else(|| {
+            let input = input.as_ref().display();
+            anyhow::anyhow!("{input} doesn't start with a {} header", format.name)
+        })?;
+        if parsed.crc_ok == Some(false) {
+            eprintln!("warning: the header crc doesn't match the payload");
+        }
+        let end = (format.size + parsed.payload_size as usize).min(data.len());
+        data[format.size..end].to_vec()
+    } else {
+        if format.parse(&data).is_some() {
+            eprintln!("warning: the input already has a {} header", format.name);
+        }
+        [format.header(&data, backup_offset, version)?, data].concat()
+    };
+    fs::write(&output, &out)?;
+    eprintln!("wrote {} bytes to {}", out.len(), output.as_ref().display());
+
+    Ok(())
+}
+
+/// Of the last composed image, it may have grown past the layout.
+fn image_size(config: &Config) -> usize {
+    manifest::Manifest::load()
+        .ok()
+        .flatten()
+        .map_or(config.layout.size, |manifest| manifest.layout.size)
+}
+
+/// How full the SD regions of `board` are with its SPL and OpenSBI, as
+/// `format` writes them, and `tau` bytes of tau. Firmware not built yet is
+/// left out.
+fn built_usage(board: &Board, tau: usize) -> anyhow::Result<Vec<board::Usage>> {
+    let layout = board.sd;
+    let spl = match board.uboot.as_ref().map(|uboot| fs::read(uboot.spl())) {
+        Some(Ok(spl)) => Some(board.spl_header.header(&spl, layout.spl)?.len() + spl.len()),
+        _ => None,
+    };
+    let opensbi = fs::metadata(board.opensbi_image())
+        .ok()
+        .map(|meta| meta.len() as usize);
+    let usage = layout.usage(spl.unwrap_or(0), opensbi.unwrap_or(0), tau);
+    let built = [spl.is_some(), opensbi.is_some(), true];
+    Ok(usage
+        .into_iter()
+        .zip(built)
+        .filter_map(|(usage, built)| built.then_some(usage))
+        .collect())
+}
+
+fn verify<P>(board: &Board, image_size: usize, path: P) -> anyhow::Result<()>
+where
+    P: AsRef<Path>,
+{
+    disk::prepare_target(&path)?;
+    let layout = match disk::emmc_boot_partition(&path) {
+        Some(_) => board.emmc_boot,
+        None => board.sd,
+    };
+    let (whole, _) = disk::partition_of(&path).unwrap_or((path.as_ref().to_owned(), 0));
+    let mut file = fs::File::open(&whole)?;
+
+    let read = |path: &Path| fs::read(path).ok();
+    let spl = board.uboot.as_ref().and_then(|uboot| read(&uboot.spl()));
+    let header = spl
+        .as_deref()
+        .map(|spl| board.spl_header.header(spl, layout.spl))
+        .transpose()?
+        .filter(|header| !header.is_empty());
+    let header_len = header.as_ref().map_or(0, Vec::len) as u64;
+    let backup = header.as_ref().zip(spl.as_ref()).and_then(|(header, spl)| {
+        let copy = [&header[..], spl].concat();
+        let at = board.spl_header.backup(layout.spl, copy.len())?;
+        Some((layout.spl.offset + u64::from(at), copy))
+    });
+    let mut open_sbi = read(&board.opensbi_image());
+    // `update` replaces the payload part of fw_payload.bin with the tau image.
+    if let Some(data) = &mut open_sbi {
+        data.truncate((layout.tau.offset - layout.opensbi.offset) as usize);
+    }
+    let mut regions = vec![
+        ("spl-header", layout.spl.offset, header),
+        ("spl", layout.spl.offset + header_len, spl),
+    ];
+    if let Some((offset, copy)) = backup {
+        regions.push(("spl-backup", offset, Some(copy)));
+    }
+    regions.extend([
+        ("opensbi", layout.opensbi.offset, open_sbi),
+        ("tau", layout.tau.offset, read(&dirs::image())),
+    ]);
+
+    let mut differ = vec![];
+    for (name, offset, data) in regions {
+        let Some(data) = data else {
+            println!("{name:<10} skipped, no local artifact");
+            continue;
+        };
+        let end = offset + data.len() as u64;
+        match disk::verify(&mut file, offset, &data) {
+            Ok(()) => println!("{name:<10} {offset:#010x}..{end:#010x} ok"),
+            Err(disk::DiskError::Verify(at)) => {
+                println!("{name:<10} {offset:#010x}..{end:#010x} differs at {at:#x}");
+                differ.push(name);
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    // Doesn't need the local image, the card can be checked against itself.
+    let tau = footer::read_at(&mut file, layout.tau.offset, image_size)?;
+    match footer::check(&tau, image_size) {
+        footer::Check::Ok(f) => println!("{:<10} crc32 {:08x} ok", "footer", f.crc32),
+        footer::Check::Mismatch(f) => {
+            let damaged = f.damaged(&tau);
+            if damaged.is_empty() {
+                println!("{:<10} tau on the disk doesn't match it", "footer");
+            } else {
+                println!(
+                    "{:<10} damaged on the disk: {}",
+                    "footer",
+                    damaged.join(", ")
+                );
+            }
+            differ.push("footer");
+        }
+        footer::Check::Missing => println!("{:<10} none", "footer"),
+    }
+    if !differ.is_empty() {
+        return Err(anyhow::anyhow!("mismatch in {}", differ.join(", ")));
+    }
+
+    Ok(())
+}
+
+/// `signing` is the key directory with the key name and algorithm.
+fn fit(
+    config: &Config,
+    res: &Resources,
+    compose: &ComposeArgs,
+    signing: Option<(&Path, (&str, &str))>,
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
+    let board = &config.board;
+    compose_update(config, compose, summary)?;
+    let dtb = board_dtb(board, res)?;
+    ensure_dtb(&dtb)?;
+    let image = fs::canonicalize(dirs::image())?;
+    let (its, itb) = (dirs::out("tau.its"), dirs::out("tau.itb"));
+    summary.step("fit", |_| {
+        let fit = fit::Fit {
+            board: &board.name,
+            image: &image,
+            dtb: &dtb,
+            load: board.payload_base(),
+            signature: signing.map(|(_, key)| key),
+        };
+        common::write_atomic(&its, fit.its().as_bytes())?;
+        let built = board
+            .uboot
+            .as_ref()
+            .map(|u| u.build_dir().join("tools/mkimage"));
+        fit::mkimage(built, &its, &itb, signing.map(|(dir, _)| dir))?;
+        anyhow::Ok(Outcome::Rebuilt)
+    })?;
+    summary.artifact(&itb);
+
+    Ok(())
+}
+
+fn dist<P>(config: &Config, out: P, release: Option<String>) -> anyhow::Result<()>
+where
+    P: AsRef<Path>,
+{
+    let board = &config.board;
+    let read = |path: &Path, hint: &str| {
+        fs::read(path).map_err(|err| anyhow::anyhow!("{}: {err}, run {hint} first", path.display()))
+    };
+    let spl = read(&board.uboot()?.spl(), "build-firmware")?;
+    let header = board.spl_header.header(&spl, board.sd.spl)?;
+    let mut artifacts = vec![
+        dist::Artifact {
+            name: format!("u-boot-spl-{}", board.name),
+            ext: "img",
+            data: [header, spl].concat(),
+        },
+        dist::Artifact {
+            name: format!(
+                "{}-{}",
+                board.firmware_mode.file().trim_end_matches(".bin"),
+                board.name
+            ),
+            ext: "bin",
+            data: read(&board.opensbi_image(), "build-firmware")?,
+        },
+        dist::Artifact {
+            name: "tau".to_owned(),
+            ext: "bin",
+            data: read(&dirs::image(), "update or build-tau --qemu")?,
+        },
+    ];
+    let qemu = config.qemu.opensbi_firmware("fw_payload.bin");
+    match fs::read(&qemu) {
+        Ok(data) => artifacts.push(dist::Artifact {
+            name: "fw_payload-qemu-virt".to_owned(),
+            ext: "bin",
+            data,
+        }),
+        Err(_) => eprintln!(
+            "warning: {} not found, run build-tau --qemu to include it",
+            qemu.display()
+        ),
+    }
+
+    let release = release
+        .or_else(dist::version)
+        .unwrap_or_else(|| "unversioned".to_owned());
+    for path in dist::write(out, &release, &artifacts)? {
+        println!("{}", path.display());
+    }
+
+    Ok(())
+}
+
+fn clean(config: &Config, artifacts: bool, clones: bool) -> anyhow::Result<()> {
+    let uboot = config.board.uboot.as_ref();
+    let opensbi = [&config.board.opensbi, &config.qemu.opensbi];
+    let mut paths = vec![];
+    if artifacts {
+        paths.extend(uboot.map(board::Uboot::build_dir));
+        paths.extend(
+            [
+                "tau",
+                "tau.tmp",
+                "tau.toml",
+                "tau.its",
+                "tau.itb",
+                "tau-qemu.img",
+                "tau-qemu.img.tmp",
+            ]
+            .map(dirs::out),
+        );
+        paths.push(memory_map::default_dir());
+        // u-boot builds out of tree, OpenSBI inside its clone.
+        for clone in opensbi {
+            paths.push(clone.dir().join("build"));
+            paths.push(clone.dir().join(opensbi::STAMP));
+        }
+    }
+    if clones {
+        for clone in uboot.map(|u| &u.source).into_iter().chain(opensbi) {
+            paths.push(clone.dir());
+            paths.push(dirs::clones().join(format!("{}.tmp", clone.name)));
+        }
+    }
+
+    for path in paths {
+        let res = if path.is_dir() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        };
+        match res {
+            Ok(()) => eprintln!("removed {}", path.display()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(anyhow::anyhow!("{}: {err}", path.display())),
+        }
+    }
+
+    Ok(())
+}
+
+fn qemu_command(board: &Board, machine: &MachineArgs) -> anyhow::Result<Command> {
+    let fw = board.opensbi_firmware("fw_payload.elf");
+    if !fw.exists() {
+        return Err(anyhow::anyhow!(
+            "{} not found, run build-tau --qemu first",
+            fw.display()
+        ));
+    }
+    let qemu =
+        common::find_in_path(QEMU).ok_or_else(|| anyhow::anyhow!("{QEMU} not found in PATH"))?;
+    let mut command = Command::new(qemu);
+    command
+        .args(["-M", "virt", "-nographic", "-bios"])
+        .arg(fw)
+        .arg("-smp")
+        .arg(machine.smp.to_string())
+        .args(["-m", &machine.memory]);
+    for device in &machine.device {
+        command.args(["-device", device]);
+    }
+    if let Some(drive) = &machine.drive {
+        command
+            .arg("-drive")
+            .arg(format!("file={},format=raw,if=virtio", drive.display()));
+    }
+
+    Ok(command)
+}
+
+fn run(board: &Board, machine: &MachineArgs, extra: &[String]) -> anyhow::Result<()> {
+    let mut command = qemu_command(board, machine)?;
+    // QEMU owns the terminal, so it stays in the foreground process group
+    // instead of going through `interrupt::run`.
+    let status = command.args(extra).status()?;
+    if !status.success() {
+        return Err(anyhow::anyhow!("qemu exited with {status}"));
+    }
+
+    Ok(())
+}
+
+fn gen_layout(config: &Config, out_dir: &Path, qemu: bool, c_header: bool) -> anyhow::Result<()> {
+    let board = config.board(qemu);
+    for path in memory_map::generate(&config.layout, board, out_dir, c_header)? {
+        println!("{}", path.display());
+    }
+
+    Ok(())
+}
+
+fn diff_image(
+    config: &Config,
+    a: &Path,
+    b: &Path,
+    elf_a: Option<&Path>,
+    elf_b: Option<&Path>,
+    json: bool,
+) -> anyhow::Result<()> {
+    let image_a = fs::read(a)?;
+    let image_b = fs::read(b)?;
+    let diffs = diff::diff_images(&config.layout, &image_a, &image_b, elf_a, elf_b);
+    diff::print(&diffs, json)?;
+    if diffs.iter().any(|diff| !diff.same) {
+        return Err(anyhow::anyhow!("images differ"));
+    }
+
+    Ok(())
+}
+
+fn diff(config: &Config, a: &Path, b: &Path, json: bool) -> anyhow::Result<()> {
+    let (mut file_a, layout, _) = open_firmware(&config.board, a, false)?;
+    let (mut file_b, _, _) = open_firmware(&config.board, b, false)?;
+    let block_a = disk::block_size(a).or_else(|| disk::gpt_block_size(&mut file_a));
+    let block_b = disk::block_size(b).or_else(|| disk::gpt_block_size(&mut file_b));
+    let block = match (block_a, block_b) {
+        (Some(block_a), Some(block_b)) if block_a != block_b => {
+            return Err(anyhow::anyhow!(
+                "{} has {block_a} byte sectors, {} {block_b} byte ones",
+                a.display(),
+                b.display()
+            ));
+        }
+        (block_a, block_b) => block_a
+            .or(block_b)
+            .unwrap_or(gpt::disk::LogicalBlockSize::Lb512),
+    };
+    let regions = diff::disk_regions(&layout, &config.layout, block);
+    let diffs = diff::diff_disks(&mut file_a, &mut file_b, &regions)?;
+    diff::print_disks(&diffs, json)?;
+    if diffs.iter().any(|diff| !diff.changed.is_empty()) {
+        return Err(anyhow::anyhow!("disks differ"));
+    }
+
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let Args {
+        no_summary,
+        no_wait,
+        dry_run,
+        events,
+        command_timeout,
+        quiet,
+        board_dir,
+        board,
+        firmware_mode,
+        workspace,
+        out_dir,
+        command,
+    } = Args::parse();
+    disk::set_quiet(quiet);
+    let dry_runs = matches!(
+        command,
+        ArgsCommand::BuildTau { .. } | ArgsCommand::Update { .. } | ArgsCommand::Format { .. }
+    );
+    if dry_run && !dry_runs {
+        eprintln!("--dry-run is for build-tau, update and format");
+        return ExitCode::FAILURE;
+    }
+    if let Some(secs) = command_timeout {
+        interrupt::set_timeout(Duration::from_secs(secs));
+    }
+    if let Some(workspace) = workspace
+        && let Err(err) = std::env::set_current_dir(&workspace)
+    {
+        eprintln!("workspace {}: {err}", workspace.display());
+        return ExitCode::FAILURE;
+    }
+    if let Some(out_dir) = out_dir {
+        dirs::set_out_dir(out_dir);
+    }
+    let res = match Resources::new(board_dir, &board) {
+        Ok(res) => res,
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Err(err) = interrupt::install() {
+        eprintln!("failed to install the Ctrl-C handler: {err}");
+    }
+    let _lock = if command.needs_lock() {
+        match lock::Lock::acquire(dirs::out(".lock"), !no_wait) {
+            Ok(lock) => Some(lock),
+            Err(err) => {
+                eprintln!("lock: {err}");
+                return ExitCode::FAILURE;
+            }
+        }
+    } else {
+        None
+    };
+    let mut config = match Config::load(&board) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Some(mode) = firmware_mode {
+        config.board.firmware_mode = mode;
+    }
+    config.dry_run = dry_run;
+    let mut summary = Summary::default();
+    if let Some(path) = events {
+        let file = fs::OpenOptions::new().create(true).append(true).open(&path);
+        match file {
+            Ok(file) => summary.events(file),
+            Err(err) => {
+                eprintln!("events {}: {err}", path.display());
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    let res = match command {
+        ArgsCommand::BuildFirmware {
+            force_rebuild,
+            uboot_proper,
+            sources,
+            overlay,
+            opensbi,
+            plan,
+        } => {
+            let mut board = config.board.clone();
+            sources.apply(&mut board).and_then(|()| {
+                for path in overlay {
+                    let path = fs::canonicalize(&path)
+                        .map_err(|err| anyhow::anyhow!("{}: {err}", path.display()))?;
+                    board.overlays.push(path.display().to_string());
+                }
+                build_firmware(
+                    force_rebuild,
+                    uboot_proper,
+                    &board,
+                    &opensbi.options(&config, &board),
+                    &plan,
+                    &res,
+                    &mut summary,
+                )
+            })
+        }
+        ArgsCommand::Format {
+            path,
+            size,
+            sizes,
+            opts,
+            env,
+        } => format(&config, path, size, &sizes, &opts, &env, &mut summary),
+        ArgsCommand::BuildTau {
+            qemu,
+            opensbi,
+            build,
+            plan,
+            check_reproducible,
+        } => build_tau(
+            &config,
+            &qemu,
+            &build,
+            &opensbi.options(&config, &config.qemu),
+            &plan,
+            &res,
+            &mut summary,
+        )
+        .and_then(|()| {
+            if !check_reproducible || plan.list_steps {
+                return Ok(());
+            }
+            let board = config.board(qemu.qemu);
+            reproducible(&config.layout, board, &build, &mut summary)
+        }),
+        ArgsCommand::Update {
+            usb: Some(mode),
+            usb_timeout,
+            compose,
+            write,
+            ..
+        } => update_usb(
+            &config,
+            mode,
+            Duration::from_secs(usb_timeout),
+            &compose,
+            &write,
+            &mut summary,
+        ),
+        ArgsCommand::Update {
+            path,
+            usb: None,
+            compose,
+            write,
+            ..
+        } => update(
+            &config,
+            path.expect("clap requires either --path or --usb"),
+            &compose,
+            &write,
+            &mut summary,
+        ),
+        ArgsCommand::Run { machine, qemu_args } => run(&config.qemu, &machine, &qemu_args),
+        ArgsCommand::TestBoot {
+            machine,
+            success,
+            failure,
+            timeout,
+        } => test_boot(
+            &config.qemu,
+            &machine,
+            &success,
+            failure,
+            Duration::from_secs(timeout),
+        ),
+        ArgsCommand::Verify { path } => verify(&config.board, image_size(&config), path),
+        ArgsCommand::Inspect {
+            path,
+            any_format,
+            image,
+        } => inspect(&config.board, image_size(&config), path, any_format, image),
+        ArgsCommand::Flash {
+            image,
+            path,
+            force,
+            io: IoArgs { io },
+        } => flash(&config.board, image, path, force, io, &mut summary),
+        ArgsCommand::Expand {
+            path,
+            confirm: ConfirmArgs { yes },
+            io: IoArgs { io },
+        } => expand(&config.board, path, yes, io, &mut summary),
+        ArgsCommand::Extract {
+            path,
+            out,
+            any_format,
+        } => extract(&config.board, path, out, any_format),
+        ArgsCommand::InspectDtb { path } => inspect_dtb(&config.board, &res, path),
+        ArgsCommand::Compose {
+            elf,
+            bin,
+            input,
+            out,
+            size,
+            footer,
+            dump_layout,
+        } => {
+            let elf = elf
+                .into_iter()
+                .map(|(p, o)| (layout::ComponentKind::Elf, p, o));
+            let bin = bin
+                .into_iter()
+                .map(|(p, o)| (layout::ComponentKind::Raw, p, o));
+            let input = input
+                .into_iter()
+                .map(|(p, o)| (layout::ComponentKind::Auto, p, o));
+            let inputs = elf.chain(bin).chain(input).collect();
+            let size = size.map(|size| size as usize);
+            compose(&config.board, inputs, out, size, footer, dump_layout)
+        }
+        ArgsCommand::SplHeader {
+            input,
+            output,
+            format,
+            backup_offset,
+            version,
+            strip,
+        } => {
+            // The board's overrides only go with the board's format.
+            let header = &config.board.spl_header;
+            let (format, backup_offset, version) = match format {
+                Some(format) => (format, backup_offset, version),
+                None => (
+                    header.format,
+                    backup_offset.or(header.backup_offset),
+                    version.or(header.version),
+                ),
+            };
+            spl_header_command(input, output, format, backup_offset, version, strip)
+        }
+        ArgsCommand::Disasm { path, components } => disasm(&config, path, &components),
+        ArgsCommand::Dist { out, release } => dist(&config, out, release),
+        ArgsCommand::Fit {
+            compose,
+            key_dir,
+            key_name,
+            key_algo,
+        } => {
+            let key = key_name.as_deref().map(|name| (name, key_algo.as_str()));
+            let signing = key_dir.as_deref().zip(key);
+            fit(&config, &res, &compose, signing, &mut summary)
+        }
+        ArgsCommand::Serial { device, baud, log } => {
+            let log = log.unwrap_or_else(serial::default_log);
+            serial::monitor(device, baud, log).map_err(Into::into)
+        }
+        ArgsCommand::Watch {
+            dirs,
+            interval,
+            qemu,
+            build,
+        } => watch(&config, dirs, Duration::from_millis(interval), qemu, &build),
+        ArgsCommand::Env { command } => env(command),
+        ArgsCommand::Size => size(&config),
+        ArgsCommand::Recover {
+            device,
+            baud,
+            file,
+            wait,
+        } => recover(&config.board, device, baud, file, Duration::from_secs(wait)),
+        ArgsCommand::Rollback {
+            path,
+            to,
+            io: IoArgs { io },
+        } => rollback(&config.board, path, to.as_deref(), io, &mut summary),
+        ArgsCommand::ListBackups => backup::list()
+            .map(|backups| backup::print(&backups))
+            .map_err(Into::into),
+        ArgsCommand::Status => {
+            status(&config, &res);
+            Ok(())
+        }
+        ArgsCommand::Doctor => match doctor::check() {
+            0 => Ok(()),
+            n => Err(anyhow::anyhow!("{n} problem(s) found")),
+        },
+        ArgsCommand::Clean {
+            artifacts,
+            clones,
+            all,
+        } => clean(&config, artifacts || all, clones || all),
+        ArgsCommand::GenLayout {
+            dir,
+            qemu,
+            c_header,
+        } => gen_layout(
+            &config,
+            &dir.unwrap_or_else(memory_map::default_dir),
+            qemu,
+            c_header,
+        ),
+        ArgsCommand::History { command } => history(&config.board, command),
+        ArgsCommand::DiffImage {
+            a,
+            b,
+            elf_a,
+            elf_b,
+            json,
+        } => diff_image(&config, &a, &b, elf_a.as_deref(), elf_b.as_deref(), json),
+        ArgsCommand::Diff { a, b, json } => diff(&config, &a, &b, json),
+    };
+    if !no_summary {
+        summary.print();
+    }
+    if let Err(err) = res {
+        eprintln!("{err}");
+        return ExitCode::FAILURE;
+    }
+
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs,
+        path::{Path, PathBuf},
+        process::Command,
+    };
+
+    use clap::Parser;
+
+    use super::{Args, ArgsCommand, Config, Summary, board, dirs, format, testing::TempDir};
+
+    /// Where `format_image` works, set only in the child process
+    /// `format_twice_keeps_disk_guid` runs with an output directory and a
+    /// history of its own.
+    const CHILD_DIR: &str = "TAU_BUILDER_TEST_FORMAT";
+
+    #[test]
+    fn format_twice_keeps_disk_guid() {
+        let tmp = TempDir::new("format-twice");
+        let dir = tmp.path();
+        let out = Command::new(env::current_exe().unwrap())
+            .args(["--exact", "tests::format_image", "--include-ignored"])
+            .env(CHILD_DIR, dir)
+            .env("XDG_CACHE_HOME", dir.join("cache"))
+            .env("XDG_DATA_HOME", dir.join("data"))
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&out.stdout);
+        assert!(out.status.success(), "{stdout}");
+        assert!(stdout.contains("1 passed"), "{stdout}");
+    }
+
+    fn disk_guid(path: &Path) -> uuid::Uuid {
+        let disk = gpt::GptConfig::new().writable(false).open(path).unwrap();
+        *disk.guid()
+    }
+
+    #[test]
+    #[ignore = "run by format_twice_keeps_disk_guid"]
+    fn format_image() {
+        let Some(dir) = env::var_os(CHILD_DIR).map(PathBuf::from) else {
+            return;
+        };
+        dirs::set_out_dir(dir.join("out"));
+        let config = Config {
+            board: board::visionfive2(),
+            ..Config::default()
+        };
+        let firmware = [
+            (config.board.uboot().unwrap().spl(), [0x13; 0x1000]),
+            (config.board.opensbi_image(), [0x73; 0x1000]),
+        ];
+        for (path, data) in firmware {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, data).unwrap();
+        }
+        let image = dir.join("disk.img");
+        let format_with = |extra: &[&str]| {
+            let image = image.to_str().unwrap();
+            let args = ["tau-builder", "format", "--path", image, "--size", "64M"];
+            let args = Args::parse_from(args.iter().chain(extra));
+            let ArgsCommand::Format {
+                path,
+                size,
+                sizes,
+                opts,
+                env,
+            } = args.command
+            else {
+                unreachable!("parsed a format command");
+            };
+            let summary = &mut Summary::default();
+            format(&config, path, size, &sizes, &opts, &env, summary).unwrap();
+            disk_guid(Path::new(image))
+        };
+
+        let first = format_with(&[]);
+        assert_eq!(format_with(&[]), first);
+        assert_eq!(format_with(&["--keep-partitions"]), first);
+        assert_ne!(format_with(&["--reinit"]), first);
     }
 }
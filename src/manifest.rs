@@ -0,0 +1,104 @@
+use std::{fs, io};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{board::Usage, dirs, layout::Layout, opensbi};
+
+#[derive(Debug, Error)]
+pub enum ManifestError {
+    #[error("read the build manifest: {0}")]
+    Read(#[from] io::Error),
+    #[error("parse the build manifest: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("write the build manifest: {0}")]
+    Write(#[from] toml::ser::Error),
+}
+
+/// What `tau.toml` in the output directory holds: the layout the image was
+/// composed with, and what the build found out about it, for CI to keep.
+#[derive(Deserialize, Serialize)]
+pub struct Manifest {
+    #[serde(flatten)]
+    pub layout: Layout,
+    /// How full the disk regions are with the firmware built so far.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub usage: Vec<RegionUsage>,
+    /// What OpenSBI was built with, to trace a flashed board back to it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub opensbi: Option<opensbi::Build>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct RegionUsage {
+    pub region: String,
+    pub used: u64,
+    pub size: u64,
+    pub percent: f64,
+}
+
+impl From<&Usage> for RegionUsage {
+    fn from(usage: &Usage) -> Self {
+        RegionUsage {
+            region: usage.name.to_owned(),
+            used: usage.used,
+            size: usage.size,
+            // One decimal is enough for a chart and keeps the file readable.
+            percent: (usage.percent() * 10.0).round() / 10.0,
+        }
+    }
+}
+
+impl Manifest {
+    /// The manifest of the last compose, if there is one.
+    pub fn load() -> Result<Option<Self>, ManifestError> {
+        match fs::read_to_string(dirs::manifest()) {
+            Ok(text) => Ok(Some(toml::from_str(&text)?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn to_toml(&self) -> Result<String, ManifestError> {
+        Ok(toml::to_string(self)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Manifest, RegionUsage};
+    use crate::{board, layout::Layout, opensbi};
+
+    #[test]
+    fn round_trip() {
+        let usage = board::visionfive2().sd.usage(0x1a000, 0x180000, 0x41000);
+        let manifest = Manifest {
+            layout: Layout::default(),
+            usage: usage.iter().map(RegionUsage::from).collect(),
+            opensbi: Some(opensbi::Build {
+                toolchain: "gnu, found riscv64-linux-gnu-gcc".to_owned(),
+                variables: vec![
+                    "CROSS_COMPILE=riscv64-linux-gnu-".to_owned(),
+                    "PLATFORM=generic".to_owned(),
+                    "FW_OPTIONS=0x2".to_owned(),
+                ],
+            }),
+        };
+        let text = manifest.to_toml().unwrap();
+        let read = toml::from_str::<Manifest>(&text).unwrap();
+        assert_eq!(
+            read.layout.to_toml().unwrap(),
+            Layout::default().to_toml().unwrap()
+        );
+        let regions = read.usage.iter().map(|u| &*u.region).collect::<Vec<_>>();
+        assert_eq!(regions, ["spl", "opensbi", "tau"]);
+        assert_eq!(read.usage[1].used, 0x180000);
+        assert_eq!(read.usage[1].percent, 37.5);
+        let opensbi = read.opensbi.unwrap();
+        assert_eq!(opensbi.variables[1], "PLATFORM=generic");
+
+        // The manifest of an older build has the layout only.
+        let old = toml::from_str::<Manifest>(&Layout::default().to_toml().unwrap()).unwrap();
+        assert!(old.usage.is_empty());
+    }
+}
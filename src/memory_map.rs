@@ -0,0 +1,203 @@
+use std::{
+    fmt::Write as _,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{board::Board, common, dirs, footer, layout::Layout};
+
+/// Where `build-tau` puts the generated files before building the firmware.
+pub fn default_dir() -> PathBuf {
+    dirs::out("layout")
+}
+
+/// `loader` -> `LOADER`, `my-blob` -> `MY_BLOB`.
+fn ident(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Every constant as name and value, in the order of the layout.
+fn items(layout: &Layout, board: &Board) -> Vec<(String, u64)> {
+    let base = board.payload_base();
+    let mut items = vec![
+        ("FW_TEXT_START".to_owned(), board.fw_text_start),
+        ("TAU_IMAGE_ADDR".to_owned(), base),
+        ("TAU_IMAGE_SIZE".to_owned(), layout.size as u64),
+        ("TAU_FOOTER_OFFSET".to_owned(), layout.size as u64),
+        ("TAU_FOOTER_SIZE".to_owned(), footer::SIZE as u64),
+    ];
+    if let Some(grow_to) = layout.grow_to {
+        items.push(("TAU_IMAGE_MAX_SIZE".to_owned(), grow_to as u64));
+    }
+    for c in &layout.components {
+        let name = ident(&c.name);
+        items.push((format!("TAU_{name}_OFFSET"), c.offset as u64));
+        items.push((format!("TAU_{name}_SIZE"), c.max_size as u64));
+        items.push((format!("TAU_{name}_ADDR"), base + c.offset as u64));
+    }
+    if let Some(m) = layout.metadata {
+        items.push(("TAU_METADATA_OFFSET".to_owned(), m.offset as u64));
+        items.push(("TAU_METADATA_SIZE".to_owned(), m.max_size as u64));
+    }
+    items
+}
+
+fn header(board: &Board, comment: &str) -> String {
+    format!(
+        "{comment} Generated by tau-builder gen-layout for {}, do not edit.\n",
+        board.name
+    )
+}
+
+/// `MEMORY` regions and symbols for a linker script, to be pulled in with
+/// `INCLUDE`.
+pub fn ld(layout: &Layout, board: &Board) -> String {
+    let mut out = header(board, "/*");
+    out.pop();
+    out.push_str(" */\n\nMEMORY\n{\n");
+    let base = board.payload_base();
+    for c in &layout.components {
+        let _ = writeln!(
+            out,
+            "    {} (rwx) : ORIGIN = {:#x}, LENGTH = {:#x}",
+            c.name.replace(|c: char| !c.is_ascii_alphanumeric(), "_"),
+            base + c.offset as u64,
+            c.max_size
+        );
+    }
+    out.push_str("}\n\n");
+    for (name, value) in items(layout, board) {
+        let _ = writeln!(out, "{name} = {value:#x};");
+    }
+    out
+}
+
+/// `pub const` items, to be pulled in with `include!`.
+pub fn rust(layout: &Layout, board: &Board) -> String {
+    let mut out = header(board, "//");
+    out.push('\n');
+    for (name, value) in items(layout, board) {
+        let _ = writeln!(out, "pub const {name}: usize = {value:#x};");
+    }
+    out
+}
+
+pub fn c(layout: &Layout, board: &Board) -> String {
+    let mut out = header(board, "/*");
+    out.pop();
+    out.push_str(" */\n\n#pragma once\n\n");
+    for (name, value) in items(layout, board) {
+        let _ = writeln!(out, "#define {name} {value:#x}UL");
+    }
+    out
+}
+
+/// Writes `layout.ld`, `layout.rs` and optionally `layout.h` into `dir`.
+/// Files whose content didn't change are left alone so cargo doesn't rebuild
+/// the firmware for nothing.
+pub fn generate<P>(
+    layout: &Layout,
+    board: &Board,
+    dir: P,
+    c_header: bool,
+) -> io::Result<Vec<PathBuf>>
+where
+    P: AsRef<Path>,
+{
+    let dir = dir.as_ref();
+    fs::create_dir_all(dir)?;
+    let mut files = vec![
+        ("layout.ld", ld(layout, board)),
+        ("layout.rs", rust(layout, board)),
+    ];
+    if c_header {
+        files.push(("layout.h", c(layout, board)));
+    }
+    let mut written = vec![];
+    for (name, content) in files {
+        let path = dir.join(name);
+        if fs::read(&path).ok().as_deref() != Some(content.as_bytes()) {
+            common::write_atomic(&path, content.as_bytes())?;
+        }
+        written.push(path);
+    }
+
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{board, layout::Layout, testing::TempDir};
+
+    const RUST: &str = "\
+// Generated by tau-builder gen-layout for visionfive2, do not edit.
+
+pub const FW_TEXT_START: usize = 0x40000000;
+pub const TAU_IMAGE_ADDR: usize = 0x40200000;
+pub const TAU_IMAGE_SIZE: usize = 0x40000;
+pub const TAU_FOOTER_OFFSET: usize = 0x40000;
+pub const TAU_FOOTER_SIZE: usize = 0x38;
+pub const TAU_LOADER_OFFSET: usize = 0x0;
+pub const TAU_LOADER_SIZE: usize = 0x5000;
+pub const TAU_LOADER_ADDR: usize = 0x40200000;
+pub const TAU_SUPERVISOR_OFFSET: usize = 0x5000;
+pub const TAU_SUPERVISOR_SIZE: usize = 0xb000;
+pub const TAU_SUPERVISOR_ADDR: usize = 0x40205000;
+pub const TAU_SYSTEM_OFFSET: usize = 0x10000;
+pub const TAU_SYSTEM_SIZE: usize = 0x30000;
+pub const TAU_SYSTEM_ADDR: usize = 0x40210000;
+";
+
+    const LD: &str = "\
+/* Generated by tau-builder gen-layout for visionfive2, do not edit. */
+
+MEMORY
+{
+    loader (rwx) : ORIGIN = 0x40200000, LENGTH = 0x5000
+    supervisor (rwx) : ORIGIN = 0x40205000, LENGTH = 0xb000
+    system (rwx) : ORIGIN = 0x40210000, LENGTH = 0x30000
+}
+
+FW_TEXT_START = 0x40000000;
+TAU_IMAGE_ADDR = 0x40200000;
+TAU_IMAGE_SIZE = 0x40000;
+TAU_FOOTER_OFFSET = 0x40000;
+TAU_FOOTER_SIZE = 0x38;
+TAU_LOADER_OFFSET = 0x0;
+TAU_LOADER_SIZE = 0x5000;
+TAU_LOADER_ADDR = 0x40200000;
+TAU_SUPERVISOR_OFFSET = 0x5000;
+TAU_SUPERVISOR_SIZE = 0xb000;
+TAU_SUPERVISOR_ADDR = 0x40205000;
+TAU_SYSTEM_OFFSET = 0x10000;
+TAU_SYSTEM_SIZE = 0x30000;
+TAU_SYSTEM_ADDR = 0x40210000;
+";
+
+    #[test]
+    fn default_layout() {
+        let (layout, board) = (Layout::default(), board::visionfive2());
+        assert_eq!(super::rust(&layout, &board), RUST);
+        assert_eq!(super::ld(&layout, &board), LD);
+
+        let tmp = TempDir::new("gen-layout");
+        let dir = tmp.path();
+        let files = super::generate(&layout, &board, dir, true).unwrap();
+        let names = files
+            .iter()
+            .map(|f| f.file_name().unwrap().to_str().unwrap());
+        assert!(names.eq(["layout.ld", "layout.rs", "layout.h"]));
+        assert_eq!(fs::read_to_string(dir.join("layout.rs")).unwrap(), RUST);
+        assert_eq!(fs::read_to_string(dir.join("layout.ld")).unwrap(), LD);
+    }
+}
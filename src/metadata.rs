@@ -0,0 +1,111 @@
+//! The build metadata block compose writes into the image, so what a board
+//! runs can be told from the card alone.
+
+use std::{path::Path, process::Command};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    history::{self, ComponentHash},
+    status,
+};
+
+const MAGIC: &[u8; 8] = b"TAU-META";
+
+/// Magic, length and CRC-32 of the JSON that follows.
+const HEADER: usize = 16;
+
+#[derive(Serialize, Deserialize)]
+pub struct BuildInfo {
+    /// Of the builder.
+    pub version: String,
+    /// Of the tau checkout.
+    pub commit: Option<String>,
+    /// The checkout had uncommitted changes.
+    pub dirty: bool,
+    /// Seconds since the Unix epoch, see `source_date_epoch`.
+    pub time: u64,
+    pub board: String,
+    pub components: Vec<ComponentHash>,
+}
+
+fn dirty() -> bool {
+    Command::new("git")
+        .args(["status", "--porcelain", "--untracked-files=no"])
+        .output()
+        .is_ok_and(|out| out.status.success() && !out.stdout.is_empty())
+}
+
+/// `SOURCE_DATE_EPOCH` if set, otherwise the time of the commit checked out
+/// in `dir`, so that building the same commit twice gives the same output.
+/// The current time only outside of a git repository.
+pub fn source_date_epoch(dir: &Path) -> u64 {
+    std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .or_else(|| status::head_time(dir))
+        .unwrap_or_else(history::now)
+}
+
+impl BuildInfo {
+    /// For the tau checkout in the current directory.
+    pub fn new(board: &str, components: Vec<ComponentHash>) -> Self {
+        let commit = status::head(Path::new("."));
+        BuildInfo {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            dirty: commit.is_some() && dirty(),
+            commit,
+            time: source_date_epoch(Path::new(".")),
+            board: board.to_owned(),
+            components,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let json = serde_json::to_vec(self).expect("serializes");
+        let mut b = Vec::with_capacity(HEADER + json.len());
+        b.extend_from_slice(MAGIC);
+        b.extend_from_slice(&(json.len() as u32).to_le_bytes());
+        b.extend_from_slice(&history::crc32(&json).to_le_bytes());
+        b.extend_from_slice(&json);
+        b
+    }
+
+    /// The block at the start of `b`, if there is an intact one.
+    pub fn parse(b: &[u8]) -> Option<Self> {
+        if b.get(..8)? != MAGIC {
+            return None;
+        }
+        let len = u32::from_le_bytes(b.get(8..12)?.try_into().ok()?) as usize;
+        let crc = u32::from_le_bytes(b.get(12..16)?.try_into().ok()?);
+        let json = b.get(HEADER..HEADER + len)?;
+        if history::crc32(json) != crc {
+            return None;
+        }
+        serde_json::from_slice(json).ok()
+    }
+
+    /// Looks for the block anywhere in `data`, a composed image or the tau
+    /// region of a disk, and returns its offset.
+    pub fn find(data: &[u8]) -> Option<(usize, Self)> {
+        data.windows(MAGIC.len())
+            .enumerate()
+            .filter(|(_, w)| w == MAGIC)
+            .find_map(|(at, _)| Some((at, Self::parse(&data[at..])?)))
+    }
+
+    pub fn print(&self) {
+        let commit = self.commit.as_deref().unwrap_or("unknown");
+        let dirty = if self.dirty { " (dirty)" } else { "" };
+        println!("  commit {commit}{dirty}");
+        println!(
+            "  built {} for {}",
+            history::timestamp(self.time),
+            self.board
+        );
+        println!("  builder {}", self.version);
+        for c in &self.components {
+            println!("  {:<12} crc32 {}", c.name, c.crc32);
+        }
+    }
+}
@@ -0,0 +1,237 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{common, interrupt};
+
+/// Variables the builder relies on, which `--opensbi-opt` may only override
+/// together with `--opensbi-opt-unsafe`.
+const PROTECTED: [&str; 3] = ["PLATFORM", "FW_FDT_PATH", "FW_TEXT_START"];
+
+pub const STAMP: &str = ".tau-builder-stamp";
+
+/// What an OpenSBI tree was last built with, as its stamp records it.
+#[derive(Deserialize, Serialize)]
+pub struct Build {
+    /// The toolchain and why it was picked.
+    pub toolchain: String,
+    /// The make variables in order, the builder's own among them.
+    pub variables: Vec<String>,
+}
+
+impl Build {
+    fn stamp(&self) -> String {
+        let mut stamp = format!("toolchain: {}", self.toolchain);
+        for var in &self.variables {
+            stamp += "\n";
+            stamp += var;
+        }
+        stamp
+    }
+
+    /// The last build in the tree `dir`, if it has one.
+    pub fn last<P>(dir: P) -> Option<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let stamp = fs::read_to_string(dir.as_ref().join(STAMP)).ok()?;
+        let mut lines = stamp.lines();
+        let toolchain = lines.next()?.strip_prefix("toolchain: ")?.to_owned();
+        Some(Build {
+            toolchain,
+            variables: lines.map(str::to_owned).collect(),
+        })
+    }
+}
+
+pub fn parse_opt(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_owned(), value.to_owned())),
+        _ => Err(format!("expected KEY=VALUE, got {s:?}")),
+    }
+}
+
+pub const LLVM: [&str; 2] = ["clang", "ld.lld"];
+
+pub const GNU_PREFIXES: [&str; 4] = [
+    "riscv64-unknown-linux-gnu-",
+    "riscv64-linux-gnu-",
+    "riscv64-unknown-elf-",
+    "riscv64-elf-",
+];
+
+#[derive(Debug, Error)]
+#[error("no usable {0} toolchain, probed {1}")]
+pub struct ToolchainError(&'static str, String);
+
+#[derive(Clone, Copy, Default, clap::ValueEnum)]
+pub enum Toolchain {
+    Llvm,
+    Gnu,
+    /// LLVM if clang and ld.lld are found, GNU otherwise.
+    #[default]
+    Auto,
+}
+
+/// The toolchain chosen for a build and the make variables selecting it.
+pub struct Selected {
+    pub vars: Vec<String>,
+    pub reason: String,
+}
+
+impl Toolchain {
+    pub fn select(self) -> Result<Selected, ToolchainError> {
+        let llvm = || {
+            let found = LLVM.iter().all(|name| common::find_in_path(name).is_some());
+            found.then(|| Selected {
+                vars: vec!["CC=clang".into(), "LD=ld.lld".into(), "LLVM=1".into()],
+                reason: format!("llvm, found {}", LLVM.join(" and ")),
+            })
+        };
+        let gnu = || {
+            GNU_PREFIXES
+                .iter()
+                .find(|prefix| common::find_in_path(&format!("{prefix}gcc")).is_some())
+                .map(|prefix| Selected {
+                    vars: vec![format!("CROSS_COMPILE={prefix}")],
+                    reason: format!("gnu, found {prefix}gcc"),
+                })
+        };
+        let gnu_probed = || GNU_PREFIXES.map(|prefix| format!("{prefix}gcc")).join(", ");
+        match self {
+            Toolchain::Llvm => llvm().ok_or_else(|| ToolchainError("llvm", LLVM.join(", "))),
+            Toolchain::Gnu => gnu().ok_or_else(|| ToolchainError("gnu", gnu_probed())),
+            Toolchain::Auto => llvm()
+                .or_else(|| {
+                    let mut selected = gnu()?;
+                    selected.reason += "; clang or ld.lld is missing";
+                    Some(selected)
+                })
+                .ok_or_else(|| {
+                    let probed = format!("{}, {}", LLVM.join(", "), gnu_probed());
+                    ToolchainError("OpenSBI", probed)
+                }),
+        }
+    }
+}
+
+/// Extra make variables appended after the builder's own.
+#[derive(Default)]
+pub struct Options {
+    pub extra: BTreeMap<String, String>,
+    pub allow_unsafe: bool,
+    pub toolchain: Toolchain,
+}
+
+impl Options {
+    /// The variables of the board, then of the config file, then of the
+    /// command line.
+    pub fn new(
+        board: &BTreeMap<String, String>,
+        config: &BTreeMap<String, String>,
+        cli: &[(String, String)],
+        allow_unsafe: bool,
+        toolchain: Toolchain,
+    ) -> Self {
+        let mut extra = board.clone();
+        extra.extend(config.clone());
+        extra.extend(cli.iter().cloned());
+        Options {
+            extra,
+            allow_unsafe,
+            toolchain,
+        }
+    }
+}
+
+/// Runs make in the OpenSBI tree `dir` with the toolchain variables, `args`
+/// and the extra variables. They are all recorded in a stamp file, along
+/// with the toolchain, for the build manifest, and the tree is cleaned first
+/// whenever they change, since make itself doesn't notice that.
+pub fn make<P>(dir: P, args: &[String], opts: &Options, what: &str) -> anyhow::Result<()>
+where
+    P: AsRef<Path>,
+{
+    let dir = dir.as_ref();
+    let protected = opts
+        .extra
+        .keys()
+        .find(|key| PROTECTED.contains(&key.as_str()));
+    if let (false, Some(key)) = (opts.allow_unsafe, protected) {
+        return Err(anyhow::anyhow!(
+            "{key} is set by the builder, pass --opensbi-opt-unsafe to override it"
+        ));
+    }
+    let toolchain = opts.toolchain.select()?;
+    eprintln!("opensbi toolchain: {}", toolchain.reason);
+    let args = toolchain
+        .vars
+        .into_iter()
+        .chain(args.iter().cloned())
+        .chain(
+            opts.extra
+                .iter()
+                .map(|(key, value)| format!("{key}={value}")),
+        )
+        .collect::<Vec<_>>();
+    let build = Build {
+        toolchain: toolchain.reason,
+        variables: args,
+    };
+    let args = &build.variables;
+
+    let stamp = build.stamp();
+    let stamp_path = dir.join(STAMP);
+    if fs::read_to_string(&stamp_path).ok().as_deref() != Some(&stamp) {
+        let out = interrupt::run(
+            Command::new("make")
+                .current_dir(dir)
+                .arg("clean")
+                .stdout(Stdio::inherit())
+                .stderr(Stdio::inherit()),
+        )?;
+        common::bail(&out, || anyhow::anyhow!("clean before {what}"))?;
+        fs::remove_file(&stamp_path).unwrap_or_default();
+    }
+
+    let out = interrupt::run(
+        Command::new("make")
+            .current_dir(dir)
+            .args(args)
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit()),
+    )?;
+    common::bail(&out, || anyhow::anyhow!("{what}"))?;
+    fs::write(stamp_path, stamp)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{Build, STAMP};
+    use crate::testing::TempDir;
+
+    #[test]
+    fn stamp_round_trip() {
+        let tmp = TempDir::new("stamp");
+        let dir = tmp.path();
+        assert!(Build::last(dir).is_none());
+        let build = Build {
+            toolchain: "llvm, found clang and ld.lld".to_owned(),
+            variables: vec!["CC=clang".to_owned(), "PLATFORM=generic".to_owned()],
+        };
+        fs::write(dir.join(STAMP), build.stamp()).unwrap();
+        let last = Build::last(dir).unwrap();
+        assert_eq!(last.toolchain, build.toolchain);
+        assert_eq!(last.variables, build.variables);
+    }
+}
@@ -0,0 +1,285 @@
+//! The GPT `format` lays down. `[[partitions]]` in `tau-builder.toml`, or
+//! else in the board, describes it, by default it is the two firmware
+//! partitions and the `[data]` one. Partitions are numbered in the order
+//! of the table, the `gpt` crate leaves no holes between the entries.
+//!
+//! `start` and `size` are numbers or expressions of terms joined by ` + `
+//! and ` - `. A term is a size, `4MiB` or `0x1000`, or `NAME.start`,
+//! `NAME.end` or `NAME.size` of a region of the disk layout (`spl`,
+//! `opensbi`, `tau`, `id`, `recovery`), of a partition before it, of
+//! `firmware`, all of the regions, or of `disk`, the space between the two
+//! GPTs.
+
+use gpt::disk::LogicalBlockSize;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::{
+    board::{DiskLayout, Gpt, Region},
+    common, disk,
+    disk::PartitionSpec,
+};
+
+#[derive(Debug, Error)]
+pub enum PartitionError {
+    #[error("partition {name}: {expr:?}: {reason}")]
+    Expr {
+        name: String,
+        expr: String,
+        reason: String,
+    },
+    #[error("partition {name} at {offset:#x} of {size:#x} bytes isn't made of whole sectors")]
+    Sectors {
+        name: String,
+        offset: u64,
+        size: u64,
+    },
+    #[error("partitions {0} and {1} overlap")]
+    Overlap(String, String),
+    #[error("partition {name} ends at {end:#x}, after the end of the disk at {disk:#x}")]
+    TooBig { name: String, end: u64, disk: u64 },
+}
+
+/// What `format` writes the table as.
+#[derive(Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Table {
+    #[default]
+    Gpt,
+    /// A GPT, with its first three partitions in the MBR as well for
+    /// readers of MBRs only.
+    Hybrid,
+    /// Primary partitions of a plain MBR, for firmware that doesn't read a
+    /// GPT. Four at most, below 2 TiB.
+    Mbr,
+}
+
+/// A number, or an expression for one.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Expr {
+    Number(u64),
+    Expr(String),
+}
+
+/// One of `[[partitions]]`.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Entry {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ty: uuid::Uuid,
+    pub start: Expr,
+    pub size: Expr,
+    /// Rounds `start` up to a multiple of this.
+    pub align: Option<u64>,
+    /// The partition type in an MBR, by default the one of `type`.
+    pub mbr_type: Option<u8>,
+}
+
+/// The MBR partition type for the GPT one `ty`, non-filesystem data for
+/// anything unknown, the firmware included.
+pub fn mbr_type(ty: uuid::Uuid) -> u8 {
+    use gpt::partition_types::{BASIC, EFI, LINUX_FS, LINUX_SWAP};
+
+    [
+        (EFI, 0xef),
+        (LINUX_FS, 0x83),
+        (LINUX_SWAP, 0x82),
+        (BASIC, 0x0c),
+    ]
+    .into_iter()
+    .find(|(known, _)| known.guid == ty)
+    .map_or(0xda, |(_, mbr)| mbr)
+}
+
+/// Type of the data partition, unless `[data]` sets another.
+pub const DATA_PARTITION_TYPE: uuid::Uuid = uuid::uuid!("12994EAA-2996-437A-84B8-BEB34F4A2C81");
+
+/// `[data]` in `tau-builder.toml`, a partition after the firmware regions
+/// for tau to keep its state in, added to the default table.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DataPartition {
+    pub size: u64,
+    #[serde(default = "data_name")]
+    pub name: String,
+    #[serde(rename = "type", default = "data_type")]
+    pub ty: uuid::Uuid,
+    /// The partition starts at the first multiple of this after the last
+    /// firmware region.
+    #[serde(default = "data_align")]
+    pub align: u64,
+}
+
+fn data_name() -> String {
+    "tau-data".to_owned()
+}
+
+fn data_type() -> uuid::Uuid {
+    DATA_PARTITION_TYPE
+}
+
+fn data_align() -> u64 {
+    0x100000
+}
+
+/// The firmware partitions over the SPL and OpenSBI regions and the data
+/// partition.
+pub fn default_table(firmware: &Gpt, data: Option<&DataPartition>) -> Vec<Entry> {
+    let region = |part: &crate::board::Partition, region: &str| Entry {
+        name: part.name.clone(),
+        ty: part.ty,
+        start: Expr::Expr(format!("{region}.start")),
+        size: Expr::Expr(format!("{region}.size")),
+        align: None,
+        mbr_type: None,
+    };
+    let mut table = vec![
+        region(&firmware.spl, "spl"),
+        region(&firmware.opensbi, "opensbi"),
+    ];
+    table.extend(data.map(|data| Entry {
+        name: data.name.clone(),
+        ty: data.ty,
+        start: Expr::Expr("firmware.end".to_owned()),
+        size: Expr::Number(data.size),
+        align: Some(data.align),
+        mbr_type: None,
+    }));
+    table
+}
+
+/// The partitions of `table` on a disk of `disk_size` bytes in blocks of
+/// `block`, laid out as `layout`.
+pub fn resolve(
+    table: &[Entry],
+    layout: &DiskLayout,
+    disk_size: u64,
+    block: LogicalBlockSize,
+) -> Result<Vec<PartitionSpec>, PartitionError> {
+    let (gpt_end, block) = (disk::gpt_end(block), block.as_u64());
+    let disk = Region {
+        offset: gpt_end,
+        size: disk_size.saturating_sub(2 * gpt_end - block),
+    };
+    let mut named = vec![
+        ("spl".to_owned(), layout.spl),
+        ("opensbi".to_owned(), layout.opensbi),
+        ("tau".to_owned(), layout.tau),
+        ("id".to_owned(), layout.id),
+        (
+            "firmware".to_owned(),
+            Region {
+                offset: 0,
+                size: layout.end(),
+            },
+        ),
+        ("disk".to_owned(), disk),
+    ];
+    named.extend(layout.recovery.map(|r| ("recovery".to_owned(), r)));
+
+    let mut specs: Vec<PartitionSpec> = vec![];
+    for (entry, id) in table.iter().zip(1..) {
+        let eval = |expr: &Expr| {
+            eval(expr, &named).map_err(|reason| PartitionError::Expr {
+                name: entry.name.clone(),
+                expr: match expr {
+                    Expr::Number(n) => format!("{n:#x}"),
+                    Expr::Expr(expr) => expr.clone(),
+                },
+                reason,
+            })
+        };
+        let mut offset = eval(&entry.start)?;
+        if let Some(align) = entry.align.filter(|align| *align != 0) {
+            offset = offset.next_multiple_of(align);
+        }
+        let region = Region {
+            offset,
+            size: eval(&entry.size)?,
+        };
+        if region.size == 0 || !offset.is_multiple_of(block) || !region.size.is_multiple_of(block) {
+            return Err(PartitionError::Sectors {
+                name: entry.name.clone(),
+                offset,
+                size: region.size,
+            });
+        }
+        if region.end() > disk.end() {
+            return Err(PartitionError::TooBig {
+                name: entry.name.clone(),
+                end: region.end(),
+                disk: disk.end(),
+            });
+        }
+        if let Some(other) = specs
+            .iter()
+            .find(|s| s.region.offset < region.end() && region.offset < s.region.end())
+        {
+            return Err(PartitionError::Overlap(
+                other.name.clone(),
+                entry.name.clone(),
+            ));
+        }
+        named.push((entry.name.clone(), region));
+        specs.push(PartitionSpec {
+            id,
+            name: entry.name.clone(),
+            ty: entry.ty,
+            mbr_type: entry.mbr_type.unwrap_or_else(|| mbr_type(entry.ty)),
+            region,
+            guid: None,
+        });
+    }
+
+    Ok(specs)
+}
+
+/// A GUID that is the same for the same `namespace`, `board` and `name`:
+/// the start of their SHA-256 as a version 8 UUID.
+pub fn derived_guid(namespace: uuid::Uuid, board: &str, name: &str) -> uuid::Uuid {
+    let hash = Sha256::new()
+        .chain_update(namespace.as_bytes())
+        .chain_update(board)
+        .chain_update([0])
+        .chain_update(name)
+        .finalize();
+    uuid::Builder::from_custom_bytes(hash[..16].try_into().expect("16 bytes")).into_uuid()
+}
+
+fn eval(expr: &Expr, named: &[(String, Region)]) -> Result<u64, String> {
+    let expr = match expr {
+        Expr::Number(n) => return Ok(*n),
+        Expr::Expr(expr) => expr,
+    };
+    let term = |term: &str| -> Result<u64, String> {
+        let Some((name, field)) = term.rsplit_once('.') else {
+            return common::parse_size(term);
+        };
+        let (_, region) = named
+            .iter()
+            .rev()
+            .find(|(n, _)| n == name)
+            .ok_or_else(|| format!("unknown region or partition {name}"))?;
+        match field {
+            "start" => Ok(region.offset),
+            "end" => Ok(region.end()),
+            "size" => Ok(region.size),
+            _ => Err(format!("{term}: expected start, end or size")),
+        }
+    };
+
+    let mut tokens = expr.split_whitespace();
+    let mut value = term(tokens.next().ok_or("empty")?)?;
+    while let Some(op) = tokens.next() {
+        let x = term(tokens.next().ok_or_else(|| format!("nothing after {op}"))?)?;
+        value = match op {
+            "+" => value.checked_add(x),
+            "-" => value.checked_sub(x),
+            _ => return Err(format!("unknown operator {op}, expected + or -")),
+        }
+        .ok_or("out of range")?;
+    }
+    Ok(value)
+}
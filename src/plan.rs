@@ -0,0 +1,168 @@
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum PlanError {
+    #[error("no step {0}, the steps are: {1}")]
+    Unknown(String, String),
+    #[error("step {step} is skipped, but its output {} is missing", artifact.display())]
+    Missing {
+        step: &'static str,
+        artifact: PathBuf,
+    },
+}
+
+/// One named unit of work of a subcommand. The name is the one the summary
+/// reports.
+pub struct Step {
+    pub name: &'static str,
+    /// Files the steps that need this one read.
+    pub outputs: Vec<PathBuf>,
+    pub needs: Vec<&'static str>,
+}
+
+impl Step {
+    pub fn new<I, P>(name: &'static str, outputs: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        Step {
+            name,
+            outputs: outputs.into_iter().map(Into::into).collect(),
+            needs: vec![],
+        }
+    }
+
+    /// Steps whose outputs this one reads.
+    pub fn needs(mut self, names: &[&'static str]) -> Self {
+        self.needs.extend_from_slice(names);
+        self
+    }
+}
+
+#[derive(clap::Args)]
+pub struct PlanArgs {
+    /// Print the steps of the command in order and exit.
+    #[clap(long)]
+    pub list_steps: bool,
+    /// Don't run this step, may be repeated.
+    #[clap(long, value_name = "STEP")]
+    pub skip: Vec<String>,
+    /// Skip every step before this one.
+    #[clap(long, value_name = "STEP")]
+    pub from: Option<String>,
+}
+
+impl PlanArgs {
+    pub fn print(steps: &[Step]) {
+        for step in steps {
+            println!("{}", step.name);
+        }
+    }
+
+    /// Which of `steps` run. Every skipped step needed by a step that runs
+    /// must have left its outputs behind.
+    pub fn resolve(&self, steps: &[Step]) -> Result<Plan, PlanError> {
+        let find = |name: &str| {
+            steps.iter().position(|s| s.name == name).ok_or_else(|| {
+                let names = steps.iter().map(|s| s.name).collect::<Vec<_>>();
+                PlanError::Unknown(name.to_owned(), names.join(", "))
+            })
+        };
+        let first = self.from.as_deref().map(find).transpose()?.unwrap_or(0);
+        let mut skip = steps.iter().map(|_| false).collect::<Vec<_>>();
+        skip[..first].fill(true);
+        for name in &self.skip {
+            skip[find(name)?] = true;
+        }
+
+        let running = steps.iter().zip(&skip).filter(|(_, skip)| !**skip);
+        for name in running.flat_map(|(step, _)| &step.needs) {
+            let i = find(name)?;
+            let needed = &steps[i];
+            if !skip[i] {
+                continue;
+            }
+            if let Some(artifact) = needed.outputs.iter().find(|p| !p.exists()) {
+                return Err(PlanError::Missing {
+                    step: needed.name,
+                    artifact: artifact.clone(),
+                });
+            }
+        }
+
+        let skipped = steps
+            .iter()
+            .zip(skip)
+            .filter_map(|(step, skip)| skip.then_some(step.name))
+            .collect();
+        Ok(Plan { skipped })
+    }
+}
+
+/// The steps `PlanArgs` left to run.
+pub struct Plan {
+    skipped: Vec<&'static str>,
+}
+
+impl Plan {
+    pub fn runs(&self, name: &str) -> bool {
+        !self.skipped.contains(&name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{PlanArgs, PlanError, Step};
+    use crate::testing::TempDir;
+
+    fn steps(dir: &std::path::Path) -> Vec<Step> {
+        vec![
+            Step::new("build-spl", [dir.join("spl")]),
+            Step::new("build-opensbi", [dir.join("fw_payload.bin")]),
+            Step::new("build-uboot-proper", [dir.join("u-boot.itb")]).needs(&["build-opensbi"]),
+        ]
+    }
+
+    fn args(skip: &[&str], from: Option<&str>) -> PlanArgs {
+        PlanArgs {
+            list_steps: false,
+            skip: skip.iter().map(|s| s.to_string()).collect(),
+            from: from.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn skip_without_output() {
+        let tmp = TempDir::new("plan");
+        let dir = tmp.path();
+        match args(&["build-opensbi"], None).resolve(&steps(dir)) {
+            Err(PlanError::Missing { step, artifact }) => {
+                assert_eq!(step, "build-opensbi");
+                assert_eq!(artifact, dir.join("fw_payload.bin"));
+            }
+            _ => panic!("expected the missing OpenSBI payload"),
+        }
+        assert!(matches!(
+            args(&["build-dtb"], None).resolve(&steps(dir)),
+            Err(PlanError::Unknown(name, _)) if name == "build-dtb"
+        ));
+    }
+
+    #[test]
+    fn from() {
+        let tmp = TempDir::new("from");
+        let dir = tmp.path();
+        fs::write(dir.join("fw_payload.bin"), b"").unwrap();
+        let plan = args(&[], Some("build-uboot-proper"))
+            .resolve(&steps(dir))
+            .unwrap();
+        assert!(!plan.runs("build-spl"));
+        assert!(!plan.runs("build-opensbi"));
+        assert!(plan.runs("build-uboot-proper"));
+    }
+}
@@ -0,0 +1,143 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ResourceError {
+    #[error("board file {name} not found in {}, pass --board-dir", dir.display())]
+    Missing { dir: PathBuf, name: String },
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// Looked up relative to the working directory unless `--board-dir` is given.
+pub const DEFAULT_DIR: &str = "board";
+
+/// Where the files embedded in the binary are extracted, per board.
+pub const EXTRACTED_DIR: &str = "target/board-resources";
+
+/// The files of `board/` the binary was built with, by their path in it.
+const EMBEDDED: &[(&str, &[u8])] = include!(concat!(env!("OUT_DIR"), "/board_files.rs"));
+
+/// Where the board files (DTBs, u-boot patches) are taken from.
+pub struct Resources {
+    dir: PathBuf,
+}
+
+impl Resources {
+    /// `dir` if given, else `board` in the working directory if there is
+    /// one, else the files embedded in the binary, extracted for `board`.
+    pub fn new(dir: Option<PathBuf>, board: &str) -> Result<Self, ResourceError> {
+        let dir = match dir {
+            Some(dir) => dir,
+            None if Path::new(DEFAULT_DIR).is_dir() || EMBEDDED.is_empty() => DEFAULT_DIR.into(),
+            None => {
+                let dir = Path::new(EXTRACTED_DIR).join(board);
+                extract(&dir, EMBEDDED)?;
+                dir
+            }
+        };
+        Ok(Resources { dir })
+    }
+
+    /// Absolute path of the file `name`, so it can be handed to tools
+    /// running in other directories.
+    pub fn path(&self, name: &str) -> Result<PathBuf, ResourceError> {
+        let path = self.dir.join(name);
+        if !path.is_file() {
+            return Err(ResourceError::Missing {
+                dir: self.dir.clone(),
+                name: name.to_owned(),
+            });
+        }
+        Ok(fs::canonicalize(path)?)
+    }
+
+    /// The `*.patch` files in the directory `name`, in the order of their
+    /// names, none if there is no such directory.
+    pub fn series(&self, name: &str) -> Result<Vec<PathBuf>, ResourceError> {
+        let dir = self.dir.join(name);
+        if !dir.is_dir() {
+            return Ok(vec![]);
+        }
+        let mut patches = vec![];
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_file() && path.extension().is_some_and(|ext| ext == "patch") {
+                patches.push(fs::canonicalize(path)?);
+            }
+        }
+        patches.sort();
+        Ok(patches)
+    }
+}
+
+/// Writes `files` into `dir`, unless an earlier run already did. An
+/// extraction by another version of the binary, or edited since, is
+/// replaced as a whole, so no file of it lingers.
+fn extract(dir: &Path, files: &[(&str, &[u8])]) -> Result<(), ResourceError> {
+    let mut hasher = Sha256::new();
+    for (name, data) in files {
+        hasher.update(name.as_bytes());
+        hasher.update(Sha256::digest(data));
+    }
+    let digest = format!("{:x}", hasher.finalize());
+    let stamp = dir.join(".digest");
+    let fresh = fs::read_to_string(&stamp).is_ok_and(|found| found == digest)
+        && files
+            .iter()
+            .all(|(name, data)| fs::read(dir.join(name)).is_ok_and(|found| found == *data));
+    if fresh {
+        return Ok(());
+    }
+    match fs::remove_dir_all(dir) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+    for (name, data) in files {
+        let path = dir.join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, data)?;
+    }
+    fs::write(stamp, digest)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::extract;
+    use crate::testing::TempDir;
+
+    const FILES: &[(&str, &[u8])] = &[
+        ("board.dtb", b"\xd0\x0d\xfe\xed"),
+        ("board/patches/0001-fix.patch", b"diff"),
+    ];
+
+    #[test]
+    fn extract_refreshes() {
+        let tmp = TempDir::new("resources");
+        let dir = tmp.path();
+        extract(dir, FILES).unwrap();
+        assert_eq!(fs::read(dir.join("board.dtb")).unwrap(), FILES[0].1);
+
+        // An edited file and one of an older binary are both replaced.
+        fs::write(dir.join("board.dtb"), b"edited").unwrap();
+        fs::write(dir.join("stale.patch"), b"old").unwrap();
+        extract(dir, FILES).unwrap();
+        assert_eq!(fs::read(dir.join("board.dtb")).unwrap(), FILES[0].1);
+        assert!(!dir.join("stale.patch").exists());
+
+        // A newer binary brings other files.
+        extract(dir, &FILES[1..]).unwrap();
+        assert!(!dir.join("board.dtb").exists());
+        assert_eq!(fs::read(dir.join(FILES[1].0)).unwrap(), b"diff");
+    }
+}
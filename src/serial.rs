@@ -0,0 +1,220 @@
+use std::{
+    fs,
+    io::{self, Read, Write},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::fs::OpenOptionsExt,
+    },
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+use crate::{dirs, history};
+
+#[derive(Debug, Error)]
+pub enum SerialError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("{0}: {1}")]
+    Open(PathBuf, io::Error),
+    #[error("unsupported baud rate {0}")]
+    Baud(u32),
+}
+
+/// Ctrl-], as in telnet.
+pub const EXIT_KEY: u8 = 0x1d;
+
+fn speed(baud: u32) -> Option<libc::speed_t> {
+    let speed = match baud {
+        9600 => libc::B9600,
+        19200 => libc::B19200,
+        38400 => libc::B38400,
+        57600 => libc::B57600,
+        115200 => libc::B115200,
+        230400 => libc::B230400,
+        #[cfg(target_os = "linux")]
+        460800 => libc::B460800,
+        #[cfg(target_os = "linux")]
+        921600 => libc::B921600,
+        #[cfg(target_os = "linux")]
+        1500000 => libc::B1500000,
+        _ => return None,
+    };
+    Some(speed)
+}
+
+/// Puts `fd` into raw mode, the previous settings come back on drop.
+struct Raw {
+    fd: RawFd,
+    saved: libc::termios,
+}
+
+impl Raw {
+    fn new(fd: RawFd, speed: Option<libc::speed_t>) -> io::Result<Self> {
+        // SAFETY: `termios` is plain data, filled in by `tcgetattr`
+        let mut saved = unsafe { std::mem::zeroed::<libc::termios>() };
+        // SAFETY: `fd` is open for the lifetime of the guard
+        unsafe {
+            if libc::tcgetattr(fd, &mut saved) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut raw = saved;
+            libc::cfmakeraw(&mut raw);
+            if let Some(speed) = speed {
+                raw.c_cflag |= libc::CLOCAL | libc::CREAD;
+                libc::cfsetispeed(&mut raw, speed);
+                libc::cfsetospeed(&mut raw, speed);
+            }
+            if libc::tcsetattr(fd, libc::TCSANOW, &raw) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(Raw { fd, saved })
+    }
+}
+
+impl Drop for Raw {
+    fn drop(&mut self) {
+        // SAFETY: restores what `new` read from the same fd
+        unsafe { libc::tcsetattr(self.fd, libc::TCSANOW, &self.saved) };
+    }
+}
+
+/// The serial device in raw mode at the requested baud rate.
+pub struct Port {
+    pub file: fs::File,
+    _mode: Raw,
+}
+
+impl Port {
+    pub fn open<P>(device: P, baud: u32) -> Result<Self, SerialError>
+    where
+        P: AsRef<Path>,
+    {
+        let device = device.as_ref();
+        let speed = speed(baud).ok_or(SerialError::Baud(baud))?;
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(device)
+            .map_err(|err| SerialError::Open(device.to_owned(), err))?;
+        let mode = Raw::new(file.as_raw_fd(), Some(speed))?;
+        Ok(Port { file, _mode: mode })
+    }
+
+    /// The next byte, `None` if nothing arrives within `timeout`.
+    pub fn read_byte(&mut self, timeout: std::time::Duration) -> io::Result<Option<u8>> {
+        let mut fd = libc::pollfd {
+            fd: self.file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ms = timeout.as_millis().min(i32::MAX as u128) as i32;
+        // SAFETY: `fd` outlives the call
+        match unsafe { libc::poll(&mut fd, 1, ms) } {
+            0 => return Ok(None),
+            n if n < 0 => return Err(io::Error::last_os_error()),
+            _ => {}
+        }
+        let mut b = [0];
+        match self.file.read(&mut b)? {
+            0 => Err(io::ErrorKind::UnexpectedEof.into()),
+            _ => Ok(Some(b[0])),
+        }
+    }
+}
+
+/// `serial/<date>_<time>.log` in the output directory.
+pub fn default_log() -> PathBuf {
+    let stamp = history::file_stamp(history::now());
+    dirs::out("serial").join(format!("{stamp}.log"))
+}
+
+/// Connects the terminal to `device` until `EXIT_KEY` is pressed or the
+/// device goes away. Everything received is also appended to `log`.
+pub fn monitor<P, Q>(device: P, baud: u32, log: Q) -> Result<(), SerialError>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let device = device.as_ref();
+    let Port {
+        file: mut port,
+        _mode,
+    } = Port::open(device, baud)?;
+
+    if let Some(parent) = log.as_ref().parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut log_file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log)?;
+    eprintln!(
+        "connected to {} at {baud}, logging to {}, Ctrl-] to exit\r",
+        device.display(),
+        log.as_ref().display()
+    );
+
+    let stdin = io::stdin();
+    // SAFETY: plain query
+    let is_tty = unsafe { libc::isatty(stdin.as_raw_fd()) } == 1;
+    let _stdin_mode = is_tty
+        .then(|| Raw::new(stdin.as_raw_fd(), None))
+        .transpose()?;
+
+    let mut fds = [
+        libc::pollfd {
+            fd: port.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+        libc::pollfd {
+            fd: stdin.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+    ];
+    let mut buf = [0; 4096];
+    let mut stdout = io::stdout();
+    loop {
+        // SAFETY: `fds` outlives the call
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as _, -1) } < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err.into());
+        }
+        if fds[0].revents != 0 {
+            let n = port.read(&mut buf)?;
+            if n == 0 {
+                eprintln!("\r\n{} closed\r", device.display());
+                break;
+            }
+            stdout.write_all(&buf[..n])?;
+            stdout.flush()?;
+            log_file.write_all(&buf[..n])?;
+        }
+        if fds[1].revents != 0 {
+            let n = io::stdin().lock().read(&mut buf)?;
+            if n == 0 {
+                // stdin is not a terminal and ran out, keep listening
+                fds[1].fd = -1;
+                continue;
+            }
+            let input = &buf[..n];
+            let end = input.iter().position(|b| *b == EXIT_KEY);
+            port.write_all(&input[..end.unwrap_or(n)])?;
+            if end.is_some() {
+                eprintln!("\r");
+                break;
+            }
+        }
+    }
+    log_file.flush()?;
+
+    Ok(())
+}
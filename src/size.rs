@@ -0,0 +1,222 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use object::{Object, ObjectSection, ObjectSegment, SectionFlags};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    dirs, history,
+    layout::{Component, ComponentKind, Layout},
+};
+
+#[derive(Debug, Error)]
+pub enum SizeError {
+    #[error("{0}: {1}")]
+    Io(String, io::Error),
+    #[error("{0}: {1}")]
+    Elf(String, object::Error),
+    #[error("{}", .0.join(", "))]
+    Regressed(Vec<String>),
+}
+
+pub struct Section {
+    pub name: String,
+    pub size: u64,
+}
+
+/// How much of its slot a component takes.
+pub struct Budget {
+    pub name: String,
+    pub max_size: u64,
+    /// Bytes from the lowest loaded address to the end of the highest
+    /// segment, BSS included, for ELFs; the file size otherwise.
+    pub used: u64,
+    /// Allocated sections, biggest first, empty for raw components.
+    pub sections: Vec<Section>,
+}
+
+impl Budget {
+    pub fn exceeded(&self) -> bool {
+        self.used > self.max_size
+    }
+}
+
+fn budget(component: &Component) -> Result<Budget, SizeError> {
+    let path = component.path.display().to_string();
+    let data = fs::read(&component.path).map_err(|err| SizeError::Io(path.clone(), err))?;
+    let (used, mut sections) = match component.kind.detect(&data) {
+        ComponentKind::Raw | ComponentKind::Payload | ComponentKind::Auto => {
+            (data.len() as u64, vec![])
+        }
+        ComponentKind::Elf => {
+            let file = object::File::parse(&*data).map_err(|err| SizeError::Elf(path, err))?;
+            let loaded = file
+                .segments()
+                .filter(|seg| seg.size() != 0)
+                .map(|seg| (seg.address(), seg.address() + seg.size()))
+                .collect::<Vec<_>>();
+            let start = loaded.iter().map(|(start, _)| *start).min().unwrap_or(0);
+            let end = loaded.iter().map(|(_, end)| *end).max().unwrap_or(0);
+            let sections = file
+                .sections()
+                .filter(|s| {
+                    let alloc = u64::from(object::elf::SHF_ALLOC);
+                    matches!(s.flags(), SectionFlags::Elf { sh_flags } if sh_flags & alloc != 0)
+                })
+                .filter(|s| s.size() != 0)
+                .map(|s| Section {
+                    name: s.name().unwrap_or("?").to_owned(),
+                    size: s.size(),
+                })
+                .collect();
+            (end - start, sections)
+        }
+    };
+    sections.sort_by_key(|s: &Section| std::cmp::Reverse(s.size));
+
+    Ok(Budget {
+        name: component.name.clone(),
+        max_size: component.max_size as u64,
+        used,
+        sections,
+    })
+}
+
+pub fn budgets(layout: &Layout) -> Result<Vec<Budget>, SizeError> {
+    layout.components.iter().map(budget).collect()
+}
+
+pub fn print(budgets: &[Budget]) {
+    for b in budgets {
+        let percent = b.used as f64 * 100.0 / b.max_size as f64;
+        let headroom = if b.exceeded() {
+            format!("over by {:#x}", b.used - b.max_size)
+        } else {
+            format!("{:#x} free", b.max_size - b.used)
+        };
+        println!(
+            "{:<12} {:#8x} of {:#8x} ({percent:5.1}%), {headroom}",
+            b.name, b.used, b.max_size
+        );
+        for s in &b.sections {
+            println!("  {:<20} {:#8x}", s.name, s.size);
+        }
+    }
+}
+
+/// `[size]` in `tau-builder.toml`, what compose warns about.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Percent a component may grow by from one compose to the next.
+    pub max_growth: f64,
+    /// Percent of its slot a component may take.
+    pub max_usage: f64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_growth: 10.0,
+            max_usage: 90.0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ComponentSize {
+    pub name: String,
+    pub size: u64,
+    pub max_size: u64,
+}
+
+/// One line of `sizes.jsonl`.
+#[derive(Serialize, Deserialize)]
+pub struct SizeRecord {
+    /// Seconds since the Unix epoch.
+    pub time: u64,
+    /// Output directory of the cargo profile, sizes of different profiles
+    /// aren't compared.
+    pub profile: String,
+    pub components: Vec<ComponentSize>,
+}
+
+impl SizeRecord {
+    pub fn new(profile: &str, components: Vec<ComponentSize>) -> Self {
+        SizeRecord {
+            time: history::now(),
+            profile: profile.to_owned(),
+            components,
+        }
+    }
+
+    /// What grew by more than `limits.max_growth` since `previous` or now
+    /// takes more than `limits.max_usage` of its slot.
+    pub fn regressions(&self, previous: Option<&SizeRecord>, limits: &Limits) -> Vec<String> {
+        let percent = |size: u64, of: u64| size as f64 * 100.0 / of as f64;
+        let mut found = vec![];
+        for c in &self.components {
+            let before = previous.and_then(|p| p.components.iter().find(|b| b.name == c.name));
+            if let Some(b) = before
+                && c.size > b.size
+                && percent(c.size - b.size, b.size) > limits.max_growth
+            {
+                found.push(format!(
+                    "{} grew from {:#x} to {:#x} bytes (+{:.1}%)",
+                    c.name,
+                    b.size,
+                    c.size,
+                    percent(c.size - b.size, b.size)
+                ));
+            }
+            // only when crossing, not on every compose after
+            let usage = percent(c.size, c.max_size);
+            if usage > limits.max_usage
+                && before.is_none_or(|b| percent(b.size, b.max_size) <= limits.max_usage)
+            {
+                found.push(format!(
+                    "{} takes {usage:.1}% of its {:#x} byte slot",
+                    c.name, c.max_size
+                ));
+            }
+        }
+        found
+    }
+}
+
+/// `sizes.jsonl` in the output directory.
+pub fn history_path() -> PathBuf {
+    dirs::out("sizes.jsonl")
+}
+
+/// The latest record of `profile`.
+pub fn last(profile: &str) -> Result<Option<SizeRecord>, SizeError> {
+    let path = history_path();
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(SizeError::Io(path.display().to_string(), err)),
+    };
+    Ok(text
+        .lines()
+        .rev()
+        .filter_map(|line| serde_json::from_str::<SizeRecord>(line).ok())
+        .find(|r| r.profile == profile))
+}
+
+pub fn append(record: &SizeRecord) -> Result<(), SizeError> {
+    let path = history_path();
+    let io_err = |err| SizeError::Io(path.display().to_string(), err);
+    let mut line = serde_json::to_string(record).map_err(|err| io_err(err.into()))?;
+    line.push('\n');
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .map_err(io_err)
+}
@@ -0,0 +1,265 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum SplHeaderError {
+    #[error("spl of {size} bytes is too big for the {format} header, max {max}")]
+    TooBig {
+        format: &'static str,
+        size: usize,
+        max: usize,
+    },
+}
+
+/// Layout of the header the boot ROM (or the previous stage) expects in
+/// front of the SPL. Offsets are in bytes, every field is a little endian
+/// `u32`, absent fields are `None`.
+pub struct SplHeaderFormat {
+    pub name: &'static str,
+    pub size: usize,
+    pub max_payload: usize,
+    /// Offset and value of the magic.
+    pub magic: Option<(usize, u32)>,
+    /// Where the ROM looks for the backup copy, with the default value.
+    pub backup_offset: Option<(usize, u32)>,
+    /// Header version, with the default value.
+    pub version: Option<(usize, u32)>,
+    pub payload_size: Option<usize>,
+    /// The header stores its own size here.
+    pub header_size: Option<usize>,
+    /// CRC-32 of the payload.
+    pub crc: Option<usize>,
+}
+
+/// The JH7110 boot ROM header.
+pub const JH7110: SplHeaderFormat = SplHeaderFormat {
+    name: "jh7110",
+    size: 0x400,
+    max_payload: 180048,
+    magic: Some((0x0, 0x240)),
+    backup_offset: Some((0x4, 0x200000)),
+    version: Some((0x284, 0x01010101)),
+    payload_size: Some(0x288),
+    header_size: Some(0x28c),
+    crc: Some(0x290),
+};
+
+/// The JH7100 secondBoot loader only prepends the payload size, as done by
+/// StarFive's `fsz.sh`. The payload runs from the 128 KiB intRAM0.
+pub const JH7100: SplHeaderFormat = SplHeaderFormat {
+    name: "jh7100",
+    size: 0x4,
+    max_payload: 0x20000,
+    magic: None,
+    backup_offset: None,
+    version: None,
+    payload_size: Some(0x0),
+    header_size: None,
+    crc: None,
+};
+
+/// No header at all, the TH1520 boot ROM takes `u-boot-with-spl.bin` as it
+/// is. It is never detected.
+pub const NONE: SplHeaderFormat = SplHeaderFormat {
+    name: "none",
+    size: 0,
+    max_payload: usize::MAX,
+    magic: None,
+    backup_offset: None,
+    version: None,
+    payload_size: None,
+    header_size: None,
+    crc: None,
+};
+
+pub const FORMATS: &[&SplHeaderFormat] = &[&JH7110, &JH7100, &NONE];
+
+/// Looks a format up by name, for `--format`.
+pub fn by_name(name: &str) -> Result<&'static SplHeaderFormat, String> {
+    FORMATS
+        .iter()
+        .copied()
+        .find(|f| f.name == name)
+        .ok_or_else(|| {
+            let names = FORMATS.iter().map(|f| f.name).collect::<Vec<_>>();
+            format!(
+                "unknown format {name:?}, expected one of {}",
+                names.join(", ")
+            )
+        })
+}
+
+/// Fields read back from a header.
+pub struct Parsed {
+    pub format: &'static str,
+    pub payload_size: u32,
+    pub backup_offset: Option<u32>,
+    pub version: Option<u32>,
+    /// Whether the stored CRC matches the payload that follows the header,
+    /// `None` if the format has no CRC or the payload is cut short.
+    pub crc_ok: Option<bool>,
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC).checksum(data)
+}
+
+impl SplHeaderFormat {
+    /// The header for `spl`, `backup_offset` and `version` override the
+    /// defaults of the format.
+    pub fn header(
+        &self,
+        spl: &[u8],
+        backup_offset: Option<u32>,
+        version: Option<u32>,
+    ) -> Result<Vec<u8>, SplHeaderError> {
+        if spl.len() > self.max_payload {
+            return Err(SplHeaderError::TooBig {
+                format: self.name,
+                size: spl.len(),
+                max: self.max_payload,
+            });
+        }
+
+        let mut header = vec![0; self.size];
+        let mut write_at = |i: usize, x: u32| {
+            header[i..(i + 4)].clone_from_slice(&x.to_le_bytes());
+        };
+        if let Some((at, magic)) = self.magic {
+            write_at(at, magic);
+        }
+        if let Some((at, default)) = self.backup_offset {
+            write_at(at, backup_offset.unwrap_or(default));
+        }
+        if let Some((at, default)) = self.version {
+            write_at(at, version.unwrap_or(default));
+        }
+        if let Some(at) = self.payload_size {
+            write_at(at, spl.len() as u32);
+        }
+        if let Some(at) = self.header_size {
+            write_at(at, self.size as u32);
+        }
+        if let Some(at) = self.crc {
+            write_at(at, crc32(spl));
+        }
+
+        Ok(header)
+    }
+
+    /// Reads the header at the start of `data`, `None` if it isn't one of
+    /// this format.
+    pub fn parse(&self, data: &[u8]) -> Option<Parsed> {
+        let read_at = |i: usize| Some(u32::from_le_bytes(data.get(i..(i + 4))?.try_into().ok()?));
+        if data.len() < self.size {
+            return None;
+        }
+        if let Some((at, magic)) = self.magic
+            && read_at(at)? != magic
+        {
+            return None;
+        }
+        if let Some(at) = self.header_size
+            && read_at(at)? as usize != self.size
+        {
+            return None;
+        }
+        let payload_size = read_at(self.payload_size?)?;
+        if payload_size as usize > self.max_payload {
+            return None;
+        }
+        let payload = data.get(self.size..)?.get(..payload_size as usize);
+        let crc_ok = match (self.crc, payload) {
+            (Some(at), Some(payload)) => Some(read_at(at)? == crc32(payload)),
+            _ => None,
+        };
+        Some(Parsed {
+            format: self.name,
+            payload_size,
+            backup_offset: self.backup_offset.and_then(|(at, _)| read_at(at)),
+            version: self.version.and_then(|(at, _)| read_at(at)),
+            crc_ok,
+        })
+    }
+}
+
+/// Tries `formats` in order.
+pub fn detect(formats: &[&SplHeaderFormat], data: &[u8]) -> Option<Parsed> {
+    formats.iter().find_map(|f| f.parse(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FORMATS, JH7100, JH7110, NONE, SplHeaderError, detect};
+
+    /// The CRC-32 of it is the check value of the algorithm, 0xcbf43926.
+    const PAYLOAD: &[u8] = b"123456789";
+
+    #[test]
+    fn jh7110_golden() {
+        let mut expected = vec![0; 0x400];
+        expected[..8].copy_from_slice(&[0x40, 0x02, 0, 0, 0, 0, 0x20, 0]);
+        expected[0x284..0x294].copy_from_slice(&[
+            0x01, 0x01, 0x01, 0x01, // version
+            0x09, 0, 0, 0, // payload size
+            0, 0x04, 0, 0, // header size
+            0x26, 0x39, 0xf4, 0xcb, // crc
+        ]);
+        assert_eq!(JH7110.header(PAYLOAD, None, None).unwrap(), expected);
+
+        let header = JH7110.header(PAYLOAD, Some(0x100000), Some(2)).unwrap();
+        assert_eq!(header[4..8], [0, 0, 0x10, 0]);
+        assert_eq!(header[0x284..0x288], [2, 0, 0, 0]);
+    }
+
+    #[test]
+    fn jh7100_golden() {
+        assert_eq!(JH7100.header(PAYLOAD, None, None).unwrap(), [9, 0, 0, 0]);
+    }
+
+    #[test]
+    fn payload_limit() {
+        let spl = vec![0; JH7110.max_payload + 1];
+        assert!(matches!(
+            JH7110.header(&spl, None, None),
+            Err(SplHeaderError::TooBig { size: 180049, .. })
+        ));
+        assert!(JH7110.header(&spl[1..], None, None).is_ok());
+    }
+
+    #[test]
+    fn round_trip() {
+        for format in [&JH7110, &JH7100] {
+            let data = [
+                format.header(PAYLOAD, None, None).unwrap(),
+                PAYLOAD.to_vec(),
+            ]
+            .concat();
+            let parsed = detect(FORMATS, &data).unwrap();
+            assert_eq!(parsed.format, format.name);
+            assert_eq!(parsed.payload_size, PAYLOAD.len() as u32);
+            assert_eq!(parsed.crc_ok, format.crc.map(|_| true));
+        }
+
+        let mut data = [
+            JH7110.header(PAYLOAD, Some(0x80000), None).unwrap(),
+            PAYLOAD.to_vec(),
+        ]
+        .concat();
+        let parsed = JH7110.parse(&data).unwrap();
+        assert_eq!(parsed.backup_offset, Some(0x80000));
+        assert_eq!(parsed.version, Some(0x01010101));
+        *data.last_mut().unwrap() ^= 1;
+        assert_eq!(JH7110.parse(&data).unwrap().crc_ok, Some(false));
+        assert!(JH7110.parse(&data[..0x3ff]).is_none());
+    }
+
+    #[test]
+    fn none_is_never_detected() {
+        let header = NONE.header(PAYLOAD, None, None).unwrap();
+        assert!(header.is_empty());
+        for data in [PAYLOAD.to_vec(), vec![0; 0x1000], vec![0xff; 0x1000]] {
+            assert!(NONE.parse(&data).is_none());
+            assert!(detect(&[&NONE], &data).is_none());
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    time::SystemTime,
+};
+
+use crate::{common, history, manifest::RegionUsage, watch};
+
+/// Build output kept inside source trees.
+const SKIP: &[&str] = &["target", "build"];
+
+/// An artifact and what it is built from.
+pub struct Stage {
+    pub name: String,
+    pub artifact: PathBuf,
+    /// Files and directories whose changes make the artifact stale.
+    pub sources: Vec<PathBuf>,
+    /// The clone it is built from and the revision it is pinned to.
+    pub clone: Option<(PathBuf, String)>,
+    /// Present while a build is unfinished or after it failed.
+    pub failed_marker: Option<PathBuf>,
+}
+
+pub enum State {
+    Missing,
+    Stale(String),
+    UpToDate,
+}
+
+pub struct Report {
+    pub name: String,
+    pub artifact: PathBuf,
+    pub size: u64,
+    pub crc32: Option<u32>,
+    /// The revision checked out in the clone.
+    pub revision: Option<String>,
+    pub state: State,
+}
+
+/// The commit checked out in `dir`, `None` outside of a git repository.
+pub fn head(dir: &Path) -> Option<String> {
+    let out = Command::new("git")
+        .current_dir(dir)
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()?;
+    let rev = String::from_utf8(out.stdout).ok()?;
+    Some(rev.trim().to_owned()).filter(|_| out.status.success())
+}
+
+/// Commit time of the commit checked out in `dir`, in seconds since the
+/// Unix epoch.
+pub fn head_time(dir: &Path) -> Option<u64> {
+    let out = Command::new("git")
+        .current_dir(dir)
+        .args(["log", "-1", "--format=%ct"])
+        .output()
+        .ok()?;
+    let time = String::from_utf8(out.stdout).ok()?;
+    time.trim().parse().ok().filter(|_| out.status.success())
+}
+
+/// The most recently modified source, if any.
+fn newest(sources: &[PathBuf]) -> Option<(PathBuf, SystemTime)> {
+    let (files, dirs) = sources.iter().partition::<Vec<_>, _>(|p| p.is_file());
+    let mut times = watch::snapshot(&dirs, SKIP);
+    for file in files {
+        if let Ok(time) = fs::metadata(file).and_then(|m| m.modified()) {
+            times.insert(file.clone(), time);
+        }
+    }
+    times.into_iter().max_by_key(|(_, time)| *time)
+}
+
+pub fn check(stage: &Stage) -> Report {
+    let revision = stage.clone.as_ref().and_then(|(dir, _)| head(dir));
+    let mut report = Report {
+        name: stage.name.clone(),
+        artifact: stage.artifact.clone(),
+        size: 0,
+        crc32: None,
+        revision,
+        state: State::Missing,
+    };
+    let Ok(meta) = fs::metadata(&stage.artifact) else {
+        return report;
+    };
+    report.size = meta.len();
+    report.crc32 = fs::read(&stage.artifact).ok().map(|d| history::crc32(&d));
+
+    let built = meta.modified().ok();
+    report.state = if stage.failed_marker.as_ref().is_some_and(|m| m.exists()) {
+        State::Stale("the last build didn't finish".to_owned())
+    } else if let (Some((_, pinned)), Some(rev)) = (&stage.clone, &report.revision)
+        // a clone following a branch can't be told to be behind it offline
+        && common::is_commit(pinned)
+        && rev != pinned
+    {
+        State::Stale(format!("checked out {:.10}, pinned {pinned:.10}", rev))
+    } else if let (Some(built), Some((path, time))) = (built, newest(&stage.sources))
+        && time > built
+    {
+        State::Stale(format!("{} is newer", path.display()))
+    } else {
+        State::UpToDate
+    };
+    report
+}
+
+/// How full the disk regions were at the last compose.
+pub fn print_usage(usage: &[RegionUsage]) {
+    if usage.is_empty() {
+        return;
+    }
+    println!("regions");
+    for u in usage {
+        println!(
+            "  {:<8} {:#10x} / {:#10x} bytes {:5.1}%",
+            u.region, u.used, u.size, u.percent
+        );
+    }
+}
+
+pub fn print(reports: &[Report]) {
+    let width = reports
+        .iter()
+        .map(|r| r.name.len())
+        .max()
+        .unwrap_or(0)
+        .max(14);
+    for r in reports {
+        let state = match &r.state {
+            State::Missing => "missing".to_owned(),
+            State::Stale(why) => format!("stale, {why}"),
+            State::UpToDate => "up to date".to_owned(),
+        };
+        println!("{:<width$} {state}", r.name);
+        println!("  {}", r.artifact.display());
+        if let Some(crc) = r.crc32 {
+            println!("  {:#x} bytes, crc32 {crc:08x}", r.size);
+        }
+        if let Some(rev) = &r.revision {
+            println!("  revision {rev}");
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use std::{
+    fmt, fs,
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// What a successful step actually did.
+pub enum Outcome {
+    Rebuilt,
+    Cached,
+}
+
+enum Status {
+    Rebuilt,
+    Cached,
+    Skipped,
+    Failed(String),
+}
+
+struct Step {
+    name: String,
+    duration: Duration,
+    status: Status,
+}
+
+struct Written {
+    device: PathBuf,
+    offset: u64,
+    len: u64,
+}
+
+/// Collects the facts of a run and prints them as one block at the end.
+#[derive(Default)]
+pub struct Summary {
+    steps: Vec<Step>,
+    events: Option<fs::File>,
+    artifacts: Vec<PathBuf>,
+    writes: Vec<Written>,
+    verified: Option<bool>,
+    next: Option<String>,
+}
+
+impl Summary {
+    /// Also write each step to `file` as it starts and ends, one JSON object
+    /// per line.
+    pub fn events(&mut self, file: fs::File) {
+        self.events = Some(file);
+    }
+
+    fn event(&mut self, event: serde_json::Value) {
+        if let Some(file) = &mut self.events
+            && let Err(err) = writeln!(file, "{event}")
+        {
+            eprintln!("events: {err}");
+            self.events = None;
+        }
+    }
+
+    fn push(&mut self, step: Step) {
+        let secs = step.duration.as_secs_f64();
+        let event = match &step.status {
+            Status::Rebuilt => serde_json::json!({"step": step.name, "status": "ok", "secs": secs}),
+            Status::Cached => {
+                serde_json::json!({"step": step.name, "status": "cached", "secs": secs})
+            }
+            Status::Skipped => serde_json::json!({"step": step.name, "status": "skipped"}),
+            Status::Failed(err) => serde_json::json!({
+                "step": step.name,
+                "status": "failed",
+                "secs": secs,
+                "error": err,
+            }),
+        };
+        self.event(event);
+        self.steps.push(step);
+    }
+
+    /// Records `name` as skipped.
+    pub fn skipped(&mut self, name: &str) {
+        self.push(Step {
+            name: name.to_owned(),
+            duration: Duration::ZERO,
+            status: Status::Skipped,
+        });
+    }
+
+    pub fn step<E>(
+        &mut self,
+        name: &str,
+        f: impl FnOnce(&mut Self) -> Result<Outcome, E>,
+    ) -> Result<(), E>
+    where
+        E: fmt::Display,
+    {
+        self.event(serde_json::json!({"step": name, "status": "started"}));
+        let start = Instant::now();
+        let res = f(self);
+        let status = match &res {
+            Ok(Outcome::Rebuilt) => Status::Rebuilt,
+            Ok(Outcome::Cached) => Status::Cached,
+            Err(err) => Status::Failed(err.to_string()),
+        };
+        self.push(Step {
+            name: name.to_owned(),
+            duration: start.elapsed(),
+            status,
+        });
+        res.map(drop)
+    }
+
+    pub fn artifact<P>(&mut self, path: P)
+    where
+        P: AsRef<Path>,
+    {
+        self.artifacts.push(path.as_ref().to_owned());
+    }
+
+    pub fn written<P>(&mut self, device: P, offset: u64, len: usize)
+    where
+        P: AsRef<Path>,
+    {
+        self.writes.push(Written {
+            device: device.as_ref().to_owned(),
+            offset,
+            len: len as u64,
+        });
+    }
+
+    pub fn verified(&mut self, ok: bool) {
+        self.verified = Some(self.verified.unwrap_or(true) && ok);
+    }
+
+    pub fn next(&mut self, command: impl Into<String>) {
+        self.next = Some(command.into());
+    }
+
+    pub fn print(&self) {
+        if self.steps.is_empty() {
+            return;
+        }
+        let paint = Paint(io::stderr().is_terminal());
+
+        eprintln!();
+        eprintln!("{}", paint.bold("summary"));
+        for step in &self.steps {
+            let secs = step.duration.as_secs_f32();
+            match &step.status {
+                Status::Rebuilt => {
+                    eprintln!("  {} {:<24} {secs:>7.1}s", paint.green("ok"), step.name)
+                }
+                Status::Cached => eprintln!(
+                    "  {} {:<24} {secs:>7.1}s cached",
+                    paint.green("ok"),
+                    step.name
+                ),
+                Status::Skipped => eprintln!("  -- {:<24}          skipped", step.name),
+                Status::Failed(err) => eprintln!(
+                    "  {} {:<24} {secs:>7.1}s {err}",
+                    paint.red("!!"),
+                    paint.red(&step.name)
+                ),
+            }
+        }
+        for path in &self.artifacts {
+            match fs::read(path) {
+                Ok(data) => {
+                    let c = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+                    eprintln!(
+                        "  {} {} bytes crc32 {:08x}",
+                        path.display(),
+                        data.len(),
+                        c.checksum(&data)
+                    );
+                }
+                Err(err) => eprintln!("  {} {err}", path.display()),
+            }
+        }
+        for w in &self.writes {
+            eprintln!(
+                "  wrote {} {:#x}..{:#x}",
+                w.device.display(),
+                w.offset,
+                w.offset + w.len
+            );
+        }
+        match self.verified {
+            Some(true) => eprintln!("  verification {}", paint.green("passed")),
+            Some(false) => eprintln!("  verification {}", paint.red("failed")),
+            None => {}
+        }
+        let failed = self
+            .steps
+            .iter()
+            .any(|step| matches!(step.status, Status::Failed(_)));
+        if let (false, Some(next)) = (failed, &self.next) {
+            eprintln!("  next: {}", paint.bold(next));
+        }
+    }
+}
+
+struct Paint(bool);
+
+impl Paint {
+    fn wrap(&self, code: &str, s: &str) -> String {
+        if self.0 {
+            format!("\x1b[{code}m{s}\x1b[0m")
+        } else {
+            s.to_owned()
+        }
+    }
+
+    fn bold(&self, s: &str) -> String {
+        self.wrap("1", s)
+    }
+
+    fn green(&self, s: &str) -> String {
+        self.wrap("32", s)
+    }
+
+    fn red(&self, s: &str) -> String {
+        self.wrap("1;31", s)
+    }
+}
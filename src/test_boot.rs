@@ -0,0 +1,79 @@
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    process::{Command, ExitStatus, Stdio},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::interrupt;
+
+/// Patterns that fail the boot unless `--failure` replaces them: a Rust
+/// panic and OpenSBI's trap dump.
+pub const DEFAULT_FAILURE: [&str; 2] = ["panicked at", "sbi_trap_error"];
+
+pub enum Verdict {
+    Success,
+    /// The line with the failure marker.
+    Failure(String),
+    Timeout,
+    /// The machine stopped before printing any marker.
+    Exited(ExitStatus),
+    Interrupted,
+}
+
+/// Runs `command`, echoing its output, until a line contains one of
+/// `success` or `failure` or `timeout` passes, then kills it.
+pub fn run(
+    command: &mut Command,
+    success: &[String],
+    failure: &[String],
+    timeout: Duration,
+) -> io::Result<Verdict> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()?;
+    let stdout = child.stdout.take().expect("piped above");
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        // the serial console writes `\r\n`, and may write invalid UTF-8
+        for line in BufReader::new(stdout).split(b'\n') {
+            let Ok(line) = line else { break };
+            let line = String::from_utf8_lossy(&line).trim_end().to_owned();
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let deadline = Instant::now() + timeout;
+    let verdict = loop {
+        if interrupt::interrupted() {
+            break Verdict::Interrupted;
+        }
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            break Verdict::Timeout;
+        }
+        match rx.recv_timeout(left.min(Duration::from_millis(200))) {
+            Ok(line) => {
+                println!("{line}");
+                if failure.iter().any(|m| line.contains(m.as_str())) {
+                    break Verdict::Failure(line);
+                }
+                if success.iter().any(|m| line.contains(m.as_str())) {
+                    break Verdict::Success;
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => break Verdict::Exited(child.wait()?),
+        }
+    };
+    io::stdout().flush()?;
+    let _ = child.kill();
+    let _ = child.wait();
+
+    Ok(verdict)
+}
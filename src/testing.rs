@@ -0,0 +1,198 @@
+//! Fixtures for exercising the disk code against image files and in-memory
+//! images instead of real devices.
+
+use std::{
+    fs,
+    io::{self, SeekFrom},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    board::{DiskLayout, Gpt, Region},
+    disk::{self, DiskError, Target},
+};
+
+/// Zeroed in-memory image of `size` bytes.
+pub fn image(size: usize) -> io::Cursor<Vec<u8>> {
+    io::Cursor::new(vec![0; size])
+}
+
+/// Sparse image file of `size` bytes named `name` in the temporary directory.
+pub fn sparse_file(name: &str, size: u64) -> io::Result<PathBuf> {
+    let path = std::env::temp_dir().join(name);
+    fs::File::create(&path)?.set_len(size)?;
+    Ok(path)
+}
+
+/// A directory of the test's own in the temporary directory, removed with
+/// everything in it when dropped, so a failing assert doesn't leave it
+/// behind.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// `tau-builder-{name}-{pid}`, emptied of what an earlier run left.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("tau-builder-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Lays down the GPT of `layout` and writes `spl` and `opensbi` into their
+/// regions, like `format` does.
+pub fn populate<T>(
+    target: &mut T,
+    layout: &DiskLayout,
+    firmware: &Gpt,
+    spl: &[u8],
+    opensbi: &[u8],
+) -> Result<(), DiskError>
+where
+    T: Target + std::fmt::Debug,
+{
+    let target = disk::write_gpt(
+        target,
+        &disk::firmware_partitions(layout, firmware),
+        false,
+        gpt::disk::LogicalBlockSize::Lb512,
+        None,
+    )?;
+    disk::write_verified(target, layout.spl.offset, spl, 0)?;
+    disk::write_verified(target, layout.opensbi.offset, opensbi, 0)
+}
+
+/// Flips every bit in `range`.
+pub fn corrupt<T>(target: &mut T, range: Range<u64>) -> io::Result<()>
+where
+    T: Target,
+{
+    let mut data = vec![0; (range.end - range.start) as usize];
+    target.seek(SeekFrom::Start(range.start))?;
+    target.read_exact(&mut data)?;
+    data.iter_mut().for_each(|b| *b = !*b);
+    target.seek(SeekFrom::Start(range.start))?;
+    target.write_all(&data)?;
+    target.sync()
+}
+
+/// An image whose writes to `bad` don't stick, every bit of them flipped,
+/// like the worn out blocks of a card.
+#[derive(Debug)]
+pub struct Faulty<T> {
+    pub inner: T,
+    pub bad: Range<u64>,
+}
+
+impl<T> io::Read for Faulty<T>
+where
+    T: Target,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<T> io::Write for Faulty<T>
+where
+    T: Target,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let at = self.inner.stream_position()?;
+        let mut data = buf.to_vec();
+        for (byte, offset) in data.iter_mut().zip(at..) {
+            if self.bad.contains(&offset) {
+                *byte = !*byte;
+            }
+        }
+        self.inner.write(&data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T> io::Seek for Faulty<T>
+where
+    T: Target,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl<T> Target for Faulty<T>
+where
+    T: Target,
+{
+    fn sync(&mut self) -> io::Result<()> {
+        self.inner.sync()
+    }
+}
+
+/// CRC-32 of `region`.
+pub fn region_crc<T>(target: &mut T, region: Region) -> io::Result<u32>
+where
+    T: Target,
+{
+    let mut data = vec![0; region.size as usize];
+    target.seek(SeekFrom::Start(region.offset))?;
+    target.read_exact(&mut data)?;
+    Ok(crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC).checksum(&data))
+}
+
+/// A program header of `elf`.
+#[derive(Clone, Copy)]
+pub struct Segment<'a> {
+    /// `PT_LOAD`, `PT_NOTE`, ...
+    pub kind: u32,
+    pub vaddr: u64,
+    pub data: &'a [u8],
+    pub memsz: u64,
+}
+
+/// A 64-bit little endian RISC-V executable entered at `entry`, the data
+/// of `segments` following the program headers in order.
+pub fn elf(entry: u64, segments: &[Segment]) -> Vec<u8> {
+    const EHDR: usize = 64;
+    const PHDR: usize = 56;
+
+    let mut out = vec![0; EHDR + PHDR * segments.len()];
+    let put = |out: &mut Vec<u8>, at: usize, bytes: &[u8]| {
+        out[at..at + bytes.len()].copy_from_slice(bytes);
+    };
+    put(&mut out, 0, b"\x7fELF\x02\x01\x01");
+    put(&mut out, 16, &object::elf::ET_EXEC.to_le_bytes());
+    put(&mut out, 18, &object::elf::EM_RISCV.to_le_bytes());
+    put(&mut out, 20, &1u32.to_le_bytes());
+    put(&mut out, 24, &entry.to_le_bytes());
+    put(&mut out, 32, &(EHDR as u64).to_le_bytes());
+    put(&mut out, 52, &(EHDR as u16).to_le_bytes());
+    put(&mut out, 54, &(PHDR as u16).to_le_bytes());
+    put(&mut out, 56, &(segments.len() as u16).to_le_bytes());
+    for (i, s) in segments.iter().enumerate() {
+        let (at, offset) = (EHDR + PHDR * i, out.len() as u64);
+        put(&mut out, at, &s.kind.to_le_bytes());
+        put(&mut out, at + 4, &object::elf::PF_R.to_le_bytes());
+        put(&mut out, at + 8, &offset.to_le_bytes());
+        put(&mut out, at + 16, &s.vaddr.to_le_bytes());
+        put(&mut out, at + 24, &s.vaddr.to_le_bytes());
+        put(&mut out, at + 32, &(s.data.len() as u64).to_le_bytes());
+        put(&mut out, at + 40, &s.memsz.to_le_bytes());
+        put(&mut out, at + 48, &1u64.to_le_bytes());
+        out.extend_from_slice(s.data);
+    }
+    out
+}
@@ -0,0 +1,123 @@
+use std::collections::BTreeMap;
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum EnvError {
+    #[error("line {line}: expected KEY=VALUE, got {text:?}")]
+    Parse { line: usize, text: String },
+    #[error("environment of {len} bytes doesn't fit into {size} bytes")]
+    TooBig { len: usize, size: usize },
+    #[error("no valid environment, crc32 doesn't match")]
+    Crc,
+}
+
+/// Layout of the environment block, as in u-boot's `CONFIG_ENV_SIZE` and
+/// `CONFIG_SYS_REDUNDAND_ENVIRONMENT`.
+#[derive(Clone, Copy, clap::Args)]
+pub struct EnvFormat {
+    /// Size of the environment block.
+    #[clap(long, value_parser = crate::common::parse_u64, default_value = "0x10000")]
+    pub env_size: u64,
+    /// The block has the flags byte of a redundant environment.
+    #[clap(long)]
+    pub env_redundant: bool,
+}
+
+impl EnvFormat {
+    fn header(&self) -> usize {
+        if self.env_redundant { 5 } else { 4 }
+    }
+}
+
+/// Variables of a u-boot environment.
+#[derive(Default)]
+pub struct Env {
+    pub vars: BTreeMap<String, String>,
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC).checksum(data)
+}
+
+impl Env {
+    /// One `key=value` per line, blank lines and `#` comments are skipped.
+    pub fn parse_text(text: &str) -> Result<Self, EnvError> {
+        let mut vars = BTreeMap::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim_end();
+            if line.trim().is_empty() || line.trim_start().starts_with('#') {
+                continue;
+            }
+            match line.split_once('=') {
+                Some((key, value)) if !key.trim().is_empty() => {
+                    vars.insert(key.trim().to_owned(), value.to_owned());
+                }
+                _ => {
+                    return Err(EnvError::Parse {
+                        line: i + 1,
+                        text: line.to_owned(),
+                    });
+                }
+            }
+        }
+        Ok(Env { vars })
+    }
+
+    pub fn to_text(&self) -> String {
+        self.vars
+            .iter()
+            .map(|(k, v)| format!("{k}={v}\n"))
+            .collect()
+    }
+
+    /// The CRC-32 of the data area, the flags byte if redundant, then the
+    /// `key=value` strings, each NUL terminated, the rest padded with NUL
+    /// bytes like a saved environment.
+    pub fn to_bytes(&self, format: &EnvFormat) -> Result<Vec<u8>, EnvError> {
+        let size = format.env_size as usize;
+        let header = format.header();
+        let mut data = vec![];
+        for (k, v) in &self.vars {
+            data.extend_from_slice(k.as_bytes());
+            data.push(b'=');
+            data.extend_from_slice(v.as_bytes());
+            data.push(0);
+        }
+        // the list ends with an empty string
+        let len = header + data.len() + 1;
+        if len > size {
+            return Err(EnvError::TooBig { len, size });
+        }
+        data.resize(size - header, 0);
+
+        let mut block = crc32(&data).to_le_bytes().to_vec();
+        if format.env_redundant {
+            // u-boot picks the copy with the higher flags
+            block.push(1);
+        }
+        block.extend_from_slice(&data);
+        Ok(block)
+    }
+
+    pub fn parse_bytes(block: &[u8], format: &EnvFormat) -> Result<Self, EnvError> {
+        let header = format.header();
+        let data = block
+            .get(header..(format.env_size as usize).min(block.len()))
+            .ok_or(EnvError::Crc)?;
+        let stored = u32::from_le_bytes(block[..4].try_into().expect("checked by get above"));
+        if stored != crc32(data) {
+            return Err(EnvError::Crc);
+        }
+        let vars = data
+            .split(|b| *b == 0)
+            .take_while(|entry| !entry.is_empty())
+            .filter_map(|entry| {
+                let entry = String::from_utf8_lossy(entry);
+                let (k, v) = entry.split_once('=')?;
+                Some((k.to_owned(), v.to_owned()))
+            })
+            .collect();
+        Ok(Env { vars })
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use thiserror::Error;
+
+use crate::{board::UsbId, common, interrupt};
+
+#[derive(Debug, Error)]
+pub enum UsbError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error(
+        "no {what} device {id} showed up in {secs}s; put the board in {what} mode first: run `{command}` at the u-boot prompt"
+    )]
+    Timeout {
+        what: &'static str,
+        id: UsbId,
+        secs: u64,
+        command: &'static str,
+    },
+    #[error("waiting for USB devices is only supported on Linux")]
+    UnsupportedHost,
+    #[error("dfu-util not found in PATH")]
+    NoDfuUtil,
+    #[error("dfu-util exited with {0}")]
+    DfuUtil(std::process::ExitStatus),
+    #[error("interrupted")]
+    Interrupted,
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum Mode {
+    /// Wait for the mass storage device and update it like any other disk.
+    Ums,
+    /// Download the image with `dfu-util`.
+    Dfu,
+}
+
+pub const UMS_COMMAND: &str = "ums 0 mmc 0";
+pub const DFU_COMMAND: &str = "dfu 0 mmc 0";
+
+/// Whether some sysfs directory above `dev` is the USB device `id`.
+#[cfg(target_os = "linux")]
+fn is_usb_device(dev: &Path, id: UsbId) -> bool {
+    use std::fs;
+
+    let read = |dir: &Path, name: &str| {
+        let s = fs::read_to_string(dir.join(name)).ok()?;
+        u16::from_str_radix(s.trim(), 16).ok()
+    };
+    dev.ancestors()
+        .any(|dir| read(dir, "idVendor") == Some(id.vid) && read(dir, "idProduct") == Some(id.pid))
+}
+
+/// Whole-disk block devices backed by the USB device `id`.
+#[cfg(target_os = "linux")]
+fn find_block(id: UsbId) -> io::Result<Option<PathBuf>> {
+    use std::fs;
+
+    for entry in fs::read_dir("/sys/class/block")? {
+        let sys = fs::canonicalize(entry?.path())?;
+        if sys.join("partition").exists() {
+            continue;
+        }
+        if is_usb_device(&sys, id) {
+            let name = sys.file_name().unwrap_or_default();
+            return Ok(Some(Path::new("/dev").join(name)));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(target_os = "linux")]
+fn find_usb(id: UsbId) -> io::Result<Option<PathBuf>> {
+    use std::fs;
+
+    for entry in fs::read_dir("/sys/bus/usb/devices")? {
+        let sys = fs::canonicalize(entry?.path())?;
+        if is_usb_device(&sys, id) {
+            return Ok(Some(sys));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn find_block(id: UsbId) -> io::Result<Option<PathBuf>> {
+    let _ = id;
+    Ok(None)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn find_usb(id: UsbId) -> io::Result<Option<PathBuf>> {
+    let _ = id;
+    Ok(None)
+}
+
+fn poll<F>(
+    what: &'static str,
+    id: UsbId,
+    timeout: Duration,
+    command: &'static str,
+    mut find: F,
+) -> Result<PathBuf, UsbError>
+where
+    F: FnMut(UsbId) -> io::Result<Option<PathBuf>>,
+{
+    if cfg!(not(target_os = "linux")) {
+        return Err(UsbError::UnsupportedHost);
+    }
+    let start = Instant::now();
+    eprintln!("waiting for {what} device {id}, run `{command}` at the u-boot prompt");
+    loop {
+        if let Some(path) = find(id)? {
+            return Ok(path);
+        }
+        if interrupt::interrupted() {
+            return Err(UsbError::Interrupted);
+        }
+        if start.elapsed() > timeout {
+            return Err(UsbError::Timeout {
+                what,
+                id,
+                secs: timeout.as_secs(),
+                command,
+            });
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+}
+
+/// Waits for u-boot's `ums` gadget to enumerate and returns its block device.
+pub fn wait_ums(id: UsbId, timeout: Duration) -> Result<PathBuf, UsbError> {
+    let dev = poll("ums", id, timeout, UMS_COMMAND, find_block)?;
+    // udev may still be creating the node.
+    for _ in 0..50 {
+        if dev.exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    eprintln!("found {}", dev.display());
+    Ok(dev)
+}
+
+/// Waits for u-boot's `dfu` gadget and downloads `file` into the `alt`
+/// setting with `dfu-util`, which prints its own progress.
+pub fn dfu<P>(id: UsbId, alt: &str, file: P, timeout: Duration) -> Result<(), UsbError>
+where
+    P: AsRef<Path>,
+{
+    let dfu_util = common::find_in_path("dfu-util").ok_or(UsbError::NoDfuUtil)?;
+    poll("dfu", id, timeout, DFU_COMMAND, find_usb)?;
+    let out = interrupt::run(
+        Command::new(dfu_util)
+            .arg("--device")
+            .arg(id.to_string())
+            .args(["--alt", alt, "--download"])
+            .arg(file.as_ref())
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit()),
+    )?;
+    if !out.status.success() {
+        return Err(UsbError::DfuUtil(out.status));
+    }
+
+    Ok(())
+}
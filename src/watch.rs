@@ -0,0 +1,101 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use crate::interrupt;
+
+/// Crates of the firmware whose sources are watched by default.
+pub const DEFAULT_DIRS: [&str; 2] = ["supervisor", "system"];
+
+/// Build output inside the watched crates.
+const SKIP: &[&str] = &["target"];
+
+/// Modification times of every file under `dirs`, hidden entries and
+/// directories named in `skip` excluded. A file in `dirs` is taken as it is.
+pub fn snapshot<P>(dirs: &[P], skip: &[&str]) -> BTreeMap<PathBuf, SystemTime>
+where
+    P: AsRef<Path>,
+{
+    fn walk(dir: &Path, skip: &[&str], out: &mut BTreeMap<PathBuf, SystemTime>) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let ty = entry.file_type()?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with('.') {
+                continue;
+            }
+            if ty.is_dir() {
+                if !skip.contains(&&*name) {
+                    walk(&path, skip, out)?;
+                }
+            } else if ty.is_file() {
+                out.insert(path, entry.metadata()?.modified()?);
+            }
+        }
+        Ok(())
+    }
+
+    let mut out = BTreeMap::new();
+    for dir in dirs {
+        let dir = dir.as_ref();
+        if let Ok(modified) = fs::metadata(dir).and_then(|m| m.modified())
+            && dir.is_file()
+        {
+            out.insert(dir.to_owned(), modified);
+            continue;
+        }
+        // a directory that is missing or being rewritten shows up next time
+        let _ = walk(dir, skip, &mut out);
+    }
+    out
+}
+
+/// Polls directories for files that change, appear or go away.
+pub struct Watcher {
+    dirs: Vec<PathBuf>,
+    interval: Duration,
+    last: BTreeMap<PathBuf, SystemTime>,
+}
+
+impl Watcher {
+    pub fn new(dirs: Vec<PathBuf>, interval: Duration) -> Self {
+        let last = snapshot(&dirs, SKIP);
+        Watcher {
+            dirs,
+            interval,
+            last,
+        }
+    }
+
+    pub fn files(&self) -> usize {
+        self.last.len()
+    }
+
+    /// Blocks until something under the directories changes, returns the
+    /// changed paths, or `None` on Ctrl-C.
+    pub fn wait(&mut self) -> Option<Vec<PathBuf>> {
+        loop {
+            thread::sleep(self.interval);
+            if interrupt::interrupted() {
+                return None;
+            }
+            let now = snapshot(&self.dirs, SKIP);
+            let mut changed = now
+                .iter()
+                .filter(|(path, time)| self.last.get(*path) != Some(time))
+                .map(|(path, _)| path.clone())
+                .collect::<Vec<_>>();
+            changed.extend(self.last.keys().filter(|p| !now.contains_key(*p)).cloned());
+            self.last = now;
+            if !changed.is_empty() {
+                return Some(changed);
+            }
+        }
+    }
+}
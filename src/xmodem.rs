@@ -0,0 +1,96 @@
+//! XMODEM-CRC sender, as spoken by the JH7110 boot ROM in UART boot mode.
+
+use std::{
+    io::{self, Write},
+    time::Duration,
+};
+
+use thiserror::Error;
+
+use crate::{interrupt, serial::Port};
+
+#[derive(Debug, Error)]
+pub enum XmodemError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error(
+        "the receiver didn't ask for data in {0}s; set the boot switches to UART and reset the board"
+    )]
+    NoReceiver(u64),
+    #[error("block {0} was rejected too many times")]
+    Retries(usize),
+    #[error("the receiver cancelled the transfer")]
+    Cancelled,
+    #[error("interrupted")]
+    Interrupted,
+}
+
+const SOH: u8 = 0x01;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const CAN: u8 = 0x18;
+/// Asks for the CRC variant.
+const CRC_MODE: u8 = b'C';
+/// Pads the last block.
+const SUB: u8 = 0x1a;
+
+const BLOCK: usize = 128;
+const RETRIES: usize = 10;
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sends `data`, waiting up to `wait` for the receiver to start.
+pub fn send(port: &mut Port, data: &[u8], wait: Duration) -> Result<(), XmodemError> {
+    let crc = crc::Crc::<u16>::new(&crc::CRC_16_XMODEM);
+
+    let started = std::time::Instant::now();
+    loop {
+        if interrupt::interrupted() {
+            return Err(XmodemError::Interrupted);
+        }
+        if started.elapsed() > wait {
+            return Err(XmodemError::NoReceiver(wait.as_secs()));
+        }
+        // the ROM keeps printing `C` while it waits, other bytes are its banner
+        if port.read_byte(Duration::from_secs(1))? == Some(CRC_MODE) {
+            break;
+        }
+    }
+
+    let blocks = data.len().div_ceil(BLOCK);
+    for (i, chunk) in data.chunks(BLOCK).enumerate() {
+        let mut payload = [SUB; BLOCK];
+        payload[..chunk.len()].copy_from_slice(chunk);
+        let n = (i + 1) as u8;
+        let mut packet = vec![SOH, n, !n];
+        packet.extend_from_slice(&payload);
+        packet.extend_from_slice(&crc.checksum(&payload).to_be_bytes());
+
+        let mut tries = 0;
+        loop {
+            if interrupt::interrupted() {
+                port.file.write_all(&[CAN, CAN])?;
+                return Err(XmodemError::Interrupted);
+            }
+            port.file.write_all(&packet)?;
+            match port.read_byte(REPLY_TIMEOUT)? {
+                Some(ACK) => break,
+                Some(CAN) => return Err(XmodemError::Cancelled),
+                // NAK, a late `C`, noise or nothing, all mean resend
+                _ => tries += 1,
+            }
+            if tries == RETRIES {
+                return Err(XmodemError::Retries(i + 1));
+            }
+        }
+        eprint!("\rsent block {}/{blocks}", i + 1);
+    }
+    eprintln!();
+
+    for _ in 0..RETRIES {
+        port.file.write_all(&[EOT])?;
+        if port.read_byte(REPLY_TIMEOUT)? == Some(ACK) {
+            return Ok(());
+        }
+    }
+    Err(XmodemError::Retries(blocks + 1))
+}